@@ -0,0 +1,472 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use tokio::sync::Mutex as AsyncMutex;
+use url::Url;
+
+use crate::mpd::emulate::{ack, fallback, parse_index, parse_range, parse_song, parse_time, Changes, EmulatedQueue, Emulator};
+use crate::mpd::protocol::{AckCode, Attributes};
+use crate::subsonic::types::{JukeboxPlaylist, JukeboxStatus, TrackId};
+use crate::subsonic::{AuthParams, Subsonic, SubsonicBase};
+
+// how often idle polls the jukebox for changes
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// how far the position may drift from where we expect it to be before we
+// take it that someone has seeked
+const SEEK_TOLERANCE: f64 = 2.0;
+
+#[derive(Clone)]
+pub struct Config {
+    pub url: Url,
+    pub username: String,
+    pub password: String,
+    pub http: reqwest::Client,
+}
+
+/// stands in for mpd on hosts without it, by speaking enough of mpd's
+/// protocol on top of subsonic's jukebox api. the jukebox plays through the
+/// subsonic server's own audio device, and has no partitions, outputs,
+/// stored playlists or playback options
+pub struct Jukebox {
+    subsonic: Subsonic,
+    queue: AsyncMutex<Queue>,
+    changes: Changes,
+    /// jukebox status as of the last poll, and when that was
+    polled: Mutex<Option<(JukeboxStatus, Instant)>>,
+    /// whether the last request to subsonic succeeded
+    healthy: AtomicBool,
+}
+
+// the jukebox only knows the track ids of its playlist, mpd's song ids and
+// the locations they were added by are ours to keep
+#[derive(Default)]
+struct Queue {
+    items: Vec<Item>,
+    next_id: u64,
+    version: u32,
+    /// the jukebox has no stopped state, only paused
+    stopped: bool,
+    /// whether the jukebox's playlist has been loaded, see Jukebox::sync
+    synced: bool,
+}
+
+struct Item {
+    id: u64,
+    file: String,
+    track: TrackId,
+    duration: Option<f64>,
+}
+
+impl Queue {
+    fn position(&self, id: &str) -> Result<usize> {
+        let id = id.parse::<u64>().map_err(|_| ack(AckCode::Arg, "invalid song id"))?;
+
+        self.items.iter()
+            .position(|item| item.id == id)
+            .ok_or_else(|| ack(AckCode::NoExist, "no such song"))
+    }
+
+    fn current(&self, status: &JukeboxStatus) -> Option<usize> {
+        usize::try_from(status.current_index).ok()
+            .filter(|index| *index < self.items.len())
+    }
+}
+
+impl EmulatedQueue for Queue {
+    fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    fn item(&self, pos: usize) -> (&str, u64) {
+        let item = &self.items[pos];
+        (&item.file, item.id)
+    }
+}
+
+impl Jukebox {
+    pub fn new(config: &Config) -> Jukebox {
+        let auth = AuthParams::password(&config.username, &config.password);
+
+        Jukebox {
+            subsonic: SubsonicBase::new(&config.url, config.http.clone()).with_auth(Arc::new(auth)),
+            queue: Default::default(),
+            changes: Default::default(),
+            polled: Default::default(),
+            healthy: AtomicBool::new(true),
+        }
+    }
+
+    async fn run_command(&self, cmd: &str, args: &[&str]) -> Result<Attributes> {
+        let mut attrs = Attributes::default();
+        let mut queue = self.queue.lock().await;
+
+        // the jukebox may already have a playlist from before we started
+        if !queue.synced {
+            let playlist = self.playlist().await?;
+            self.sync(&mut queue, &playlist);
+        }
+
+        match (cmd, args) {
+            ("ping", []) => {}
+            ("status", []) => {
+                let status = self.control("status", &[]).await?;
+                self.status(&queue, &status, &mut attrs);
+            }
+            ("addid", [location, pos @ ..]) => {
+                let url = Url::parse(location).map_err(|_| ack(AckCode::NoExist, "not a url"))?;
+                let track = self.subsonic.track_id_from_stream_url(&url)
+                    .ok_or_else(|| ack(AckCode::NoExist, "not a stream url of this server"))?;
+                let duration = self.subsonic.get_track(&track).await?.details.duration;
+
+                let id = queue.next_id;
+                queue.next_id += 1;
+
+                let item = Item { id, file: location.to_string(), track, duration };
+
+                match pos {
+                    [] => {
+                        self.control("add", &[("id", &item.track.0)]).await?;
+                        queue.items.push(item);
+                    }
+                    [pos] => {
+                        let pos = parse_index(pos, queue.items.len())?;
+                        let status = self.control("status", &[]).await?;
+                        let current = queue.current(&status).map(|index| queue.items[index].id);
+                        queue.items.insert(pos, item);
+                        self.set(&queue, current, &status).await?;
+                    }
+                    _ => return Err(ack(AckCode::Arg, "too many arguments")),
+                }
+
+                attrs.push("Id", id);
+                self.playlist_changed(&mut queue);
+            }
+            ("clear", []) => {
+                self.control("clear", &[]).await?;
+                queue.items.clear();
+                self.playlist_changed(&mut queue);
+            }
+            ("delete", [range]) => {
+                let (start, end) = parse_range(range, queue.items.len())?;
+                for index in (start..end).rev() {
+                    self.control("remove", &[("index", &index.to_string())]).await?;
+                    queue.items.remove(index);
+                }
+                self.playlist_changed(&mut queue);
+            }
+            ("deleteid", [id]) => {
+                let index = queue.position(id)?;
+                self.control("remove", &[("index", &index.to_string())]).await?;
+                queue.items.remove(index);
+                self.playlist_changed(&mut queue);
+            }
+            ("move", [from, to]) => {
+                let from = parse_song(from, queue.items.len())?;
+                let to = parse_song(to, queue.items.len())?;
+
+                let status = self.control("status", &[]).await?;
+                let current = queue.current(&status).map(|index| queue.items[index].id);
+                let item = queue.items.remove(from);
+                queue.items.insert(to, item);
+                self.set(&queue, current, &status).await?;
+                self.playlist_changed(&mut queue);
+            }
+            ("shuffle", []) => {
+                self.control("shuffle", &[]).await?;
+
+                // the jukebox shuffled by its own lights, match our items up
+                // with its new order
+                let playlist = self.playlist().await?;
+                let mut items = std::mem::take(&mut queue.items);
+
+                for track in playlist.tracks {
+                    if let Some(index) = items.iter().position(|item| item.track.0 == track.id.0) {
+                        queue.items.push(items.remove(index));
+                    }
+                }
+
+                self.playlist_changed(&mut queue);
+            }
+            ("playlistid", [id]) => {
+                let pos = queue.position(id)?;
+                queue.attributes(&mut attrs, pos);
+            }
+            ("currentsong", []) => {
+                let status = self.control("status", &[]).await?;
+                if let Some(pos) = queue.current(&status) {
+                    queue.attributes(&mut attrs, pos);
+                }
+            }
+            ("play", []) => {
+                let status = self.control("status", &[]).await?;
+                if queue.current(&status).is_none() && !queue.items.is_empty() {
+                    self.skip(0, 0, true).await?;
+                } else {
+                    self.control("start", &[]).await?;
+                }
+                self.player_changed(&mut queue, false);
+            }
+            ("play", [pos]) => {
+                let pos = parse_song(pos, queue.items.len())?;
+                self.skip(pos, 0, true).await?;
+                self.player_changed(&mut queue, false);
+            }
+            ("playid", [id]) => {
+                let pos = queue.position(id)?;
+                self.skip(pos, 0, true).await?;
+                self.player_changed(&mut queue, false);
+            }
+            ("stop", []) => {
+                self.control("stop", &[]).await?;
+                self.player_changed(&mut queue, true);
+            }
+            ("pause", state) => {
+                let pause = match state {
+                    [] => self.control("status", &[]).await?.playing,
+                    ["1"] => true,
+                    ["0"] => false,
+                    _ => return Err(ack(AckCode::Arg, "boolean (0/1) expected")),
+                };
+
+                self.control(if pause { "stop" } else { "start" }, &[]).await?;
+                self.player_changed(&mut queue, false);
+            }
+            ("next" | "previous", []) => {
+                let status = self.control("status", &[]).await?;
+                let Some(current) = queue.current(&status) else {
+                    return Err(ack(AckCode::Arg, "not playing"));
+                };
+
+                let pos = match cmd {
+                    "next" => current + 1,
+                    _ => current.saturating_sub(1),
+                };
+
+                if pos < queue.items.len() {
+                    self.skip(pos, 0, status.playing).await?;
+                    self.player_changed(&mut queue, false);
+                } else {
+                    // off the end of the queue, as mpd does
+                    self.control("stop", &[]).await?;
+                    self.player_changed(&mut queue, true);
+                }
+            }
+            ("seek", [pos, time]) => {
+                let pos = parse_song(pos, queue.items.len())?;
+                let status = self.control("status", &[]).await?;
+                // the jukebox only seeks to whole seconds
+                self.skip(pos, parse_time(time)? as u64, status.playing).await?;
+                self.player_changed(&mut queue, false);
+            }
+            ("seekcur", [time]) => {
+                let status = self.control("status", &[]).await?;
+                let Some(current) = queue.current(&status) else {
+                    return Err(ack(AckCode::Arg, "not playing"));
+                };
+
+                self.skip(current, parse_time(time)? as u64, status.playing).await?;
+                self.player_changed(&mut queue, false);
+            }
+            ("setvol", [volume]) => {
+                let volume = volume.parse::<u8>().ok()
+                    .filter(|volume| *volume <= 100)
+                    .ok_or_else(|| ack(AckCode::Arg, "invalid volume"))?;
+
+                let gain = (f64::from(volume) / 100.0).to_string();
+                self.control("setGain", &[("gain", &gain)]).await?;
+                self.changes.push(&["mixer"]);
+            }
+            // options can't be changed, but can be set to what they already are
+            ("random" | "repeat" | "consume" | "single" | "crossfade", ["0"]) => {}
+            ("replay_gain_mode", ["off"]) => {}
+            ("random" | "repeat" | "consume" | "single" | "crossfade" | "replay_gain_mode", [_]) => {
+                return Err(ack(AckCode::Arg, "not supported by the jukebox"));
+            }
+            _ => fallback(cmd, args, &*queue, &mut attrs, "the jukebox")?,
+        }
+
+        Ok(attrs)
+    }
+
+    fn status(&self, queue: &Queue, status: &JukeboxStatus, attrs: &mut Attributes) {
+        let current = queue.current(status);
+
+        let state = match current {
+            Some(_) if status.playing => "play",
+            Some(_) if !queue.stopped => "pause",
+            _ => "stop",
+        };
+
+        attrs.push("volume", (status.gain * 100.0).round());
+        attrs.push("repeat", 0);
+        attrs.push("random", 0);
+        attrs.push("single", 0);
+        attrs.push("consume", 0);
+        attrs.push("playlist", queue.version);
+        attrs.push("playlistlength", queue.items.len());
+        attrs.push("state", state);
+
+        if let Some(current) = current {
+            let item = &queue.items[current];
+            attrs.push("song", current);
+            attrs.push("songid", item.id);
+
+            if state != "stop" {
+                attrs.push("elapsed", status.position);
+                if let Some(duration) = item.duration {
+                    attrs.push("duration", duration);
+                }
+            }
+        }
+    }
+
+    async fn control(&self, action: &str, params: &[(&str, &str)]) -> Result<JukeboxStatus> {
+        let result = self.subsonic.jukebox_control(action, params).await;
+        self.healthy.store(result.is_ok(), Ordering::SeqCst);
+        result
+    }
+
+    async fn playlist(&self) -> Result<JukeboxPlaylist> {
+        let result = self.subsonic.jukebox_playlist().await;
+        self.healthy.store(result.is_ok(), Ordering::SeqCst);
+        result
+    }
+
+    /// brings the queue in line with the jukebox's playlist, which others
+    /// may have changed. items keep their ids while their track is still
+    /// in the playlist, tracks we didn't add get new ones
+    fn sync(&self, queue: &mut Queue, playlist: &JukeboxPlaylist) {
+        queue.synced = true;
+
+        let unchanged = queue.items.len() == playlist.tracks.len()
+            && queue.items.iter().zip(&playlist.tracks).all(|(item, track)| item.track.0 == track.id.0);
+
+        if unchanged {
+            return;
+        }
+
+        let mut items = std::mem::take(&mut queue.items);
+
+        for track in &playlist.tracks {
+            let item = match items.iter().position(|item| item.track.0 == track.id.0) {
+                Some(index) => items.remove(index),
+                None => {
+                    let Ok(url) = self.subsonic.stream_url(&track.id) else { continue };
+                    let id = queue.next_id;
+                    queue.next_id += 1;
+                    Item { id, file: url.to_string(), track: track.id.clone(), duration: track.details.duration }
+                }
+            };
+
+            queue.items.push(item);
+        }
+
+        self.playlist_changed(queue);
+    }
+
+    /// jumps to a position in the playlist, leaving the jukebox playing or
+    /// paused as asked
+    async fn skip(&self, index: usize, offset: u64, playing: bool) -> Result<()> {
+        let index = index.to_string();
+        let offset = offset.to_string();
+        let status = self.control("skip", &[("index", &index), ("offset", &offset)]).await?;
+
+        if status.playing != playing {
+            self.control(if playing { "start" } else { "stop" }, &[]).await?;
+        }
+
+        Ok(())
+    }
+
+    /// replaces the jukebox playlist with the queue, then puts the track
+    /// which was current back to where it was
+    async fn set(&self, queue: &Queue, current: Option<u64>, status: &JukeboxStatus) -> Result<()> {
+        let ids = queue.items.iter()
+            .map(|item| ("id", item.track.0.as_str()))
+            .collect::<Vec<_>>();
+
+        self.control("set", &ids).await?;
+
+        if let Some(index) = current.and_then(|id| queue.items.iter().position(|item| item.id == id)) {
+            self.skip(index, status.position, status.playing).await?;
+        }
+
+        Ok(())
+    }
+
+    fn playlist_changed(&self, queue: &mut Queue) {
+        queue.version += 1;
+        self.changes.push(&["playlist", "player"]);
+    }
+
+    fn player_changed(&self, queue: &mut Queue, stopped: bool) {
+        queue.stopped = stopped;
+        self.changes.push(&["player"]);
+    }
+
+    /// compares the jukebox's playlist and status against the last poll,
+    /// for changes made by someone else or by the jukebox moving on to the
+    /// next track
+    async fn poll_status(&self) -> Result<()> {
+        // held while fetching, so that no command changes the playlist
+        // between it being fetched and synced
+        let mut queue = self.queue.lock().await;
+        let playlist = self.playlist().await?;
+        let status = playlist.status;
+        let now = Instant::now();
+
+        self.sync(&mut queue, &playlist);
+        drop(queue);
+
+        let previous = self.polled.lock().unwrap().replace((status, now));
+        let Some((previous, at)) = previous else { return Ok(()) };
+
+        let mut expected = previous.position as f64;
+        if previous.playing {
+            expected += now.duration_since(at).as_secs_f64();
+        }
+
+        if status.playing != previous.playing
+            || status.current_index != previous.current_index
+            || (status.position as f64 - expected).abs() > SEEK_TOLERANCE
+        {
+            self.changes.push(&["player"]);
+        }
+
+        if status.gain != previous.gain {
+            self.changes.push(&["mixer"]);
+        }
+
+        Ok(())
+    }
+}
+
+impl Emulator for Jukebox {
+    async fn run(&self, cmd: &str, args: &[&str]) -> Result<Attributes> {
+        self.run_command(cmd, args).await
+    }
+
+    fn changes(&self) -> &Changes {
+        &self.changes
+    }
+
+    // the jukebox api has no way to wait for changes
+    fn poll_interval(&self) -> Option<Duration> {
+        Some(POLL_INTERVAL)
+    }
+
+    async fn poll(&self) {
+        // a failed poll is as good as no change, the server may well be back
+        // by the next one
+        if let Err(err) = self.poll_status().await {
+            log::warn!("polling jukebox status: {err:?}");
+        }
+    }
+
+    fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::SeqCst)
+    }
+}
@@ -0,0 +1,121 @@
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use serde::Serialize;
+use url::Url;
+
+use crate::subsonic::types::Track;
+
+pub const DEFAULT_URL: &str = "https://api.listenbrainz.org/";
+
+#[derive(Clone)]
+pub struct Config {
+    pub url: Url,
+    /// user token from listenbrainz settings, listens are submitted as that
+    /// user no matter who is using sonicast
+    pub token: String,
+}
+
+#[derive(Clone)]
+pub struct ListenBrainz {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    client: reqwest::Client,
+    url: Url,
+    token: String,
+}
+
+#[derive(Serialize)]
+struct Submission<'a> {
+    listen_type: &'static str,
+    payload: [Listen<'a>; 1],
+}
+
+#[derive(Serialize)]
+struct Listen<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    listened_at: Option<u64>,
+    track_metadata: TrackMetadata<'a>,
+}
+
+#[derive(Serialize)]
+struct TrackMetadata<'a> {
+    artist_name: &'a str,
+    track_name: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    release_name: Option<&'a str>,
+    additional_info: AdditionalInfo<'a>,
+}
+
+#[derive(Serialize)]
+struct AdditionalInfo<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    recording_mbid: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duration_ms: Option<u64>,
+    submission_client: &'static str,
+    submission_client_version: &'static str,
+}
+
+impl ListenBrainz {
+    pub fn new(config: &Config) -> Self {
+        ListenBrainz {
+            inner: Arc::new(Inner {
+                client: reqwest::Client::new(),
+                url: config.url.clone(),
+                token: config.token.clone(),
+            }),
+        }
+    }
+
+    pub async fn playing_now(&self, track: &Track) -> Result<()> {
+        self.submit("playing_now", track, None).await
+    }
+
+    /// submits a listen of a track which started playing at the given time
+    pub async fn single(&self, track: &Track, started: SystemTime) -> Result<()> {
+        let listened_at = started.duration_since(UNIX_EPOCH)?.as_secs();
+        self.submit("single", track, Some(listened_at)).await
+    }
+
+    async fn submit(&self, listen_type: &'static str, track: &Track, listened_at: Option<u64>) -> Result<()> {
+        // listenbrainz requires both, there's nothing useful to submit
+        // without them anyway
+        let (Some(artist_name), Some(track_name)) = (&track.details.artist, &track.details.title) else {
+            return Ok(());
+        };
+
+        let submission = Submission {
+            listen_type,
+            payload: [Listen {
+                listened_at,
+                track_metadata: TrackMetadata {
+                    artist_name,
+                    track_name,
+                    release_name: track.details.album.as_deref(),
+                    additional_info: AdditionalInfo {
+                        recording_mbid: track.details.music_brainz_id.as_deref()
+                            .filter(|mbid| !mbid.is_empty()),
+                        duration_ms: track.details.duration
+                            .map(|secs| (secs * 1000.0) as u64),
+                        submission_client: "sonicast",
+                        submission_client_version: env!("CARGO_PKG_VERSION"),
+                    },
+                },
+            }],
+        };
+
+        let url = self.inner.url.join("1/submit-listens")?;
+
+        self.inner.client.post(url)
+            .header("Authorization", format!("Token {}", self.inner.token))
+            .json(&submission)
+            .send().await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,789 @@
+use std::hash::{BuildHasher, RandomState};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use rodio::mixer::Mixer;
+use rodio::source::EmptyCallback;
+use rodio::{Decoder, OutputStreamBuilder, Sink, Source};
+use tokio::sync::{mpsc, Mutex as AsyncMutex, Notify};
+
+use crate::mpd::emulate::{ack, fallback, parse_index, parse_range, parse_song, parse_time, Changes, EmulatedQueue, Emulator};
+use crate::mpd::protocol::{AckCode, Attributes};
+use crate::mpd::types::SingleMode;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// longest wait for the next part of a track, after which playback fails
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+// how far ahead of playback a stream without a length is downloaded. those
+// are radio streams, which can't be seeked in and never end, so what's been
+// played is dropped rather than kept
+const MAX_READ_AHEAD: usize = 4 * 1024 * 1024;
+
+/// stands in for mpd on small devices, by playing audio on the host itself.
+/// tracks are streamed from their urls and decoded with rodio (by way of
+/// symphonia) as they download, then played on the default output device
+pub struct Local {
+    http: reqwest::Client,
+    mixer: Mixer,
+    state: AsyncMutex<State>,
+    changes: Changes,
+    /// sent the generation of each track which plays through to its end
+    ended: mpsc::UnboundedSender<u64>,
+    /// for loading tracks in the background, see load
+    this: Weak<Local>,
+}
+
+struct State {
+    items: Vec<Item>,
+    next_id: u64,
+    version: u32,
+    /// id of the current song, which stays current while stopped
+    current: Option<u64>,
+    /// none while stopped
+    playing: Option<Playing>,
+    /// bumped with each track loaded into a sink, so that a track replaced
+    /// before it finished isn't taken to have ended
+    generation: u64,
+    volume: u8,
+    repeat: bool,
+    random: bool,
+    consume: bool,
+    single: SingleMode,
+    /// why the last track failed to load, until cleared
+    error: Option<String>,
+}
+
+struct Item {
+    id: u64,
+    file: String,
+}
+
+struct Playing {
+    /// none while the track is loading
+    sink: Option<Sink>,
+    paused: bool,
+    /// kept for seeking within the track, unless it's a stream which can't
+    /// be seeked in
+    download: Option<Arc<Download>>,
+    /// where in the track the sink started playing from
+    offset: f64,
+    duration: Option<f64>,
+}
+
+impl Playing {
+    fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+
+        if let Some(sink) = &self.sink {
+            match paused {
+                true => sink.pause(),
+                false => sink.play(),
+            }
+        }
+    }
+}
+
+impl State {
+    fn position(&self, id: &str) -> Result<usize> {
+        let id = id.parse::<u64>().map_err(|_| ack(AckCode::Arg, "invalid song id"))?;
+        self.index_of(id).ok_or_else(|| ack(AckCode::NoExist, "no such song"))
+    }
+
+    fn index_of(&self, id: u64) -> Option<usize> {
+        self.items.iter().position(|item| item.id == id)
+    }
+
+    fn current_index(&self) -> Option<usize> {
+        self.current.and_then(|id| self.index_of(id))
+    }
+
+    fn elapsed(&self) -> Option<f64> {
+        let playing = self.playing.as_ref()?;
+        let played = playing.sink.as_ref().map_or(0.0, |sink| sink.get_pos().as_secs_f64());
+        Some(playing.offset + played)
+    }
+
+    fn is_paused(&self) -> bool {
+        self.playing.as_ref().is_some_and(|playing| playing.paused)
+    }
+
+    // the song after the current one, as mpd would choose it
+    fn next_index(&self, current: usize) -> Option<usize> {
+        let len = self.items.len();
+
+        if self.random && len > 1 {
+            // anything but the current song
+            return Some((current + 1 + random(len - 1)) % len);
+        }
+
+        match current + 1 {
+            next if next < len => Some(next),
+            _ if self.repeat && len > 0 => Some(0),
+            _ => None,
+        }
+    }
+}
+
+impl EmulatedQueue for State {
+    fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    fn item(&self, pos: usize) -> (&str, u64) {
+        let item = &self.items[pos];
+        (&item.file, item.id)
+    }
+}
+
+impl Local {
+    /// opens the default output device, which stays open for as long as
+    /// sonicast runs
+    pub fn open() -> Result<Arc<Local>> {
+        let (tx, rx) = std::sync::mpsc::channel();
+
+        // the output stream can't be sent between threads, so it lives out
+        // its life on one of its own
+        std::thread::Builder::new()
+            .name("audio output".to_string())
+            .spawn(move || {
+                match OutputStreamBuilder::open_default_stream() {
+                    Ok(mut stream) => {
+                        stream.log_on_drop(false);
+                        let _ = tx.send(Ok(stream.mixer().clone()));
+
+                        loop {
+                            std::thread::park();
+                        }
+                    }
+                    Err(err) => {
+                        let _ = tx.send(Err(err));
+                    }
+                }
+            })?;
+
+        let mixer = rx.recv()?.context("opening audio output")?;
+        let (ended, ended_rx) = mpsc::unbounded_channel();
+
+        // no timeout for the whole request, tracks take as long as they take
+        // to play, and radio streams forever
+        let http = reqwest::Client::builder()
+            .connect_timeout(CONNECT_TIMEOUT)
+            .read_timeout(READ_TIMEOUT)
+            .build()?;
+
+        let local = Arc::new_cyclic(|this| Local {
+            http,
+            mixer,
+            state: AsyncMutex::new(State {
+                items: Vec::new(),
+                next_id: 0,
+                version: 0,
+                current: None,
+                playing: None,
+                generation: 0,
+                volume: 100,
+                repeat: false,
+                random: false,
+                consume: false,
+                single: SingleMode::Off,
+                error: None,
+            }),
+            changes: Default::default(),
+            ended,
+            this: this.clone(),
+        });
+
+        tokio::task::spawn(ended_task(Arc::downgrade(&local), ended_rx));
+
+        Ok(local)
+    }
+
+    async fn run_command(&self, cmd: &str, args: &[&str]) -> Result<Attributes> {
+        let mut attrs = Attributes::default();
+        let mut state = self.state.lock().await;
+
+        match (cmd, args) {
+            ("ping", []) => {}
+            ("status", []) => self.status(&state, &mut attrs),
+            ("addid", [location, pos @ ..]) => {
+                let id = state.next_id;
+                state.next_id += 1;
+
+                let item = Item { id, file: location.to_string() };
+
+                match pos {
+                    [] => state.items.push(item),
+                    [pos] => {
+                        let pos = parse_index(pos, state.items.len())?;
+                        state.items.insert(pos, item);
+                    }
+                    _ => return Err(ack(AckCode::Arg, "too many arguments")),
+                }
+
+                attrs.push("Id", id);
+                self.playlist_changed(&mut state);
+            }
+            ("clear", []) => {
+                state.items.clear();
+                self.stop(&mut state);
+                self.playlist_changed(&mut state);
+            }
+            ("delete", [range]) => {
+                let (start, end) = parse_range(range, state.items.len())?;
+                let current = state.current_index();
+                state.items.drain(start..end);
+
+                if current.is_some_and(|current| (start..end).contains(&current)) {
+                    self.removed_current(&mut state, start);
+                }
+
+                self.playlist_changed(&mut state);
+            }
+            ("deleteid", [id]) => {
+                let pos = state.position(id)?;
+                let current = state.current_index();
+                state.items.remove(pos);
+
+                if current == Some(pos) {
+                    self.removed_current(&mut state, pos);
+                }
+
+                self.playlist_changed(&mut state);
+            }
+            ("move", [from, to]) => {
+                let from = parse_song(from, state.items.len())?;
+                let to = parse_song(to, state.items.len())?;
+
+                let item = state.items.remove(from);
+                state.items.insert(to, item);
+                self.playlist_changed(&mut state);
+            }
+            ("shuffle", []) => {
+                for index in (1..state.items.len()).rev() {
+                    state.items.swap(index, random(index + 1));
+                }
+
+                self.playlist_changed(&mut state);
+            }
+            ("playlistid", [id]) => {
+                let pos = state.position(id)?;
+                state.attributes(&mut attrs, pos);
+            }
+            ("currentsong", []) => {
+                if let Some(pos) = state.current_index() {
+                    state.attributes(&mut attrs, pos);
+                }
+            }
+            ("play", []) => {
+                if let Some(playing) = &mut state.playing {
+                    playing.set_paused(false);
+                } else if !state.items.is_empty() {
+                    let pos = state.current_index().unwrap_or(0);
+                    self.play(&mut state, pos, 0.0, false);
+                }
+
+                self.changes.push(&["player"]);
+            }
+            ("play", [pos]) => {
+                let pos = parse_song(pos, state.items.len())?;
+                self.play(&mut state, pos, 0.0, false);
+                self.changes.push(&["player"]);
+            }
+            ("playid", [id]) => {
+                let pos = state.position(id)?;
+                self.play(&mut state, pos, 0.0, false);
+                self.changes.push(&["player"]);
+            }
+            ("stop", []) => {
+                self.stop(&mut state);
+                self.changes.push(&["player"]);
+            }
+            ("pause", pause) => {
+                let Some(playing) = &mut state.playing else { return Ok(attrs) };
+
+                let pause = match pause {
+                    [] => !playing.paused,
+                    ["1"] => true,
+                    ["0"] => false,
+                    _ => return Err(ack(AckCode::Arg, "boolean (0/1) expected")),
+                };
+
+                playing.set_paused(pause);
+                self.changes.push(&["player"]);
+            }
+            ("next" | "previous", []) => {
+                let Some(current) = state.current_index().filter(|_| state.playing.is_some()) else {
+                    return Err(ack(AckCode::Arg, "not playing"));
+                };
+
+                let pos = match cmd {
+                    "next" => state.next_index(current),
+                    _ => Some(current.saturating_sub(1)),
+                };
+
+                match pos {
+                    Some(pos) => {
+                        let paused = state.is_paused();
+                        self.play(&mut state, pos, 0.0, paused);
+                    }
+                    None => self.stop(&mut state),
+                }
+
+                self.changes.push(&["player"]);
+            }
+            ("seek", [pos, time]) => {
+                let pos = parse_song(pos, state.items.len())?;
+                let time = parse_time(time)?;
+                let paused = state.is_paused();
+                self.play(&mut state, pos, time, paused);
+                self.changes.push(&["player"]);
+            }
+            ("seekcur", [time]) => {
+                let Some(current) = state.current_index().filter(|_| state.playing.is_some()) else {
+                    return Err(ack(AckCode::Arg, "not playing"));
+                };
+
+                let time = parse_time(time)?;
+                let paused = state.is_paused();
+                self.play(&mut state, current, time, paused);
+                self.changes.push(&["player"]);
+            }
+            ("setvol", [volume]) => {
+                state.volume = volume.parse::<u8>().ok()
+                    .filter(|volume| *volume <= 100)
+                    .ok_or_else(|| ack(AckCode::Arg, "invalid volume"))?;
+
+                if let Some(sink) = state.playing.as_ref().and_then(|playing| playing.sink.as_ref()) {
+                    sink.set_volume(f32::from(state.volume) / 100.0);
+                }
+
+                self.changes.push(&["mixer"]);
+            }
+            ("repeat" | "random" | "consume", [value]) => {
+                let value = match *value {
+                    "0" => false,
+                    "1" => true,
+                    _ => return Err(ack(AckCode::Arg, "boolean (0/1) expected")),
+                };
+
+                match cmd {
+                    "repeat" => state.repeat = value,
+                    "random" => state.random = value,
+                    _ => state.consume = value,
+                }
+
+                self.changes.push(&["options"]);
+            }
+            ("single", [mode]) => {
+                let mode = mode.parse::<SingleMode>()
+                    .map_err(|_| ack(AckCode::Arg, "unrecognized single mode"))?;
+
+                state.single = mode;
+                self.changes.push(&["options"]);
+            }
+            ("clearerror", []) => {
+                state.error = None;
+                self.changes.push(&["player"]);
+            }
+            // tracks are played one at a time as they come, so there's
+            // nothing to crossfade with, and no replay gain
+            ("crossfade", ["0"]) | ("replay_gain_mode", ["off"]) => {}
+            ("crossfade" | "replay_gain_mode", [_]) => {
+                return Err(ack(AckCode::Arg, "not supported by local playback"));
+            }
+            _ => fallback(cmd, args, &*state, &mut attrs, "local playback")?,
+        }
+
+        Ok(attrs)
+    }
+
+    fn status(&self, state: &State, attrs: &mut Attributes) {
+        let single = match state.single {
+            SingleMode::Off => "0",
+            SingleMode::On => "1",
+            SingleMode::Oneshot => "oneshot",
+        };
+
+        let player = match &state.playing {
+            Some(playing) if playing.paused => "pause",
+            Some(_) => "play",
+            None => "stop",
+        };
+
+        attrs.push("volume", state.volume);
+        attrs.push("repeat", u8::from(state.repeat));
+        attrs.push("random", u8::from(state.random));
+        attrs.push("single", single);
+        attrs.push("consume", u8::from(state.consume));
+        attrs.push("playlist", state.version);
+        attrs.push("playlistlength", state.items.len());
+        attrs.push("state", player);
+
+        if let Some(current) = state.current_index() {
+            attrs.push("song", current);
+            attrs.push("songid", state.items[current].id);
+        }
+
+        if let Some(elapsed) = state.elapsed() {
+            attrs.push("elapsed", format!("{elapsed:.3}"));
+        }
+
+        if let Some(duration) = state.playing.as_ref().and_then(|playing| playing.duration) {
+            attrs.push("duration", format!("{duration:.3}"));
+        }
+
+        if let Some(error) = &state.error {
+            attrs.push("error", error);
+        }
+    }
+
+    /// starts playing the song at pos from offset seconds in, or leaves it
+    /// paused there. the track loads in the background, and as with mpd, a
+    /// track which fails to play shows up as an error in status
+    fn play(&self, state: &mut State, pos: usize, offset: f64, paused: bool) {
+        let id = state.items[pos].id;
+        let file = state.items[pos].file.clone();
+
+        // seeking within the current track needn't download it again
+        let download = match &state.playing {
+            Some(playing) if state.current == Some(id) => playing.download.clone(),
+            _ => None,
+        };
+
+        state.generation += 1;
+        state.current = Some(id);
+        state.error = None;
+        state.playing = Some(Playing { sink: None, paused, download: download.clone(), offset, duration: None });
+
+        let Some(this) = self.this.upgrade() else { return };
+        tokio::task::spawn(load(this, state.generation, file, download, offset));
+    }
+
+    /// plays a loaded track, unless it was replaced while loading
+    fn loaded(&self, state: &mut State, generation: u64, loaded: Loaded) {
+        if state.generation != generation {
+            return;
+        }
+
+        let volume = f32::from(state.volume) / 100.0;
+        let Some(playing) = &mut state.playing else { return };
+        let ended = self.ended.clone();
+
+        // a new sink for each track, replacing the last stops it
+        let sink = Sink::connect_new(&self.mixer);
+        sink.pause();
+        sink.set_volume(volume);
+        sink.append(loaded.decoder);
+        sink.append(EmptyCallback::new(Box::new(move || {
+            let _ = ended.send(generation);
+        })));
+
+        if !playing.paused {
+            sink.play();
+        }
+
+        playing.sink = Some(sink);
+        playing.download = loaded.download.is_seekable().then_some(loaded.download);
+        playing.duration = loaded.duration;
+    }
+
+    fn stop(&self, state: &mut State) {
+        state.playing = None;
+        state.generation += 1;
+    }
+
+    // as with mpd, removing the song being played moves on to the one which
+    // took its place
+    fn removed_current(&self, state: &mut State, pos: usize) {
+        state.current = None;
+
+        match &state.playing {
+            Some(_) if pos < state.items.len() => {
+                let paused = state.is_paused();
+                self.play(state, pos, 0.0, paused);
+            }
+            _ => self.stop(state),
+        }
+
+        self.changes.push(&["player"]);
+    }
+
+    fn playlist_changed(&self, state: &mut State) {
+        state.version += 1;
+        self.changes.push(&["playlist"]);
+    }
+
+    /// moves on from a track which played through to its end
+    async fn track_ended(&self, generation: u64) {
+        let mut state = self.state.lock().await;
+
+        if state.generation != generation {
+            return;
+        }
+
+        let Some(current) = state.current_index() else {
+            self.stop(&mut state);
+            return;
+        };
+
+        let mut next = match state.single {
+            SingleMode::On if state.repeat => Some(current),
+            SingleMode::On | SingleMode::Oneshot => None,
+            SingleMode::Off => state.next_index(current),
+        };
+
+        if state.single == SingleMode::Oneshot {
+            state.single = SingleMode::Off;
+            self.changes.push(&["options"]);
+        }
+
+        if state.consume {
+            state.items.remove(current);
+            state.current = None;
+            self.playlist_changed(&mut state);
+
+            next = match next {
+                Some(next) if next > current => Some(next - 1),
+                Some(next) if next < current => Some(next),
+                _ => None,
+            };
+        }
+
+        match next {
+            Some(next) => self.play(&mut state, next, 0.0, false),
+            None => self.stop(&mut state),
+        }
+
+        self.changes.push(&["player"]);
+    }
+}
+
+async fn ended_task(local: Weak<Local>, mut ended: mpsc::UnboundedReceiver<u64>) {
+    while let Some(generation) = ended.recv().await {
+        let Some(local) = local.upgrade() else { break };
+        local.track_ended(generation).await;
+    }
+}
+
+impl Emulator for Local {
+    async fn run(&self, cmd: &str, args: &[&str]) -> Result<Attributes> {
+        self.run_command(cmd, args).await
+    }
+
+    fn changes(&self) -> &Changes {
+        &self.changes
+    }
+}
+
+struct Loaded {
+    decoder: Decoder<StreamReader>,
+    download: Arc<Download>,
+    duration: Option<f64>,
+}
+
+// fetches and starts decoding a track, without holding up other commands
+async fn load(local: Arc<Local>, generation: u64, file: String, download: Option<Arc<Download>>, offset: f64) {
+    let result = open(&local.http, &file, download, offset).await;
+    let mut state = local.state.lock().await;
+
+    if state.generation != generation {
+        return;
+    }
+
+    match result {
+        Ok(loaded) => local.loaded(&mut state, generation, loaded),
+        Err(err) => {
+            log::warn!("local playback: loading {file}: {err:?}");
+            state.error = Some(format!("{file}: {err:#}"));
+            local.stop(&mut state);
+        }
+    }
+
+    local.changes.push(&["player"]);
+}
+
+async fn open(http: &reqwest::Client, file: &str, download: Option<Arc<Download>>, offset: f64) -> Result<Loaded> {
+    let download = match download {
+        Some(download) => download,
+        None => Download::start(http, file).await?,
+    };
+
+    let reader = StreamReader { download: download.clone(), pos: 0 };
+    let (seekable, len) = (download.is_seekable(), download.len);
+
+    // decoding reads the start of the track, which may not have arrived yet
+    let mut decoder = tokio::task::spawn_blocking(move || {
+        let mut builder = Decoder::builder()
+            .with_data(reader)
+            .with_seekable(seekable);
+
+        if let Some(len) = len {
+            builder = builder.with_byte_len(len);
+        }
+
+        builder.build()
+    }).await?.context("decoding")?;
+
+    let duration = decoder.total_duration().map(|duration| duration.as_secs_f64());
+
+    if offset > 0.0 {
+        decoder = tokio::task::spawn_blocking(move || {
+            decoder.try_seek(Duration::from_secs_f64(offset))
+                .map(|()| decoder)
+                .map_err(|err| anyhow::anyhow!("seeking: {err}"))
+        }).await??;
+    }
+
+    Ok(Loaded { decoder, download, duration })
+}
+
+/// a track as it downloads, read by the decoder on the audio thread, which
+/// waits for whatever hasn't arrived yet
+struct Download {
+    buffer: Mutex<Buffer>,
+    /// notified as data arrives
+    arrived: Condvar,
+    /// notified as data is dropped, see MAX_READ_AHEAD
+    drained: Notify,
+    /// known for files, not for radio streams
+    len: Option<u64>,
+}
+
+#[derive(Default)]
+struct Buffer {
+    data: Vec<u8>,
+    /// offset into the track of the start of data
+    start: u64,
+    done: bool,
+    error: Option<String>,
+}
+
+impl Download {
+    async fn start(http: &reqwest::Client, file: &str) -> Result<Arc<Download>> {
+        let response = http.get(file).send().await
+            .and_then(|response| response.error_for_status())
+            .context("fetching")?;
+
+        let download = Arc::new(Download {
+            buffer: Mutex::default(),
+            arrived: Condvar::new(),
+            drained: Notify::new(),
+            len: response.content_length(),
+        });
+
+        tokio::task::spawn(download_task(response, download.clone()));
+        Ok(download)
+    }
+
+    fn is_seekable(&self) -> bool {
+        self.len.is_some()
+    }
+}
+
+async fn download_task(mut response: reqwest::Response, download: Arc<Download>) {
+    loop {
+        // nobody is left to read it
+        if Arc::strong_count(&download) == 1 {
+            break;
+        }
+
+        let buffered = download.buffer.lock().unwrap().data.len();
+
+        if !download.is_seekable() && buffered >= MAX_READ_AHEAD {
+            // checked again now and then, in case the reader went away
+            let _ = tokio::time::timeout(READ_TIMEOUT, download.drained.notified()).await;
+            continue;
+        }
+
+        let chunk = response.chunk().await;
+        let mut buffer = download.buffer.lock().unwrap();
+
+        match chunk {
+            Ok(Some(chunk)) => buffer.data.extend_from_slice(&chunk),
+            Ok(None) => buffer.done = true,
+            Err(err) => {
+                buffer.error = Some(err.to_string());
+                buffer.done = true;
+            }
+        }
+
+        download.arrived.notify_all();
+
+        if buffer.done {
+            break;
+        }
+    }
+}
+
+struct StreamReader {
+    download: Arc<Download>,
+    pos: u64,
+}
+
+impl Read for StreamReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let download = &self.download;
+        let mut buffer = download.buffer.lock().unwrap();
+
+        loop {
+            let offset = (self.pos - buffer.start) as usize;
+
+            if offset < buffer.data.len() {
+                let n = buf.len().min(buffer.data.len() - offset);
+                buf[..n].copy_from_slice(&buffer.data[offset..offset + n]);
+                self.pos += n as u64;
+
+                if !download.is_seekable() {
+                    buffer.data.drain(..offset + n);
+                    buffer.start = self.pos;
+                    download.drained.notify_one();
+                }
+
+                return Ok(n);
+            }
+
+            if let Some(error) = &buffer.error {
+                return Err(io::Error::other(error.clone()));
+            }
+
+            if buffer.done {
+                return Ok(0);
+            }
+
+            buffer = download.arrived.wait(buffer).unwrap();
+        }
+    }
+}
+
+impl Seek for StreamReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let unsupported = || io::Error::new(io::ErrorKind::Unsupported, "can't seek in a stream");
+
+        let pos = match (pos, self.download.len) {
+            (SeekFrom::Start(pos), _) => Some(pos),
+            (SeekFrom::Current(delta), _) => self.pos.checked_add_signed(delta),
+            (SeekFrom::End(delta), Some(len)) => len.checked_add_signed(delta),
+            (SeekFrom::End(_), None) => return Err(unsupported()),
+        };
+
+        let pos = pos.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek before start"))?;
+
+        // what's been played of a stream is gone
+        if !self.download.is_seekable() && pos != self.pos {
+            return Err(unsupported());
+        }
+
+        self.pos = pos;
+        Ok(pos)
+    }
+}
+
+// a random number below n, which is all shuffling needs
+fn random(n: usize) -> usize {
+    let random = RandomState::new().hash_one(());
+    (random % n as u64) as usize
+}
@@ -1,20 +1,47 @@
-use std::io::{self, IsTerminal, Write};
+use std::fmt;
+use std::io::IsTerminal;
 
-use env_logger::fmt::Formatter;
-use log::Record;
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::EnvFilter;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields};
+use tracing_subscriber::fmt::format::{FmtSpan, Writer};
+use tracing_subscriber::registry::LookupSpan;
 
-pub fn init() {
-    let mut builder = env_logger::builder();
+/// LOG_FORMAT=json logs json lines, otherwise human readable text. closing
+/// spans are logged with their timings, enable debug for commands and trace
+/// for individual mpd and subsonic calls within them
+pub fn init(level: Option<LevelFilter>) {
+    let mut filter = EnvFilter::builder()
+        .with_default_directive(default_log_level().into())
+        .from_env_lossy();
 
-    if under_systemd() {
-        builder.format(systemd_log_format);
+    // added after RUST_LOG so it takes precedence as the global level
+    if let Some(level) = level {
+        filter = filter.add_directive(level.into());
     }
 
-    builder
-        .format_timestamp_millis()
-        .filter_level(default_log_level())
-        .parse_default_env()
-        .init();
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_span_events(FmtSpan::CLOSE);
+
+    let json = std::env::var("LOG_FORMAT").is_ok_and(|format| format == "json");
+
+    if json {
+        builder.json().init();
+    } else if under_systemd() {
+        // journald timestamps lines itself
+        let format = tracing_subscriber::fmt::format()
+            .without_time()
+            .with_ansi(false);
+
+        builder
+            .with_ansi(false)
+            .event_format(SystemdFormat(format))
+            .init();
+    } else {
+        builder.init();
+    }
 }
 
 pub fn error(err: &anyhow::Error) {
@@ -22,28 +49,35 @@ pub fn error(err: &anyhow::Error) {
     log::error!("{}", err.backtrace());
 }
 
-fn default_log_level() -> log::LevelFilter {
+fn default_log_level() -> LevelFilter {
     if cfg!(debug_assertions) {
-        log::LevelFilter::Debug
+        LevelFilter::DEBUG
     } else {
-        log::LevelFilter::Info
+        LevelFilter::INFO
     }
 }
 
-fn systemd_log_format(buf: &mut Formatter, record: &Record) -> io::Result<()> {
-    writeln!(
-        buf,
-        "<{}>{}: {}",
-        match record.level() {
-            log::Level::Error => 3,
-            log::Level::Warn => 4,
-            log::Level::Info => 6,
-            log::Level::Debug => 7,
-            log::Level::Trace => 7,
-        },
-        record.target(),
-        record.args()
-    )
+// prefixes lines with their syslog priority, for journald
+struct SystemdFormat<F>(F);
+
+impl<S, N, F> FormatEvent<S, N> for SystemdFormat<F>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+    F: FormatEvent<S, N>,
+{
+    fn format_event(&self, ctx: &FmtContext<'_, S, N>, mut writer: Writer<'_>, event: &Event<'_>) -> fmt::Result {
+        let priority = match *event.metadata().level() {
+            Level::ERROR => 3,
+            Level::WARN => 4,
+            Level::INFO => 6,
+            Level::DEBUG => 7,
+            Level::TRACE => 7,
+        };
+
+        write!(writer, "<{priority}>")?;
+        self.0.format_event(ctx, writer, event)
+    }
 }
 
 fn under_systemd() -> bool {
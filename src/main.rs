@@ -1,64 +1,524 @@
-use std::env::VarError;
+use std::collections::HashMap;
 use std::fmt::Display;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
+use clap::{Parser, Subcommand};
 
+mod jukebox;
+mod listenbrainz;
+#[cfg(feature = "local")]
+mod local;
 mod logging;
 mod mpd;
 mod player;
 mod podcasts;
+mod snapcast;
 mod subsonic;
 mod util;
 
+#[derive(Parser)]
+#[command(version, about = "websocket player for subsonic, backed by mpd")]
+struct Cli {
+    /// address to listen on, or unix:<path> for a unix socket, overriding
+    /// SONICAST_LISTEN
+    #[arg(long, global = true)]
+    listen: Option<String>,
+
+    /// file of NAME=value lines, read for any config not set in the
+    /// environment
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+
+    /// log level, overriding the default but not RUST_LOG directives for
+    /// specific modules
+    #[arg(long, global = true)]
+    log_level: Option<tracing_subscriber::filter::LevelFilter>,
+
+    #[command(subcommand)]
+    command: Option<Cmd>,
+}
+
+#[derive(Subcommand)]
+enum Cmd {
+    /// run the player server, the default
+    Serve,
+    /// check config is complete and valid, then exit
+    CheckConfig,
+    /// connect to the mpd instance of every room, then exit
+    PingMpd,
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
-    logging::init();
+    let cli = Cli::parse();
+
+    logging::init(cli.log_level);
 
-    let config = config();
-    player::run(&config).await
+    let env = match &cli.config {
+        Some(path) => Env::from_file(path)?,
+        None => Env::default(),
+    };
+
+    let mut config = config(&env)?;
+
+    if let Some(listen) = cli.listen {
+        config.listen = Some(player::Listen::parse(&listen));
+    }
+
+    match cli.command.unwrap_or(Cmd::Serve) {
+        Cmd::Serve => player::run(&config).await,
+        Cmd::CheckConfig => {
+            check_config(&config);
+            Ok(())
+        }
+        Cmd::PingMpd => ping_mpd(&config).await,
+    }
 }
 
-fn config() -> player::Config {
-    player::Config {
-        listen: env("SONICAST_LISTEN"),
-        subsonic_url: env("SUBSONIC_URL"),
-        mpd: mpd(),
-        podcasts: podcasts(),
+fn check_config(config: &player::Config) {
+    println!("config ok");
+    match &config.listen {
+        Some(listen) => println!("listen: {listen}"),
+        None => println!("listen: systemd socket only"),
+    }
+    if let Some(tls) = &config.tls {
+        println!("tls: {}", tls.cert.display());
+    }
+    println!("subsonic: {}", config.subsonic_url);
+
+    for server in &config.servers {
+        println!("subsonic {}: {}", server.name, server.url);
+    }
+
+    for room in &config.rooms {
+        match &room.backend {
+            player::RoomBackend::Mpd(mpd) => println!("room {}: {}", room.name, mpd.socket.display()),
+            player::RoomBackend::Jukebox(jukebox) => println!("room {}: jukebox of {}", room.name, jukebox.url),
+            #[cfg(feature = "local")]
+            player::RoomBackend::Local => println!("room {}: local playback", room.name),
+        }
+    }
+
+    if let Some(podcasts) = &config.podcasts {
+        println!("podcasts: {}", podcasts.server_url);
     }
 }
 
-fn podcasts() -> Option<podcasts::Config> {
-    let server_url = opt_env("PODCASTS_URL")?;
+async fn ping_mpd(config: &player::Config) -> Result<()> {
+    let mut failed = false;
 
-    Some(podcasts::Config {
-        server_url,
-        episode_prefix: env("PODCAST_EPISODE_PREFIX"),
+    for room in &config.rooms {
+        let result = async {
+            let mpd = match &room.backend {
+                player::RoomBackend::Mpd(config) => mpd::Mpd::connect(config).await?,
+                player::RoomBackend::Jukebox(config) => mpd::Mpd::new(Arc::new(jukebox::Jukebox::new(config))),
+                #[cfg(feature = "local")]
+                player::RoomBackend::Local => mpd::Mpd::new(local::Local::open()?),
+            };
+            mpd.status().await
+        }.await;
+
+        match result {
+            Ok(status) => println!("room {}: ok, {:?}", room.name, status.state),
+            Err(err) => {
+                println!("room {}: {err:#}", room.name);
+                failed = true;
+            }
+        }
+    }
+
+    anyhow::ensure!(!failed, "could not reach mpd for every room");
+    Ok(())
+}
+
+fn config(env: &Env) -> Result<player::Config> {
+    let client = client_config(env)?;
+    let http = client.build()?;
+    let rooms = rooms(env, &http)?;
+    let stream_proxy = stream_proxy(env, &client)?;
+
+    let jukebox = rooms.iter().any(|room| matches!(room.backend, player::RoomBackend::Jukebox(_)));
+    anyhow::ensure!(!(jukebox && stream_proxy.is_some()),
+        "STREAM_PROXY_URL can't be used with the jukebox, which plays from subsonic directly");
+
+    Ok(player::Config {
+        listen: env.opt::<String>("SONICAST_LISTEN")?
+            .map(|listen| player::Listen::parse(&listen)),
+        tls: tls(env)?,
+        subsonic_url: env.get("SUBSONIC_URL")?,
+        http: http.clone(),
+        stream_proxy,
+        servers: servers(env)?,
+        external_sources: env.opt("EXTERNAL_SOURCES")?.unwrap_or_default(),
+        rooms,
+        podcasts: podcasts(env, &http)?,
+        resolve_concurrency: env.opt("RESOLVE_CONCURRENCY")?
+            .unwrap_or(player::DEFAULT_RESOLVE_CONCURRENCY),
+        rate_proxy: env.opt("RATE_PROXY_URL")?,
+        alarms: env.opt("ALARMS")?.unwrap_or_default(),
+        state_file: env.opt("STATE_FILE")?,
+        restore_state: env.opt("RESTORE_STATE")?.unwrap_or(true),
+        listenbrainz: listenbrainz(env)?,
+        scrobble_subsonic: env.opt("SCROBBLE_SUBSONIC")?.unwrap_or(true),
+        mqtt: mqtt(env)?,
+        snapcast: snapcast(env)?,
+        upnp: env.opt("UPNP_URL")?
+            .map(|base_url| player::UpnpConfig { base_url }),
+        volume_limits: volume_limits(env)?,
+        announce_volume: env.opt("ANNOUNCE_VOLUME")?,
+        bookmark_prefixes: bookmark_prefixes(env)?,
+        skip_offsets: env.opt("SKIP_OFFSETS")?.unwrap_or_default(),
+        history_file: env.opt("HISTORY_FILE")?,
+        tag_queue: env.opt("TAG_QUEUE")?.unwrap_or(false),
+        skip_unavailable: env.opt("SKIP_UNAVAILABLE")?.unwrap_or(false),
+        guests: guests(env)?,
+        event_retention: env.opt("EVENT_RETENTION")?
+            .unwrap_or(player::DEFAULT_EVENT_RETENTION),
+        intervals: intervals(env)?,
     })
 }
 
-fn mpd() -> mpd::Config {
-    mpd::Config {
-        socket: env("MPD_SOCKET"),
+// PLAYBACK_POLL_INTERVAL is in seconds, the rest in milliseconds
+fn intervals(env: &Env) -> Result<player::Intervals> {
+    let defaults = player::Intervals::default();
+
+    let playback_poll = env.opt("PLAYBACK_POLL_INTERVAL")?
+        .map(Duration::from_secs)
+        .unwrap_or(defaults.playback_poll);
+    anyhow::ensure!(!playback_poll.is_zero(), "PLAYBACK_POLL_INTERVAL must be at least a second");
+
+    Ok(player::Intervals {
+        playback_poll,
+        queue_debounce: env.opt("QUEUE_DEBOUNCE_MS")?
+            .map(Duration::from_millis)
+            .unwrap_or(defaults.queue_debounce),
+        save_delay: env.opt("STATE_SAVE_DELAY_MS")?
+            .map(Duration::from_millis)
+            .unwrap_or(defaults.save_delay),
+    })
+}
+
+// timeouts are in seconds. SUBSONIC_ROOT_CA is a pem file, and each
+// SUBSONIC_HEADER_<NAME> is sent as a header, underscores in the name
+// becoming dashes
+fn client_config(env: &Env) -> Result<subsonic::ClientConfig> {
+    let mut headers = Vec::new();
+
+    for name in env.names() {
+        let Some(header) = name.strip_prefix("SUBSONIC_HEADER_") else { continue };
+        headers.push((header.replace('_', "-"), env.get(&name)?));
+    }
+
+    Ok(subsonic::ClientConfig {
+        timeout: env.opt("SUBSONIC_TIMEOUT")?.map(Duration::from_secs),
+        connect_timeout: env.opt("SUBSONIC_CONNECT_TIMEOUT")?.map(Duration::from_secs),
+        proxy: env.opt("SUBSONIC_PROXY")?,
+        root_ca: env.opt("SUBSONIC_ROOT_CA")?,
+        accept_invalid_certs: env.opt("SUBSONIC_ACCEPT_INVALID_CERTS")?.unwrap_or(false),
+        headers,
+    })
+}
+
+// STREAM_PROXY_URL is sonicast's own address as mpd reaches it, to stream
+// through sonicast rather than from subsonic directly. STREAM_PROXY_SECRET
+// keeps queued stream urls working across restarts
+fn stream_proxy(env: &Env, client: &subsonic::ClientConfig) -> Result<Option<subsonic::StreamProxyConfig>> {
+    let Some(url) = env.opt::<reqwest::Url>("STREAM_PROXY_URL")? else { return Ok(None) };
+    anyhow::ensure!(!url.cannot_be_a_base(), "STREAM_PROXY_URL must be an http url");
+
+    Ok(Some(subsonic::StreamProxyConfig {
+        url,
+        secret: env.opt("STREAM_PROXY_SECRET")?,
+        http: client.build_for_streams()?,
+    }))
+}
+
+fn tls(env: &Env) -> Result<Option<player::TlsConfig>> {
+    match (env.opt("TLS_CERT")?, env.opt("TLS_KEY")?) {
+        (Some(cert), Some(key)) => Ok(Some(player::TlsConfig { cert, key })),
+        (None, None) => Ok(None),
+        _ => anyhow::bail!("TLS_CERT and TLS_KEY must be set together"),
     }
 }
 
-fn env<T: FromStr<Err: Display>>(name: &str) -> T {
-    match opt_env(name) {
-        Some(value) => value,
-        None => { panic!("missing env var: {name}") }
+fn listenbrainz(env: &Env) -> Result<Option<listenbrainz::Config>> {
+    let Some(token) = env.opt("LISTENBRAINZ_TOKEN")? else { return Ok(None) };
+
+    Ok(Some(listenbrainz::Config {
+        url: match env.opt("LISTENBRAINZ_URL")? {
+            Some(url) => url,
+            None => listenbrainz::DEFAULT_URL.parse()?,
+        },
+        token,
+    }))
+}
+
+fn mqtt(env: &Env) -> Result<Option<player::MqttConfig>> {
+    let Some(url) = env.opt("MQTT_URL")? else { return Ok(None) };
+
+    Ok(Some(player::MqttConfig {
+        url,
+        topic_prefix: env.opt("MQTT_TOPIC_PREFIX")?
+            .unwrap_or_else(|| player::DEFAULT_MQTT_TOPIC_PREFIX.to_owned()),
+        homeassistant: match env.opt("MQTT_HOMEASSISTANT")?.unwrap_or(false) {
+            true => Some(env.opt("MQTT_HOMEASSISTANT_PREFIX")?
+                .unwrap_or_else(|| player::DEFAULT_DISCOVERY_PREFIX.to_owned())),
+            false => None,
+        },
+    }))
+}
+
+fn snapcast(env: &Env) -> Result<Option<String>> {
+    let Some(host) = env.opt::<String>("SNAPCAST_HOST")? else { return Ok(None) };
+
+    if host.contains(':') {
+        Ok(Some(host))
+    } else {
+        Ok(Some(format!("{host}:{}", snapcast::DEFAULT_PORT)))
     }
 }
 
-fn opt_env<T: FromStr<Err: Display>>(name: &str) -> Option<T> {
-    let value = match std::env::var(name) {
-        Ok(value) => value,
-        Err(VarError::NotPresent) => { return None }
-        Err(VarError::NotUnicode(_)) => panic!("env var is invalid utf-8: {name}"),
+// MAX_VOLUME caps volume from 0-1, REPLAY_GAIN_PREAMP scales it by dB
+fn volume_limits(env: &Env) -> Result<player::VolumeLimits> {
+    let defaults = player::VolumeLimits::default();
+
+    let max_volume = env.opt("MAX_VOLUME")?.unwrap_or(defaults.max_volume);
+    anyhow::ensure!((0.0..=1.0).contains(&max_volume), "MAX_VOLUME must be from 0 to 1");
+
+    Ok(player::VolumeLimits {
+        max_volume,
+        preamp: env.opt("REPLAY_GAIN_PREAMP")?.unwrap_or(defaults.preamp),
+    })
+}
+
+// GUEST_ACCESS lets in clients with no credentials at all, GUEST_TOKENS is a
+// comma separated list of tokens which are let in too
+fn guests(env: &Env) -> Result<player::GuestConfig> {
+    let tokens = env.opt::<String>("GUEST_TOKENS")?.unwrap_or_default();
+
+    Ok(player::GuestConfig {
+        anonymous: env.opt("GUEST_ACCESS")?.unwrap_or(false),
+        tokens: tokens.split(',')
+            .map(str::trim)
+            .filter(|token| !token.is_empty())
+            .map(str::to_owned)
+            .collect(),
+    })
+}
+
+// comma separated track id prefixes, eg. audiobook ids on the server
+fn bookmark_prefixes(env: &Env) -> Result<Vec<String>> {
+    let Some(prefixes) = env.opt::<String>("BOOKMARK_PREFIXES")? else { return Ok(Vec::new()) };
+
+    Ok(prefixes.split(',')
+        .map(str::trim)
+        .filter(|prefix| !prefix.is_empty())
+        .map(str::to_owned)
+        .collect())
+}
+
+fn podcasts(env: &Env, http: &reqwest::Client) -> Result<Option<podcasts::Config>> {
+    let Some(server_url) = env.opt("PODCASTS_URL")? else { return Ok(None) };
+
+    Ok(Some(podcasts::Config {
+        server_url,
+        episode_prefix: env.get("PODCAST_EPISODE_PREFIX")?,
+        http: http.clone(),
+    }))
+}
+
+// each SUBSONIC_URL_<NAME> adds a server named <name> to queue from, logged
+// in to with SUBSONIC_USERNAME_<NAME> and SUBSONIC_PASSWORD_<NAME>
+fn servers(env: &Env) -> Result<Vec<player::ServerConfig>> {
+    let mut servers = Vec::new();
+
+    for name in env.names() {
+        let Some(server) = name.strip_prefix("SUBSONIC_URL_") else { continue };
+
+        servers.push(player::ServerConfig {
+            name: server.to_lowercase(),
+            url: env.get(&name)?,
+            username: env.get(&format!("SUBSONIC_USERNAME_{server}"))?,
+            password: env.get(&format!("SUBSONIC_PASSWORD_{server}"))?,
+        });
+    }
+
+    Ok(servers)
+}
+
+// MPD_SOCKET configures the default room, and each MPD_SOCKET_<NAME> an
+// additional room named <name>, with an optional MPD_PASSWORD_<NAME>. on
+// hosts without mpd, JUKEBOX_USERNAME and JUKEBOX_PASSWORD instead make the
+// default room subsonic's jukebox, or LOCAL_PLAYBACK plays it on this host
+fn rooms(env: &Env, http: &reqwest::Client) -> Result<Vec<player::RoomConfig>> {
+    let default = match jukebox(env, http)? {
+        Some(jukebox) => player::RoomBackend::Jukebox(jukebox),
+        None => match local(env)? {
+            Some(local) => local,
+            None => player::RoomBackend::Mpd(mpd(env, "")?),
+        },
     };
 
-    match value.parse() {
-        Ok(value) => Some(value),
-        Err(err) => panic!("invalid format for env var: {name}: {err}"),
+    let mut rooms = vec![player::RoomConfig {
+        name: player::DEFAULT_ROOM.to_string(),
+        backend: default,
+        visualizer: visualizer(env, "")?,
+    }];
+
+    for name in env.names() {
+        let Some(room) = name.strip_prefix("MPD_SOCKET_") else { continue };
+
+        rooms.push(player::RoomConfig {
+            name: room.to_lowercase(),
+            backend: player::RoomBackend::Mpd(mpd(env, &format!("_{room}"))?),
+            visualizer: visualizer(env, &format!("_{room}"))?,
+        });
+    }
+
+    Ok(rooms)
+}
+
+// VISUALIZER_FIFO is the path of an mpd fifo output to draw spectrums from,
+// with VISUALIZER_FIFO_<NAME> for each additional room
+fn visualizer(env: &Env, suffix: &str) -> Result<Option<player::VisualizerConfig>> {
+    let Some(fifo) = env.opt(&format!("VISUALIZER_FIFO{suffix}"))? else { return Ok(None) };
+
+    Ok(Some(player::VisualizerConfig {
+        fifo,
+        format: env.opt("VISUALIZER_FORMAT")?.unwrap_or_default(),
+        rate: env.opt("VISUALIZER_RATE")?.unwrap_or(player::DEFAULT_VISUALIZER_RATE),
+        bins: env.opt("VISUALIZER_BINS")?.unwrap_or(player::DEFAULT_VISUALIZER_BINS),
+    }))
+}
+
+fn jukebox(env: &Env, http: &reqwest::Client) -> Result<Option<jukebox::Config>> {
+    let Some(username) = env.opt("JUKEBOX_USERNAME")? else { return Ok(None) };
+
+    Ok(Some(jukebox::Config {
+        url: env.get("SUBSONIC_URL")?,
+        username,
+        password: env.get("JUKEBOX_PASSWORD")?,
+        http: http.clone(),
+    }))
+}
+
+fn local(env: &Env) -> Result<Option<player::RoomBackend>> {
+    if !env.opt("LOCAL_PLAYBACK")?.unwrap_or(false) {
+        return Ok(None);
+    }
+
+    #[cfg(feature = "local")]
+    return Ok(Some(player::RoomBackend::Local));
+
+    #[cfg(not(feature = "local"))]
+    anyhow::bail!("LOCAL_PLAYBACK needs sonicast built with the local feature");
+}
+
+fn mpd(env: &Env, suffix: &str) -> Result<mpd::Config> {
+    Ok(mpd::Config {
+        socket: env.get(&format!("MPD_SOCKET{suffix}"))?,
+        password: match env.opt(&format!("MPD_PASSWORD{suffix}"))? {
+            Some(password) => Some(password),
+            None => env.opt("MPD_PASSWORD")?,
+        },
+        command_timeout: env.opt("MPD_COMMAND_TIMEOUT")?
+            .map(Duration::from_secs)
+            .unwrap_or(mpd::DEFAULT_COMMAND_TIMEOUT),
+        limits: mpd_limits(env)?,
+        status_max_age: env.opt("MPD_STATUS_CACHE_MS")?
+            .map(Duration::from_millis)
+            .unwrap_or(mpd::DEFAULT_STATUS_MAX_AGE),
+        // seconds, 0 turns keepalive off
+        keepalive_interval: match env.opt("MPD_KEEPALIVE_INTERVAL")? {
+            Some(0) => None,
+            Some(secs) => Some(Duration::from_secs(secs)),
+            None => Some(mpd::DEFAULT_KEEPALIVE_INTERVAL),
+        },
+    })
+}
+
+// bounds on responses from mpd, shared by every room
+fn mpd_limits(env: &Env) -> Result<mpd::Limits> {
+    let defaults = mpd::Limits::default();
+
+    Ok(mpd::Limits {
+        max_line_length: env.opt("MPD_MAX_LINE_LENGTH")?.unwrap_or(defaults.max_line_length),
+        max_attributes: env.opt("MPD_MAX_ATTRIBUTES")?.unwrap_or(defaults.max_attributes),
+        max_binary_size: env.opt("MPD_MAX_BINARY_SIZE")?.unwrap_or(defaults.max_binary_size),
+    })
+}
+
+/// config variables, from the environment or failing that the config file
+#[derive(Default)]
+struct Env {
+    file: HashMap<String, String>,
+}
+
+impl Env {
+    fn from_file(path: &Path) -> Result<Env> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("reading config file {}", path.display()))?;
+
+        let mut file = HashMap::new();
+
+        for (lineno, line) in contents.lines().enumerate() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let Some((name, value)) = line.split_once('=') else {
+                anyhow::bail!("{}:{}: expected NAME=value", path.display(), lineno + 1);
+            };
+
+            let value = value.trim();
+            let value = value.strip_prefix('"')
+                .and_then(|value| value.strip_suffix('"'))
+                .unwrap_or(value);
+
+            file.insert(name.trim().to_string(), value.to_string());
+        }
+
+        Ok(Env { file })
+    }
+
+    fn get<T: FromStr<Err: Display>>(&self, name: &str) -> Result<T> {
+        self.opt(name)?
+            .with_context(|| format!("missing config: {name}"))
+    }
+
+    fn opt<T: FromStr<Err: Display>>(&self, name: &str) -> Result<Option<T>> {
+        let value = match std::env::var(name) {
+            Ok(value) => value,
+            Err(std::env::VarError::NotPresent) => match self.file.get(name) {
+                Some(value) => value.clone(),
+                None => { return Ok(None) }
+            },
+            Err(std::env::VarError::NotUnicode(_)) => {
+                anyhow::bail!("env var is invalid utf-8: {name}");
+            }
+        };
+
+        match value.parse() {
+            Ok(value) => Ok(Some(value)),
+            Err(err) => anyhow::bail!("invalid format for config: {name}: {err}"),
+        }
+    }
+
+    /// every variable name set in either the environment or config file
+    fn names(&self) -> Vec<String> {
+        let mut names = std::env::vars_os()
+            .filter_map(|(name, _)| name.into_string().ok())
+            .chain(self.file.keys().cloned())
+            .collect::<Vec<_>>();
+
+        names.sort();
+        names.dedup();
+        names
     }
 }
@@ -0,0 +1,252 @@
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::Result;
+use futures::future::BoxFuture;
+use tokio::sync::Notify;
+
+use super::protocol::{AckCode, Attributes, ErrorResponse, OkResponse};
+use super::types::Changed;
+use super::{Backend, Command};
+
+// helpers for backends which speak mpd's protocol themselves, rather than
+// passing commands through to mpd
+
+/// a backend which runs mpd's commands itself, one at a time, and records
+/// what they change for idle to report
+pub trait Emulator: Send + Sync {
+    fn run(&self, cmd: &str, args: &[&str]) -> impl Future<Output = Result<Attributes>> + Send;
+
+    fn changes(&self) -> &Changes;
+
+    /// how often idle polls for changes made behind the emulator's back,
+    /// if there are any to look for
+    fn poll_interval(&self) -> Option<Duration> {
+        None
+    }
+
+    /// pushes whatever changed since the last poll
+    fn poll(&self) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+
+    fn is_healthy(&self) -> bool {
+        true
+    }
+}
+
+impl<T: Emulator> Backend for T {
+    fn command<'a>(&'a self, cmd: &'a str, args: &'a [&'a str]) -> BoxFuture<'a, Result<OkResponse>> {
+        Box::pin(async move {
+            let attributes = self.run(cmd, args).await
+                .map_err(|err| failed(err, 0, &Command::new(cmd, args)))?;
+
+            Ok(OkResponse { attributes, binary: None, list: Vec::new() })
+        })
+    }
+
+    fn command_list<'a>(&'a self, commands: &'a [Command]) -> BoxFuture<'a, Result<Vec<Attributes>>> {
+        Box::pin(async move {
+            let mut list = Vec::new();
+
+            for (index, command) in commands.iter().enumerate() {
+                let args = command.args.iter().map(String::as_str).collect::<Vec<_>>();
+
+                let attributes = self.run(&command.command, &args).await
+                    .map_err(|err| failed(err, index, command))?;
+
+                list.push(attributes);
+            }
+
+            Ok(list)
+        })
+    }
+
+    fn idle<'a>(&'a self, cancel: BoxFuture<'a, ()>) -> BoxFuture<'a, Result<Changed>> {
+        Box::pin(async move {
+            let mut cancel = cancel;
+            let mut poll = self.poll_interval().map(tokio::time::interval);
+
+            loop {
+                if let Some(changed) = self.changes().take() {
+                    return Ok(changed);
+                }
+
+                let tick = async {
+                    match &mut poll {
+                        Some(poll) => { poll.tick().await; }
+                        None => std::future::pending().await,
+                    }
+                };
+
+                tokio::select! {
+                    () = &mut cancel => return Ok(Changes::none()),
+                    () = self.changes().notified() => {}
+                    () = tick => self.poll().await,
+                }
+            }
+        })
+    }
+
+    fn is_healthy(&self) -> bool {
+        Emulator::is_healthy(self)
+    }
+}
+
+/// an emulator's queue, as far as the commands every emulator answers the
+/// same way need to see it
+pub trait EmulatedQueue {
+    fn len(&self) -> usize;
+
+    /// file and song id of the item at pos
+    fn item(&self, pos: usize) -> (&str, u64);
+
+    fn attributes(&self, attrs: &mut Attributes, pos: usize) {
+        let (file, id) = self.item(pos);
+        attrs.push("file", file);
+        attrs.push("Pos", pos);
+        attrs.push("Id", id);
+    }
+}
+
+/// runs the commands which don't depend on the backend, for anything an
+/// emulator doesn't handle itself. backend names it in the error for
+/// commands nobody handles
+pub fn fallback(cmd: &str, args: &[&str], queue: &impl EmulatedQueue, attrs: &mut Attributes, backend: &str) -> Result<()> {
+    match (cmd, args) {
+        ("playlistinfo", []) | ("plchanges", [_]) => {
+            // no record is kept of what changed when, so every item
+            // counts as changed
+            for pos in 0..queue.len() {
+                queue.attributes(attrs, pos);
+            }
+        }
+        ("replay_gain_status", []) => {
+            attrs.push("replay_gain_mode", "off");
+        }
+        // nothing to list, and only the one partition
+        ("outputs" | "listplaylists", []) => {}
+        ("listpartitions", []) => {
+            attrs.push("partition", "default");
+        }
+        ("partition", ["default"]) => {}
+        ("readpicture" | "albumart", _) => return Err(ack(AckCode::NoExist, "no picture")),
+        // priorities only order mpd's shuffled play order, which emulators
+        // don't keep
+        ("prio" | "prioid", [_, _]) => {}
+        _ => return Err(ack(AckCode::Unknown, &format!("not supported by {backend}"))),
+    }
+
+    Ok(())
+}
+
+/// subsystems changed by commands since the last idle
+#[derive(Default)]
+pub struct Changes {
+    changed: Mutex<Vec<&'static str>>,
+    notify: Notify,
+}
+
+impl Changes {
+    pub fn push(&self, subsystems: &[&'static str]) {
+        let mut changed = self.changed.lock().unwrap();
+
+        for subsystem in subsystems {
+            if !changed.contains(subsystem) {
+                changed.push(subsystem);
+            }
+        }
+
+        self.notify.notify_one();
+    }
+
+    /// takes whatever has changed, if anything has
+    pub fn take(&self) -> Option<Changed> {
+        let changed = std::mem::take(&mut *self.changed.lock().unwrap());
+
+        if changed.is_empty() {
+            return None;
+        }
+
+        let mut attrs = Attributes::default();
+        for subsystem in changed {
+            attrs.push("changed", subsystem);
+        }
+
+        Changed::from_attributes(&attrs).ok()
+    }
+
+    /// resolves once there may be something to take
+    pub async fn notified(&self) {
+        self.notify.notified().await
+    }
+
+    /// what idle returns when cancelled with nothing changed
+    pub fn none() -> Changed {
+        Changed::from_attributes(&Attributes::default())
+            .expect("empty changed")
+    }
+}
+
+pub fn ack(code: AckCode, message: &str) -> anyhow::Error {
+    ErrorResponse {
+        code,
+        index: 0,
+        command: String::new(),
+        message: message.to_string(),
+    }.into()
+}
+
+/// fills in the failing command and its position in a command list, as mpd
+/// would have reported them
+pub fn failed(mut err: anyhow::Error, index: usize, command: &Command) -> anyhow::Error {
+    if let Some(ack) = err.downcast_mut::<ErrorResponse>() {
+        ack.index = index;
+        ack.command = command.command.clone();
+    }
+
+    err.context(command.clone())
+}
+
+/// a position in the queue no greater than max
+pub fn parse_index(index: &str, max: usize) -> Result<usize> {
+    index.parse::<usize>().ok()
+        .filter(|index| *index <= max)
+        .ok_or_else(|| ack(AckCode::Arg, "bad song index"))
+}
+
+/// position of a song in a queue of len songs
+pub fn parse_song(pos: &str, len: usize) -> Result<usize> {
+    pos.parse::<usize>().ok()
+        .filter(|pos| *pos < len)
+        .ok_or_else(|| ack(AckCode::Arg, "bad song index"))
+}
+
+/// START:END, START: or a single position, as a half open range
+pub fn parse_range(range: &str, len: usize) -> Result<(usize, usize)> {
+    let bad_range = || ack(AckCode::Arg, "bad song index");
+    let parse = |index: &str| index.parse::<usize>().map_err(|_| bad_range());
+
+    let (start, end) = match range.split_once(':') {
+        Some((start, "")) => (parse(start)?, len),
+        Some((start, end)) => (parse(start)?, parse(end)?),
+        None => {
+            let pos = parse(range)?;
+            (pos, pos + 1)
+        }
+    };
+
+    if start > end || end > len {
+        return Err(bad_range());
+    }
+
+    Ok((start, end))
+}
+
+/// a time in seconds
+pub fn parse_time(time: &str) -> Result<f64> {
+    time.parse::<f64>().ok()
+        .filter(|time| time.is_finite() && *time >= 0.0)
+        .ok_or_else(|| ack(AckCode::Arg, "bad time"))
+}
@@ -0,0 +1,71 @@
+use std::future::Future;
+use std::sync::Arc;
+
+use anyhow::Result;
+
+use super::types::{Changed, CurrentSong, Status};
+use super::{Backend, Config, Conn, StatusCache};
+
+pub(super) const SUBSYSTEMS: &[&str] = &[
+    "player",
+    "playlist",
+    "options",
+    "mixer",
+    "output",
+];
+
+/// connection dedicated to waiting on mpd's idle command. while idle is
+/// pending, mpd only accepts noidle on the connection, so idle takes
+/// &mut self to rule out any other command being pipelined behind it.
+pub struct MpdIdleClient {
+    conn: Arc<dyn Backend>,
+    /// invalidated on every change, see share_status_cache
+    status_cache: Option<Arc<StatusCache>>,
+}
+
+impl MpdIdleClient {
+    pub async fn connect(config: &Config) -> Result<Self> {
+        // no keepalive here, mpd doesn't time out connections while in idle
+        let (conn, _) = Conn::connect(config).await?;
+        Ok(MpdIdleClient { conn: Arc::new(conn), status_cache: None })
+    }
+
+    pub fn new(backend: Arc<dyn Backend>) -> Self {
+        MpdIdleClient { conn: backend, status_cache: None }
+    }
+
+    /// invalidates mpd's cached status whenever idle returns changes, so
+    /// that it's never reused past a change
+    pub fn share_status_cache(&mut self, cache: Arc<StatusCache>) {
+        self.status_cache = Some(cache);
+    }
+
+    /// waits for changes, or until cancel resolves, in which case noidle is
+    /// sent and whatever changes mpd had accumulated so far are returned
+    pub async fn idle_until(&mut self, cancel: impl Future<Output = ()> + Send) -> Result<Changed> {
+        let changed = self.conn.idle(Box::pin(cancel)).await?;
+
+        if let Some(cache) = &self.status_cache {
+            cache.invalidate();
+        }
+
+        Ok(changed)
+    }
+
+    /// switches this connection to the named partition, so that idle
+    /// reports changes to that partition
+    pub async fn partition(&self, name: &str) -> Result<()> {
+        self.conn.command("partition", &[name]).await?;
+        Ok(())
+    }
+
+    pub async fn status(&self) -> Result<Status> {
+        let resp = self.conn.command("status", &[]).await?;
+        Status::from_attributes(&resp.attributes)
+    }
+
+    pub async fn currentsong(&self) -> Result<Option<CurrentSong>> {
+        let resp = self.conn.command("currentsong", &[]).await?;
+        CurrentSong::from_attributes(&resp.attributes)
+    }
+}
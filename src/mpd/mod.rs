@@ -1,3 +1,5 @@
+pub mod emulate;
+pub mod idle;
 pub mod protocol;
 pub mod types;
 
@@ -7,59 +9,297 @@ use std::collections::VecDeque;
 use std::ops::Range;
 use std::path::PathBuf;
 use std::sync::Arc;
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 
 use anyhow::{Context, Result};
-use derive_more::Display;
+use futures::future::BoxFuture;
 use protocol::OkResponse;
+use thiserror::Error;
+use tracing::Instrument;
 use tokio::net::UnixStream;
 use tokio::sync::{oneshot, Mutex as AsyncMutex};
 
-use protocol::{MpdReader, MpdWriter, Protocol, Response, Attributes};
-use types::{Changed, Id, Playlist, PlaylistItem, ReplayGainMode, Status};
+use protocol::{MpdReader, MpdWriter, Protocol, Response, Attributes, AckCode, ErrorResponse};
 
-const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(1);
+pub use idle::MpdIdleClient;
+pub use protocol::{Command, Limits};
+use types::{Changed, CurrentSong, Decoder, Id, LibraryEntry, Output, Picture, Playlist, PlaylistItem, ReplayGainMode, SingleMode, Stats, Status, StoredPlaylist};
+
+pub const DEFAULT_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(1);
+pub const DEFAULT_COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
 
 pub struct Mpd {
-    conn: Conn,
+    conn: std::sync::Mutex<Arc<dyn Backend>>,
+    /// set for connections to mpd itself, which are made again once they're
+    /// unhealthy
+    config: Option<Config>,
+    /// held while reconnecting, so that concurrent commands make one new
+    /// connection between them
+    reconnect: AsyncMutex<()>,
+    /// switched to again after reconnecting, see partition
+    partition: std::sync::Mutex<Option<String>>,
+    status_cache: Arc<StatusCache>,
+    status_max_age: Duration,
+}
+
+/// how long status responses are reused for by default, a few of the
+/// zone's tasks tend to ask for status on the same change
+pub const DEFAULT_STATUS_MAX_AGE: Duration = Duration::from_millis(250);
+
+// commands which don't change anything status reports, and so don't
+// invalidate the status cache
+const QUERY_COMMANDS: &[&str] = &[
+    "status",
+    "currentsong",
+    "playlistinfo",
+    "playlistid",
+    "plchanges",
+    "listplaylists",
+    "lsinfo",
+    "outputs",
+    "replay_gain_status",
+    "albumart",
+    "readpicture",
+    "listpartitions",
+    "stats",
+    "decoders",
+    "urlhandlers",
+    "ping",
+];
+
+/// the last status fetched, reused until it's older than the caller allows
+/// or invalidated. every change mpd reports to the idle client invalidates
+/// it, as does any command which might change status
+#[derive(Default)]
+pub struct StatusCache {
+    /// bumped on every invalidation
+    version: AtomicU64,
+    last: std::sync::Mutex<Option<CachedStatus>>,
+}
+
+struct CachedStatus {
+    status: Status,
+    /// version of the cache when the status was requested
+    version: u64,
+    fetched: Instant,
+}
+
+impl StatusCache {
+    pub fn invalidate(&self) {
+        self.version.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn version(&self) -> u64 {
+        self.version.load(Ordering::SeqCst)
+    }
+
+    fn get(&self, max_age: Duration) -> Option<Status> {
+        let last = self.last.lock().unwrap();
+        let last = last.as_ref()?;
+
+        let fresh = last.version == self.version() && last.fetched.elapsed() <= max_age;
+        fresh.then(|| last.status.clone())
+    }
+
+    // version is from before the request, so a status which raced with an
+    // invalidation is never reused
+    fn put(&self, status: &Status, version: u64) {
+        *self.last.lock().unwrap() = Some(CachedStatus {
+            status: status.clone(),
+            version,
+            fetched: Instant::now(),
+        });
+    }
 }
 
+/// what Mpd sends its commands to. normally a connection to mpd itself, but
+/// anything which speaks enough of mpd's protocol can stand in for it
+pub trait Backend: Send + Sync {
+    fn command<'a>(&'a self, cmd: &'a str, args: &'a [&'a str]) -> BoxFuture<'a, Result<OkResponse>>;
+
+    /// runs commands in order, stopping at the first error
+    fn command_list<'a>(&'a self, commands: &'a [Command]) -> BoxFuture<'a, Result<Vec<Attributes>>>;
+
+    /// waits for changes, or until cancel resolves, see MpdIdleClient
+    fn idle<'a>(&'a self, cancel: BoxFuture<'a, ()>) -> BoxFuture<'a, Result<Changed>>;
+
+    fn is_healthy(&self) -> bool;
+}
+
+#[derive(Clone)]
 pub struct Config {
     pub socket: PathBuf,
+    pub password: Option<String>,
+    pub command_timeout: Duration,
+    pub limits: protocol::Limits,
+    /// see StatusCache
+    pub status_max_age: Duration,
+    /// how often the command connection is pinged, None for never
+    pub keepalive_interval: Option<Duration>,
 }
 
 impl Mpd {
     pub async fn connect(config: &Config) -> Result<Mpd> {
+        let conn = Self::open(config).await?;
+
+        Ok(Mpd {
+            conn: std::sync::Mutex::new(conn),
+            config: Some(config.clone()),
+            reconnect: AsyncMutex::new(()),
+            partition: Default::default(),
+            status_cache: Default::default(),
+            status_max_age: config.status_max_age,
+        })
+    }
+
+    pub fn new(backend: Arc<dyn Backend>) -> Mpd {
+        Mpd {
+            conn: std::sync::Mutex::new(backend),
+            config: None,
+            reconnect: AsyncMutex::new(()),
+            partition: Default::default(),
+            status_cache: Default::default(),
+            status_max_age: DEFAULT_STATUS_MAX_AGE,
+        }
+    }
+
+    async fn open(config: &Config) -> Result<Arc<dyn Backend>> {
         let (conn, proto) = Conn::connect(config).await?;
+
         log::info!("Connected to mpd at {}, protocol version {}",
             config.socket.display(), proto.version);
-        Ok(Mpd { conn })
+
+        Ok(Arc::new(conn))
+    }
+
+    /// the connection to send commands to, made again first if it has gone
+    /// unhealthy, such as after a command timed out
+    async fn conn(&self) -> Result<Arc<dyn Backend>> {
+        let conn = self.conn.lock().unwrap().clone();
+
+        let Some(config) = &self.config else { return Ok(conn) };
+
+        if conn.is_healthy() {
+            return Ok(conn);
+        }
+
+        let _reconnect = self.reconnect.lock().await;
+
+        // another command may have reconnected while we waited
+        let conn = self.conn.lock().unwrap().clone();
+
+        if conn.is_healthy() {
+            return Ok(conn);
+        }
+
+        log::warn!("mpd connection to {} unhealthy, reconnecting", config.socket.display());
+
+        let conn = Self::open(config).await.context("reconnecting to mpd")?;
+
+        // connections start out in the default partition
+        let partition = self.partition.lock().unwrap().clone();
+
+        if let Some(partition) = partition {
+            conn.command("partition", &[&partition]).await?;
+        }
+
+        // whatever was cached may have changed while disconnected
+        self.status_cache.invalidate();
+
+        *self.conn.lock().unwrap() = conn.clone();
+        Ok(conn)
+    }
+
+    /// to be shared with the idle client on the same partition, which
+    /// invalidates it as mpd reports changes
+    pub fn status_cache(&self) -> Arc<StatusCache> {
+        self.status_cache.clone()
+    }
+
+    // anything besides a query may change what status reports
+    async fn command(&self, cmd: &str, args: &[&str]) -> Result<OkResponse> {
+        if !QUERY_COMMANDS.contains(&cmd) {
+            self.status_cache.invalidate();
+        }
+
+        self.conn().await?.command(cmd, args).await
+    }
+
+    /// false once a command has timed out, after which the connection is
+    /// out of sync with mpd, or once mpd has gone away. the next command
+    /// reconnects
+    #[allow(unused)]
+    pub fn is_healthy(&self) -> bool {
+        self.conn.lock().unwrap().is_healthy()
+    }
+
+    /// keeps the connection from timing out, see zones::keepalive_task
+    pub async fn ping(&self) -> Result<()> {
+        self.command("ping", &[]).await?;
+        Ok(())
+    }
+
+    /// whether there's a healthy connection, reconnecting first if not
+    pub async fn check_connection(&self) -> bool {
+        match self.conn().await {
+            Ok(conn) => conn.is_healthy(),
+            Err(err) => {
+                log::debug!("checking mpd connection: {err:?}");
+                false
+            }
+        }
+    }
+
+    /// sends all commands in a single command list, returning the response
+    /// attributes of each. if any command fails, subsequent commands in the
+    /// list are not executed by mpd
+    pub async fn command_list(&self, commands: &[Command]) -> Result<Vec<Attributes>> {
+        self.status_cache.invalidate();
+        self.conn().await?.command_list(commands).await
     }
 
     pub async fn addid(&self, location: &str) -> Result<Id> {
-        let resp = self.conn.command("addid", &[location]).await?;
+        let resp = self.command("addid", &[location]).await?;
         resp.attributes.get("Id")
     }
 
-    pub async fn delete(&self, pos: isize) -> Result<()> {
-        let pos = position(pos);
-        self.conn.command("deleteid", &[&pos]).await?;
+    pub async fn delete(&self, pos: usize) -> Result<()> {
+        let pos = pos.to_string();
+        self.command("delete", &[&pos]).await?;
+        Ok(())
+    }
+
+    // end of None deletes through to the end of the queue
+    pub async fn delete_range(&self, start: usize, end: Option<usize>) -> Result<()> {
+        let range = match end {
+            Some(end) => format!("{start}:{end}"),
+            None => format!("{start}:"),
+        };
+        self.command("delete", &[&range]).await?;
+        Ok(())
+    }
+
+    pub async fn move_pos(&self, from: usize, to: usize) -> Result<()> {
+        let from = from.to_string();
+        let to = to.to_string();
+        self.command("move", &[&from, &to]).await?;
         Ok(())
     }
 
     #[allow(unused)]
     pub async fn deleteid(&self, id: &Id) -> Result<()> {
-        self.conn.command("deleteid", &[id.as_str()]).await?;
+        self.command("deleteid", &[id.as_str()]).await?;
         Ok(())
     }
 
     pub async fn clear(&self) -> Result<()> {
-        self.conn.command("clear", &[]).await?;
+        self.command("clear", &[]).await?;
         Ok(())
     }
 
     pub async fn playlistinfo(&self) -> Result<Playlist> {
-        let resp = self.conn.command("playlistinfo", &[]).await?;
+        let resp = self.command("playlistinfo", &[]).await?;
 
         let items = resp.attributes.split_at("file")
             .into_iter()
@@ -70,13 +310,87 @@ impl Mpd {
         Ok(Playlist { items })
     }
 
+    /// contents of a directory in mpd's database, the root if path is empty
+    pub async fn lsinfo(&self, path: &str) -> Result<Vec<LibraryEntry>> {
+        let resp = self.command("lsinfo", &[path]).await?;
+
+        LibraryEntry::list_from_attributes(&resp.attributes)
+            .context("parsing lsinfo response")
+    }
+
+    /// updates the database under path, or all of it if path is empty.
+    /// returns the job id, which status reports until the update is done
+    pub async fn update(&self, path: &str) -> Result<u32> {
+        let resp = self.command("update", &[path]).await?;
+        resp.attributes.get("updating_db")
+    }
+
+    /// like update, but also rereads files which haven't changed
+    pub async fn rescan(&self, path: &str) -> Result<u32> {
+        let resp = self.command("rescan", &[path]).await?;
+        resp.attributes.get("updating_db")
+    }
+
+    /// adds a file from mpd's database, or a directory recursively
+    pub async fn add(&self, path: &str) -> Result<()> {
+        self.command("add", &[path]).await?;
+        Ok(())
+    }
+
+    pub async fn listplaylists(&self) -> Result<Vec<StoredPlaylist>> {
+        let resp = self.command("listplaylists", &[]).await?;
+
+        resp.attributes.split_at("playlist")
+            .into_iter()
+            .map(|attrs| Ok(StoredPlaylist {
+                name: attrs.get("playlist")?,
+                last_modified: attrs.get_opt("Last-Modified")?,
+            }))
+            .collect::<Result<Vec<_>>>()
+            .context("parsing listplaylists response")
+    }
+
+    pub async fn save(&self, name: &str) -> Result<()> {
+        self.command("save", &[name]).await?;
+        Ok(())
+    }
+
+    pub async fn rm(&self, name: &str) -> Result<()> {
+        self.command("rm", &[name]).await?;
+        Ok(())
+    }
+
+    pub async fn rename(&self, name: &str, new_name: &str) -> Result<()> {
+        self.command("rename", &[name, new_name]).await?;
+        Ok(())
+    }
+
+    /// plchanges along with the status as of those changes, in one command
+    /// list so that the queue can't change between the two
+    pub async fn plchanges_status(&self, version: u32) -> Result<(Vec<PlaylistItem>, Status)> {
+        let version = version.to_string();
+        let commands = [Command::new("plchanges", &[&version]), Command::new("status", &[])];
+
+        let Ok([changes, status]) = <[Attributes; 2]>::try_from(self.command_list(&commands).await?) else {
+            anyhow::bail!("expected a response to each of plchanges and status");
+        };
+
+        let changes = changes.split_at("file")
+            .into_iter()
+            .map(parse_playlist_item)
+            .collect::<Result<Vec<_>>>()
+            .context("parsing plchanges response")?;
+
+        Ok((changes, Status::from_attributes(&status)?))
+    }
+
     pub async fn playlistclear(&self, name: &str) -> Result<()> {
-        self.conn.command("playlistclear", &[name]).await?;
+        self.command("playlistclear", &[name]).await?;
         Ok(())
     }
 
     pub async fn playlistadd(&self, name: &str, location: &str) -> Result<()> {
-        self.conn.command("playlistadd", &[name, location]).await?;
+        self.command("playlistadd", &[name, location]).await?;
         Ok(())
     }
 
@@ -86,112 +400,299 @@ impl Mpd {
             Some(range) => Cow::Owned(format!("{}:{}", range.start, range.end)),
         };
 
-        let pos = match pos {
-            None => Cow::Borrowed(""),
-            Some(pos) => Cow::Owned(position(pos)),
+        match pos {
+            None => self.command("load", &[name, &range]).await?,
+            Some(pos) => self.command("load", &[name, &range, &position(pos)]).await?,
         };
 
-        self.conn.command("load", &[name, &range, &pos]).await?;
         Ok(())
     }
 
-    pub async fn idle(&self) -> Result<Changed> {
-        const SUBSYSTEMS: &[&str] = &[
-            "player",
-            "playlist",
-            "options",
-            "mixer",
-        ];
-        let resp = self.conn.command("idle", SUBSYSTEMS).await?;
-        Ok(Changed::from_attributes(&resp.attributes)?)
-    }
-
     pub async fn play(&self) -> Result<()> {
-        self.conn.command("play", &[]).await?;
+        self.command("play", &[]).await?;
         Ok(())
     }
 
     pub async fn playpos(&self, pos: usize) -> Result<()> {
         let pos = pos.to_string();
-        self.conn.command("play", &[&pos]).await?;
+        self.command("play", &[&pos]).await?;
         Ok(())
     }
 
     #[allow(unused)]
     pub async fn playid(&self, id: Id) -> Result<()> {
-        self.conn.command("playid", &[id.as_str()]).await?;
+        self.command("playid", &[id.as_str()]).await?;
+        Ok(())
+    }
+
+    pub async fn clearerror(&self) -> Result<()> {
+        self.command("clearerror", &[]).await?;
         Ok(())
     }
 
     pub async fn stop(&self) -> Result<()> {
-        self.conn.command("stop", &[]).await?;
+        self.command("stop", &[]).await?;
         Ok(())
     }
 
     pub async fn pause(&self) -> Result<()> {
-        self.conn.command("pause", &[]).await?;
+        self.command("pause", &[]).await?;
         Ok(())
     }
 
     pub async fn next(&self) -> Result<()> {
-        self.conn.command("next", &[]).await?;
+        self.command("next", &[]).await?;
         Ok(())
     }
 
     pub async fn previous(&self) -> Result<()> {
-        self.conn.command("previous", &[]).await?;
+        self.command("previous", &[]).await?;
         Ok(())
     }
 
+    #[allow(unused)]
     pub async fn seek(&self, index: usize, time: f64) -> Result<()> {
         let index = format!("{index}");
         let time = format!("{time}");
-        self.conn.command("seek", &[&index, &time]).await?;
+        self.command("seek", &[&index, &time]).await?;
         Ok(())
     }
 
     pub async fn seekcur(&self, pos: f64) -> Result<()> {
         let pos = format!("{pos}");
-        self.conn.command("seekcur", &[&pos]).await?;
+        self.command("seekcur", &[&pos]).await?;
         Ok(())
     }
 
+    /// status no older than the configured max age, see StatusCache
     pub async fn status(&self) -> Result<Status> {
-        let resp = self.conn.command("status", &[]).await?;
-        Ok(Status::from_attributes(&resp.attributes)?)
+        self.status_within(self.status_max_age).await
+    }
+
+    /// for callers which need a more or less recent status than usual, eg.
+    /// a zero max age for positions which have to be accurate to the moment
+    pub async fn status_within(&self, max_age: Duration) -> Result<Status> {
+        if let Some(status) = self.status_cache.get(max_age) {
+            return Ok(status);
+        }
+
+        let version = self.status_cache.version();
+        let resp = self.command("status", &[]).await?;
+        let status = Status::from_attributes(&resp.attributes)?;
+
+        self.status_cache.put(&status, version);
+        Ok(status)
+    }
+
+    /// None if there's no current song
+    pub async fn currentsong(&self) -> Result<Option<CurrentSong>> {
+        let resp = self.command("currentsong", &[]).await?;
+        CurrentSong::from_attributes(&resp.attributes)
     }
 
     pub async fn replay_gain_status(&self) -> Result<ReplayGainMode> {
-        let resp = self.conn.command("replay_gain_status", &[]).await?;
+        let resp = self.command("replay_gain_status", &[]).await?;
         let mode = resp.attributes.get_opt("replay_gain_mode")?;
         Ok(mode.unwrap_or(ReplayGainMode::None))
     }
 
-    #[allow(unused)]
     pub async fn playlistid(&self, id: &Id) -> Result<PlaylistItem> {
-        let resp = self.conn.command("playlistid", &[id.as_str()]).await?;
+        let resp = self.command("playlistid", &[id.as_str()]).await?;
         parse_playlist_item(resp.attributes)
     }
 
     pub async fn random(&self, shuffle: bool) -> Result<()> {
-        self.conn.command("random", &[boolean(shuffle)]).await?;
+        self.command("random", &[boolean(shuffle)]).await?;
         Ok(())
     }
 
     pub async fn repeat(&self, repeat: bool) -> Result<()> {
-        self.conn.command("repeat", &[boolean(repeat)]).await?;
+        self.command("repeat", &[boolean(repeat)]).await?;
+        Ok(())
+    }
+
+    pub async fn single(&self, mode: SingleMode) -> Result<()> {
+        self.command("single", &[single_mode(mode)]).await?;
+        Ok(())
+    }
+
+    pub async fn consume(&self, consume: bool) -> Result<()> {
+        self.command("consume", &[boolean(consume)]).await?;
+        Ok(())
+    }
+
+    pub async fn crossfade(&self, secs: usize) -> Result<()> {
+        let secs = secs.to_string();
+        self.command("crossfade", &[&secs]).await?;
+        Ok(())
+    }
+
+    pub async fn mixrampdb(&self, db: f64) -> Result<()> {
+        let db = format!("{db}");
+        self.command("mixrampdb", &[&db]).await?;
+        Ok(())
+    }
+
+    // None disables mixramp, falling back to plain crossfade
+    pub async fn mixrampdelay(&self, secs: Option<f64>) -> Result<()> {
+        let secs = match secs {
+            Some(secs) => Cow::Owned(format!("{secs}")),
+            None => Cow::Borrowed("nan"),
+        };
+        self.command("mixrampdelay", &[&secs]).await?;
+        Ok(())
+    }
+
+    /// sets the priority of queue positions within range. in random mode,
+    /// higher priority items are played first, 255 being the highest
+    pub async fn prio(&self, priority: u8, range: Range<usize>) -> Result<()> {
+        let priority = priority.to_string();
+        let range = format!("{}:{}", range.start, range.end);
+        self.command("prio", &[&priority, &range]).await?;
+        Ok(())
+    }
+
+    #[allow(unused)]
+    pub async fn prioid(&self, priority: u8, id: &Id) -> Result<()> {
+        let priority = priority.to_string();
+        self.command("prioid", &[&priority, id.as_str()]).await?;
         Ok(())
     }
 
     pub async fn shuffle(&self) -> Result<()> {
-        self.conn.command("shuffle", &[]).await?;
+        self.command("shuffle", &[]).await?;
         Ok(())
     }
 
     pub async fn setvol(&self, volume: usize) -> Result<()> {
         let volume = cmp::min(100, volume);
         let volume = volume.to_string();
-        self.conn.command("setvol", &[&volume]).await?;
+        self.command("setvol", &[&volume]).await?;
+        Ok(())
+    }
+
+    /// fetches cover art from the directory containing uri, starting at
+    /// offset. returns None if there is no cover art
+    pub async fn albumart(&self, uri: &str, offset: usize) -> Result<Option<Picture>> {
+        self.binary_command("albumart", uri, offset).await
+    }
+
+    /// fetches a picture embedded in the file or stream at uri, starting at
+    /// offset. returns None if there is no embedded picture
+    pub async fn readpicture(&self, uri: &str, offset: usize) -> Result<Option<Picture>> {
+        self.binary_command("readpicture", uri, offset).await
+    }
+
+    // mpd returns binary data in chunks, loop until we have all of it
+    async fn binary_command(&self, cmd: &str, uri: &str, mut offset: usize) -> Result<Option<Picture>> {
+        let mut picture = Picture { mime: None, data: Vec::new() };
+
+        loop {
+            let offset_arg = offset.to_string();
+            let resp = match self.command(cmd, &[uri, &offset_arg]).await {
+                Ok(resp) => resp,
+                Err(err) if no_exist(&err) => { return Ok(None) }
+                Err(err) => { return Err(err) }
+            };
+
+            // readpicture returns an empty response if there's no picture
+            let Some(binary) = resp.binary else { return Ok(None) };
+
+            let size: usize = resp.attributes.get("size")?;
+            if picture.mime.is_none() {
+                picture.mime = resp.attributes.get_opt("type")?;
+            }
+
+            // guard against looping forever on a zero length chunk
+            if binary.is_empty() {
+                break;
+            }
+
+            offset += binary.len();
+            picture.data.extend(binary);
+
+            if offset >= size {
+                break;
+            }
+        }
+
+        Ok(Some(picture))
+    }
+
+    pub async fn outputs(&self) -> Result<Vec<Output>> {
+        let resp = self.command("outputs", &[]).await?;
+
+        resp.attributes.split_at("outputid")
+            .into_iter()
+            .map(parse_output)
+            .collect::<Result<Vec<_>>>()
+            .context("parsing outputs response")
+    }
+
+    pub async fn stats(&self) -> Result<Stats> {
+        let resp = self.command("stats", &[]).await?;
+        Stats::from_attributes(&resp.attributes).context("parsing stats response")
+    }
+
+    pub async fn decoders(&self) -> Result<Vec<Decoder>> {
+        let resp = self.command("decoders", &[]).await?;
+
+        resp.attributes.split_at("plugin")
+            .into_iter()
+            .map(|attrs| Ok(Decoder {
+                plugin: attrs.get("plugin")?,
+                suffixes: attrs.get_all("suffix").map(str::to_owned).collect(),
+                mime_types: attrs.get_all("mime_type").map(str::to_owned).collect(),
+            }))
+            .collect::<Result<Vec<_>>>()
+            .context("parsing decoders response")
+    }
+
+    /// url schemes mpd can play, eg. http://
+    pub async fn urlhandlers(&self) -> Result<Vec<String>> {
+        let resp = self.command("urlhandlers", &[]).await?;
+        Ok(resp.attributes.get_all("handler").map(str::to_owned).collect())
+    }
+
+    pub async fn enableoutput(&self, id: usize) -> Result<()> {
+        let id = id.to_string();
+        self.command("enableoutput", &[&id]).await?;
+        Ok(())
+    }
+
+    pub async fn disableoutput(&self, id: usize) -> Result<()> {
+        let id = id.to_string();
+        self.command("disableoutput", &[&id]).await?;
+        Ok(())
+    }
+
+    #[allow(unused)]
+    pub async fn toggleoutput(&self, id: usize) -> Result<()> {
+        let id = id.to_string();
+        self.command("toggleoutput", &[&id]).await?;
+        Ok(())
+    }
+
+    /// switches to the named partition, including on any later connection
+    pub async fn partition(&self, name: &str) -> Result<()> {
+        self.command("partition", &[name]).await?;
+        *self.partition.lock().unwrap() = Some(name.to_string());
+        Ok(())
+    }
+
+    pub async fn newpartition(&self, name: &str) -> Result<()> {
+        self.command("newpartition", &[name]).await?;
+        Ok(())
+    }
+
+    pub async fn listpartitions(&self) -> Result<Vec<String>> {
+        let resp = self.command("listpartitions", &[]).await?;
+        Ok(resp.attributes.get_all("partition").map(str::to_owned).collect())
+    }
+
+    /// moves the named output into this connection's partition
+    pub async fn moveoutput(&self, name: &str) -> Result<()> {
+        self.command("moveoutput", &[name]).await?;
         Ok(())
     }
 
@@ -203,15 +704,92 @@ impl Mpd {
             ReplayGainMode::Auto => "auto",
         };
 
-        self.conn.command("replay_gain_mode", &[mode]).await?;
+        self.command("replay_gain_mode", &[mode]).await?;
         Ok(())
     }
 }
 
+// constructors for commands commonly sent in a command list
+impl Command {
+    pub fn clear() -> Self {
+        Command::new("clear", &[])
+    }
+
+    pub fn addid(location: &str) -> Self {
+        Command::new("addid", &[location])
+    }
+
+    pub fn addid_at(location: &str, pos: usize) -> Self {
+        Command::new("addid", &[location, &pos.to_string()])
+    }
+
+    /// only remote songs can be tagged
+    pub fn addtagid(id: &Id, tag: &str, value: &str) -> Self {
+        Command::new("addtagid", &[id.as_str(), tag, value])
+    }
+
+    pub fn single(mode: SingleMode) -> Self {
+        Command::new("single", &[single_mode(mode)])
+    }
+
+    pub fn consume(consume: bool) -> Self {
+        Command::new("consume", &[boolean(consume)])
+    }
+
+    pub fn load(name: &str) -> Self {
+        Command::new("load", &[name])
+    }
+
+    pub fn setvol(volume: usize) -> Self {
+        Command::new("setvol", &[&cmp::min(100, volume).to_string()])
+    }
+
+    pub fn pause() -> Self {
+        Command::new("pause", &["1"])
+    }
+
+    pub fn delete(pos: usize) -> Self {
+        Command::new("delete", &[&pos.to_string()])
+    }
+
+    pub fn play() -> Self {
+        Command::new("play", &[])
+    }
+
+    pub fn playpos(pos: usize) -> Self {
+        Command::new("play", &[&pos.to_string()])
+    }
+
+    pub fn seek(index: usize, time: f64) -> Self {
+        Command::new("seek", &[&index.to_string(), &time.to_string()])
+    }
+
+    pub fn random(shuffle: bool) -> Self {
+        Command::new("random", &[boolean(shuffle)])
+    }
+
+    pub fn repeat(repeat: bool) -> Self {
+        Command::new("repeat", &[boolean(repeat)])
+    }
+}
+
 fn position(pos: isize) -> String {
     format!("{pos:+}")
 }
 
+fn no_exist(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<ErrorResponse>()
+        .is_some_and(|ack| ack.code == AckCode::NoExist)
+}
+
+fn single_mode(mode: SingleMode) -> &'static str {
+    match mode {
+        SingleMode::Off => "0",
+        SingleMode::On => "1",
+        SingleMode::Oneshot => "oneshot",
+    }
+}
+
 fn boolean(b: bool) -> &'static str {
     if b { "1" } else { "0" }
 }
@@ -223,18 +801,31 @@ fn parse_playlist_item(attrs: Attributes) -> Result<PlaylistItem> {
         id: attrs.get("Id")?,
         title: attrs.get_one("Title").map(str::to_owned),
         name: attrs.get_one("Name").map(str::to_owned),
+        artist: attrs.get_one("Artist").map(str::to_owned),
+        album: attrs.get_one("Album").map(str::to_owned),
+        duration: attrs.get_opt("duration")?,
+    })
+}
+
+fn parse_output(attrs: Attributes) -> Result<Output> {
+    Ok(Output {
+        id: attrs.get("outputid")?,
+        name: attrs.get("outputname")?,
+        plugin: attrs.get_opt("plugin")?,
+        enabled: attrs.get_bool("outputenabled")?,
     })
 }
 
 struct Conn {
     reader: tokio::task::JoinHandle<()>,
-    keepalive: tokio::task::JoinHandle<()>,
     shared: Arc<ConnShared>,
 }
 
 struct ConnShared {
     writer: AsyncMutex<MpdWriter>,
     queue: ResponseQueue,
+    timeout: Duration,
+    healthy: AtomicBool,
 }
 
 type ResponseQueue = Arc<AsyncMutex<VecDeque<ResponseWait>>>;
@@ -243,44 +834,98 @@ struct ResponseWait {
     finish: oneshot::Sender<Response>,
 }
 
-#[derive(Debug, Display)]
-#[display("args: {args:?}")]
-struct Command {
-    command: String,
-    args: Vec<String>,
-}
-
 impl Conn {
     pub async fn connect(config: &Config) -> Result<(Conn, Protocol)> {
         let sock = UnixStream::connect(&config.socket).await?;
         let (rx, tx) = sock.into_split();
-        let (reader, proto) = MpdReader::open(rx).await?;
+        let (reader, proto) = MpdReader::open(rx, config.limits).await?;
 
         let shared = Arc::new(ConnShared {
             writer: tokio::sync::Mutex::new(MpdWriter::open(tx)),
             queue: ResponseQueue::default(),
+            timeout: config.command_timeout,
+            healthy: AtomicBool::new(true),
         });
 
         let reader = tokio::task::spawn(conn_reader(reader, shared.clone()));
-        let keepalive = tokio::task::spawn(conn_keepalive(shared.clone()));
 
-        Ok((Conn { reader, keepalive, shared }, proto))
+        // don't go through Conn::command, its error context would include
+        // the password
+        if let Some(password) = &config.password {
+            let result = try_command(&shared, "password", &[password]).await;
+            if let Err(err) = ok_response(result) {
+                reader.abort();
+                return Err(err.context("mpd password authentication failed"));
+            }
+        }
+
+        Ok((Conn { reader, shared }, proto))
     }
+}
 
-    async fn command(&self, cmd: &str, args: &[&str]) -> Result<OkResponse> {
-        let result = try_command(&self.shared, cmd, args).await;
+impl Backend for Conn {
+    fn command<'a>(&'a self, cmd: &'a str, args: &'a [&'a str]) -> BoxFuture<'a, Result<OkResponse>> {
+        Box::pin(async move {
+            let span = tracing::trace_span!("mpd", command = cmd);
+            let result = try_command(&self.shared, cmd, args).instrument(span).await;
 
-        ok_response(result).with_context(|| Command {
-            command: cmd.to_string(),
-            args: args.iter().map(|s| s.to_string()).collect(),
+            ok_response(result).with_context(|| Command::new(cmd, args))
+        })
+    }
+
+    fn command_list<'a>(&'a self, commands: &'a [Command]) -> BoxFuture<'a, Result<Vec<Attributes>>> {
+        Box::pin(async move {
+            let span = tracing::trace_span!("mpd", commands = commands.len());
+            let result = try_command_list(&self.shared, commands).instrument(span).await;
+
+            match ok_response(result) {
+                Ok(resp) => Ok(resp.list),
+                Err(err) => {
+                    // mpd reports the index of the failing command in the list
+                    let index = err.downcast_ref::<ErrorResponse>().map(|ack| ack.index);
+                    let command = index.and_then(|index| commands.get(index)).cloned();
+
+                    Err(match command {
+                        Some(command) => err.context(command),
+                        None => err.context("command list"),
+                    })
+                }
+            }
         })
     }
+
+    fn idle<'a>(&'a self, cancel: BoxFuture<'a, ()>) -> BoxFuture<'a, Result<Changed>> {
+        Box::pin(async move {
+            let shared = &self.shared;
+            check_healthy(shared)?;
+
+            let mut rx = send_command(shared, "idle", idle::SUBSYSTEMS).await?;
+
+            // idle waits indefinitely by design, so is exempt from the timeout
+            let response = tokio::select! {
+                response = &mut rx => response?,
+                () = cancel => {
+                    // mpd finishes the pending idle in response to noidle, there
+                    // is no separate response to wait for
+                    shared.writer.lock().await.send_command("noidle", &[]).await?;
+                    rx.await?
+                }
+            };
+
+            let resp = ok_response(Ok(response)).context("idle")?;
+            Changed::from_attributes(&resp.attributes)
+        })
+    }
+
+    // the reader stops once mpd goes away, or if it panics
+    fn is_healthy(&self) -> bool {
+        self.shared.healthy.load(Ordering::SeqCst) && !self.reader.is_finished()
+    }
 }
 
 impl Drop for Conn {
     fn drop(&mut self) {
         self.reader.abort();
-        self.keepalive.abort();
     }
 }
 
@@ -288,7 +933,40 @@ fn ok_response(result: Result<Response>) -> Result<OkResponse> {
     Ok(result??)
 }
 
+#[derive(Error, Debug)]
+#[error("timed out waiting for mpd to respond")]
+pub struct TimeoutError;
+
+#[derive(Error, Debug)]
+#[error("mpd connection is unhealthy after a previous command timed out")]
+pub struct UnhealthyError;
+
+async fn try_command_list(shared: &ConnShared, commands: &[Command]) -> Result<Response> {
+    check_healthy(shared)?;
+
+    let (tx, rx) = oneshot::channel();
+
+    // same locking protocol as send_command, the whole list is written while
+    // holding the writer lock and receives a single response
+    {
+        let mut writer = shared.writer.lock().await;
+        let mut queue = shared.queue.lock().await;
+        queue.push_back(ResponseWait { finish: tx });
+
+        writer.send_command_list(commands).await?;
+    }
+
+    wait_response(shared, rx).await
+}
+
 async fn try_command(shared: &ConnShared, cmd: &str, args: &[&str]) -> Result<Response> {
+    check_healthy(shared)?;
+
+    let rx = send_command(shared, cmd, args).await?;
+    wait_response(shared, rx).await
+}
+
+async fn send_command(shared: &ConnShared, cmd: &str, args: &[&str]) -> Result<oneshot::Receiver<Response>> {
     let (tx, rx) = oneshot::channel();
 
     // first take async lock on writer to write command to socket
@@ -296,49 +974,153 @@ async fn try_command(shared: &ConnShared, cmd: &str, args: &[&str]) -> Result<Re
 
     // take sync lock on queue while still holding async writer
     // lock to ensure correct queue ordering
-    {
-        let mut queue = shared.queue.lock().await;
-        queue.push_back(ResponseWait { finish: tx });
+    let mut queue = shared.queue.lock().await;
+    queue.push_back(ResponseWait { finish: tx });
 
-        writer.send_command(cmd, args).await?;
-    }
+    writer.send_command(cmd, args).await?;
+    Ok(rx)
+}
 
-    // if command is "idle", then the connection goes into a special state
-    // where it is invalid to send additional pipelined commands. the simple
-    // way to enforce this is to continue holding the async writer lock while
-    // waiting for a response. for all other commands, unlock here.
-    if !is_idle(cmd) {
-        drop(writer);
+async fn wait_response(shared: &ConnShared, rx: oneshot::Receiver<Response>) -> Result<Response> {
+    let result = tokio::time::timeout(shared.timeout, rx).await;
+
+    match result {
+        Ok(response) => Ok(response?),
+        Err(_) => {
+            // if mpd does respond later, that response would be matched up
+            // with the wrong waiter. there's no recovering from that, so
+            // mark the connection unhealthy and fail everything in flight,
+            // including our own stale waiter
+            shared.healthy.store(false, Ordering::SeqCst);
+            shared.queue.lock().await.clear();
+            Err(TimeoutError.into())
+        }
     }
-
-    Ok(rx.await?)
 }
 
-fn is_idle(cmd: &str) -> bool {
-    cmd.trim_ascii().eq_ignore_ascii_case("idle")
+fn check_healthy(shared: &ConnShared) -> Result<()> {
+    if shared.healthy.load(Ordering::SeqCst) {
+        Ok(())
+    } else {
+        Err(UnhealthyError.into())
+    }
 }
 
 async fn conn_reader(mut reader: MpdReader, shared: Arc<ConnShared>) {
     loop {
-        let response = reader.read_response().await
-            .expect("lost mpd connection");
+        let response = match reader.read_response().await {
+            Ok(response) => response,
+            Err(err) => {
+                log::warn!("lost mpd connection: {err:?}");
+
+                // fail everything in flight rather than leave it to time out
+                shared.healthy.store(false, Ordering::SeqCst);
+                shared.queue.lock().await.clear();
+                return;
+            }
+        };
 
         let mut queue = shared.queue.lock().await;
-        let Some(front) = queue.pop_front() else { unreachable!() };
+        let Some(front) = queue.pop_front() else {
+            // waiters are only ever removed after a timeout
+            log::warn!("discarding late response from mpd: {response:?}");
+            continue;
+        };
         let _ = front.finish.send(response);
     }
 }
 
-async fn conn_keepalive(shared: Arc<ConnShared>) {
-    loop {
-        tokio::time::sleep(KEEPALIVE_INTERVAL).await;
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
 
-        match try_command(&shared, "ping", &[]).await {
-            Ok(Ok(_)) => {}
-            Ok(Err(err)) => {
-                log::warn!("error pinging in keepalive task: {err}");
-            }
-            Err(_) => break
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::UnixListener;
+
+    use super::*;
+
+    // answers every command with OK, besides the first command on the first
+    // connection, which it never answers
+    async fn stalling_mpd(listener: UnixListener) {
+        let mut stall = true;
+
+        loop {
+            let (sock, _) = listener.accept().await.unwrap();
+            let mut stalled = !std::mem::replace(&mut stall, false);
+
+            tokio::spawn(async move {
+                let (rx, mut tx) = sock.into_split();
+                tx.write_all(b"OK MPD 0.23.5\n").await.unwrap();
+
+                let mut lines = BufReader::new(rx).lines();
+
+                while let Ok(Some(_)) = lines.next_line().await {
+                    if !stalled {
+                        stalled = true;
+                        continue;
+                    }
+
+                    if tx.write_all(b"OK\n").await.is_err() {
+                        break;
+                    }
+                }
+            });
         }
     }
+
+    fn listen(name: &str) -> (PathBuf, UnixListener) {
+        let socket = std::env::temp_dir().join(format!("sonicast-{name}-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&socket);
+        let listener = UnixListener::bind(&socket).unwrap();
+        (socket, listener)
+    }
+
+    fn config(socket: &Path) -> Config {
+        Config {
+            socket: socket.to_path_buf(),
+            password: None,
+            command_timeout: Duration::from_millis(100),
+            limits: Limits::default(),
+            status_max_age: DEFAULT_STATUS_MAX_AGE,
+            keepalive_interval: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn reconnects_after_timeout() {
+        let (socket, listener) = listen("reconnect");
+        tokio::spawn(stalling_mpd(listener));
+
+        let config = config(&socket);
+
+        let mpd = Mpd::connect(&config).await.unwrap();
+
+        let err = mpd.command("ping", &[]).await.unwrap_err();
+        assert!(err.chain().any(|cause| cause.is::<TimeoutError>()));
+        assert!(!mpd.is_healthy());
+
+        mpd.command("ping", &[]).await.unwrap();
+        assert!(mpd.is_healthy());
+
+        let _ = std::fs::remove_file(&socket);
+    }
+
+    #[tokio::test]
+    async fn connection_check_recovers() {
+        let (socket, listener) = listen("recovers");
+        tokio::spawn(stalling_mpd(listener));
+
+        let config = config(&socket);
+
+        let mpd = Mpd::connect(&config).await.unwrap();
+        assert!(mpd.check_connection().await);
+
+        mpd.command("ping", &[]).await.unwrap_err();
+        assert!(!mpd.is_healthy());
+
+        assert!(mpd.check_connection().await);
+        assert!(mpd.is_healthy());
+
+        let _ = std::fs::remove_file(&socket);
+    }
 }
@@ -1,11 +1,43 @@
+use std::fmt::{self, Write as _};
+use std::ops::Range;
 use std::str::FromStr;
+use std::sync::Arc;
 
 use anyhow::{Context, anyhow, bail};
+use derive_more::Display;
 use thiserror::Error;
 use tokio::io::{BufReader, AsyncRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 pub struct MpdReader {
     r: BufReader<Box<dyn AsyncRead + Sync + Send + Unpin>>,
+    limits: Limits,
+}
+
+// binary data is read this much at a time, rather than allocating the
+// length mpd claims up front
+const BINARY_CHUNK_SIZE: usize = 64 * 1024;
+
+/// bounds on responses, so a misbehaving mpd can't run sonicast out of
+/// memory. responses which exceed them fail the connection
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// bytes, excluding the newline
+    pub max_line_length: usize,
+    /// attributes in one response, across every command of a command list
+    pub max_attributes: usize,
+    /// bytes of binary data in one response
+    pub max_binary_size: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_line_length: 1024 * 1024,
+            // a generous queue of 100k tracks at ~10 tags each
+            max_attributes: 1_000_000,
+            max_binary_size: 16 * 1024 * 1024,
+        }
+    }
 }
 
 pub struct Protocol {
@@ -21,50 +53,77 @@ pub enum Error {
 }
 
 impl MpdReader {
-    pub async fn open<R>(r: R) -> anyhow::Result<(Self, Protocol)>
+    pub async fn open<R>(r: R, limits: Limits) -> anyhow::Result<(Self, Protocol)>
         where R: AsyncRead + Sync + Send + Unpin + 'static
     {
-        let mut r = BufReader::new(Box::new(r) as Box<_>);
+        let r = BufReader::new(Box::new(r) as Box<_>);
+        let mut reader = MpdReader { r, limits };
 
-        let mut line = String::new();
-        r.read_line(&mut line).await?;
+        let mut line = Vec::new();
+        reader.read_line(&mut line).await?;
+        let line = String::from_utf8_lossy(&line);
         let line = line.trim_end();
 
-        let Some(proto) = prefixed("OK MPD ", &line) else {
+        let Some(proto) = prefixed("OK MPD ", line) else {
             bail!("unexpected initial line from mpd: {line:?}")
         };
 
-        let reader = MpdReader { r };
         let protocol = Protocol { version: proto.to_string() };
 
         Ok((reader, protocol))
     }
 
+    /// reads up to and including the newline, leaving buff empty at eof.
+    /// lines need not be utf-8, eg. file names on some filesystems
+    async fn read_line(&mut self, buff: &mut Vec<u8>) -> anyhow::Result<()> {
+        let max = self.limits.max_line_length;
+
+        // one extra for the newline
+        (&mut self.r).take(max as u64 + 1).read_until(b'\n', buff).await?;
+
+        if buff.len() > max && buff.last() != Some(&b'\n') {
+            bail!("line from mpd longer than {max} bytes");
+        }
+
+        Ok(())
+    }
+
     pub async fn read_response(&mut self) -> Result<Response, Error> {
         let mut attributes = Attributes::default();
         let mut binary = None;
+        let mut list = Vec::new();
 
-        let mut buff = String::new();
+        let mut attribute_count = 0;
+
+        let mut buff = Vec::new();
         loop {
             buff.truncate(0);
-            self.r.read_line(&mut buff).await?;
-            if buff.len() == 0 {
+            self.read_line(&mut buff).await?;
+            if buff.is_empty() {
                 return Err(Error::ProtocolError(anyhow!("connection eof")));
             }
 
-            let line = buff.trim_end();
+            let line = String::from_utf8_lossy(&buff);
+            let line = line.trim_end();
             log::trace!("recv: {line}");
 
             if line == "OK" {
                 return Ok(Ok(OkResponse {
                     attributes,
                     binary,
+                    list,
                 }));
             }
 
+            // sent after each command's response inside a command list
+            // started with command_list_ok_begin
+            if line == "list_OK" {
+                list.push(std::mem::take(&mut attributes));
+                continue;
+            }
+
             if let Some(line) = prefixed("ACK ", line) {
-                let line = line.to_string();
-                return Ok(Err(ErrorResponse { line }));
+                return Ok(Err(ErrorResponse::parse(line)));
             }
 
             if let Some(len) = prefixed("binary: ", line) {
@@ -73,8 +132,14 @@ impl MpdReader {
             }
 
             if let Some((key, value)) = line.split_once(":") {
-                let value = value.trim_start();
-                attributes.attrs.push((key.to_string(), value.to_string()));
+                attribute_count += 1;
+
+                if attribute_count > self.limits.max_attributes {
+                    let max = self.limits.max_attributes;
+                    return Err(Error::ProtocolError(anyhow!("response from mpd has more than {max} attributes")));
+                }
+
+                attributes.push(key, value.trim_start());
             } else {
                 return Err(Error::ProtocolError(anyhow!("unrecognised response line from mpd: {line:?}")));
             }
@@ -82,9 +147,22 @@ impl MpdReader {
     }
 
     async fn read_binary(&mut self, len: &str) -> anyhow::Result<Vec<u8>> {
-        let len = len.parse().context("parsing length of binary data")?;
-        let mut bin = Vec::with_capacity(len);
-        self.r.read_exact(&mut bin).await.context("reading binary data")?;
+        let len = len.parse::<usize>().context("parsing length of binary data")?;
+
+        let max = self.limits.max_binary_size;
+        if len > max {
+            bail!("binary data from mpd of {len} bytes is over the limit of {max}");
+        }
+
+        // grows as data actually arrives
+        let mut bin = Vec::new();
+        while bin.len() < len {
+            let chunk = (len - bin.len()).min(BINARY_CHUNK_SIZE);
+            let start = bin.len();
+            bin.resize(start + chunk, 0);
+            self.r.read_exact(&mut bin[start..]).await.context("reading binary data")?;
+        }
+
         let nl = self.r.read_u8().await.context("reading binary trailing newline")?;
         if nl != b'\n' {
             bail!("binary data did not end with trailing newline");
@@ -94,39 +172,122 @@ impl MpdReader {
 }
 
 fn prefixed<'a>(prefix: &str, s: &'a str) -> Option<&'a str> {
-    if s.starts_with(prefix) {
-        Some(&s[prefix.len()..])
-    } else {
-        None
-    }
+    s.strip_prefix(prefix)
 }
 
 pub type Response = Result<OkResponse, ErrorResponse>;
 
 #[derive(Error, Debug)]
-#[error("command returned error: {line}")]
+#[error("command returned error: [{code}@{index}] {{{command}}} {message}")]
 pub struct ErrorResponse {
-    pub line: String,
+    pub code: AckCode,
+    /// position of the failing command within a command list, 0 otherwise
+    pub index: usize,
+    pub command: String,
+    pub message: String,
+}
+
+impl ErrorResponse {
+    /// parses the remainder of an ACK line, which is of the form:
+    /// `[errorcode@command_listNum] {current_command} message_text`.
+    /// anything else is still an error for the command, and the connection
+    /// carries on, so it's kept whole as the message
+    fn parse(line: &str) -> Self {
+        let parse = || -> Option<ErrorResponse> {
+            let (ack, rest) = prefixed("[", line)?.split_once("] ")?;
+            let (code, index) = ack.split_once('@')?;
+            let (command, message) = prefixed("{", rest)?.split_once("}")?;
+
+            Some(ErrorResponse {
+                code: code.parse::<usize>().ok()?.into(),
+                index: index.parse().ok()?,
+                command: command.to_string(),
+                message: message.trim_start().to_string(),
+            })
+        };
+
+        parse().unwrap_or_else(|| {
+            log::warn!("malformed ACK line from mpd: {line:?}");
+
+            ErrorResponse {
+                code: AckCode::Other(0),
+                index: 0,
+                command: String::new(),
+                message: line.to_string(),
+            }
+        })
+    }
+}
+
+/// ack error codes, see `enum ack` in mpd's src/protocol/Ack.hxx
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+pub enum AckCode {
+    NotList,
+    Arg,
+    Password,
+    Permission,
+    Unknown,
+    NoExist,
+    PlaylistMax,
+    System,
+    PlaylistLoad,
+    UpdateAlready,
+    PlayerSync,
+    Exist,
+    Other(usize),
+}
+
+impl From<usize> for AckCode {
+    fn from(code: usize) -> Self {
+        match code {
+            1 => AckCode::NotList,
+            2 => AckCode::Arg,
+            3 => AckCode::Password,
+            4 => AckCode::Permission,
+            5 => AckCode::Unknown,
+            50 => AckCode::NoExist,
+            51 => AckCode::PlaylistMax,
+            52 => AckCode::System,
+            53 => AckCode::PlaylistLoad,
+            54 => AckCode::UpdateAlready,
+            55 => AckCode::PlayerSync,
+            56 => AckCode::Exist,
+            _ => AckCode::Other(code),
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct OkResponse {
     pub attributes: Attributes,
-    #[allow(unused)]
     pub binary: Option<Vec<u8>>,
+    /// per-command responses when this is the response to a command list
+    pub list: Vec<Attributes>,
 }
 
-#[derive(Debug, Default)]
+/// a response's attributes, kept back to back in one buffer rather than
+/// as a pair of strings each. responses such as status are read several
+/// times a second, this saves allocating for every line of them. the
+/// buffer is shared by the parts split_at returns, rather than copied
+#[derive(Default)]
 pub struct Attributes {
-    attrs: Vec<(String, String)>
+    buf: Arc<String>,
+    /// where each key and value is in buf
+    attrs: Vec<(Range<usize>, Range<usize>)>,
+}
+
+impl fmt::Debug for Attributes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
 }
 
 impl Attributes {
     pub fn get<T: FromStr<Err = E>, E: Send + Sync + std::error::Error + 'static>(&self, name: &str) -> anyhow::Result<T> {
-        Ok(self.get_one(name)
+        self.get_one(name)
             .ok_or_else(|| anyhow!("missing {name} attribute"))?
             .parse()
-            .with_context(|| format!("malformed {name} attribute"))?)
+            .with_context(|| format!("malformed {name} attribute"))
     }
 
     pub fn get_bool(&self, name: &str) -> anyhow::Result<bool> {
@@ -145,14 +306,29 @@ impl Attributes {
             .with_context(|| format!("malformed {name} attribute"))
     }
 
+    pub fn push(&mut self, name: &str, value: impl fmt::Display) {
+        // only copies if the buffer is shared, which it never is while a
+        // response is being read
+        let buf = Arc::make_mut(&mut self.buf);
+
+        let key_start = buf.len();
+        buf.push_str(name);
+        let value_start = buf.len();
+
+        // can't fail writing to a string
+        let _ = write!(buf, "{value}");
+
+        self.attrs.push((key_start..value_start, value_start..buf.len()));
+    }
+
     pub fn get_one(&self, name: &str) -> Option<&'_ str> {
-        Some(&self.attrs.iter().find(|(k, _)| k == name)?.1)
+        self.iter().find(|(k, _)| *k == name).map(|(_, v)| v)
     }
 
     pub fn get_all<'a, 'n: 'a>(&'a self, name: &'n str) -> impl Iterator<Item = &'a str> {
-        self.attrs.iter().filter_map(move |(k, v)| {
+        self.iter().filter_map(move |(k, v)| {
             if k == name {
-                Some(v.as_str())
+                Some(v)
             } else {
                 None
             }
@@ -160,11 +336,11 @@ impl Attributes {
     }
 
     pub fn split_at(self, name: &str) -> Vec<Attributes> {
-        let mut splits = Vec::new();
+        let mut splits = Vec::<Attributes>::new();
 
         for (k, v) in self.attrs {
-            if k == name {
-                splits.push(Attributes::default());
+            if self.buf[k.clone()] == *name {
+                splits.push(Attributes { buf: self.buf.clone(), attrs: Vec::new() });
             }
 
             if let Some(split) = splits.last_mut() {
@@ -175,9 +351,8 @@ impl Attributes {
         splits
     }
 
-    #[allow(unused)]
     pub fn iter(&self) -> impl Iterator<Item = (&'_ str, &'_ str)> {
-        self.attrs.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+        self.attrs.iter().map(|(k, v)| (&self.buf[k.clone()], &self.buf[v.clone()]))
     }
 }
 
@@ -193,30 +368,67 @@ impl MpdWriter {
     }
 
     pub async fn send_command(&mut self, cmd: &str, args: &[&str]) -> anyhow::Result<()> {
-        let mut line = cmd.to_string();
-        for arg in args {
-            line.push(' ');
-            line.push('"');
-            for c in arg.chars() {
-                match c {
-                    '"' | '\\' => {
-                        line.push('\\');
-                        line.push(c);
-                    }
-                    '\n' => {
-                        bail!("newline in command argument");
-                    }
-                    _ => {
-                        line.push(c);
-                    }
+        let mut line = String::new();
+        push_command(&mut line, cmd, args)?;
+        self.write(&line).await
+    }
+
+    pub async fn send_command_list(&mut self, commands: &[Command]) -> anyhow::Result<()> {
+        let mut lines = String::new();
+        push_command::<&str>(&mut lines, "command_list_ok_begin", &[])?;
+        for command in commands {
+            push_command(&mut lines, &command.command, &command.args)?;
+        }
+        push_command::<&str>(&mut lines, "command_list_end", &[])?;
+        self.write(&lines).await
+    }
+
+    async fn write(&mut self, lines: &str) -> anyhow::Result<()> {
+        self.w.write_all(lines.as_bytes()).await?;
+        for line in lines.lines() {
+            log::trace!("send: {line}");
+        }
+        Ok(())
+    }
+}
+
+fn push_command<A: AsRef<str>>(line: &mut String, cmd: &str, args: &[A]) -> anyhow::Result<()> {
+    line.push_str(cmd);
+    for arg in args {
+        line.push(' ');
+        line.push('"');
+        for c in arg.as_ref().chars() {
+            match c {
+                '"' | '\\' => {
+                    line.push('\\');
+                    line.push(c);
+                }
+                '\n' => {
+                    bail!("newline in command argument");
+                }
+                _ => {
+                    line.push(c);
                 }
             }
-            line.push('"');
         }
-        line.push('\n');
+        line.push('"');
+    }
+    line.push('\n');
+    Ok(())
+}
 
-        self.w.write_all(line.as_bytes()).await?;
-        log::trace!("send: {}", line.trim());
-        Ok(())
+#[derive(Debug, Display, Clone)]
+#[display("args: {args:?}")]
+pub struct Command {
+    pub command: String,
+    pub args: Vec<String>,
+}
+
+impl Command {
+    pub fn new(command: &str, args: &[&str]) -> Self {
+        Command {
+            command: command.to_string(),
+            args: args.iter().map(|s| s.to_string()).collect(),
+        }
     }
 }
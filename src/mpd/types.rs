@@ -7,7 +7,7 @@ use serde::{Serialize, Deserialize};
 
 use crate::mpd::protocol::Attributes;
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Id(String);
 
 impl Id {
@@ -39,6 +39,158 @@ pub struct PlaylistItem {
     pub name: Option<String>,
     #[allow(unused)]
     pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub duration: Option<Seconds>,
+}
+
+/// an entry of a directory in mpd's database, see lsinfo
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum LibraryEntry {
+    Directory {
+        path: String,
+    },
+    File {
+        path: String,
+        title: Option<String>,
+        artist: Option<String>,
+        album: Option<String>,
+        /// seconds
+        duration: Option<f64>,
+    },
+    Playlist {
+        path: String,
+    },
+}
+
+impl LibraryEntry {
+    /// entries start with a directory, file or playlist attribute, and
+    /// files carry their tags in the attributes that follow
+    pub fn list_from_attributes(attrs: &Attributes) -> Result<Vec<Self>> {
+        let mut entries = Vec::new();
+
+        for (name, value) in attrs.iter() {
+            let path = value.to_owned();
+
+            match name {
+                "directory" => entries.push(LibraryEntry::Directory { path }),
+                "playlist" => entries.push(LibraryEntry::Playlist { path }),
+                "file" => entries.push(LibraryEntry::File {
+                    path,
+                    title: None,
+                    artist: None,
+                    album: None,
+                    duration: None,
+                }),
+                _ => {
+                    let Some(LibraryEntry::File { title, artist, album, duration, .. }) = entries.last_mut() else {
+                        continue;
+                    };
+
+                    match name {
+                        "Title" => *title = Some(path),
+                        "Artist" => *artist = Some(path),
+                        "Album" => *album = Some(path),
+                        "duration" => *duration = Some(value.parse()?),
+                        _ => {}
+                    }
+                }
+            }
+        }
+
+        Ok(entries)
+    }
+}
+
+/// the current song with tags as the decoder sees them, which for radio
+/// streams includes the title the station sends
+#[derive(Debug, Clone)]
+pub struct CurrentSong {
+    pub pos: usize,
+    pub id: Id,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    /// name of a radio station
+    pub name: Option<String>,
+}
+
+impl CurrentSong {
+    /// None if there's no current song
+    pub fn from_attributes(attrs: &Attributes) -> Result<Option<Self>> {
+        if attrs.get_one("file").is_none() {
+            return Ok(None);
+        }
+
+        Ok(Some(CurrentSong {
+            pos: attrs.get("Pos")?,
+            id: attrs.get("Id")?,
+            title: attrs.get_one("Title").map(str::to_owned),
+            artist: attrs.get_one("Artist").map(str::to_owned),
+            album: attrs.get_one("Album").map(str::to_owned),
+            name: attrs.get_one("Name").map(str::to_owned),
+        }))
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct StoredPlaylist {
+    pub name: String,
+    pub last_modified: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Output {
+    pub id: usize,
+    pub name: String,
+    pub plugin: Option<String>,
+    pub enabled: bool,
+}
+
+/// counts and times from mpd's stats command. times are in seconds
+#[derive(Serialize, Debug, Clone)]
+pub struct Stats {
+    pub artists: usize,
+    pub albums: usize,
+    pub songs: usize,
+    pub uptime: u64,
+    /// time spent playing since mpd started
+    pub playtime: u64,
+    /// total duration of every song in the database
+    pub db_playtime: u64,
+    /// when the database was last updated, seconds since the unix epoch
+    pub db_update: Option<u64>,
+}
+
+impl Stats {
+    pub fn from_attributes(attrs: &Attributes) -> Result<Self> {
+        Ok(Stats {
+            // mpd leaves the counts out without a database
+            artists: attrs.get_opt("artists")?.unwrap_or_default(),
+            albums: attrs.get_opt("albums")?.unwrap_or_default(),
+            songs: attrs.get_opt("songs")?.unwrap_or_default(),
+            uptime: attrs.get("uptime")?,
+            playtime: attrs.get("playtime")?,
+            db_playtime: attrs.get_opt("db_playtime")?.unwrap_or_default(),
+            db_update: attrs.get_opt("db_update")?,
+        })
+    }
+}
+
+/// a decoder plugin and the files it can decode
+#[derive(Serialize, Debug, Clone)]
+pub struct Decoder {
+    pub plugin: String,
+    pub suffixes: Vec<String>,
+    pub mime_types: Vec<String>,
+}
+
+#[derive(Debug)]
+pub struct Picture {
+    pub mime: Option<String>,
+    pub data: Vec<u8>,
 }
 
 #[derive(Debug)]
@@ -75,6 +227,11 @@ pub enum MpdEvent {
     Player,
     Options,
     Mixer,
+    Output,
+    /// a database update started or finished
+    Update,
+    /// the database changed, after an update
+    Database,
 }
 
 impl FromStr for MpdEvent {
@@ -86,6 +243,9 @@ impl FromStr for MpdEvent {
             "playlist" => Ok(MpdEvent::Playlist),
             "options" => Ok(MpdEvent::Options),
             "mixer" => Ok(MpdEvent::Mixer),
+            "output" => Ok(MpdEvent::Output),
+            "update" => Ok(MpdEvent::Update),
+            "database" => Ok(MpdEvent::Database),
             _ => Err(()),
         }
     }
@@ -101,20 +261,65 @@ pub enum PlaybackState {
 #[derive(Debug, Copy, Clone, FromStr)]
 pub struct Seconds(pub f64);
 
-#[derive(Debug)]
+/// format of the audio being decoded, from mpd's samplerate:bits:channels
+#[derive(Debug, Copy, Clone, Serialize)]
+pub struct AudioFormat {
+    pub sample_rate: u32,
+    /// none for floating point samples
+    pub bits: Option<u32>,
+    pub channels: u32,
+}
+
+impl FromStr for AudioFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parts = s.split(':').collect::<Vec<_>>();
+
+        match parts.as_slice() {
+            // dsd is one bit at a multiple of 44.1kHz, eg. dsd64:2
+            [rate, channels] if rate.starts_with("dsd") => Ok(AudioFormat {
+                sample_rate: rate["dsd".len()..].parse::<u32>()? * 44100,
+                bits: Some(1),
+                channels: channels.parse()?,
+            }),
+            [rate, bits, channels] => Ok(AudioFormat {
+                sample_rate: rate.parse()?,
+                bits: match *bits {
+                    "f" => None,
+                    bits => Some(bits.parse()?),
+                },
+                channels: channels.parse()?,
+            }),
+            _ => bail!("unknown audio format: {s}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Status {
     pub state: PlaybackState,
     pub song: Option<usize>,
     pub song_id: Option<Id>,
     pub elapsed: Option<Seconds>,
     pub duration: Option<Seconds>,
-    #[allow(unused)]
-    pub audio_format: Option<String>,
+    pub audio_format: Option<AudioFormat>,
+    /// kbps of the current song, mpd only knows it while playing
+    pub bitrate: Option<u32>,
     pub playlist_version: u32,
+    pub playlist_length: usize,
     pub repeat: bool,
     pub random: bool,
-    pub single: bool,
+    pub single: SingleMode,
+    pub consume: bool,
+    pub crossfade: Option<Seconds>,
+    pub mixramp_db: Option<f64>,
+    pub mixramp_delay: Option<Seconds>,
     pub volume: Option<usize>,
+    /// job id of the database update in progress, if any
+    pub updating_db: Option<u32>,
+    /// why the last song failed to play, until cleared with clearerror
+    pub error: Option<String>,
 }
 
 impl Status {
@@ -133,12 +338,21 @@ impl Status {
             song_id: attrs.get_opt("songid")?,
             elapsed: attrs.get_opt("elapsed")?,
             duration: attrs.get_opt("duration")?,
-            audio_format: attrs.get_opt("audio")?,
+            // formats this doesn't understand aren't worth failing status over
+            audio_format: attrs.get_one("audio").and_then(|audio| audio.parse().ok()),
+            bitrate: attrs.get_opt("bitrate")?.filter(|bitrate| *bitrate > 0),
             playlist_version: attrs.get("playlist")?,
+            playlist_length: attrs.get("playlistlength")?,
             repeat: attrs.get_bool("repeat")?,
             random: attrs.get_bool("random")?,
-            single: attrs.get_bool("single")?,
+            single: attrs.get_opt("single")?.unwrap_or(SingleMode::Off),
+            consume: attrs.get_bool("consume")?,
+            crossfade: attrs.get_opt("xfade")?,
+            mixramp_db: attrs.get_opt("mixrampdb")?,
+            mixramp_delay: attrs.get_opt("mixrampdelay")?,
             volume: attrs.get_opt("volume")?,
+            updating_db: attrs.get_opt("updating_db")?,
+            error: attrs.get_one("error").map(str::to_owned),
         })
     }
 }
@@ -164,3 +378,23 @@ impl FromStr for ReplayGainMode {
         }
     }
 }
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SingleMode {
+    Off,
+    On,
+    Oneshot,
+}
+
+impl FromStr for SingleMode {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "0" => Ok(SingleMode::Off),
+            "1" => Ok(SingleMode::On),
+            "oneshot" => Ok(SingleMode::Oneshot),
+            _ => anyhow::bail!("unknown single mode: {s}")
+        }
+    }
+}
@@ -1,61 +1,262 @@
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
 
 use crate::podcasts::{Podcasts, PodcastsBase};
-use crate::{logging, podcasts};
-use crate::mpd::{self, Mpd};
-use crate::subsonic::{AuthParams, Subsonic, SubsonicBase};
+use crate::{listenbrainz, logging, podcasts};
+use crate::listenbrainz::ListenBrainz;
+use crate::snapcast::Snapcast;
+use crate::mpd::Mpd;
+use crate::subsonic::{AuthParams, CircuitOpen, StreamProxyConfig, Subsonic, SubsonicBase, SubsonicError, SubsonicErrorCode};
 use crate::util::broken_pipe;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use async_stream::stream;
-use axum::extract::State;
+use axum::extract::{Query, State};
 use axum::extract::ws::{self, WebSocket, WebSocketUpgrade};
 use axum::http::Method;
 use axum::response::IntoResponse;
-use axum::Form;
-use futures::{future, Stream};
+use axum::{Form, Json};
+use futures::Stream;
 use futures::sink::SinkExt;
 use futures::stream::{SplitSink, SplitStream};
 use futures::{pin_mut, StreamExt};
 use reqwest::StatusCode;
 use serde::{Deserialize, Serialize};
-use tokio::sync::{RwLock, RwLockWriteGuard, Mutex as AsyncMutex};
+use tokio::sync::{mpsc, watch, RwLockWriteGuard, Mutex as AsyncMutex};
 use tower_http::cors::{Any, CorsLayer};
 use tower::ServiceBuilder;
 use url::Url;
 
+mod albumart;
+mod alarms;
+mod announce;
+mod autoqueue;
+mod bookmarks;
+mod codec;
 mod commands;
+mod duck;
 mod events;
+mod external;
+mod flight;
+mod guests;
 mod helper;
+mod history;
+mod listen;
+mod mqtt;
+mod rate;
+mod persist;
+mod poll;
+mod resume;
+mod rooms;
+mod servers;
+mod skip;
+mod smart;
+mod sse;
+mod tokens;
+mod state;
+mod stream;
+mod tags;
+mod stats;
+mod supervisor;
 mod types;
+mod unavailable;
+mod upnp;
+mod visualizer;
+mod volume;
+mod zones;
+
+use rate::RateProxy;
+pub use guests::Config as GuestConfig;
+pub use listen::{Listen, TlsConfig};
+pub use mqtt::{Config as MqttConfig, DEFAULT_DISCOVERY_PREFIX, DEFAULT_TOPIC_PREFIX as DEFAULT_MQTT_TOPIC_PREFIX};
+pub use poll::DEFAULT_RETENTION as DEFAULT_EVENT_RETENTION;
+pub use rooms::{RoomBackend, RoomConfig, DEFAULT_ROOM};
+pub use servers::Config as ServerConfig;
+pub use upnp::Config as UpnpConfig;
+pub use volume::VolumeLimits;
+pub use visualizer::{Config as VisualizerConfig, DEFAULT_BINS as DEFAULT_VISUALIZER_BINS, DEFAULT_RATE as DEFAULT_VISUALIZER_RATE};
+use zones::Zone;
+
+pub const DEFAULT_RESOLVE_CONCURRENCY: usize = 8;
+
+/// bumped on incompatible changes to the websocket protocol. additions such
+/// as new commands don't need a bump, clients can check the command list
+/// sent in the server hello instead
+pub const PROTOCOL_VERSION: u32 = 2;
+
+static NEXT_SESSION_ID: AtomicU64 = AtomicU64::new(1);
+
+// sessions are closed after missing this many pongs in a row, so that the
+// event load of clients which silently dropped off the network goes away
+const PING_INTERVAL: Duration = Duration::from_secs(15);
+const MAX_MISSED_PONGS: usize = 3;
 
 pub struct Config {
-    pub listen: String,
+    /// ignored when systemd passes a listening socket
+    pub listen: Option<Listen>,
+    /// serve https and wss directly rather than behind a reverse proxy
+    pub tls: Option<TlsConfig>,
     pub subsonic_url: Url,
-    pub mpd: mpd::Config,
+    /// client for subsonic, including the extra servers and podcasts
+    pub http: reqwest::Client,
+    /// relay streams from subsonic to mpd, see stream::stream
+    pub stream_proxy: Option<StreamProxyConfig>,
+    /// more servers to queue from, with ids prefixed by their name
+    pub servers: Vec<servers::Config>,
+    /// track id prefixes mapped onto urls outside subsonic
+    pub external_sources: external::ExternalSources,
+    /// always includes the default room
+    pub rooms: Vec<rooms::RoomConfig>,
     pub podcasts: Option<podcasts::Config>,
+    /// maximum concurrent subsonic requests when resolving tracks
+    pub resolve_concurrency: usize,
+    /// transcoding proxy used to implement playback rates, see RateProxy
+    pub rate_proxy: Option<Url>,
+    pub alarms: alarms::AlarmList,
+    /// where to save queues and options so they survive restarts
+    pub state_file: Option<PathBuf>,
+    /// whether to restore saved state into empty queues on startup
+    pub restore_state: bool,
+    pub listenbrainz: Option<listenbrainz::Config>,
+    /// scrobble to the subsonic server, which may pass them on to last.fm
+    pub scrobble_subsonic: bool,
+    pub mqtt: Option<mqtt::Config>,
+    /// host:port of snapserver's control socket
+    pub snapcast: Option<String>,
+    /// expose each room as a upnp media renderer. its control endpoints
+    /// are unauthenticated, so anyone who can reach the http server can
+    /// play and replace the queue of every room
+    pub upnp: Option<upnp::Config>,
+    /// limits every zone starts out with
+    pub volume_limits: VolumeLimits,
+    /// volume 0-1 announcements play at, rather than the zone's volume
+    pub announce_volume: Option<f64>,
+    /// track id prefixes of long tracks, such as audiobooks, whose
+    /// positions are kept as subsonic bookmarks
+    pub bookmark_prefixes: Vec<String>,
+    /// seconds to skip at the start and end of each podcast's episodes
+    pub skip_offsets: skip::SkipOffsetList,
+    /// where to keep each user's recently played tracks
+    pub history_file: Option<PathBuf>,
+    /// tag queued stream urls with their track's title, artist and album
+    /// for the benefit of other mpd clients
+    pub tag_queue: bool,
+    /// play the next track when mpd fails to play the current one, rather
+    /// than stopping
+    pub skip_unavailable: bool,
+    /// who may connect without an account of their own, read only
+    pub guests: guests::Config,
+    /// events kept for clients which poll for them
+    pub event_retention: usize,
+    pub intervals: Intervals,
+}
+
+/// how often zones poll, and how long they let changes settle. battery
+/// powered servers may want these longer, so as to wake less
+#[derive(Debug, Clone, Copy)]
+pub struct Intervals {
+    /// playback events are resent this often, to correct clients' drift
+    pub playback_poll: Duration,
+    /// how long the queue must be quiet before its changes are signalled,
+    /// so that adding many tracks makes one queue event rather than one each
+    pub queue_debounce: Duration,
+    /// how long state waits after a change before being saved
+    pub save_delay: Duration,
+}
+
+impl Default for Intervals {
+    fn default() -> Self {
+        Intervals {
+            playback_poll: state::DEFAULT_PLAYBACK_POLL_INTERVAL,
+            queue_debounce: events::DEFAULT_QUEUE_DEBOUNCE,
+            save_delay: persist::DEFAULT_SAVE_DELAY,
+        }
+    }
 }
 
 pub async fn run(config: &Config) -> Result<()> {
     use axum::Router;
     use axum::routing::get;
 
-    let subsonic = SubsonicBase::new(&config.subsonic_url);
-    let podcasts = config.podcasts.as_ref().map(|config| PodcastsBase::new(config));
+    let state_file = config.state_file.clone().map(|path| Arc::new(persist::StateFile::new(path)));
+
+    let services = Services {
+        subsonic: match &config.stream_proxy {
+            Some(proxy) => SubsonicBase::proxied(&config.subsonic_url, config.http.clone(), proxy.clone()),
+            None => SubsonicBase::new(&config.subsonic_url, config.http.clone()),
+        },
+        podcasts: config.podcasts.as_ref().map(PodcastsBase::new),
+        rate_proxy: config.rate_proxy.clone().map(RateProxy::new),
+        stations: Default::default(),
+        servers: Arc::new(servers::Servers::new(&config.servers, &config.http)),
+        external: Arc::new(config.external_sources.clone()),
+        resolve_concurrency: config.resolve_concurrency,
+        state_file: state_file.clone(),
+        restore_state: config.restore_state,
+        listenbrainz: config.listenbrainz.as_ref().map(ListenBrainz::new),
+        scrobble_subsonic: config.scrobble_subsonic,
+        snapcast: config.snapcast.clone().map(Snapcast::new),
+        volume_limits: config.volume_limits,
+        announce_volume: config.announce_volume,
+        bookmark_prefixes: config.bookmark_prefixes.clone(),
+        skips: Arc::new(skip::Skips::new(config.skip_offsets.0.clone())),
+        positions: Arc::new(resume::Positions::open(state_file.clone()).await),
+        history: match &config.history_file {
+            Some(path) => Some(Arc::new(history::History::open(path.clone()).await?)),
+            None => None,
+        },
+        tag_queue: config.tag_queue,
+        skip_unavailable: config.skip_unavailable,
+        guests: config.guests.clone(),
+        event_log: Arc::new(poll::EventLog::new(config.event_retention)),
+        resume_tokens: Default::default(),
+        queue_reads: Default::default(),
+        supervisor: Default::default(),
+        intervals: config.intervals,
+    };
+
+    if let Some(state_file) = &services.state_file {
+        match state_file.load_stream_quality().await {
+            Ok(qualities) => {
+                for (user, quality) in qualities {
+                    services.subsonic.set_stream_quality(user, quality);
+                }
+            }
+            Err(err) => log::warn!("loading stream quality: {err:?}"),
+        }
+    }
 
-    let mpd = Mpd::connect(&config.mpd).await?;
-    let mpd_event = Mpd::connect(&config.mpd).await?;
+    let rooms = rooms::Rooms::open(&config.rooms, &services).await?;
 
-    let mpd = Arc::new(RwLock::new(mpd));
     let ctx = Ctx::new(AppData {
-        subsonic,
-        podcasts,
-        mpd,
-        events: events::MpdEvents::default(),
+        services,
+        rooms,
+        alarms: alarms::Alarms::new(config.alarms.0.clone()),
+        upnp: config.upnp.clone().map(upnp::Upnp::new),
+    });
+
+    let supervisor = &ctx.services.supervisor;
+
+    supervisor.spawn("alarms", {
+        let ctx = ctx.clone();
+        move || alarms::task(ctx.clone())
     });
 
-    // spawn mpd event task
-    tokio::task::spawn(events::task(mpd_event, ctx.events.clone()));
+    if let Some(mqtt) = config.mqtt.clone() {
+        supervisor.spawn("mqtt", {
+            let ctx = ctx.clone();
+            move || mqtt::task(ctx.clone(), mqtt.clone())
+        });
+    }
+
+    if ctx.upnp.is_some() {
+        supervisor.spawn("upnp", {
+            let ctx = ctx.clone();
+            move || upnp::task(ctx.clone())
+        });
+    }
 
     let cors = CorsLayer::new()
         .allow_methods([Method::GET, Method::POST])
@@ -64,84 +265,348 @@ pub async fn run(config: &Config) -> Result<()> {
 
     let app = Router::new()
         .route("/ws", get(websocket))
+        .route("/albumart", get(albumart::albumart))
+        .route("/stats", get(stats::stats))
+        .route("/events", get(sse::events))
+        .route("/poll", get(poll::poll))
+        .route("/stream/{track_id}", get(stream::stream))
+        .route("/readyz", get(supervisor::readyz))
+        .merge(commands::router())
+        .merge(upnp::router())
         .layer(ServiceBuilder::new().layer(cors))
         .with_state(ctx);
 
-    let listener = tokio::net::TcpListener::bind(&config.listen).await?;
-    axum::serve(listener, app).await?;
-    Ok(())
+    listen::serve(config.listen.as_ref(), config.tls.as_ref(), app).await
 }
 
 pub type Ctx = Arc<AppData>;
 
 pub struct AppData {
+    services: Services,
+    rooms: rooms::Rooms,
+    alarms: alarms::Alarms,
+    upnp: Option<upnp::Upnp>,
+}
+
+/// shared by every room, zone and session
+#[derive(Clone)]
+pub struct Services {
     subsonic: SubsonicBase,
     podcasts: Option<PodcastsBase>,
-    mpd: Arc<RwLock<Mpd>>,
-    events: events::MpdEvents,
+    rate_proxy: Option<RateProxy>,
+    stations: Arc<helper::StationCache>,
+    servers: Arc<servers::Servers>,
+    external: Arc<external::ExternalSources>,
+    resolve_concurrency: usize,
+    state_file: Option<Arc<persist::StateFile>>,
+    restore_state: bool,
+    listenbrainz: Option<ListenBrainz>,
+    scrobble_subsonic: bool,
+    snapcast: Option<Snapcast>,
+    volume_limits: VolumeLimits,
+    announce_volume: Option<f64>,
+    bookmark_prefixes: Vec<String>,
+    skips: Arc<skip::Skips>,
+    positions: Arc<resume::Positions>,
+    history: Option<Arc<history::History>>,
+    tag_queue: bool,
+    skip_unavailable: bool,
+    guests: guests::Config,
+    event_log: Arc<poll::EventLog>,
+    resume_tokens: Arc<tokens::ResumeTokens>,
+    /// queue reads in flight, shared by sessions asking at the same time
+    queue_reads: Arc<flight::SingleFlight<commands::Queue>>,
+    supervisor: Arc<supervisor::Supervisor>,
+    intervals: Intervals,
+}
+
+/// an http request refused, with a json body saying why so that frontends
+/// can show something more helpful than the status
+#[derive(Debug, Serialize)]
+pub struct HttpError {
+    #[serde(skip)]
+    status: StatusCode,
+    /// machine readable, eg. unauthorized or subsonic-unreachable
+    error: &'static str,
+    message: String,
+}
+
+impl HttpError {
+    fn new(status: StatusCode, error: &'static str, message: impl Into<String>) -> Self {
+        HttpError { status, error, message: message.into() }
+    }
+}
+
+impl From<StatusCode> for HttpError {
+    fn from(status: StatusCode) -> Self {
+        let message = status.canonical_reason().unwrap_or_default();
+        HttpError::new(status, "other", message)
+    }
+}
+
+impl IntoResponse for HttpError {
+    fn into_response(self) -> axum::response::Response {
+        (self.status, Json(self)).into_response()
+    }
+}
+
+/// works out from a failed authenticate whether the credentials were wrong
+/// or subsonic couldn't be reached
+fn auth_error(err: anyhow::Error) -> HttpError {
+    log::warn!("subsonic authenticate: {err:?}");
+
+    for cause in err.chain() {
+        if let Some(err) = cause.downcast_ref::<SubsonicError>() {
+            return match err.code() {
+                SubsonicErrorCode::Unauthorized => {
+                    HttpError::new(StatusCode::UNAUTHORIZED, "unauthorized", "wrong username or password")
+                }
+                _ => HttpError::new(StatusCode::BAD_GATEWAY, "subsonic-error", format!("{err}")),
+            };
+        }
+
+        if cause.is::<CircuitOpen>() {
+            return HttpError::new(StatusCode::SERVICE_UNAVAILABLE, "subsonic-unreachable", "subsonic is down");
+        }
+
+        if let Some(err) = cause.downcast_ref::<reqwest::Error>() {
+            return match err.is_timeout() {
+                true => HttpError::new(StatusCode::GATEWAY_TIMEOUT, "subsonic-timeout", "timed out reaching subsonic"),
+                false => HttpError::new(StatusCode::BAD_GATEWAY, "subsonic-unreachable", "couldn't reach subsonic"),
+            };
+        }
+    }
+
+    HttpError::new(StatusCode::INTERNAL_SERVER_ERROR, "other", format!("{err}"))
+}
+
+/// query params of the websocket, and of commands posted over http
+#[derive(Debug, Deserialize)]
+struct ConnectParams {
+    room: Option<String>,
+    zone: Option<String>,
+    /// gzip large server messages, see codec::Encoding::compress
+    #[serde(default)]
+    compress: bool,
+    /// connect read only, with one of the configured guest tokens
+    guest: Option<String>,
+    /// seq of the last queue event seen on a previous connection. queue
+    /// events missed since are sent before anything else, so the client
+    /// needn't fetch the whole queue again
+    resume: Option<u64>,
+    /// token from the hello of a previous connection, in place of
+    /// credentials
+    resume_token: Option<String>,
 }
 
 async fn websocket(
     ctx: State<Ctx>,
     ws: WebSocketUpgrade,
+    params: Query<ConnectParams>,
     auth: Form<AuthParams>,
-) -> Result<impl IntoResponse, StatusCode> {
-    let auth = Arc::new(auth.0);
-
-    let subsonic = ctx.subsonic.authenticate(auth.clone()).await
-        .map_err(|err| {
-            log::warn!("subsonic authenticate: {err:?}");
-            StatusCode::INTERNAL_SERVER_ERROR
-        })?;
-
-    let podcasts = open_podcasts(ctx.podcasts.as_ref(), auth).await
-        .map_err(|err| {
-            log::warn!("podcasts authenticate: {err:?}");
-            StatusCode::INTERNAL_SERVER_ERROR
-        })?;
-
-    Ok(ws.on_upgrade(move |socket| {
-        run_websocket(ctx.0, socket, subsonic, podcasts)
+) -> Result<impl IntoResponse, HttpError> {
+    let connection = connect(&ctx, &params, auth.0).await?;
+    let compress = params.compress;
+    let resume = params.resume;
+
+    Ok(ws.protocols(codec::PROTOCOLS).on_upgrade(move |socket| {
+        run_websocket(ctx.0, socket, connection, compress, resume)
     }))
 }
 
+/// who a session is for and what it controls, worked out from the params
+/// it connected with
+struct Connection {
+    subsonic: Subsonic,
+    podcasts: Option<Podcasts>,
+    zone: Arc<Zone>,
+    guest: bool,
+}
+
+impl Connection {
+    fn into_session(self, ctx: Ctx, tx: Sender, subscriptions: events::EventSet) -> Session {
+        Session {
+            id: NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed),
+            ctx,
+            tx,
+            subsonic: self.subsonic,
+            podcasts: self.podcasts,
+            zone: self.zone,
+            subscriptions: watch::Sender::new(subscriptions),
+            guest: self.guest,
+        }
+    }
+}
+
+async fn connect(ctx: &Ctx, params: &ConnectParams, auth: AuthParams) -> Result<Connection, HttpError> {
+    let (auth, guest, authenticated) = match &params.resume_token {
+        Some(token) => resume(ctx, token)?,
+        None => {
+            let auth = Arc::new(auth);
+            let guest = ctx.services.guests.admits(params.guest.as_deref(), &auth)?;
+
+            // guests have no credentials to check, they borrow the zone's below
+            let authenticated = match guest {
+                true => None,
+                false => Some(authenticate(ctx, auth.clone()).await?),
+            };
+
+            (auth, guest, authenticated)
+        }
+    };
+
+    let zones = match &params.room {
+        None => ctx.rooms.default_room(),
+        Some(name) => ctx.rooms.get(name)
+            .map_err(|err| {
+                log::warn!("opening room: {err:?}");
+                HttpError::new(StatusCode::NOT_FOUND, "not-found", format!("{err}"))
+            })?,
+    };
+
+    let zone = match &params.zone {
+        None => zones.default_zone().clone(),
+        Some(name) => zones.get(name).await
+            .map_err(|err| {
+                log::warn!("opening zone: {err:?}");
+                HttpError::new(StatusCode::NOT_FOUND, "not-found", format!("{err}"))
+            })?,
+    };
+
+    let (subsonic, podcasts) = match authenticated {
+        Some(clients) => {
+            zone.set_auth(auth);
+            clients
+        }
+        None => {
+            let auth = zone.auth().ok_or_else(|| {
+                log::warn!("rejecting guest, nobody has connected to zone {} yet", zone.name);
+                HttpError::new(StatusCode::SERVICE_UNAVAILABLE, "no-credentials", "nobody has connected to the zone yet")
+            })?;
+
+            (
+                ctx.services.subsonic.with_auth(auth.clone()),
+                ctx.services.podcasts.as_ref().map(|podcasts| podcasts.with_auth(auth)),
+            )
+        }
+    };
+
+    Ok(Connection { subsonic, podcasts, zone, guest })
+}
+
+type Authenticated = (Arc<AuthParams>, bool, Option<(Subsonic, Option<Podcasts>)>);
+
+// credentials were checked when the token was issued, so aren't again
+fn resume(ctx: &Ctx, token: &str) -> Result<Authenticated, HttpError> {
+    let Some(redeemed) = ctx.services.resume_tokens.redeem(token) else {
+        log::warn!("rejecting connection with unknown or expired resume token");
+        return Err(HttpError::new(StatusCode::UNAUTHORIZED, "unauthorized", "unknown or expired resume token"));
+    };
+
+    let authenticated = (!redeemed.guest).then(|| (
+        ctx.services.subsonic.with_auth(redeemed.auth.clone()),
+        ctx.services.podcasts.as_ref().map(|podcasts| podcasts.with_auth(redeemed.auth.clone())),
+    ));
+
+    Ok((redeemed.auth, redeemed.guest, authenticated))
+}
+
+async fn authenticate(ctx: &Ctx, auth: Arc<AuthParams>) -> Result<(Subsonic, Option<Podcasts>), HttpError> {
+    let subsonic = ctx.services.subsonic.authenticate(auth.clone()).await
+        .map_err(auth_error)?;
+
+    let podcasts = open_podcasts(ctx.services.podcasts.as_ref(), auth).await
+        .context("podcasts authenticate")
+        .map_err(auth_error)?;
+
+    Ok((subsonic, podcasts))
+}
+
 async fn open_podcasts(base: Option<&PodcastsBase>, params: Arc<AuthParams>) -> Result<Option<Podcasts>> {
     let Some(base) = base else { return Ok(None) };
     Ok(Some(base.authenticate(params).await?))
 }
 
-async fn run_websocket(ctx: Ctx, socket: WebSocket, subsonic: Subsonic, podcasts: Option<Podcasts>) {
+async fn run_websocket(ctx: Ctx, socket: WebSocket, connection: Connection, compress: bool, resume: Option<u64>) {
+    let encoding = codec::Encoding::from_protocol(socket.protocol());
     let (tx, rx) = socket.split();
 
-    let session = Session {
+    // guests resuming borrow the zone's credentials afresh, rather than the
+    // ones they had before
+    let resume_token = ctx.services.resume_tokens.issue(connection.subsonic.auth().clone(), connection.guest);
+
+    let session = connection.into_session(
         ctx,
-        tx: Sender::new(tx),
-        subsonic,
-        podcasts,
-    };
+        Sender::new(tx, encoding, compress),
+        events::EventKind::DEFAULT.iter().copied().collect(),
+    );
 
-    let receive_task = receive_task(&session, rx);
-    pin_mut!(receive_task);
+    // pings sent since the last pong
+    let missed_pongs = AtomicUsize::new(0);
 
-    let events_task = events::run_events(&session);
-    pin_mut!(events_task);
+    let result = tokio::select! {
+        result = receive_task(&session, rx, &missed_pongs, &resume_token) => result,
+        result = events::run_events(&session, resume) => result,
+        result = keepalive_task(&session.tx, &missed_pongs) => result,
+    };
 
-    let fut = future::select(receive_task, events_task);
-    let result = fut.await.factor_first().0;
+    session.ctx.services.resume_tokens.release(&resume_token);
 
     if let Err(err) = result {
         logging::error(&err);
     }
 }
 
-async fn receive_task(session: &Session, rx: SplitStream<WebSocket>) -> Result<()> {
-    let messages = message_stream(rx);
+async fn keepalive_task(tx: &Sender, missed_pongs: &AtomicUsize) -> Result<()> {
+    loop {
+        tokio::time::sleep(PING_INTERVAL).await;
+
+        let missed = missed_pongs.fetch_add(1, Ordering::SeqCst);
+        if missed >= MAX_MISSED_PONGS {
+            log::info!("closing websocket after {missed} missed pongs");
+            return Ok(());
+        }
+
+        // a dead connection can also show up as a send that never completes
+        tokio::time::timeout(PING_INTERVAL, tx.ping()).await
+            .map_err(|_| anyhow::format_err!("timed out sending websocket ping"))??;
+    }
+}
+
+async fn receive_task(session: &Session, rx: SplitStream<WebSocket>, missed_pongs: &AtomicUsize, resume_token: &str) -> Result<()> {
+    let messages = message_stream(rx, missed_pongs);
     pin_mut!(messages);
 
     while let Some(msg) = messages.next().await {
+        let msg = match msg {
+            Ok(msg) => msg,
+            Err(malformed) => {
+                commands::reject(session, malformed.seq, malformed.name.as_deref(), &malformed.error).await;
+                continue;
+            }
+        };
+
         match msg {
+            ClientMsg::Hello(hello) => {
+                log::info!("client {} speaking protocol version {}",
+                    hello.client.as_deref().unwrap_or("(unknown)"), hello.protocol_version);
+
+                session.tx.send(ServerMsg::Hello(ServerHello {
+                    protocol_version: PROTOCOL_VERSION,
+                    server: concat!("sonicast ", env!("CARGO_PKG_VERSION")),
+                    commands: commands::command_names(),
+                    resume_token: resume_token.to_owned(),
+                })).await;
+            }
             ClientMsg::Command(command) => {
-                commands::dispatch(session, command).await;
+                commands::dispatch(session, *command).await;
+            }
+            ClientMsg::Batch(batch) => {
+                commands::dispatch_batch(session, batch).await;
+            }
+            ClientMsg::Subscribe(subscribe) => {
+                let events = subscribe.events.into_iter().collect();
+                session.subscriptions.send_replace(events);
             }
         }
     }
@@ -149,7 +614,7 @@ async fn receive_task(session: &Session, rx: SplitStream<WebSocket>) -> Result<(
     Ok(())
 }
 
-fn message_stream(rx: SplitStream<WebSocket>) -> impl Stream<Item = ClientMsg> {
+fn message_stream(rx: SplitStream<WebSocket>, missed_pongs: &AtomicUsize) -> impl Stream<Item = Result<ClientMsg, MalformedCommand>> {
     stream! {
         pin_mut!(rx);
 
@@ -163,36 +628,107 @@ fn message_stream(rx: SplitStream<WebSocket>) -> impl Stream<Item = ClientMsg> {
                 }
             };
 
-            let ws::Message::Text(text) = msg else { continue };
-            log::debug!("rx msg: {text}");
+            if let ws::Message::Pong(_) = msg {
+                missed_pongs.store(0, Ordering::SeqCst);
+                continue;
+            }
 
-            let msg = match serde_json::from_str(&text) {
-                Ok(msg) => msg,
-                Err(err) => {
-                    log::warn!("json parse error in websocket message: {err}");
-                    continue;
-                }
-            };
+            let Some(frame) = codec::Frame::from_message(&msg) else { continue };
+
+            if let codec::Frame::Json(text) = frame {
+                log::debug!("rx msg: {text}");
+            }
+
+            match frame.decode() {
+                Ok(msg) => yield Ok(msg),
+                Err(error) => {
+                    log::warn!("parse error in websocket message: {error}");
 
-            yield msg;
+                    // commands we can't parse still get a response if we
+                    // can at least find their seq
+                    if let Ok(MalformedMsg { command }) = frame.decode() {
+                        yield Err(MalformedCommand { seq: command.seq, name: command.name, error });
+                    }
+                }
+            }
         }
     }
 }
 
 pub struct Session {
+    /// identifies the session in logs
+    id: u64,
     ctx: Ctx,
     tx: Sender,
     subsonic: Subsonic,
     podcasts: Option<Podcasts>,
+    zone: Arc<Zone>,
+    subscriptions: watch::Sender<events::EventSet>,
+    /// read only, see guests::Config
+    guest: bool,
 }
 
 impl Session {
+    /// a session for running commands against another room, sharing this
+    /// session's credentials and websocket
+    pub fn in_room(&self, room: &str) -> Result<Session> {
+        let zone = self.ctx.rooms.get(room)?.default_zone().clone();
+
+        // guests' credentials are borrowed, and must not become the zone's
+        if !self.guest {
+            zone.set_auth(self.subsonic.auth().clone());
+        }
+
+        Ok(Session {
+            id: self.id,
+            ctx: self.ctx.clone(),
+            tx: self.tx.clone(),
+            subsonic: self.subsonic.clone(),
+            podcasts: self.podcasts.clone(),
+            zone,
+            subscriptions: self.subscriptions.clone(),
+            guest: self.guest,
+        })
+    }
+
+    /// a session for running commands on behalf of something other than a
+    /// websocket client, borrowing credentials from whoever last connected
+    /// to the zone, same as scrobbling does
+    pub fn detached(ctx: Ctx, zone: Arc<Zone>, tx: Sender) -> Result<Session> {
+        let auth = zone.auth()
+            .context("no session has connected to the zone yet")?;
+
+        Ok(Session {
+            id: NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed),
+            subsonic: ctx.services.subsonic.with_auth(auth.clone()),
+            podcasts: ctx.services.podcasts.as_ref().map(|podcasts| podcasts.with_auth(auth)),
+            ctx,
+            tx,
+            zone,
+            subscriptions: watch::Sender::new(events::EventSet::default()),
+            guest: false,
+        })
+    }
+
+    /// zones in this session's room
+    pub fn zones(&self) -> Result<&zones::Zones> {
+        self.ctx.rooms.get(&self.zone.room)
+    }
+
     pub async fn mpd(&self) -> RwLockWriteGuard<'_, Mpd> {
-        self.ctx.mpd.write().await
+        self.zone.mpd.write().await
     }
 
-    pub fn resolver(&self) -> helper::Resolver {
-        helper::Resolver::new(&self.subsonic, self.podcasts.as_ref())
+    pub fn resolver(&self) -> helper::Resolver<'_> {
+        helper::Resolver::new(
+            &self.subsonic,
+            self.podcasts.as_ref(),
+            &self.ctx.services.stations,
+            &self.ctx.services.servers,
+            &self.ctx.services.external,
+            self.zone.rate_proxy.as_ref(),
+            self.ctx.services.resolve_concurrency,
+        )
     }
 }
 
@@ -202,25 +738,102 @@ pub struct SeqNumber(pub usize);
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub enum ClientMsg {
-    Command(Command),
+    Hello(Hello),
+    Command(Box<Command>),
+    Batch(Batch),
+    Subscribe(Subscribe),
+}
+
+/// sent by clients on connecting, the server replies with its own hello
+#[derive(Debug, Deserialize)]
+pub struct Hello {
+    protocol_version: u32,
+    /// name and version of the client, for logging
+    client: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ServerHello {
+    protocol_version: u32,
+    server: &'static str,
+    /// every command this server supports
+    commands: Vec<String>,
+    /// passed on reconnecting in place of credentials, which skips checking
+    /// them with subsonic again
+    resume_token: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct MalformedMsg {
+    command: CommandHeader,
+}
+
+#[derive(Deserialize)]
+struct CommandHeader {
+    seq: SeqNumber,
+    name: Option<String>,
+}
+
+/// the parts of a command we could make out of an invalid one
+pub struct MalformedCommand {
+    seq: SeqNumber,
+    name: Option<String>,
+    error: anyhow::Error,
+}
+
+/// replaces the set of events the client receives, all by default
+#[derive(Debug, Deserialize)]
+pub struct Subscribe {
+    events: Vec<events::EventKind>,
 }
 
 #[derive(Debug, Serialize)]
 #[serde(rename_all = "kebab-case")]
 pub enum ServerMsg {
+    Hello(ServerHello),
     Response(Response),
-    Playback(events::PlaybackEvent),
-    Queue(events::QueueEvent),
-    Options(events::OptionsEvent),
+    Playback(Arc<events::PlaybackEvent>),
+    Queue(Arc<events::QueueEvent>),
+    QueueDelta(Arc<events::QueueDelta>),
+    Options(Arc<events::OptionsEvent>),
+    Outputs(Arc<events::OutputsEvent>),
+    NowPlaying(Arc<events::NowPlayingEvent>),
+    StreamTitle(Arc<events::StreamTitleEvent>),
+    LibraryUpdate(Arc<events::LibraryUpdateEvent>),
+    Connection(Arc<events::ConnectionEvent>),
+    TrackChanged(Arc<events::TrackChangedEvent>),
+    Visualizer(Arc<visualizer::VisualizerEvent>),
+    Resumed(events::ResumedEvent),
+}
+
+/// a server message along with the seq of the logged event it corresponds
+/// to, which clients pass back as resume when reconnecting
+#[derive(Serialize)]
+struct Sequenced<'a> {
+    #[serde(flatten)]
+    msg: &'a ServerMsg,
+    seq: u64,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct Command {
     seq: SeqNumber,
+    /// run the command against another room rather than the session's
+    room: Option<String>,
     #[serde(flatten)]
     kind: commands::CommandKind,
 }
 
+/// commands run one after the other with a single response, and without
+/// commands from other sessions on the same zone in between
+#[derive(Debug, Deserialize)]
+pub struct Batch {
+    seq: SeqNumber,
+    room: Option<String>,
+    commands: Vec<commands::CommandKind>,
+}
+
 #[derive(Debug, Serialize)]
 pub struct Response {
     seq: SeqNumber,
@@ -230,24 +843,72 @@ pub struct Response {
 
 #[derive(Clone)]
 pub struct Sender {
-    tx: Arc<AsyncMutex<SplitSink<WebSocket, ws::Message>>>,
+    transport: Transport,
+}
+
+#[derive(Clone)]
+enum Transport {
+    WebSocket {
+        tx: Arc<AsyncMutex<SplitSink<WebSocket, ws::Message>>>,
+        encoding: codec::Encoding,
+        compress: bool,
+    },
+    /// for sessions not backed by a websocket, such as mqtt commands
+    Channel(mpsc::UnboundedSender<ServerMsg>),
 }
 
 impl Sender {
-    pub fn new(tx: SplitSink<WebSocket, ws::Message>) -> Self {
-        Sender { tx: Arc::new(AsyncMutex::new(tx)) }
+    pub fn new(tx: SplitSink<WebSocket, ws::Message>, encoding: codec::Encoding, compress: bool) -> Self {
+        Sender {
+            transport: Transport::WebSocket {
+                tx: Arc::new(AsyncMutex::new(tx)),
+                encoding,
+                compress,
+            },
+        }
+    }
+
+    pub fn channel(tx: mpsc::UnboundedSender<ServerMsg>) -> Self {
+        Sender { transport: Transport::Channel(tx) }
     }
 
     pub async fn send(&self, msg: ServerMsg) {
-        if let Err(err) = self.try_send(msg).await {
+        self.send_seq(msg, None).await
+    }
+
+    /// sends msg tagged with its seq in the event log, if it has one
+    pub async fn send_seq(&self, msg: ServerMsg, seq: Option<u64>) {
+        if let Err(err) = self.try_send(msg, seq).await {
             log::warn!("websocket send error: {err}");
         }
     }
 
-    async fn try_send(&self, msg: ServerMsg) -> Result<()> {
-        let json = serde_json::to_string(&msg)?;
-        let msg = ws::Message::text(json);
-        let mut tx = self.tx.lock().await;
+    pub async fn ping(&self) -> Result<()> {
+        let Transport::WebSocket { tx, .. } = &self.transport else { return Ok(()) };
+        let mut tx = tx.lock().await;
+        tx.send(ws::Message::Ping(Default::default())).await?;
+        Ok(())
+    }
+
+    async fn try_send(&self, msg: ServerMsg, seq: Option<u64>) -> Result<()> {
+        let (tx, encoding, compress) = match &self.transport {
+            Transport::WebSocket { tx, encoding, compress } => (tx, encoding, compress),
+            Transport::Channel(tx) => {
+                tx.send(msg).map_err(|_| anyhow::format_err!("channel closed"))?;
+                return Ok(());
+            }
+        };
+
+        let mut msg = match seq {
+            Some(seq) => encoding.encode(&Sequenced { msg: &msg, seq })?,
+            None => encoding.encode(&msg)?,
+        };
+
+        if *compress {
+            msg = encoding.compress(msg)?;
+        }
+
+        let mut tx = tx.lock().await;
         tx.send(msg).await?;
         Ok(())
     }
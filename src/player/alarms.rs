@@ -0,0 +1,271 @@
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use jiff::Zoned;
+use serde::{Deserialize, Serialize};
+
+use crate::mpd::Command;
+use crate::subsonic::types::RadioId;
+
+use super::Ctx;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Alarm {
+    pub name: String,
+    pub schedule: Schedule,
+    #[serde(flatten)]
+    pub source: AlarmSource,
+    /// volume from 0-1 to set before starting playback
+    pub volume: Option<f64>,
+    /// room and zone to play in, defaulting to the default zone of the
+    /// default room
+    pub room: Option<String>,
+    pub zone: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum AlarmSource {
+    /// an mpd stored playlist
+    Playlist { playlist: String },
+    Radio { radio: RadioId },
+}
+
+/// alarms from config, as a json array
+#[derive(Debug, Default)]
+pub struct AlarmList(pub Vec<Alarm>);
+
+impl FromStr for AlarmList {
+    type Err = serde_json::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_json::from_str(s).map(AlarmList)
+    }
+}
+
+/// alarms set with SetAlarm only live in memory, config is the place for
+/// alarms which should survive a restart
+#[derive(Default)]
+pub struct Alarms {
+    alarms: Mutex<Vec<Alarm>>,
+}
+
+impl Alarms {
+    pub fn new(alarms: Vec<Alarm>) -> Self {
+        Alarms { alarms: Mutex::new(alarms) }
+    }
+
+    pub fn list(&self) -> Vec<Alarm> {
+        self.alarms.lock().unwrap().clone()
+    }
+
+    /// adds an alarm, replacing any existing alarm of the same name
+    pub fn set(&self, alarm: Alarm) {
+        let mut alarms = self.alarms.lock().unwrap();
+        alarms.retain(|existing| existing.name != alarm.name);
+        alarms.push(alarm);
+    }
+
+    /// returns whether an alarm by that name existed
+    pub fn remove(&self, name: &str) -> bool {
+        let mut alarms = self.alarms.lock().unwrap();
+        let len = alarms.len();
+        alarms.retain(|alarm| alarm.name != name);
+        alarms.len() != len
+    }
+}
+
+/// a cron style schedule of five fields: minute, hour, day of month, month
+/// and day of week. each field is `*` or a comma separated list of values
+/// or ranges, optionally stepped with `/n`. times are in local time
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Schedule {
+    expr: String,
+    minute: Field,
+    hour: Field,
+    day: Field,
+    month: Field,
+    weekday: Field,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Field {
+    bits: u64,
+    any: bool,
+}
+
+impl Schedule {
+    pub fn matches(&self, time: &Zoned) -> bool {
+        let weekday = time.weekday().to_sunday_zero_offset();
+
+        // like cron, when both day fields are restricted either may match
+        let day = match (self.day.any, self.weekday.any) {
+            (false, false) => self.day.has(time.day()) || self.weekday.has(weekday),
+            _ => self.day.has(time.day()) && self.weekday.has(weekday),
+        };
+
+        day && self.minute.has(time.minute())
+            && self.hour.has(time.hour())
+            && self.month.has(time.month())
+    }
+}
+
+impl Field {
+    fn has(&self, value: i8) -> bool {
+        self.bits & (1 << value) != 0
+    }
+
+    fn parse(field: &str, min: u8, max: u8) -> Result<Field> {
+        let mut bits = 0u64;
+
+        for part in field.split(',') {
+            let (range, step) = match part.split_once('/') {
+                Some((range, step)) => (range, step.parse::<u8>()?),
+                None => (part, 1),
+            };
+
+            anyhow::ensure!(step > 0, "step must be greater than zero: {part}");
+
+            let (start, end) = match range {
+                "*" => (min, max),
+                _ => match range.split_once('-') {
+                    Some((start, end)) => (start.parse()?, end.parse()?),
+                    None => {
+                        let value = range.parse()?;
+                        // n/step means every step from n
+                        if part.contains('/') { (value, max) } else { (value, value) }
+                    }
+                },
+            };
+
+            anyhow::ensure!(min <= start && start <= end && end <= max,
+                "value out of range {min}-{max}: {part}");
+
+            for value in (start..=end).step_by(step.into()) {
+                bits |= 1 << value;
+            }
+        }
+
+        Ok(Field { bits, any: field == "*" })
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = anyhow::Error;
+
+    fn from_str(expr: &str) -> Result<Self> {
+        let fields = expr.split_whitespace().collect::<Vec<_>>();
+
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            anyhow::bail!("expected 5 fields in schedule: {expr}");
+        };
+
+        let mut weekday = Field::parse(weekday, 0, 7)
+            .with_context(|| format!("day of week in schedule: {expr}"))?;
+
+        // both 0 and 7 are sunday
+        if weekday.has(7) {
+            weekday.bits |= 1;
+        }
+
+        Ok(Schedule {
+            expr: expr.to_string(),
+            minute: Field::parse(minute, 0, 59)
+                .with_context(|| format!("minute in schedule: {expr}"))?,
+            hour: Field::parse(hour, 0, 23)
+                .with_context(|| format!("hour in schedule: {expr}"))?,
+            day: Field::parse(day, 1, 31)
+                .with_context(|| format!("day of month in schedule: {expr}"))?,
+            month: Field::parse(month, 1, 12)
+                .with_context(|| format!("month in schedule: {expr}"))?,
+            weekday,
+        })
+    }
+}
+
+impl TryFrom<String> for Schedule {
+    type Error = anyhow::Error;
+
+    fn try_from(expr: String) -> Result<Self> {
+        expr.parse()
+    }
+}
+
+impl From<Schedule> for String {
+    fn from(schedule: Schedule) -> String {
+        schedule.expr
+    }
+}
+
+// wakes at the top of each minute and fires any alarms due
+pub async fn task(ctx: Ctx) {
+    loop {
+        tokio::time::sleep(until_next_minute()).await;
+
+        let now = Zoned::now();
+
+        let due = ctx.alarms.list().into_iter()
+            .filter(|alarm| alarm.schedule.matches(&now))
+            .collect::<Vec<_>>();
+
+        for alarm in due {
+            log::info!("alarm {} firing", alarm.name);
+
+            if let Err(err) = fire(&ctx, &alarm).await {
+                log::warn!("alarm {}: {err:?}", alarm.name);
+            }
+        }
+    }
+}
+
+fn until_next_minute() -> Duration {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let next = (now.as_secs() / 60 + 1) * 60;
+    Duration::from_secs(next) - now
+}
+
+async fn fire(ctx: &Ctx, alarm: &Alarm) -> Result<()> {
+    let zones = match &alarm.room {
+        Some(name) => ctx.rooms.get(name)?,
+        None => ctx.rooms.default_room(),
+    };
+
+    let zone = match &alarm.zone {
+        Some(name) => zones.get(name).await?,
+        None => zones.default_zone().clone(),
+    };
+
+    let mut commands = vec![Command::clear()];
+
+    match &alarm.source {
+        AlarmSource::Playlist { playlist } => {
+            commands.push(Command::load(playlist));
+        }
+        AlarmSource::Radio { radio } => {
+            // resolving the station needs subsonic credentials, borrow them
+            // from whoever last used the zone, same as scrobbling does
+            let auth = zone.auth()
+                .context("no session has connected to the zone yet")?;
+
+            let station = ctx.services.subsonic.with_auth(auth)
+                .get_radio_stations().await?
+                .into_iter()
+                .find(|station| &station.id == radio)
+                .with_context(|| format!("radio station not found: {radio:?}"))?;
+
+            commands.push(Command::addid(station.stream_url.as_str()));
+        }
+    }
+
+    if let Some(volume) = alarm.volume {
+        commands.push(Command::setvol(zone.volume_limits.borrow().mpd_volume(volume)));
+    }
+
+    commands.push(Command::play());
+
+    zone.mpd.write().await.command_list(&commands).await?;
+    Ok(())
+}
@@ -0,0 +1,59 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use axum::extract::{Query, State};
+use axum::http::header;
+use axum::response::{IntoResponse, Response};
+use reqwest::StatusCode;
+use serde::Deserialize;
+
+use crate::mpd::types::Picture;
+use crate::subsonic::AuthParams;
+
+use super::{Ctx, HttpError, auth_error};
+
+#[derive(Debug, Deserialize)]
+pub struct AlbumArtParams {
+    uri: String,
+    #[serde(flatten)]
+    auth: AuthParams,
+}
+
+// serves cover art for a queue item from mpd. tries the picture embedded in
+// the file or stream first, then falls back to cover art in its directory
+pub async fn albumart(
+    ctx: State<Ctx>,
+    params: Query<AlbumArtParams>,
+) -> Result<Response, HttpError> {
+    let Query(AlbumArtParams { uri, auth }) = params;
+
+    ctx.services.subsonic.authenticate(Arc::new(auth)).await
+        .map_err(auth_error)?;
+
+    let picture = fetch_picture(&ctx, &uri).await
+        .map_err(|err| {
+            log::warn!("fetching album art for {uri}: {err:?}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    let Some(picture) = picture else {
+        return Err(StatusCode::NOT_FOUND.into());
+    };
+
+    let mime = picture.mime
+        .unwrap_or_else(|| "application/octet-stream".to_string());
+
+    Ok(([(header::CONTENT_TYPE, mime)], picture.data).into_response())
+}
+
+async fn fetch_picture(ctx: &Ctx, uri: &str) -> Result<Option<Picture>> {
+    // cover art doesn't depend on partition, any zone will do. files are
+    // looked up in the default room's database
+    let mpd = ctx.rooms.default_room().default_zone().mpd.read().await;
+
+    if let Some(picture) = mpd.readpicture(uri, 0).await? {
+        return Ok(Some(picture));
+    }
+
+    mpd.albumart(uri, 0).await
+}
@@ -0,0 +1,80 @@
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use url::Url;
+
+use crate::mpd::Command;
+use crate::mpd::types::PlaybackState;
+
+use super::persist;
+use super::zones::Zone;
+
+// announcements are meant to be short, anything running longer than this
+// is cut off so that a stream can't take over the zone
+const MAX_DURATION: Duration = Duration::from_secs(120);
+
+/// plays url in place of whatever the zone is playing, at volume (0-1) if
+/// given, then puts back the queue, position and volume from before. the
+/// caller must hold the zone's command lock exclusively so that nothing
+/// else touches the queue in between
+pub async fn announce(zone: &Zone, url: &Url, volume: Option<f64>) -> Result<()> {
+    let snapshot = persist::snapshot(zone).await
+        .context("saving playback before announcement")?;
+
+    let prev_volume = zone.mpd.read().await.status().await?.volume;
+
+    let result = play(zone, url, volume).await;
+
+    // put playback back even if the announcement failed part way through
+    let mut restore = persist::restore(zone, &snapshot).await
+        .context("restoring playback after announcement");
+
+    if let Some(prev_volume) = prev_volume
+        && volume.is_some()
+        && restore.is_ok()
+    {
+        restore = zone.mpd.write().await.setvol(prev_volume).await;
+    }
+
+    result.and(restore)
+}
+
+async fn play(zone: &Zone, url: &Url, volume: Option<f64>) -> Result<()> {
+    let mut status = zone.events.subscribe_status();
+
+    let mut commands = vec![
+        Command::clear(),
+        Command::addid(url.as_str()),
+        Command::random(false),
+        Command::repeat(false),
+        Command::consume(false),
+    ];
+
+    if let Some(volume) = volume {
+        commands.push(Command::setvol(zone.volume_limits.borrow().mpd_volume(volume)));
+    }
+
+    commands.push(Command::play());
+
+    zone.mpd.write().await.command_list(&commands).await?;
+
+    let finished = async {
+        loop {
+            if status.changed().await.is_err() {
+                return Ok(());
+            }
+
+            if zone.mpd.read().await.status().await?.state == PlaybackState::Stop {
+                return Ok(());
+            }
+        }
+    };
+
+    match tokio::time::timeout(MAX_DURATION, finished).await {
+        Ok(result) => result,
+        Err(_) => {
+            log::warn!("announcement {url} still playing after {MAX_DURATION:?}, cutting it off");
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,124 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::mpd::Command;
+use crate::mpd::types::PlaylistItem;
+use crate::subsonic::Subsonic;
+use crate::subsonic::types::{RandomSongsFilter, Track, TrackId};
+
+use super::Services;
+use super::zones::Zone;
+
+// the queue is topped up once fewer tracks than this are left after the
+// current one
+const MIN_UPCOMING: usize = 3;
+
+// tracks added each time
+const BATCH_SIZE: usize = 10;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Source {
+    /// songs similar to the last track in the queue
+    #[default]
+    Similar,
+    Random,
+}
+
+/// endless play, adding more tracks as the queue runs out
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct AutoQueue {
+    pub enabled: bool,
+    pub source: Source,
+}
+
+pub async fn task(services: Services, zone: Arc<Zone>) {
+    let mut queue_watch = zone.events.queue.subscribe();
+    let mut status_watch = zone.events.status.subscribe();
+    let mut settings_watch = zone.auto_queue.subscribe();
+
+    loop {
+        let settings = *zone.auto_queue.borrow();
+
+        if settings.enabled
+            && let Err(err) = top_up(&services, &zone, settings.source).await
+        {
+            log::warn!("auto queue: {err:?}");
+        }
+
+        tokio::select! {
+            changed = queue_watch.changed() => { if changed.is_err() { break } }
+            changed = status_watch.changed() => { if changed.is_err() { break } }
+            changed = settings_watch.changed() => { if changed.is_err() { break } }
+        }
+    }
+}
+
+async fn top_up(services: &Services, zone: &Zone, source: Source) -> Result<()> {
+    // fetching songs needs a session's credentials, as with scrobbling
+    let Some(auth) = zone.auth() else { return Ok(()) };
+    let subsonic = services.subsonic.with_auth(auth);
+
+    let mpd = zone.mpd.read().await;
+    let status = mpd.status().await?;
+
+    // only while something is playing, so an idle zone isn't filled up
+    let Some(song) = status.song else { return Ok(()) };
+
+    if status.playlist_length.saturating_sub(song + 1) >= MIN_UPCOMING {
+        return Ok(());
+    }
+
+    let queue = mpd.playlistinfo().await?.items;
+    drop(mpd);
+
+    let queued = queue.iter()
+        .filter_map(|item| track_id(&subsonic, zone, item))
+        .map(|id| id.0)
+        .collect::<HashSet<_>>();
+
+    let mut tracks = match source {
+        Source::Similar => similar(&subsonic, zone, &queue).await?,
+        Source::Random => Vec::new(),
+    };
+
+    tracks.retain(|track| !queued.contains(&track.id.0));
+
+    // nothing new is similar, random songs at least keep the music going
+    if tracks.is_empty() {
+        tracks = subsonic.get_random_songs(BATCH_SIZE, &RandomSongsFilter::default()).await?;
+    }
+
+    let commands = tracks.iter()
+        .take(BATCH_SIZE)
+        .map(|track| Ok(Command::addid(subsonic.stream_url(&track.id)?.as_str())))
+        .collect::<Result<Vec<_>>>()?;
+
+    if commands.is_empty() {
+        return Ok(());
+    }
+
+    log::info!("auto queue adding {} tracks to zone {}/{}", commands.len(), zone.room, zone.name);
+    zone.mpd.write().await.command_list(&commands).await?;
+    Ok(())
+}
+
+async fn similar(subsonic: &Subsonic, zone: &Zone, queue: &[PlaylistItem]) -> Result<Vec<Track>> {
+    let Some(last) = queue.iter().rev().find_map(|item| track_id(subsonic, zone, item)) else {
+        return Ok(Vec::new());
+    };
+
+    let track = subsonic.get_track(&last).await?;
+    let Some(artist) = track.details.artists.first() else { return Ok(Vec::new()) };
+
+    // extra, as some may already be queued
+    subsonic.get_similar_songs2(&artist.id, BATCH_SIZE * 2).await
+}
+
+fn track_id(subsonic: &Subsonic, zone: &Zone, item: &PlaylistItem) -> Option<TrackId> {
+    let url = zone.stream_url(&item.file)?;
+    subsonic.track_id_from_stream_url(&url)
+}
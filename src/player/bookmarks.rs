@@ -0,0 +1,167 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use tokio::time::Instant;
+
+use crate::mpd::types::{Id, PlaybackState, Status};
+use crate::subsonic::{Subsonic, SubsonicBase};
+use crate::subsonic::types::TrackId;
+
+use super::resume::REWIND;
+use super::zones::Zone;
+
+const SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+// tracks stopped within this many seconds of the end count as finished
+const FINISHED_MARGIN: f64 = 30.0;
+
+struct Current {
+    id: Id,
+    // only set if the current song is bookmarked
+    track: Option<Bookmarked>,
+}
+
+// positions are kept in the track's own time, as for podcast resume
+struct Bookmarked {
+    track_id: TrackId,
+    rate: f64,
+    elapsed: f64,
+    duration: Option<f64>,
+    saved: Option<Instant>,
+}
+
+// keeps subsonic bookmarks of long tracks such as audiobooks, whose ids
+// start with one of prefixes, and seeks to the bookmark when one starts
+// playing. unlike podcast resume, bookmarks follow the user to other
+// subsonic clients
+pub async fn task(subsonic: SubsonicBase, prefixes: Vec<String>, zone: Arc<Zone>) {
+    let mut watch = zone.events.subscribe_status();
+    let mut current = None;
+
+    loop {
+        if let Err(err) = update(&zone, &subsonic, &prefixes, &mut current).await {
+            log::warn!("bookmarks: {err:?}");
+        }
+
+        tokio::select! {
+            changed = watch.changed() => {
+                if changed.is_err() { break }
+            }
+            () = tokio::time::sleep(SAVE_INTERVAL) => {}
+        }
+    }
+}
+
+async fn update(
+    zone: &Zone,
+    subsonic: &SubsonicBase,
+    prefixes: &[String],
+    current: &mut Option<Current>,
+) -> Result<()> {
+    // bookmarks belong to a user, so there's nothing to do until someone
+    // has connected to the zone
+    let Some(auth) = zone.auth() else { return Ok(()) };
+    let subsonic = subsonic.with_auth(auth);
+
+    // the mpd lock is only held for each command, not across subsonic
+    // calls, so that sessions' commands aren't held up behind them
+    let status = zone.mpd.read().await.status().await?;
+
+    if current.as_ref().map(|current| &current.id) != status.song_id.as_ref() {
+        if let Some(Current { track: Some(track), .. }) = current.take() {
+            finish_track(&subsonic, &track).await?;
+        }
+
+        if let Some(id) = &status.song_id {
+            *current = Some(start_song(zone, &subsonic, prefixes, id, &status).await?);
+        }
+    }
+
+    let Some(Current { track: Some(track), .. }) = current else {
+        return Ok(());
+    };
+
+    let Some(elapsed) = status.elapsed.map(|s| s.0 * track.rate) else {
+        return Ok(());
+    };
+
+    if elapsed == track.elapsed {
+        return Ok(());
+    }
+
+    track.elapsed = elapsed;
+    track.duration = status.duration.map(|s| s.0 * track.rate);
+
+    // save at most every interval while playing, and straight away on pause
+    let due = track.saved.is_none_or(|saved| saved.elapsed() >= SAVE_INTERVAL);
+
+    if due || status.state != PlaybackState::Play {
+        track.saved = Some(Instant::now());
+        subsonic.create_bookmark(&track.track_id, (elapsed * 1000.0) as u64).await?;
+    }
+
+    Ok(())
+}
+
+async fn start_song(
+    zone: &Zone,
+    subsonic: &Subsonic,
+    prefixes: &[String],
+    id: &Id,
+    status: &Status,
+) -> Result<Current> {
+    let item = zone.mpd.read().await.playlistid(id).await?;
+
+    let Some((url, rate)) = zone.source(&item.file) else {
+        return Ok(Current { id: id.clone(), track: None });
+    };
+
+    let Some(track_id) = subsonic.track_id_from_stream_url(&url)
+        .filter(|track_id| prefixes.iter().any(|prefix| track_id.0.starts_with(prefix)))
+    else {
+        return Ok(Current { id: id.clone(), track: None });
+    };
+
+    let saved = match zone.take_resume(url.as_str()) {
+        true => subsonic.get_bookmarks().await?
+            .into_iter()
+            .find(|bookmark| bookmark.entry.id.0 == track_id.0)
+            .map(|bookmark| bookmark.position as f64 / 1000.0),
+        false => None,
+    };
+
+    // only resume if the track is starting from the beginning, otherwise
+    // the user has already chosen where to play from
+    let elapsed = status.elapsed.map(|s| s.0 * rate).unwrap_or_default();
+    let elapsed = match saved {
+        Some(saved) if elapsed < 1.0 => {
+            let saved = (saved - REWIND).max(0.0);
+            log::info!("resuming {} at bookmark {saved}s", track_id.0);
+            zone.mpd.read().await.seekcur(saved / rate).await?;
+            saved
+        }
+        _ => elapsed,
+    };
+
+    Ok(Current {
+        id: id.clone(),
+        track: Some(Bookmarked {
+            track_id,
+            rate,
+            elapsed,
+            duration: status.duration.map(|s| s.0 * rate),
+            saved: None,
+        }),
+    })
+}
+
+async fn finish_track(subsonic: &Subsonic, track: &Bookmarked) -> Result<()> {
+    let Some(duration) = track.duration else { return Ok(()) };
+
+    if track.elapsed >= duration - FINISHED_MARGIN {
+        subsonic.delete_bookmark(&track.track_id).await?;
+    }
+
+    Ok(())
+}
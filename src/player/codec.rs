@@ -0,0 +1,114 @@
+use std::io::Write;
+
+use anyhow::Result;
+use axum::extract::ws;
+use axum::http::HeaderValue;
+use base64::Engine;
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+/// websocket subprotocols clients may ask for on upgrade, in order of our
+/// preference. without one the protocol is json
+pub const PROTOCOLS: [&str; 2] = [MSGPACK_PROTOCOL, JSON_PROTOCOL];
+
+const MSGPACK_PROTOCOL: &str = "sonicast.msgpack";
+const JSON_PROTOCOL: &str = "sonicast.json";
+
+/// messages larger than this are gzipped for clients which asked for it
+const COMPRESS_THRESHOLD: usize = 16 * 1024;
+
+/// how server messages are encoded. clients may send either encoding no
+/// matter which was negotiated, json as text and msgpack as binary frames
+#[derive(Debug, Clone, Copy)]
+pub enum Encoding {
+    Json,
+    MessagePack,
+}
+
+impl Encoding {
+    pub fn from_protocol(protocol: Option<&HeaderValue>) -> Self {
+        match protocol {
+            Some(protocol) if protocol == MSGPACK_PROTOCOL => Encoding::MessagePack,
+            _ => Encoding::Json,
+        }
+    }
+
+    pub fn encode<T: Serialize>(self, msg: &T) -> Result<ws::Message> {
+        match self {
+            Encoding::Json => Ok(ws::Message::text(serde_json::to_string(msg)?)),
+            // named so that structs encode as maps, same shape as the json
+            Encoding::MessagePack => Ok(ws::Message::binary(rmp_serde::to_vec_named(msg)?)),
+        }
+    }
+
+    /// gzips large messages into a `{"compressed": true, "data": ...}`
+    /// envelope, encoded like any other message. data is the gzipped
+    /// message, base64 encoded in json and binary in msgpack. no other
+    /// server message has a compressed field
+    pub fn compress(self, msg: ws::Message) -> Result<ws::Message> {
+        let data = match &msg {
+            ws::Message::Text(text) => text.as_str().as_bytes(),
+            ws::Message::Binary(data) => data,
+            _ => return Ok(msg),
+        };
+
+        if data.len() < COMPRESS_THRESHOLD {
+            return Ok(msg);
+        }
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+        encoder.write_all(data)?;
+        let data = encoder.finish()?;
+
+        match self {
+            Encoding::Json => self.encode(&Compressed {
+                compressed: true,
+                data: base64::engine::general_purpose::STANDARD.encode(data),
+            }),
+            Encoding::MessagePack => self.encode(&Compressed {
+                compressed: true,
+                data: Bytes(&data),
+            }),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Compressed<D> {
+    compressed: bool,
+    data: D,
+}
+
+// serializes as msgpack bin, rather than an array of numbers
+struct Bytes<'a>(&'a [u8]);
+
+impl Serialize for Bytes<'_> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(self.0)
+    }
+}
+
+/// payload of a data frame received from the client
+pub enum Frame<'a> {
+    Json(&'a str),
+    MessagePack(&'a [u8]),
+}
+
+impl<'a> Frame<'a> {
+    pub fn from_message(msg: &'a ws::Message) -> Option<Self> {
+        match msg {
+            ws::Message::Text(text) => Some(Frame::Json(text.as_str())),
+            ws::Message::Binary(data) => Some(Frame::MessagePack(data)),
+            _ => None,
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self) -> Result<T> {
+        match self {
+            Frame::Json(text) => Ok(serde_json::from_str(text)?),
+            Frame::MessagePack(data) => Ok(rmp_serde::from_slice(data)?),
+        }
+    }
+}
@@ -1,12 +1,38 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
 use anyhow::{Result, Context};
+use axum::{Json, Router};
+use axum::extract::{Query, State};
+use axum::response::{IntoResponse, Response as HttpResponse};
+use axum::routing::post;
+use reqwest::StatusCode;
 use serde::{Deserialize, Serialize};
-
-use crate::player::{Session, Command, helper};
-use crate::mpd::types::{PlaybackState, Seconds};
-use crate::mpd::{self, Mpd};
+use thiserror::Error;
+use tracing::Instrument;
+use tokio::sync::{mpsc, oneshot, RwLock};
+use url::Url;
+
+use crate::logging;
+use crate::snapcast::{self, Snapcast, SnapcastError};
+use crate::podcasts::Podcasts;
+use crate::player::{Batch, Session, Command, ConnectParams, Ctx, Sender, announce, autoqueue, connect, duck, flight, helper, persist, rate};
+use crate::player::events::EventSet;
+use crate::player::alarms::Alarm;
+use crate::player::history::{History, HistoryEntry};
+use crate::player::stats::{self, Stats};
+use crate::player::skip::SkipOffsets;
+use crate::player::smart::{self, YearRange};
+use crate::mpd::types::{Decoder, LibraryEntry, Output, PlaybackState, PlaylistItem, Seconds, Stats as MpdStats, StoredPlaylist};
+use crate::mpd::{self, Mpd, Command as MpdCommand};
+use crate::mpd::protocol::{AckCode, ErrorResponse};
+
+use crate::subsonic::{AuthParams, CircuitOpen, StreamQuality, SubsonicError, SubsonicErrorCode};
+use crate::subsonic::types::{Album, AlbumId, Artist, ArtistId, Playlist as SubsonicPlaylist, RandomSongsFilter, SearchPage, PlaylistId, RadioId, StructuredLyrics, Track, TrackId};
 
 use super::types::{AirsonicTrack, AirsonicTrackId};
-use super::{Response, ServerMsg};
+use super::{Response, SeqNumber, ServerMsg};
 
 macro_rules! commands {
     { $( $variant:ident : $func:ident ( $( $param:ty )? ) => $result:ty ; )* } => {
@@ -19,10 +45,25 @@ macro_rules! commands {
         #[derive(Debug, Serialize)]
         #[serde(rename_all = "kebab-case", tag = "kind", content = "data")]
         pub enum ResponseKind {
-            Error { message: String },
+            Error { code: ErrorCode, message: String },
+            /// responses to each command of a batch, up to the first error
+            Batch(Vec<ResponseKind>),
             $( $variant ( $result ), )*
         }
 
+        impl CommandKind {
+            fn name(&self) -> &'static str {
+                match self {
+                    $( CommandKind::$variant { .. } => stringify!($variant), )*
+                }
+            }
+        }
+
+        /// names of every command, as clients send them
+        pub fn command_names() -> Vec<String> {
+            vec![ $( kebab_case(stringify!($variant)), )* ]
+        }
+
         async fn dispatch_kind(session: &Session, command: CommandKind) -> Result<ResponseKind> {
             let command_name;
             let result = match command {
@@ -36,6 +77,21 @@ macro_rules! commands {
             };
             result.with_context(|| format!("dispatching command {command_name}"))
         }
+
+        /// a POST route for each command at /commands/<name>, taking its
+        /// param as a json body, for clients which would rather not hold a
+        /// websocket open
+        pub fn router() -> Router<Ctx> {
+            Router::new()
+                $(
+                    .route(&format!("/commands/{}", kebab_case(stringify!($variant))), post(
+                        |ctx: State<Ctx>, params: Query<ConnectParams>, auth: Query<AuthParams> $(, Json(param): Json<$param> )?| async move {
+                            let command = CommandKind::$variant $( ( commands!{@param_var param: $param} ) )?;
+                            dispatch_http(ctx.0, params.0, auth.0, command).await
+                        }
+                    ))
+                )*
+        }
     };
 
     // special internal rule to allow for $()? expansions of param
@@ -43,19 +99,284 @@ macro_rules! commands {
     { @param_var $param_ident:ident : $param_ty:ty } => { $param_ident };
 }
 
-pub async fn dispatch(session: &Session, command: Command) {
-    let kind = match dispatch_kind(session, command.kind).await {
-        Ok(kind) => kind,
-        Err(err) => {
-            log::error!("{err:?}");
-            ResponseKind::Error { message: format!("{err}") }
+/// machine readable cause of a command failing, worked out from the error
+/// chain. anything unrecognised is other, clients show the message for it
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ErrorCode {
+    NotFound,
+    Unauthorized,
+    InvalidArgument,
+    UnknownCommand,
+    StaleQueue,
+    /// mpd is down, or stopped responding
+    MpdUnavailable,
+    /// mpd rejected the command for some other reason
+    Mpd,
+    /// subsonic or podcast server was unreachable or returned an error
+    SubsonicError,
+    /// snapserver was unreachable or returned an error
+    Snapcast,
+    Other,
+}
+
+impl ErrorCode {
+    fn from_error(err: &anyhow::Error) -> Self {
+        for cause in err.chain() {
+            if cause.is::<StaleQueue>() {
+                return ErrorCode::StaleQueue;
+            }
+
+            if cause.is::<GuestForbidden>() {
+                return ErrorCode::Unauthorized;
+            }
+
+            if let Some(ack) = cause.downcast_ref::<ErrorResponse>() {
+                return match ack.code {
+                    AckCode::NoExist => ErrorCode::NotFound,
+                    AckCode::Password | AckCode::Permission => ErrorCode::Unauthorized,
+                    AckCode::Arg => ErrorCode::InvalidArgument,
+                    _ => ErrorCode::Mpd,
+                };
+            }
+
+            if let Some(err) = cause.downcast_ref::<SubsonicError>() {
+                return match err.code() {
+                    SubsonicErrorCode::NotFound => ErrorCode::NotFound,
+                    SubsonicErrorCode::Unauthorized => ErrorCode::Unauthorized,
+                    SubsonicErrorCode::Other(_) => ErrorCode::SubsonicError,
+                };
+            }
+
+            if cause.is::<SnapcastError>() {
+                return ErrorCode::Snapcast;
+            }
+
+            if cause.is::<reqwest::Error>() || cause.is::<CircuitOpen>() {
+                return ErrorCode::SubsonicError;
+            }
+
+            // the mpd connection timed out, or went away and took its
+            // reader task and the response channel with it
+            if cause.is::<mpd::TimeoutError>()
+                || cause.is::<mpd::UnhealthyError>()
+                || cause.is::<mpd::protocol::Error>()
+                || cause.is::<oneshot::error::RecvError>()
+                || cause.is::<std::io::Error>()
+            {
+                return ErrorCode::MpdUnavailable;
+            }
+        }
+
+        ErrorCode::Other
+    }
+
+    fn http_status(&self) -> StatusCode {
+        match self {
+            ErrorCode::NotFound | ErrorCode::UnknownCommand => StatusCode::NOT_FOUND,
+            ErrorCode::Unauthorized => StatusCode::FORBIDDEN,
+            ErrorCode::InvalidArgument => StatusCode::BAD_REQUEST,
+            ErrorCode::StaleQueue => StatusCode::CONFLICT,
+            ErrorCode::MpdUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+            ErrorCode::Mpd | ErrorCode::SubsonicError | ErrorCode::Snapcast => StatusCode::BAD_GATEWAY,
+            ErrorCode::Other => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+fn kebab_case(name: &str) -> String {
+    let mut kebab = String::new();
+
+    for (i, c) in name.char_indices() {
+        if c.is_ascii_uppercase() && i > 0 {
+            kebab.push('-');
         }
+        kebab.push(c.to_ascii_lowercase());
+    }
+
+    kebab
+}
+
+/// responds to a command the client sent which could not be parsed, so
+/// that clients newer than us see an error rather than no response
+pub async fn reject(session: &Session, seq: SeqNumber, name: Option<&str>, err: &anyhow::Error) {
+    let known = name.is_some_and(|name| command_names().iter().any(|known| known == name));
+
+    let code = if known {
+        ErrorCode::InvalidArgument
+    } else {
+        ErrorCode::UnknownCommand
+    };
+
+    let response = Response {
+        seq,
+        kind: ResponseKind::Error { code, message: format!("{err}") },
+    };
+
+    session.tx.send(ServerMsg::Response(response)).await;
+}
+
+pub async fn dispatch(session: &Session, command: Command) {
+    let span = tracing::debug_span!("dispatch", seq = command.seq.0, session = session.id);
+    dispatch_command(session, command).instrument(span).await
+}
+
+async fn dispatch_command(session: &Session, command: Command) {
+    let result = match &command.room {
+        None => dispatch_one(session, command.kind).await,
+        Some(room) => match session.in_room(room) {
+            Ok(session) => dispatch_one(&session, command.kind).await,
+            Err(err) => Err(err),
+        },
     };
 
+    let kind = result.unwrap_or_else(error_response);
     let response = Response { seq: command.seq, kind };
     session.tx.send(ServerMsg::Response(response)).await;
 }
 
+// a command posted over http runs as a session of its own, through the
+// same path as websocket commands, and answers with just the response
+async fn dispatch_http(ctx: Ctx, params: ConnectParams, auth: AuthParams, command: CommandKind) -> HttpResponse {
+    let connection = match connect(&ctx, &params, auth).await {
+        Ok(connection) => connection,
+        Err(err) => return err.into_response(),
+    };
+
+    // commands which send messages of their own, such as announcements,
+    // have nowhere to send them
+    let (tx, _rx) = mpsc::unbounded_channel();
+    let session = connection.into_session(ctx, Sender::channel(tx), EventSet::default());
+
+    let span = tracing::debug_span!("dispatch", session = session.id);
+    let kind = dispatch_one(&session, command).instrument(span).await
+        .unwrap_or_else(error_response);
+
+    let status = match &kind {
+        ResponseKind::Error { code, .. } => code.http_status(),
+        _ => StatusCode::OK,
+    };
+
+    (status, Json(kind)).into_response()
+}
+
+pub async fn dispatch_batch(session: &Session, batch: Batch) {
+    let span = tracing::debug_span!("dispatch", seq = batch.seq.0, session = session.id);
+    dispatch_batch_commands(session, batch).instrument(span).await
+}
+
+async fn dispatch_batch_commands(session: &Session, batch: Batch) {
+    let result = match &batch.room {
+        None => run_batch(session, batch.commands).await,
+        Some(room) => match session.in_room(room) {
+            Ok(session) => run_batch(&session, batch.commands).await,
+            Err(err) => Err(err),
+        },
+    };
+
+    let kind = result.unwrap_or_else(error_response);
+    let response = Response { seq: batch.seq, kind };
+    session.tx.send(ServerMsg::Response(response)).await;
+}
+
+async fn dispatch_one(session: &Session, command: CommandKind) -> Result<ResponseKind> {
+    let span = tracing::debug_span!("command", name = command.name());
+    check_guest(session, &command)?;
+
+    // announcements take over the queue until they're done, like batches
+    if let CommandKind::Announce(_) = command {
+        let _lock = session.zone.command_lock.write().await;
+        return dispatch_kind(session, command).instrument(span).await;
+    }
+
+    // transfers lock both of the rooms involved themselves
+    if let CommandKind::TransferPlayback(_) = command {
+        return dispatch_kind(session, command).instrument(span).await;
+    }
+
+    // shared, so that only batches exclude other commands
+    let _lock = session.zone.command_lock.read().await;
+    dispatch_kind(session, command).instrument(span).await
+}
+
+// runs commands in order with no other commands on the zone in between,
+// stopping at the first error
+async fn run_batch(session: &Session, commands: Vec<CommandKind>) -> Result<ResponseKind> {
+    let _lock = session.zone.command_lock.write().await;
+    let mut responses = Vec::new();
+
+    for command in commands {
+        let span = tracing::debug_span!("command", name = command.name());
+
+        let result = match check_guest(session, &command) {
+            // would wait on the lock held here, see dispatch_one
+            Ok(()) if matches!(command, CommandKind::TransferPlayback(_)) => {
+                Err(anyhow::anyhow!("TransferPlayback can't be batched"))
+            }
+            Ok(()) => dispatch_kind(session, command).instrument(span).await,
+            Err(err) => Err(err),
+        };
+
+        match result {
+            Ok(response) => responses.push(response),
+            Err(err) => {
+                responses.push(error_response(err));
+                break;
+            }
+        }
+    }
+
+    Ok(ResponseKind::Batch(responses))
+}
+
+/// a guest session sent a command which would change something
+#[derive(Error, Debug)]
+#[error("guests can't run {command}")]
+pub struct GuestForbidden {
+    command: &'static str,
+}
+
+fn check_guest(session: &Session, command: &CommandKind) -> Result<()> {
+    if session.guest && !command.read_only() {
+        return Err(GuestForbidden { command: command.name() }.into());
+    }
+
+    Ok(())
+}
+
+impl CommandKind {
+    /// commands guests may run. anything not listed, including commands
+    /// added later, is assumed to change something. history, stats and
+    /// subsonic playlists are left out since guests borrow someone else's
+    /// credentials
+    fn read_only(&self) -> bool {
+        matches!(self,
+            CommandKind::Queue
+            | CommandKind::BrowseLibrary(_)
+            | CommandKind::GetLyrics
+            | CommandKind::ListRadioStations
+            | CommandKind::Search(_)
+            | CommandKind::ListSkipOffsets
+            | CommandKind::ListPlaylists
+            | CommandKind::Outputs
+            | CommandKind::GetCapabilities
+            | CommandKind::GetServerStats
+            | CommandKind::ListRooms
+            | CommandKind::ListZones
+            | CommandKind::ListAlarms
+            | CommandKind::SnapcastGroups
+        )
+    }
+}
+
+fn error_response(err: anyhow::Error) -> ResponseKind {
+    tracing::error!("{err:?}");
+    ResponseKind::Error {
+        code: ErrorCode::from_error(&err),
+        message: format!("{err}"),
+    }
+}
+
 commands! {
     Play: play() => ();
     Pause: pause() => ();
@@ -68,17 +389,74 @@ commands! {
     ClearQueue: clear_queue() => ();
     AddToQueue: add_to_queue(AddToQueue) => ();
     SetNextInQueue: set_next_in_queue(AddToQueue) => ();
+    BrowseLibrary: browse_library(LibraryPath) => Vec<LibraryEntry>;
+    AddLibraryPath: add_library_path(LibraryPath) => ();
+    UpdateLibrary: update_library(UpdateLibrary) => u32;
+    SetPriority: set_priority(SetPriority) => ();
     Queue: queue() => Queue;
     PlayTrackList: play_track_list(PlayTrackList) => ();
+    GetPlaylists: get_playlists() => Vec<SubsonicPlaylist>;
+    GetPlaylist: get_playlist(GetPlaylist) => PlaylistTracks;
+    PlayPlaylist: play_playlist(PlayPlaylist) => ();
+    PlayAlbum: play_album(PlayAlbum) => ();
+    PlayArtistTopSongs: play_artist_top_songs(PlayArtistTopSongs) => ();
+    PlayRandomSongs: play_random_songs(PlayRandomSongs) => ();
+    PlaySimilar: play_similar(PlaySimilar) => ();
+    SmartQueue: smart_queue(SmartQueue) => usize;
+    GetLyrics: get_lyrics() => Vec<StructuredLyrics>;
+    ListRadioStations: list_radio_stations() => Vec<AirsonicTrack>;
+    CreateRadioStation: create_radio_station(RadioStationDetails) => ();
+    UpdateRadioStation: update_radio_station(UpdateRadioStation) => ();
+    DeleteRadioStation: delete_radio_station(DeleteRadioStation) => ();
+    Search: search(Search) => SearchResults;
+    RefreshPodcasts: refresh_podcasts() => ();
+    ListSkipOffsets: list_skip_offsets() => HashMap<String, SkipOffsets>;
+    SetSkipOffsets: set_skip_offsets(SetSkipOffsets) => ();
+    DownloadPodcastEpisode: download_podcast_episode(PodcastEpisode) => ();
+    DeletePodcastEpisode: delete_podcast_episode(PodcastEpisode) => ();
     LoadPlayerState: load_player_state(PlayerState) => ();
     UnloadPlayerState: unload_player_state() => PlayerState;
+    TransferPlayback: transfer_playback(TransferPlayback) => ();
+    RestoreState: restore_state() => ();
     RemoveFromQueue: remove_from_queue(RemoveFromQueue) => ();
+    MoveInQueue: move_in_queue(MoveInQueue) => ();
     ShuffleQueue: shuffle_queue() => ();
+    ListPlaylists: list_playlists() => Vec<StoredPlaylist>;
+    SavePlaylist: save_playlist(PlaylistName) => ();
+    LoadPlaylist: load_playlist(LoadPlaylist) => ();
+    DeletePlaylist: delete_playlist(PlaylistName) => ();
+    RenamePlaylist: rename_playlist(RenamePlaylist) => ();
     ReplayGainMode: replay_gain_mode(ReplayGainMode) => ();
     SetRepeat: set_repeat(SetRepeat) => ();
     SetShuffle: set_shuffle(SetShuffle) => ();
+    SetSingle: set_single(SetSingle) => ();
+    SetConsume: set_consume(SetConsume) => ();
+    SetCrossfade: set_crossfade(SetCrossfade) => ();
+    SetMixRamp: set_mix_ramp(SetMixRamp) => ();
     SetVolume: set_volume(SetVolume) => ();
+    SetVolumeLimits: set_volume_limits(SetVolumeLimits) => ();
+    SetAutoQueue: set_auto_queue(SetAutoQueue) => ();
+    Duck: duck(Duck) => ();
+    Announce: announce(Announce) => ();
+    Outputs: outputs() => Vec<Output>;
+    GetCapabilities: get_capabilities() => Capabilities;
+    GetServerStats: get_server_stats() => MpdStats;
+    EnableOutput: enable_output(EnableOutput) => ();
+    ListRooms: list_rooms() => Vec<Room>;
+    ListZones: list_zones() => Vec<Zone>;
+    CreateZone: create_zone(ZoneName) => ();
+    MoveOutput: move_output(MoveOutput) => ();
     SetPlaybackRate: set_playback_rate(SetPlaybackRate) => ();
+    SetStreamQuality: set_stream_quality(SetStreamQuality) => ();
+    GetHistory: get_history(GetHistory) => Vec<HistoryEntry>;
+    ClearHistory: clear_history() => ();
+    GetStats: get_stats(GetStats) => Stats;
+    ListAlarms: list_alarms() => Vec<Alarm>;
+    SetAlarm: set_alarm(Alarm) => ();
+    DeleteAlarm: delete_alarm(AlarmName) => ();
+    SnapcastGroups: snapcast_groups() => Vec<snapcast::Group>;
+    SnapcastSetVolume: snapcast_set_volume(SnapcastSetVolume) => ();
+    SnapcastSetGroup: snapcast_set_group(SnapcastSetGroup) => ();
 }
 
 async fn play(session: &Session) -> Result<()> {
@@ -117,13 +495,39 @@ async fn seek(session: &Session, param: Seek) -> Result<()> {
     player_op(&mut mpd, Op::Seek(param.position)).await
 }
 
+/// a command referring to queue positions was sent with a queue version
+/// that is no longer current, so the positions may refer to other tracks
+#[derive(Error, Debug)]
+#[error("queue has changed since version {expected}, now at {current}")]
+pub struct StaleQueue {
+    expected: u32,
+    current: u32,
+}
+
+// commands which take queue positions also take the optional queue version
+// the client saw them in. must be called with the mpd lock held through the
+// change, so no other session can change the queue in between
+async fn check_queue_version(mpd: &Mpd, expected: Option<u32>) -> Result<()> {
+    let Some(expected) = expected else { return Ok(()) };
+
+    // a cached status could miss a change another mpd client just made
+    let current = mpd.status_within(Duration::ZERO).await?.playlist_version;
+    if current != expected {
+        return Err(StaleQueue { expected, current }.into());
+    }
+
+    Ok(())
+}
+
 #[derive(Debug, Deserialize)]
 pub struct PlayIndex {
     index: usize,
+    queue_version: Option<u32>,
 }
 
 async fn play_index(session: &Session, param: PlayIndex) -> Result<()> {
     let mpd = session.mpd().await;
+    check_queue_version(&mpd, param.queue_version).await?;
     mpd.playpos(param.index).await
 }
 
@@ -138,11 +542,15 @@ async fn clear_queue(session: &Session) -> Result<()> {
 #[derive(Deserialize, Debug)]
 pub struct AddToQueue {
     tracks: Vec<AirsonicTrackId>,
+    /// whether podcast episodes and bookmarked tracks start from where they
+    /// were left off, defaults to true
+    resume: Option<bool>,
 }
 
 async fn add_to_queue(session: &Session, params: AddToQueue) -> Result<()> {
     let resolver = session.resolver();
     let track_urls = resolver.stream_urls_for(&params.tracks).await?;
+    set_resume(session, &track_urls, params.resume);
 
     let mpd = session.mpd().await;
     for url in &track_urls {
@@ -155,6 +563,7 @@ async fn add_to_queue(session: &Session, params: AddToQueue) -> Result<()> {
 async fn set_next_in_queue(session: &Session, params: AddToQueue) -> Result<()> {
     let resolver = session.resolver();
     let track_urls = resolver.stream_urls_for(&params.tracks).await?;
+    set_resume(session, &track_urls, params.resume);
 
     let mut mpd = session.mpd().await;
     helper::atomic_enqueue_tracks(&mut mpd, &track_urls, Some(0)).await?;
@@ -162,21 +571,93 @@ async fn set_next_in_queue(session: &Session, params: AddToQueue) -> Result<()>
     Ok(())
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Deserialize, Debug)]
+pub struct LibraryPath {
+    /// relative to mpd's music directory, the root if empty
+    #[serde(default)]
+    path: String,
+}
+
+// files in mpd's own database, for setups where mpd has a local library
+// alongside subsonic
+async fn browse_library(session: &Session, params: LibraryPath) -> Result<Vec<LibraryEntry>> {
+    session.mpd().await.lsinfo(&params.path).await
+}
+
+// directories are added recursively
+async fn add_library_path(session: &Session, params: LibraryPath) -> Result<()> {
+    session.mpd().await.add(&params.path).await
+}
+
+#[derive(Deserialize, Debug)]
+pub struct UpdateLibrary {
+    /// relative to mpd's music directory, everything if empty
+    #[serde(default)]
+    path: String,
+    /// reread files even if they haven't changed
+    #[serde(default)]
+    rescan: bool,
+}
+
+// returns the job id, progress follows in library update events
+async fn update_library(session: &Session, params: UpdateLibrary) -> Result<u32> {
+    let mpd = session.mpd().await;
+
+    match params.rescan {
+        true => mpd.rescan(&params.path).await,
+        false => mpd.update(&params.path).await,
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SetPriority {
+    index: usize,
+    priority: u8,
+}
+
+// before the tracks are queued, so that the resume tasks see it as they start
+fn set_resume(session: &Session, track_urls: &[Url], resume: Option<bool>) {
+    let files = track_urls.iter().map(Url::as_str);
+    session.zone.set_resume(files, resume.unwrap_or(true));
+}
+
+// unlike set_next_in_queue, this leaves queue order untouched and so works
+// with random mode, where mpd plays the highest priority items first
+async fn set_priority(session: &Session, params: SetPriority) -> Result<()> {
+    let mpd = session.mpd().await;
+    mpd.prio(params.priority, params.index..params.index + 1).await
+}
+
+#[derive(Debug, Clone, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Queue {
-    tracks: Vec<AirsonicTrack>,
-    current_track: Option<usize>,
-    current_track_position: Option<f64>,
+    pub tracks: Vec<AirsonicTrack>,
+    pub current_track: Option<usize>,
+    pub current_track_position: Option<f64>,
+    pub version: u32,
 }
 
+// every session in a zone tends to ask at once, after each queue change
 pub async fn queue(session: &Session) -> Result<Queue> {
-    let mpd = session.mpd().await;
-    let queue = mpd.playlistinfo().await?;
-    let status = mpd.status().await?;
-    drop(mpd);
+    let user = session.subsonic.auth().username();
+    let key = flight::key(user, &session.zone, "Queue", ());
 
     let resolver = session.resolver();
+    let load = async {
+        let (mut queue, items) = load_queue(&session.zone.mpd, &resolver).await?;
+        session.zone.mark_unavailable(&items, &mut queue.tracks);
+        Ok(queue)
+    };
+    session.ctx.services.queue_reads.run(key, load).await
+}
+
+// also returns the underlying mpd queue items, for use in computing deltas
+pub async fn load_queue(mpd: &RwLock<Mpd>, resolver: &helper::Resolver<'_>) -> Result<(Queue, Vec<PlaylistItem>)> {
+    let (queue, status) = {
+        let mpd = mpd.read().await;
+        (mpd.playlistinfo().await?, mpd.status().await?)
+    };
+
     let tracks = resolver.load_tracks_for(&queue.items).await?;
 
     let current_track = queue.items.iter()
@@ -184,14 +665,17 @@ pub async fn queue(session: &Session) -> Result<Queue> {
 
     let current_track_position = status.elapsed.map(|sec| sec.0);
 
-    Ok(Queue {
+    let queue_event = Queue {
         tracks,
         current_track,
         current_track_position,
-    })
+        version: status.playlist_version,
+    };
+
+    Ok((queue_event, queue.items))
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct PlayerState {
     tracks: Vec<AirsonicTrack>,
     index: usize,
@@ -199,10 +683,29 @@ pub struct PlayerState {
     shuffle: bool,
     repeat: bool,
     playing: bool,
+    /// load the play queue last saved to the subsonic server in place of
+    /// tracks, index and time
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    saved_queue: bool,
 }
 
 // loads entire player state, used for switching to this player from another
-async fn load_player_state(session: &Session, params: PlayerState) -> Result<()> {
+async fn load_player_state(session: &Session, mut params: PlayerState) -> Result<()> {
+    // the saved play queue may have come from another subsonic client
+    // entirely, and only has tracks and a position, options are as given
+    if params.saved_queue {
+        params = PlayerState {
+            shuffle: params.shuffle,
+            repeat: params.repeat,
+            playing: params.playing,
+            ..saved_player_state(session).await?
+        };
+    }
+
+    if params.tracks.is_empty() {
+        return session.mpd().await.clear().await;
+    }
+
     let resolver = session.resolver();
 
     let track_ids = params.tracks.iter()
@@ -211,32 +714,34 @@ async fn load_player_state(session: &Session, params: PlayerState) -> Result<()>
 
     let track_urls = resolver.stream_urls_for(&track_ids).await?;
 
-    let mpd = session.mpd().await;
-    mpd.clear().await?;
-
-    for url in &track_urls {
-        mpd.addid(url.as_str()).await?;
-    }
-
-    mpd.seek(params.index, params.time).await?;
-    mpd.random(params.shuffle).await?;
-    mpd.repeat(params.repeat).await?;
+    let mut commands = vec![MpdCommand::clear()];
+    commands.extend(track_urls.iter().map(|url| MpdCommand::addid(url.as_str())));
+    commands.push(MpdCommand::seek(params.index, params.time));
+    commands.push(MpdCommand::random(params.shuffle));
+    commands.push(MpdCommand::repeat(params.repeat));
 
     if params.playing {
-        mpd.play().await?;
+        commands.push(MpdCommand::play());
     }
 
+    let mpd = session.mpd().await;
+    mpd.command_list(&commands).await?;
     Ok(())
 }
 
 // dumps player state, stops, clears queue; used for switching away from this player
 async fn unload_player_state(session: &Session) -> Result<PlayerState> {
-    let mpd = session.mpd().await;
-    let status = mpd.status().await?;
-    let queue = mpd.playlistinfo().await?;
-    mpd.stop().await?;
-    mpd.clear().await?;
-    drop(mpd);
+    let state = player_state(session).await?;
+    clear_player_state(session, &state).await?;
+    Ok(state)
+}
+
+// the zone's queue and options, leaving them as they are
+async fn player_state(session: &Session) -> Result<PlayerState> {
+    let (status, queue) = {
+        let mpd = session.mpd().await;
+        (mpd.status().await?, mpd.playlistinfo().await?)
+    };
 
     let resolver = session.resolver();
     let tracks = resolver.load_tracks_for(&queue.items).await?;
@@ -248,111 +753,1048 @@ async fn unload_player_state(session: &Session) -> Result<PlayerState> {
         shuffle: status.random,
         repeat: status.repeat,
         playing: status.state == PlaybackState::Play,
+        saved_queue: false,
+    })
+}
+
+// stops and clears the zone once its state has been taken elsewhere
+async fn clear_player_state(session: &Session, state: &PlayerState) -> Result<()> {
+    let mpd = session.mpd().await;
+    mpd.stop().await?;
+    mpd.clear().await?;
+    drop(mpd);
+
+    // the queue is gone from mpd now, so failing here would lose it
+    if let Err(err) = save_player_state(session, state).await {
+        logging::error(&err.context("saving play queue to subsonic"));
+    }
+
+    Ok(())
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TransferPlayback {
+    from: String,
+    to: String,
+}
+
+// moves the queue and playback from one room to another. the source is
+// only cleared once the target has loaded, so a failure loses nothing
+async fn transfer_playback(session: &Session, params: TransferPlayback) -> Result<()> {
+    let from = session.in_room(&params.from)?;
+    let to = session.in_room(&params.to)?;
+
+    if Arc::ptr_eq(&from.zone, &to.zone) {
+        anyhow::bail!("cannot transfer playback to the same room");
+    }
+
+    // no other commands run on either zone until the transfer is done.
+    // locked in order of room name, so that transfers going opposite ways
+    // can't each hold one lock waiting on the other
+    let (first, second) = match params.from < params.to {
+        true => (&from, &to),
+        false => (&to, &from),
+    };
+
+    let _first = first.zone.command_lock.write().await;
+    let _second = second.zone.command_lock.write().await;
+
+    let state = player_state(&from).await?;
+
+    load_player_state(&to, state.clone()).await
+        .with_context(|| format!("transferring playback to {}", params.to))?;
+
+    clear_player_state(&from, &state).await
+}
+
+// restores the zone's queue and options from the state file
+async fn restore_state(session: &Session) -> Result<()> {
+    let Some(state_file) = &session.ctx.services.state_file else {
+        anyhow::bail!("no state file configured");
+    };
+
+    let Some(snapshot) = state_file.load(&session.zone).await? else {
+        anyhow::bail!("no saved state for this zone");
+    };
+
+    persist::restore(&session.zone, &snapshot).await
+}
+
+async fn saved_player_state(session: &Session) -> Result<PlayerState> {
+    let play_queue = session.subsonic.get_play_queue().await?;
+
+    let index = play_queue.current.as_ref()
+        .and_then(|current| play_queue.tracks.iter().position(|track| track.id.0 == current.0))
+        .unwrap_or_default();
+
+    Ok(PlayerState {
+        tracks: play_queue.tracks.into_iter().map(Into::into).collect(),
+        index,
+        time: play_queue.position.unwrap_or_default() as f64 / 1000.0,
+        shuffle: false,
+        repeat: false,
+        playing: false,
+        saved_queue: false,
     })
 }
 
+async fn save_player_state(session: &Session, state: &PlayerState) -> Result<()> {
+    // subsonic play queues can only hold tracks, not radio stations
+    let track_id = |track: &AirsonicTrack| match &track.id {
+        AirsonicTrackId::Track(id) if track.details.is_unavailable != Some(true) => Some(id.clone()),
+        _ => None,
+    };
+
+    let ids = state.tracks.iter()
+        .filter_map(track_id)
+        .collect::<Vec<_>>();
+
+    if ids.is_empty() {
+        return Ok(());
+    }
+
+    let current = state.tracks.get(state.index).and_then(track_id);
+    let position = (state.time * 1000.0) as u64;
+
+    session.subsonic.save_play_queue(&ids, current.as_ref(), position).await
+}
+
 #[derive(Deserialize, Debug)]
 pub struct PlayTrackList {
     tracks: Vec<AirsonicTrackId>,
     index: Option<usize>,
     shuffle: Option<bool>,
+    /// as for add-to-queue
+    resume: Option<bool>,
 }
 
 async fn play_track_list(session: &Session, params: PlayTrackList) -> Result<()> {
     let resolver = session.resolver();
     let track_urls = resolver.stream_urls_for(&params.tracks).await?;
-
-    let mpd = session.mpd().await;
+    set_resume(session, &track_urls, params.resume);
 
     // first clear the playlist
-    mpd.clear().await?;
+    let mut commands = vec![MpdCommand::clear()];
 
     // set shuffle if it was requested
     if let Some(shuffle) = params.shuffle {
-        mpd.random(shuffle).await?;
+        commands.push(MpdCommand::random(shuffle));
     }
 
     // add all tracks in the same order as they were provided
-    for url in &track_urls {
-        mpd.addid(url.as_str()).await?;
-    }
+    commands.extend(track_urls.iter().map(|url| MpdCommand::addid(url.as_str())));
 
     // then play, from index if given
     if let Some(index) = params.index {
-        mpd.playpos(index).await?;
+        commands.push(MpdCommand::playpos(index));
     } else {
-        mpd.play().await?;
+        commands.push(MpdCommand::play());
     }
 
+    let mpd = session.mpd().await;
+    mpd.command_list(&commands).await?;
     Ok(())
 }
 
+async fn get_playlists(session: &Session) -> Result<Vec<SubsonicPlaylist>> {
+    session.subsonic.get_playlists().await
+}
+
 #[derive(Deserialize, Debug)]
-pub struct RemoveFromQueue {
-    index: usize,
+pub struct GetPlaylist {
+    id: PlaylistId,
 }
 
-async fn remove_from_queue(session: &Session, params: RemoveFromQueue) -> Result<()> {
-    let mpd = session.mpd().await;
+#[derive(Serialize, Debug)]
+pub struct PlaylistTracks {
+    #[serde(flatten)]
+    playlist: SubsonicPlaylist,
+    tracks: Vec<AirsonicTrack>,
+}
 
-    if let Some(pos) = isize::try_from(params.index).ok() {
-        mpd.delete(pos).await?;
-    }
+async fn get_playlist(session: &Session, params: GetPlaylist) -> Result<PlaylistTracks> {
+    let playlist = session.subsonic.get_playlist(&params.id).await?;
 
-    Ok(())
+    Ok(PlaylistTracks {
+        playlist: playlist.playlist,
+        tracks: playlist.tracks.into_iter().map(Into::into).collect(),
+    })
 }
 
-async fn shuffle_queue(session: &Session) -> Result<()> {
-    session.mpd().await.shuffle().await
+#[derive(Deserialize, Debug)]
+pub struct PlayPlaylist {
+    id: PlaylistId,
+    index: Option<usize>,
+    shuffle: Option<bool>,
+}
+
+// resolves the playlist server side, saving the client sending every track id
+async fn play_playlist(session: &Session, params: PlayPlaylist) -> Result<()> {
+    let playlist = session.subsonic.get_playlist(&params.id).await?;
+    play_tracks(session, playlist.tracks, params.index, params.shuffle).await
 }
 
 #[derive(Deserialize, Debug)]
-pub struct ReplayGainMode {
-    mode: mpd::types::ReplayGainMode,
+pub struct PlayAlbum {
+    album_id: AlbumId,
+    index: Option<usize>,
+    shuffle: Option<bool>,
 }
 
-async fn replay_gain_mode(session: &Session, params: ReplayGainMode) -> Result<()> {
-    session.mpd().await.replay_gain_mode(params.mode).await
+async fn play_album(session: &Session, params: PlayAlbum) -> Result<()> {
+    let tracks = session.subsonic.get_album(&params.album_id).await?;
+    play_tracks(session, tracks, params.index, params.shuffle).await
 }
 
 #[derive(Deserialize, Debug)]
-pub struct SetRepeat {
-    repeat: bool,
+pub struct PlayArtistTopSongs {
+    artist_id: ArtistId,
+    index: Option<usize>,
+    shuffle: Option<bool>,
 }
 
-async fn set_repeat(session: &Session, params: SetRepeat) -> Result<()> {
-    session.mpd().await.repeat(params.repeat).await
+async fn play_artist_top_songs(session: &Session, params: PlayArtistTopSongs) -> Result<()> {
+    let tracks = session.subsonic.get_top_songs(&params.artist_id).await?;
+    play_tracks(session, tracks, params.index, params.shuffle).await
 }
 
+// the most tracks a mix is made of
+const MAX_MIX_COUNT: usize = 500;
+const DEFAULT_MIX_COUNT: usize = 50;
+
 #[derive(Deserialize, Debug)]
-pub struct SetShuffle {
-    shuffle: bool,
+pub struct PlayRandomSongs {
+    genre: Option<String>,
+    count: Option<usize>,
+    year_range: Option<YearRange>,
 }
 
-async fn set_shuffle(session: &Session, params: SetShuffle) -> Result<()> {
-    session.mpd().await.random(params.shuffle).await
+async fn play_random_songs(session: &Session, params: PlayRandomSongs) -> Result<()> {
+    let count = params.count.unwrap_or(DEFAULT_MIX_COUNT).min(MAX_MIX_COUNT);
+    let years = params.year_range.unwrap_or_default();
+
+    let filter = RandomSongsFilter {
+        genre: params.genre,
+        from_year: years.from,
+        to_year: years.to,
+    };
+
+    let tracks = session.subsonic.get_random_songs(count, &filter).await?;
+    anyhow::ensure!(!tracks.is_empty(), "no songs match");
+
+    play_tracks(session, tracks, None, None).await
 }
 
 #[derive(Deserialize, Debug)]
-pub struct SetVolume {
-    #[allow(unused)]
-    volume: f64
+pub struct PlaySimilar {
+    track_id: TrackId,
+    count: Option<usize>,
 }
 
-async fn set_volume(session: &Session, params: SetVolume) -> Result<()> {
-    // convert from 0-1 airsonic volume to 0-100 mpd volume:
-    let volume = (params.volume * 100.0).round() as usize;
-    session.mpd().await.setvol(volume).await
+// a mix starting with the track, followed by songs similar to its artist's
+async fn play_similar(session: &Session, params: PlaySimilar) -> Result<()> {
+    let count = params.count.unwrap_or(DEFAULT_MIX_COUNT).min(MAX_MIX_COUNT);
+    let track = session.subsonic.get_track(&params.track_id).await?;
+
+    let Some(artist) = track.details.artists.first() else {
+        anyhow::bail!("track has no artist to find similar songs for");
+    };
+
+    let similar = session.subsonic.get_similar_songs2(&artist.id, count).await?;
+
+    let seed = track.id.0.clone();
+    let mut tracks = vec![track];
+    tracks.extend(similar.into_iter().filter(|similar| similar.id.0 != seed));
+
+    play_tracks(session, tracks, None, None).await
 }
 
 #[derive(Deserialize, Debug)]
-pub struct SetPlaybackRate {
-    #[allow(unused)]
-    rate: f64
+pub struct SmartQueue {
+    #[serde(flatten)]
+    rules: smart::Rules,
+    count: Option<usize>,
 }
 
-async fn set_playback_rate(_session: &Session, _params: SetPlaybackRate) -> Result<()> {
-    anyhow::bail!("set-playback-rate not currently implemented on mpd");
+// adds matching songs to the end of the queue, responding with how many
+// were found
+async fn smart_queue(session: &Session, params: SmartQueue) -> Result<usize> {
+    let count = params.count.unwrap_or(DEFAULT_MIX_COUNT).min(MAX_MIX_COUNT);
+    let tracks = smart::plan(&session.subsonic, &params.rules, count).await?;
+
+    let ids = tracks.into_iter()
+        .map(|track| track.id.into())
+        .collect::<Vec<_>>();
+
+    let track_urls = session.resolver().stream_urls_for(&ids).await?;
+
+    let commands = track_urls.iter()
+        .map(|url| MpdCommand::addid(url.as_str()))
+        .collect::<Vec<_>>();
+
+    session.mpd().await.command_list(&commands).await?;
+    Ok(ids.len())
+}
+
+async fn play_tracks(session: &Session, tracks: Vec<Track>, index: Option<usize>, shuffle: Option<bool>) -> Result<()> {
+    let tracks = tracks.into_iter()
+        .map(|track| track.id.into())
+        .collect();
+
+    play_track_list(session, PlayTrackList { tracks, index, shuffle, resume: None }).await
+}
+
+// lyrics for the current track. empty if nothing is playing or the current
+// track isn't from subsonic
+async fn get_lyrics(session: &Session) -> Result<Vec<StructuredLyrics>> {
+    let mpd = session.mpd().await;
+    let Some(song_id) = mpd.status().await?.song_id else {
+        return Ok(Vec::new());
+    };
+    let item = mpd.playlistid(&song_id).await?;
+    drop(mpd);
+
+    let track_id = session.zone.stream_url(&item.file)
+        .and_then(|url| session.subsonic.track_id_from_stream_url(&url));
+
+    let Some(track_id) = track_id else {
+        return Ok(Vec::new());
+    };
+
+    session.subsonic.get_lyrics(&track_id).await
+}
+
+// stations of every server, as tracks like airsonic has them. those of the
+// extra servers have their ids prefixed, so can be queued like any other
+async fn list_radio_stations(session: &Session) -> Result<Vec<AirsonicTrack>> {
+    session.resolver().radio_stations_all().await
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RadioStationDetails {
+    name: String,
+    stream_url: Url,
+    homepage_url: Option<String>,
+}
+
+async fn create_radio_station(session: &Session, params: RadioStationDetails) -> Result<()> {
+    session.subsonic.create_radio_station(
+        &params.name,
+        &params.stream_url,
+        params.homepage_url.as_deref(),
+    ).await?;
+
+    session.ctx.services.stations.invalidate().await;
+    Ok(())
+}
+
+#[derive(Deserialize, Debug)]
+pub struct UpdateRadioStation {
+    id: RadioId,
+    #[serde(flatten)]
+    details: RadioStationDetails,
+}
+
+async fn update_radio_station(session: &Session, params: UpdateRadioStation) -> Result<()> {
+    session.subsonic.update_radio_station(
+        &params.id,
+        &params.details.name,
+        &params.details.stream_url,
+        params.details.homepage_url.as_deref(),
+    ).await?;
+
+    session.ctx.services.stations.invalidate().await;
+    Ok(())
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DeleteRadioStation {
+    id: RadioId,
+}
+
+async fn delete_radio_station(session: &Session, params: DeleteRadioStation) -> Result<()> {
+    session.subsonic.delete_radio_station(&params.id).await?;
+    session.ctx.services.stations.invalidate().await;
+    Ok(())
+}
+
+// the most results of each kind one search returns
+const MAX_SEARCH_LIMIT: usize = 500;
+const DEFAULT_SEARCH_LIMIT: usize = 20;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SearchType {
+    Artist,
+    Album,
+    Track,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Search {
+    query: String,
+    /// kinds of results wanted, defaulting to all of them
+    types: Option<Vec<SearchType>>,
+    /// results of each kind to skip, for paging
+    offset: Option<usize>,
+    /// results of each kind to return
+    limit: Option<usize>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SearchResults {
+    artists: Vec<Artist>,
+    albums: Vec<Album>,
+    tracks: Vec<AirsonicTrack>,
+}
+
+async fn search(session: &Session, params: Search) -> Result<SearchResults> {
+    let limit = params.limit.unwrap_or(DEFAULT_SEARCH_LIMIT).min(MAX_SEARCH_LIMIT);
+    let offset = params.offset.unwrap_or_default();
+
+    let page = |kind| {
+        let wanted = params.types.as_ref().is_none_or(|types| types.contains(&kind));
+        SearchPage { count: if wanted { limit } else { 0 }, offset }
+    };
+
+    let result = session.subsonic.search3(
+        &params.query,
+        page(SearchType::Artist),
+        page(SearchType::Album),
+        page(SearchType::Track),
+    ).await?;
+
+    Ok(SearchResults {
+        artists: result.artists,
+        albums: result.albums,
+        tracks: result.tracks.into_iter().map(AirsonicTrack::from).collect(),
+    })
+}
+
+fn podcasts(session: &Session) -> Result<&Podcasts> {
+    session.podcasts.as_ref()
+        .ok_or_else(|| anyhow::format_err!("podcasts are not configured"))
+}
+
+async fn refresh_podcasts(session: &Session) -> Result<()> {
+    podcasts(session)?.refresh().await
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PodcastEpisode {
+    id: TrackId,
+}
+
+async fn download_podcast_episode(session: &Session, params: PodcastEpisode) -> Result<()> {
+    podcasts(session)?.download_episode(&params.id).await
+}
+
+async fn delete_podcast_episode(session: &Session, params: PodcastEpisode) -> Result<()> {
+    podcasts(session)?.delete_episode(&params.id).await
+}
+
+async fn list_skip_offsets(session: &Session) -> Result<HashMap<String, SkipOffsets>> {
+    Ok(session.ctx.services.skips.list())
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SetSkipOffsets {
+    /// podcast channel id
+    feed: String,
+    #[serde(flatten)]
+    offsets: SkipOffsets,
+}
+
+// zero offsets stop skipping for the feed
+async fn set_skip_offsets(session: &Session, params: SetSkipOffsets) -> Result<()> {
+    let SkipOffsets { intro, outro } = params.offsets;
+    anyhow::ensure!(intro >= 0.0 && outro >= 0.0, "skip offsets must be positive");
+
+    session.ctx.services.skips.set(params.feed, params.offsets);
+    Ok(())
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RemoveFromQueue {
+    #[serde(flatten)]
+    items: RemoveItems,
+    queue_version: Option<u32>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub enum RemoveItems {
+    Index { index: usize },
+    Range { start: usize, end: Option<usize> },
+    Indexes { indexes: Vec<usize> },
+}
+
+async fn remove_from_queue(session: &Session, params: RemoveFromQueue) -> Result<()> {
+    let mpd = session.mpd().await;
+    check_queue_version(&mpd, params.queue_version).await?;
+
+    match params.items {
+        RemoveItems::Index { index } => {
+            mpd.delete(index).await
+        }
+        RemoveItems::Range { start, end } => {
+            mpd.delete_range(start, end).await
+        }
+        RemoveItems::Indexes { mut indexes } => {
+            // delete from the back so earlier deletes don't shift
+            // the positions of later ones
+            indexes.sort_unstable_by(|a, b| b.cmp(a));
+            indexes.dedup();
+
+            let commands = indexes.into_iter()
+                .map(MpdCommand::delete)
+                .collect::<Vec<_>>();
+
+            mpd.command_list(&commands).await?;
+            Ok(())
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct MoveInQueue {
+    from: usize,
+    to: usize,
+    queue_version: Option<u32>,
+}
+
+async fn move_in_queue(session: &Session, params: MoveInQueue) -> Result<()> {
+    let mpd = session.mpd().await;
+    check_queue_version(&mpd, params.queue_version).await?;
+    mpd.move_pos(params.from, params.to).await
+}
+
+async fn shuffle_queue(session: &Session) -> Result<()> {
+    session.mpd().await.shuffle().await
+}
+
+async fn list_playlists(session: &Session) -> Result<Vec<StoredPlaylist>> {
+    let playlists = session.mpd().await.listplaylists().await?;
+
+    Ok(playlists.into_iter()
+        .filter(|playlist| !playlist.name.starts_with(helper::INTERNAL_PLAYLIST_PREFIX))
+        .collect())
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PlaylistName {
+    name: String,
+}
+
+async fn save_playlist(session: &Session, params: PlaylistName) -> Result<()> {
+    session.mpd().await.save(&params.name).await
+}
+
+#[derive(Deserialize, Debug)]
+pub struct LoadPlaylist {
+    name: String,
+    // replace the current queue rather than appending to it
+    #[serde(default)]
+    replace: bool,
+}
+
+async fn load_playlist(session: &Session, params: LoadPlaylist) -> Result<()> {
+    let mpd = session.mpd().await;
+
+    if params.replace {
+        mpd.clear().await?;
+    }
+
+    mpd.load(&params.name, None, None).await
+}
+
+async fn delete_playlist(session: &Session, params: PlaylistName) -> Result<()> {
+    session.mpd().await.rm(&params.name).await
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RenamePlaylist {
+    name: String,
+    new_name: String,
+}
+
+async fn rename_playlist(session: &Session, params: RenamePlaylist) -> Result<()> {
+    session.mpd().await.rename(&params.name, &params.new_name).await
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ReplayGainMode {
+    mode: mpd::types::ReplayGainMode,
+}
+
+async fn replay_gain_mode(session: &Session, params: ReplayGainMode) -> Result<()> {
+    session.mpd().await.replay_gain_mode(params.mode).await
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SetRepeat {
+    repeat: bool,
+}
+
+async fn set_repeat(session: &Session, params: SetRepeat) -> Result<()> {
+    session.mpd().await.repeat(params.repeat).await
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SetShuffle {
+    shuffle: bool,
+}
+
+async fn set_shuffle(session: &Session, params: SetShuffle) -> Result<()> {
+    session.mpd().await.random(params.shuffle).await
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SetSingle {
+    mode: mpd::types::SingleMode,
+}
+
+async fn set_single(session: &Session, params: SetSingle) -> Result<()> {
+    session.mpd().await.single(params.mode).await
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SetConsume {
+    consume: bool,
+}
+
+async fn set_consume(session: &Session, params: SetConsume) -> Result<()> {
+    session.mpd().await.consume(params.consume).await
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SetCrossfade {
+    seconds: f64,
+}
+
+async fn set_crossfade(session: &Session, params: SetCrossfade) -> Result<()> {
+    // mpd only supports whole seconds of crossfade
+    let seconds = params.seconds.max(0.0).round() as usize;
+    session.mpd().await.crossfade(seconds).await
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SetMixRamp {
+    db: f64,
+    delay: Option<f64>,
+}
+
+async fn set_mix_ramp(session: &Session, params: SetMixRamp) -> Result<()> {
+    let mpd = session.mpd().await;
+    mpd.mixrampdb(params.db).await?;
+    mpd.mixrampdelay(params.delay).await
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SetVolume {
+    #[allow(unused)]
+    volume: f64
+}
+
+async fn set_volume(session: &Session, params: SetVolume) -> Result<()> {
+    // convert from 0-1 airsonic volume to 0-100 mpd volume:
+    let volume = session.zone.volume_limits.borrow().mpd_volume(params.volume);
+    session.mpd().await.setvol(volume).await
+}
+
+/// changes the limits of the session's zone, leaving out either one leaves
+/// it as it is
+#[derive(Deserialize, Debug)]
+pub struct SetVolumeLimits {
+    /// 0-1
+    max_volume: Option<f64>,
+    /// in dB
+    preamp: Option<f64>,
+}
+
+async fn set_volume_limits(session: &Session, params: SetVolumeLimits) -> Result<()> {
+    let mut limits = *session.zone.volume_limits.borrow();
+
+    if let Some(max_volume) = params.max_volume {
+        anyhow::ensure!((0.0..=1.0).contains(&max_volume), "max volume must be from 0 to 1");
+        limits.max_volume = max_volume;
+    }
+
+    if let Some(preamp) = params.preamp {
+        anyhow::ensure!(preamp.is_finite(), "preamp must be a number of dB");
+        limits.preamp = preamp;
+    }
+
+    // the options task picks up the change, lowering the volume if it's
+    // now over the limit
+    session.zone.volume_limits.send_replace(limits);
+    Ok(())
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SetAutoQueue {
+    enabled: bool,
+    /// where added tracks come from, left as it was if not given
+    source: Option<autoqueue::Source>,
+}
+
+async fn set_auto_queue(session: &Session, params: SetAutoQueue) -> Result<()> {
+    session.zone.auto_queue.send_modify(|auto_queue| {
+        auto_queue.enabled = params.enabled;
+
+        if let Some(source) = params.source {
+            auto_queue.source = source;
+        }
+    });
+
+    Ok(())
+}
+
+// ducks are for announcements and the like, this also keeps the end
+// time from overflowing
+const MAX_DUCK_DURATION: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Deserialize, Debug)]
+pub struct Duck {
+    /// 0-1, as for set-volume
+    level: f64,
+    /// in seconds, up to an hour
+    duration: f64,
+}
+
+async fn duck(session: &Session, params: Duck) -> Result<()> {
+    anyhow::ensure!((0.0..=1.0).contains(&params.level), "duck level must be from 0 to 1");
+
+    let duration = Duration::try_from_secs_f64(params.duration)
+        .context("bad duck duration")?;
+    anyhow::ensure!(duration <= MAX_DUCK_DURATION, "duck duration must be at most {}s",
+        MAX_DUCK_DURATION.as_secs());
+
+    duck::duck(&session.zone, params.level, duration).await
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Announce {
+    /// a short clip such as a chime or text to speech
+    url: Url,
+}
+
+// responds once the announcement has played and the queue is back
+async fn announce(session: &Session, params: Announce) -> Result<()> {
+    let volume = session.ctx.services.announce_volume;
+    announce::announce(&session.zone, &params.url, volume).await
+}
+
+pub async fn outputs(session: &Session) -> Result<Vec<Output>> {
+    session.mpd().await.outputs().await
+}
+
+// stats of the mpd instance behind the session's room
+async fn get_server_stats(session: &Session) -> Result<MpdStats> {
+    session.mpd().await.stats().await
+}
+
+/// what the zone's backend can play, so clients can hide what it can't
+#[derive(Serialize, Debug)]
+pub struct Capabilities {
+    /// None where the backend can't say, eg. the subsonic jukebox
+    url_schemes: Option<Vec<String>>,
+    decoders: Option<Vec<Decoder>>,
+    /// whether there's a proxy to implement playback rates with
+    playback_rate: bool,
+}
+
+async fn get_capabilities(session: &Session) -> Result<Capabilities> {
+    let mpd = session.mpd().await;
+
+    let url_schemes = mpd.urlhandlers().await
+        .inspect_err(|err| log::debug!("fetching url handlers: {err:#}"))
+        .ok();
+
+    let decoders = mpd.decoders().await
+        .inspect_err(|err| log::debug!("fetching decoders: {err:#}"))
+        .ok();
+
+    Ok(Capabilities {
+        url_schemes,
+        decoders,
+        playback_rate: session.zone.rate_proxy.is_some(),
+    })
+}
+
+#[derive(Deserialize, Debug)]
+pub struct EnableOutput {
+    id: usize,
+    enabled: bool,
+}
+
+async fn enable_output(session: &Session, params: EnableOutput) -> Result<()> {
+    let mpd = session.mpd().await;
+
+    if params.enabled {
+        mpd.enableoutput(params.id).await
+    } else {
+        mpd.disableoutput(params.id).await
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct Zone {
+    name: String,
+    current: bool,
+}
+
+async fn list_zones(session: &Session) -> Result<Vec<Zone>> {
+    let partitions = session.mpd().await.listpartitions().await?;
+
+    Ok(partitions.into_iter()
+        .map(|name| Zone {
+            current: name == session.zone.name,
+            name,
+        })
+        .collect())
+}
+
+#[derive(Serialize, Debug)]
+pub struct Room {
+    name: String,
+    current: bool,
+}
+
+async fn list_rooms(session: &Session) -> Result<Vec<Room>> {
+    Ok(session.ctx.rooms.names()
+        .map(|name| Room {
+            name: name.to_string(),
+            current: name == session.zone.room,
+        })
+        .collect())
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ZoneName {
+    name: String,
+}
+
+async fn create_zone(session: &Session, params: ZoneName) -> Result<()> {
+    session.mpd().await.newpartition(&params.name).await?;
+
+    // connect now so that clients switching to the new zone don't have to wait
+    session.zones()?.get(&params.name).await?;
+    Ok(())
+}
+
+#[derive(Deserialize, Debug)]
+pub struct MoveOutput {
+    name: String,
+}
+
+// moves an output from whichever zone it's in to this session's zone
+async fn move_output(session: &Session, params: MoveOutput) -> Result<()> {
+    session.mpd().await.moveoutput(&params.name).await
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SetPlaybackRate {
+    rate: f64
+}
+
+// replaces the current track with one streamed through the rate proxy at
+// the new rate, picking up from the same point in the track
+async fn set_playback_rate(session: &Session, params: SetPlaybackRate) -> Result<()> {
+    let Some(proxy) = &session.zone.rate_proxy else {
+        anyhow::bail!("set-playback-rate requires a rate proxy to be configured");
+    };
+
+    if !rate::RATE_PRESETS.contains(&params.rate) {
+        anyhow::bail!("unsupported playback rate {}, must be one of {:?}", params.rate, rate::RATE_PRESETS);
+    }
+
+    let mpd = session.mpd().await;
+    let status = mpd.status().await?;
+
+    let (Some(pos), Some(song_id)) = (status.song, &status.song_id) else {
+        anyhow::bail!("no current track");
+    };
+
+    let item = mpd.playlistid(song_id).await?;
+    let (url, current_rate) = session.zone.source(&item.file)
+        .with_context(|| format!("parsing playlist item url: {}", item.file))?;
+
+    if current_rate == params.rate {
+        return Ok(());
+    }
+
+    let file = if params.rate == 1.0 {
+        url
+    } else {
+        proxy.wrap(&url, params.rate)
+    };
+
+    let elapsed = status.elapsed.map(|s| s.0).unwrap_or_default();
+    let elapsed = elapsed * current_rate / params.rate;
+
+    let mut commands = vec![
+        MpdCommand::addid_at(file.as_str(), pos + 1),
+        MpdCommand::delete(pos),
+    ];
+
+    match status.state {
+        PlaybackState::Play => {
+            commands.push(MpdCommand::seek(pos, elapsed));
+        }
+        PlaybackState::Pause => {
+            commands.push(MpdCommand::seek(pos, elapsed));
+            commands.push(MpdCommand::pause());
+        }
+        PlaybackState::Stop => {}
+    }
+
+    mpd.command_list(&commands).await?;
+    Ok(())
+}
+
+/// transcoding for streams the user queues from now on, leaving both out
+/// goes back to whatever subsonic is configured to do
+#[derive(Deserialize, Debug)]
+pub struct SetStreamQuality {
+    /// eg. opus or mp3, or raw for the original file
+    format: Option<String>,
+    /// kbps, 0 for no limit
+    max_bit_rate: Option<u32>,
+}
+
+async fn set_stream_quality(session: &Session, params: SetStreamQuality) -> Result<()> {
+    let user = session.subsonic.auth().username()
+        .ok_or_else(|| anyhow::format_err!("stream quality needs a username"))?;
+
+    if let Some(format) = &params.format {
+        anyhow::ensure!(!format.is_empty() && format.chars().all(|c| c.is_ascii_alphanumeric()),
+            "invalid stream format: {format:?}");
+    }
+
+    let quality = StreamQuality {
+        format: params.format,
+        max_bit_rate: params.max_bit_rate,
+    };
+
+    session.ctx.services.subsonic.set_stream_quality(user.to_owned(), quality.clone());
+
+    if let Some(state_file) = &session.ctx.services.state_file {
+        state_file.save_stream_quality(user, &quality).await?;
+    }
+
+    Ok(())
+}
+
+const DEFAULT_HISTORY_LIMIT: usize = 50;
+
+fn history(session: &Session) -> Result<&History> {
+    session.ctx.services.history.as_deref()
+        .ok_or_else(|| anyhow::format_err!("play history is not configured"))
+}
+
+fn history_user(session: &Session) -> Result<&str> {
+    session.subsonic.auth().username()
+        .ok_or_else(|| anyhow::format_err!("play history needs a username"))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct GetHistory {
+    offset: Option<usize>,
+    limit: Option<usize>,
+}
+
+// the session user's recently played tracks, most recent first
+async fn get_history(session: &Session, params: GetHistory) -> Result<Vec<HistoryEntry>> {
+    let offset = params.offset.unwrap_or_default();
+    let limit = params.limit.unwrap_or(DEFAULT_HISTORY_LIMIT);
+    Ok(history(session)?.list(history_user(session)?, offset, limit).await)
+}
+
+async fn clear_history(session: &Session) -> Result<()> {
+    history(session)?.clear(history_user(session)?).await
+}
+
+#[derive(Deserialize, Debug)]
+pub struct GetStats {
+    /// days back from now to cover
+    days: Option<u32>,
+}
+
+async fn get_stats(session: &Session, params: GetStats) -> Result<Stats> {
+    let days = params.days.unwrap_or(stats::DEFAULT_DAYS);
+    stats::stats_for(history(session)?, history_user(session)?, days).await
+}
+
+async fn list_alarms(session: &Session) -> Result<Vec<Alarm>> {
+    Ok(session.ctx.alarms.list())
+}
+
+async fn set_alarm(session: &Session, alarm: Alarm) -> Result<()> {
+    session.ctx.alarms.set(alarm);
+    Ok(())
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AlarmName {
+    name: String,
+}
+
+async fn delete_alarm(session: &Session, params: AlarmName) -> Result<()> {
+    if !session.ctx.alarms.remove(&params.name) {
+        anyhow::bail!("no such alarm: {}", params.name);
+    }
+
+    Ok(())
+}
+
+fn snapcast(session: &Session) -> Result<&Snapcast> {
+    session.ctx.services.snapcast.as_ref()
+        .ok_or_else(|| anyhow::format_err!("snapcast is not configured"))
+}
+
+async fn snapcast_groups(session: &Session) -> Result<Vec<snapcast::Group>> {
+    snapcast(session)?.groups().await
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SnapcastSetVolume {
+    client: String,
+    /// 0-1, like set-volume
+    volume: f64,
+    #[serde(default)]
+    muted: bool,
+}
+
+async fn snapcast_set_volume(session: &Session, params: SnapcastSetVolume) -> Result<()> {
+    let volume = snapcast::Volume {
+        percent: (params.volume.clamp(0.0, 1.0) * 100.0).round() as u8,
+        muted: params.muted,
+    };
+
+    snapcast(session)?.set_client_volume(&params.client, volume).await
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SnapcastSetGroup {
+    group: String,
+    /// every client which should be in the group
+    clients: Option<Vec<String>>,
+    stream: Option<String>,
+}
+
+async fn snapcast_set_group(session: &Session, params: SnapcastSetGroup) -> Result<()> {
+    let snapcast = snapcast(session)?;
+
+    if let Some(clients) = &params.clients {
+        snapcast.set_group_clients(&params.group, clients).await?;
+    }
+
+    if let Some(stream) = &params.stream {
+        snapcast.set_group_stream(&params.group, stream).await?;
+    }
+
+    Ok(())
 }
 
 enum Op {
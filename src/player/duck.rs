@@ -0,0 +1,113 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Result;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+use crate::logging;
+
+use super::zones::Zone;
+
+/// temporarily lowered volume of a zone, so that announcements from
+/// elsewhere can be heard over the music
+#[derive(Default)]
+pub struct Duck {
+    state: Mutex<State>,
+    changed: Notify,
+}
+
+#[derive(Default, Clone, Copy)]
+enum State {
+    #[default]
+    Idle,
+    Ducked {
+        /// mpd volume from before the first duck, put back afterwards
+        restore: usize,
+        /// mpd volume we ducked to
+        ducked: usize,
+        until: Instant,
+    },
+}
+
+/// lowers the zone's volume to level, 0-1, for duration. ducks that
+/// overlap stay at the lowest level asked for until the last one ends, and
+/// then put back the volume from before the first
+pub async fn duck(zone: &Zone, level: f64, duration: Duration) -> Result<()> {
+    // holding mpd across both reading and setting the volume keeps this
+    // from interleaving with restoring
+    let mpd = zone.mpd.write().await;
+    let Some(volume) = mpd.status().await?.volume else {
+        anyhow::bail!("zone has no volume to duck");
+    };
+
+    let target = zone.volume_limits.borrow().mpd_volume(level);
+    let until = Instant::now() + duration;
+    let state = *zone.duck.state.lock().unwrap();
+
+    let state = match state {
+        State::Idle => State::Ducked {
+            restore: volume,
+            ducked: target.min(volume),
+            until,
+        },
+        State::Ducked { restore, ducked, until: prev_until } => State::Ducked {
+            restore,
+            ducked: target.min(ducked),
+            until: until.max(prev_until),
+        },
+    };
+
+    if let State::Ducked { ducked, .. } = state
+        && ducked != volume
+    {
+        mpd.setvol(ducked).await?;
+    }
+
+    *zone.duck.state.lock().unwrap() = state;
+    zone.duck.changed.notify_one();
+    Ok(())
+}
+
+/// puts back the volume once the last duck ends
+pub async fn task(zone: Arc<Zone>) {
+    loop {
+        let state = *zone.duck.state.lock().unwrap();
+
+        let State::Ducked { until, .. } = state else {
+            zone.duck.changed.notified().await;
+            continue;
+        };
+
+        tokio::select! {
+            () = tokio::time::sleep_until(until) => {
+                if let Err(err) = restore(&zone).await {
+                    logging::error(&err.context("restoring ducked volume"));
+                }
+            }
+            () = zone.duck.changed.notified() => {}
+        }
+    }
+}
+
+async fn restore(zone: &Zone) -> Result<()> {
+    let mpd = zone.mpd.write().await;
+
+    let State::Ducked { restore, ducked, until } = *zone.duck.state.lock().unwrap() else {
+        return Ok(());
+    };
+
+    // extended while we waited for mpd
+    if until > Instant::now() {
+        return Ok(());
+    }
+
+    *zone.duck.state.lock().unwrap() = State::Idle;
+
+    // leave the volume alone if someone changed it while ducked
+    if mpd.status().await?.volume == Some(ducked) {
+        mpd.setvol(restore).await?;
+    }
+
+    Ok(())
+}
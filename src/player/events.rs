@@ -1,172 +1,1076 @@
+use std::cmp;
+use std::collections::{HashMap, HashSet};
 use std::pin::Pin;
-use std::time::Duration;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use futures::{future, pin_mut};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use tokio::sync::watch;
+use url::Url;
 
-use crate::logging;
-use crate::mpd::Mpd;
-use crate::mpd::types::{MpdEvent, PlaybackState, ReplayGainMode};
+use crate::mpd::{Mpd, MpdIdleClient};
+use crate::mpd::types::{AudioFormat, Id, MpdEvent, Output, PlaybackState, PlaylistItem, ReplayGainMode, SingleMode, Status};
 use crate::player::ServerMsg;
+use crate::subsonic::Subsonic;
+use crate::subsonic::types::{Track, TrackId};
 
-use super::{commands, Session};
+use super::helper::Resolver;
+use super::types::AirsonicTrack;
+use super::autoqueue::AutoQueue;
+use super::volume::VolumeLimits;
+use super::zones::{IdleSource, Zone};
+use super::poll::EventLog;
+use super::visualizer::Visualizer;
+use super::{commands, Sender, Services, Session};
 
-const PLAYING_INTERVAL: Duration = Duration::from_millis(300);
+const SCROBBLE_INTERVAL: Duration = Duration::from_secs(5);
+
+const RECONNECT_MIN_BACKOFF: Duration = Duration::from_secs(1);
+const RECONNECT_MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+// how often a quiet idle connection is checked, and how long mpd gets to
+// answer before it's given up on
+const WATCHDOG_INTERVAL: Duration = Duration::from_secs(60);
+const WATCHDOG_GRACE: Duration = Duration::from_secs(10);
+
+pub const DEFAULT_QUEUE_DEBOUNCE: Duration = Duration::from_millis(150);
+
+// longest queue changes are held for while they keep coming
+const QUEUE_DEBOUNCE_MAX: Duration = Duration::from_secs(1);
 
 #[derive(Clone, Default)]
 pub struct MpdEvents {
-    queue: watch::Sender<()>,
-    status: watch::Sender<()>,
-    options: watch::Sender<()>,
+    pub queue: watch::Sender<()>,
+    pub status: watch::Sender<()>,
+    pub options: watch::Sender<()>,
+    pub outputs: watch::Sender<()>,
+    pub library: watch::Sender<()>,
+    /// sent directly by the mpd task, it already has everything in the event
+    pub stream_title: watch::Sender<Option<Arc<StreamTitleEvent>>>,
+}
+
+impl MpdEvents {
+    pub fn subscribe_status(&self) -> watch::Receiver<()> {
+        self.status.subscribe()
+    }
 }
 
 #[derive(Debug, Serialize)]
 pub struct PlaybackEvent {
-    playing: bool,
-    position: Option<f64>,
-    duration: Option<f64>,
+    pub playing: bool,
+    /// position and duration are in the track's own time, regardless of
+    /// playback rate
+    pub position: Option<f64>,
+    pub duration: Option<f64>,
+    rate: f64,
+    /// when position was sampled, in milliseconds since the unix epoch.
+    /// while playing, the position advances by rate seconds per second
+    timestamp: u64,
+    /// what mpd is decoding, while playing or paused
+    format: Option<FormatEvent>,
+    /// why mpd failed to play the last track it tried, eg. a 403 from the
+    /// stream url, until playback resumes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FormatEvent {
+    #[serde(flatten)]
+    audio: AudioFormat,
+    /// kbps
+    bitrate: Option<u32>,
 }
 
 #[derive(Debug, Serialize)]
 pub struct OptionsEvent {
-    volume: f64,
+    pub volume: f64,
+    volume_limits: VolumeLimits,
+    auto_queue: AutoQueue,
     repeat: bool,
     shuffle: bool,
     single: bool,
+    single_mode: SingleMode,
+    consume: bool,
+    crossfade: f64,
+    mixramp_db: Option<f64>,
+    mixramp_delay: Option<f64>,
     replay_gain: ReplayGainMode,
 }
 
+/// tags of the current track as mpd's decoder sees them. subsonic's
+/// metadata in queue events doesn't change while a track plays, but radio
+/// stations send the title of whatever they're playing as they go
 #[derive(Debug, Serialize)]
-pub struct QueueEvent(commands::Queue);
+pub struct NowPlayingEvent {
+    /// position of the track in the queue, None if there's no current track
+    position: Option<usize>,
+    title: Option<String>,
+    artist: Option<String>,
+    album: Option<String>,
+    /// name of the radio station
+    station: Option<String>,
+}
 
-pub async fn run_events(session: &Session) -> Result<()> {
-    let playback_event_task = playback_event_task(session);
-    pin_mut!(playback_event_task);
+/// a radio station's title changed mid-stream, sent in place of a full
+/// queue event
+#[derive(Debug, Serialize)]
+pub struct StreamTitleEvent {
+    /// position of the stream in the queue
+    position: usize,
+    title: Option<String>,
+}
 
-    let status_event_task = status_event_task(session);
-    pin_mut!(status_event_task);
+/// progress of updates to mpd's database, for setups where mpd also
+/// indexes local files
+#[derive(Debug, Serialize)]
+pub struct LibraryUpdateEvent {
+    /// job id of the update in progress, if any
+    pub updating: Option<u32>,
+    /// job id of the last update to finish while sonicast was watching
+    pub finished: Option<u32>,
+}
+
+/// health of the connections behind the zone, so that clients can tell
+/// the user what's wrong rather than commands silently failing
+#[derive(Debug, PartialEq, Serialize)]
+pub struct ConnectionEvent {
+    /// the connection to mpd is up and in sync
+    pub mpd: bool,
+    /// subsonic is answering requests
+    pub subsonic: bool,
+    /// either of the above is down, and commands are likely to fail
+    pub degraded: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct QueueEvent(pub commands::Queue);
+
+/// sent to sessions connecting with resume, once the queue events they
+/// missed have been sent
+#[derive(Debug, Serialize)]
+pub struct ResumedEvent {
+    /// the log no longer goes back as far as the seq resumed from, so the
+    /// client should fetch the queue afresh
+    missed: bool,
+}
 
-    let queue_event_task = queue_event_task(session);
+#[derive(Debug, Serialize)]
+pub struct OutputsEvent(Vec<Output>);
+
+/// sent as playback moves from one track to another, or stops or starts,
+/// so that clients needn't work out where tracks end from positions. only
+/// sent as it happens, not to clients connecting afterwards
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TrackChangedEvent {
+    /// queue index of the track which was playing, if any
+    previous_track: Option<usize>,
+    /// queue index of the track playing now, None once playback stops
+    current_track: Option<usize>,
+    /// the previous track played through to its end, rather than being
+    /// skipped or stopped
+    ended: bool,
+    /// playback stopped after the last track in the queue
+    end_of_queue: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum EventKind {
+    Playback,
+    /// both full queue snapshots and queue deltas
+    Queue,
+    Options,
+    Outputs,
+    NowPlaying,
+    LibraryUpdate,
+    Connection,
+    TrackChanged,
+    /// spectrum data many times a second, so only sent to clients which
+    /// subscribe to it
+    Visualizer,
+}
+
+impl EventKind {
+    /// what sessions are subscribed to until they say otherwise
+    pub const DEFAULT: &[EventKind] = &[
+        EventKind::Playback,
+        EventKind::Queue,
+        EventKind::Options,
+        EventKind::Outputs,
+        EventKind::NowPlaying,
+        EventKind::LibraryUpdate,
+        EventKind::Connection,
+        EventKind::TrackChanged,
+    ];
+}
+
+pub type EventSet = HashSet<EventKind>;
+
+/// tracks whether the session is subscribed to one kind of event
+struct Subscription {
+    rx: watch::Receiver<EventSet>,
+    kind: EventKind,
+    active: bool,
+}
+
+impl Subscription {
+    fn new(outlet: &Outlet, kind: EventKind) -> Self {
+        let rx = outlet.subscriptions.subscribe();
+        let active = rx.borrow().contains(&kind);
+        Subscription { rx, kind, active }
+    }
+
+    fn active(&self) -> bool {
+        self.active
+    }
+
+    /// waits until the session subscribes having previously not been. the
+    /// client will have missed changes in the meantime, so callers should
+    /// send current state in full
+    async fn subscribed(&mut self) -> Result<()> {
+        loop {
+            self.rx.changed().await?;
+
+            let was_active = self.active;
+            self.active = self.rx.borrow_and_update().contains(&self.kind);
+
+            if self.active && !was_active {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// somewhere a zone's events are forwarded to, a session's client or the
+/// event log
+pub struct Outlet<'a> {
+    pub zone: &'a Zone,
+    pub subscriptions: &'a watch::Sender<EventSet>,
+    pub tx: &'a Sender,
+    /// tags queue events with their seq in the log, and is where missed
+    /// queue events are replayed from
+    pub log: Option<&'a EventLog>,
+    /// seq of the last queue event the client saw before reconnecting
+    pub resume: Option<u64>,
+}
+
+// events are produced once per zone by the state task, sessions only
+// forward them on to their client
+pub async fn run_events(session: &Session, resume: Option<u64>) -> Result<()> {
+    let outlet = Outlet {
+        zone: &session.zone,
+        subscriptions: &session.subscriptions,
+        tx: &session.tx,
+        log: Some(&session.ctx.services.event_log),
+        resume,
+    };
+
+    forward_all(&outlet, Some(&session.zones()?.visualizer)).await
+}
+
+/// visualizer events belong to the room rather than the zone, so are only
+/// forwarded when given
+pub async fn forward_all(outlet: &Outlet<'_>, visualizer: Option<&Visualizer>) -> Result<()> {
+    let state = &outlet.zone.state;
+
+    let playback_event_task = forward_events(outlet, EventKind::Playback, &state.playback, ServerMsg::Playback);
+    pin_mut!(playback_event_task);
+
+    let queue_event_task = forward_queue_events(outlet);
     pin_mut!(queue_event_task);
 
-    let options_event_task = options_event_task(session);
+    let options_event_task = forward_events(outlet, EventKind::Options, &state.options, ServerMsg::Options);
     pin_mut!(options_event_task);
 
+    let outputs_event_task = forward_events(outlet, EventKind::Outputs, &state.outputs, ServerMsg::Outputs);
+    pin_mut!(outputs_event_task);
+
+    let now_playing_event_task = forward_events(outlet, EventKind::NowPlaying, &state.now_playing, ServerMsg::NowPlaying);
+    pin_mut!(now_playing_event_task);
+
+    // part of now playing, stream titles are a lighter weight update to it
+    let stream_title_event_task = forward_events(outlet, EventKind::NowPlaying, &outlet.zone.events.stream_title, ServerMsg::StreamTitle);
+    pin_mut!(stream_title_event_task);
+
+    let library_update_event_task = forward_events(outlet, EventKind::LibraryUpdate, &state.library_update, ServerMsg::LibraryUpdate);
+    pin_mut!(library_update_event_task);
+
+    let connection_event_task = forward_events(outlet, EventKind::Connection, &state.connection, ServerMsg::Connection);
+    pin_mut!(connection_event_task);
+
+    let track_changed_event_task = forward_new_events(outlet, EventKind::TrackChanged, &state.track_changed, ServerMsg::TrackChanged);
+    pin_mut!(track_changed_event_task);
+
+    let visualizer_event_task = async {
+        match visualizer {
+            Some(visualizer) => forward_events(outlet, EventKind::Visualizer, visualizer, ServerMsg::Visualizer).await,
+            None => future::pending().await,
+        }
+    };
+    pin_mut!(visualizer_event_task);
+
     future::select_all([
         playback_event_task as Pin<&mut (dyn Future<Output = Result<()>> + Send)>,
-        status_event_task,
         queue_event_task,
         options_event_task,
+        outputs_event_task,
+        now_playing_event_task,
+        stream_title_event_task,
+        library_update_event_task,
+        connection_event_task,
+        track_changed_event_task,
+        visualizer_event_task,
     ]).await.0
 }
 
-async fn playback_event_task(session: &Session) -> Result<()> {
+// sends the latest event on start and then on every change
+async fn forward_events<T>(
+    outlet: &Outlet<'_>,
+    kind: EventKind,
+    state: &watch::Sender<Option<Arc<T>>>,
+    msg: fn(Arc<T>) -> ServerMsg,
+) -> Result<()> {
+    let mut rx = state.subscribe();
+    let mut subscription = Subscription::new(outlet, kind);
+
     loop {
-        let status = {
-            let mpd = session.ctx.mpd.read().await;
-            mpd.status().await?
+        let event = rx.borrow_and_update().clone();
+
+        if subscription.active()
+            && let Some(event) = event
+        {
+            outlet.tx.send(msg(event)).await;
+        }
+
+        tokio::select! {
+            changed = rx.changed() => { if changed.is_err() { break } }
+            result = subscription.subscribed() => { result? }
+        }
+    }
+
+    Ok(())
+}
+
+// sends events produced from now on only, for events about something
+// happening rather than the state of things
+async fn forward_new_events<T>(
+    outlet: &Outlet<'_>,
+    kind: EventKind,
+    state: &watch::Sender<Option<Arc<T>>>,
+    msg: fn(Arc<T>) -> ServerMsg,
+) -> Result<()> {
+    let mut rx = state.subscribe();
+    rx.mark_unchanged();
+
+    let mut subscription = Subscription::new(outlet, kind);
+
+    loop {
+        tokio::select! {
+            changed = rx.changed() => {
+                if changed.is_err() { break }
+
+                let event = rx.borrow_and_update().clone();
+
+                if subscription.active()
+                    && let Some(event) = event
+                {
+                    outlet.tx.send(msg(event)).await;
+                }
+            }
+            result = subscription.subscribed() => { result? }
+        }
+    }
+
+    Ok(())
+}
+
+// sends queue deltas where the client has the queue the delta applies to,
+// and full snapshots otherwise
+async fn forward_queue_events(outlet: &Outlet<'_>) -> Result<()> {
+    let mut rx = outlet.zone.state.queue.subscribe();
+    let mut subscription = Subscription::new(outlet, EventKind::Queue);
+
+    // version of the queue last sent to the client, if any
+    let mut sent_version = None;
+
+    // after subscribing, so that changes while replaying aren't lost
+    if let (Some(log), Some(since)) = (outlet.log, outlet.resume)
+        && subscription.active()
+    {
+        sent_version = resume_queue(outlet, log, since).await;
+
+        let current = rx.borrow().as_ref().map(|state| state.queue.0.version);
+        if current != sent_version {
+            rx.mark_changed();
+        }
+    }
+
+    loop {
+        tokio::select! {
+            changed = rx.changed() => { if changed.is_err() { break } }
+            result = subscription.subscribed() => {
+                result?;
+                // deltas would be relative to a queue the client may not have
+                sent_version = None;
+            }
+        }
+
+        if !subscription.active() {
+            continue;
+        }
+
+        let Some(state) = rx.borrow_and_update().clone() else { continue };
+
+        let msg = match &state.delta {
+            Some(delta) if sent_version == Some(delta.from_version) => {
+                ServerMsg::QueueDelta(delta.clone())
+            }
+            _ => ServerMsg::Queue(state.queue.clone()),
         };
 
-        let event = PlaybackEvent {
-            playing: status.state == PlaybackState::Play,
-            position: status.elapsed.map(|s| s.0),
+        let version = state.queue.0.version;
+        let seq = outlet.log.and_then(|log| log.queue_seq(outlet.zone, version));
+
+        sent_version = Some(version);
+        outlet.tx.send_seq(msg, seq).await;
+    }
+
+    Ok(())
+}
+
+// sends the queue events the client missed while disconnected, returning
+// the version of the queue it now has, if known
+async fn resume_queue(outlet: &Outlet<'_>, log: &EventLog, since: u64) -> Option<u32> {
+    let (events, missed) = log.queue_since(outlet.zone, since);
+
+    for event in &events {
+        let msg = match event.msg() {
+            ServerMsg::Queue(queue) => ServerMsg::Queue(queue.clone()),
+            ServerMsg::QueueDelta(delta) => ServerMsg::QueueDelta(delta.clone()),
+            _ => continue,
+        };
+
+        outlet.tx.send_seq(msg, Some(event.seq())).await;
+    }
+
+    outlet.tx.send(ServerMsg::Resumed(ResumedEvent { missed })).await;
+
+    if missed {
+        return None;
+    }
+
+    // the client is now up to date with the log
+    log.latest_queue_event(outlet.zone)
+        .and_then(|event| event.queue_version())
+}
+
+pub async fn playback_event(zone: &Zone) -> Result<PlaybackEvent> {
+    let (status, rate) = {
+        let mpd = zone.mpd.read().await;
+        // the position goes out with the time now, so can't be stale
+        let status = mpd.status_within(Duration::ZERO).await?;
+        let rate = current_rate(zone, &mpd, &status).await?;
+        (status, rate)
+    };
+
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64;
+    let playing = status.state == PlaybackState::Play;
+
+    // the error is usually cleared by the time the status is, but may not be
+    // yet if this got in first
+    let error = match playing {
+        true => None,
+        false => status.error.or_else(|| zone.playback_error.borrow().clone()),
+    };
+
+    let error = error.as_deref().map(redact_urls);
+
+    Ok(PlaybackEvent {
+        playing,
+        position: status.elapsed.map(|s| s.0 * rate),
+        duration: status.duration.map(|s| s.0 * rate),
+        rate,
+        timestamp,
+        format: status.audio_format.map(|audio| FormatEvent {
+            audio,
+            bitrate: status.bitrate,
+        }),
+        error,
+    })
+}
+
+// mpd's errors quote stream urls, whose queries carry subsonic credentials
+pub fn redact_urls(message: &str) -> String {
+    message.split(' ')
+        .map(|word| match Url::parse(word) {
+            Ok(mut url) if url.query().is_some() => {
+                url.set_query(None);
+                url.to_string()
+            }
+            _ => word.to_owned(),
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// how close to its duration a track has to have got to count as having
+// played through, allowing for statuses not coming in right at the end
+const END_TOLERANCE: f64 = 2.0;
+
+/// the track playing as of the last status
+pub struct CurrentTrack {
+    song_id: Id,
+    index: Option<usize>,
+    elapsed: Option<f64>,
+    duration: Option<f64>,
+    playing: bool,
+    /// when the status was fetched, to extrapolate elapsed from
+    at: Instant,
+}
+
+impl CurrentTrack {
+    /// None while stopped
+    pub fn from_status(status: &Status) -> Option<Self> {
+        if status.state == PlaybackState::Stop {
+            return None;
+        }
+
+        Some(CurrentTrack {
+            song_id: status.song_id.clone()?,
+            index: status.song,
+            elapsed: status.elapsed.map(|s| s.0),
             duration: status.duration.map(|s| s.0),
+            playing: status.state == PlaybackState::Play,
+            at: Instant::now(),
+        })
+    }
+
+    /// whether the track would have reached its end by now. streams have
+    /// no duration, so never do
+    fn reached_end(&self) -> bool {
+        let (Some(elapsed), Some(duration)) = (self.elapsed, self.duration) else {
+            return false;
         };
 
-        session.tx.send(ServerMsg::Playback(event)).await;
+        let elapsed = match self.playing {
+            true => elapsed + self.at.elapsed().as_secs_f64(),
+            false => elapsed,
+        };
 
-        tokio::time::sleep(PLAYING_INTERVAL).await;
+        elapsed >= duration - END_TOLERANCE
     }
 }
 
-async fn options_event_task(session: &Session) -> Result<()> {
-    let mut watch = session.ctx.events.options.subscribe();
+/// the event for going from prev to current, if the track changed
+pub fn track_changed_event(prev: Option<&CurrentTrack>, current: Option<&CurrentTrack>) -> Option<TrackChangedEvent> {
+    if prev.map(|track| &track.song_id) == current.map(|track| &track.song_id) {
+        return None;
+    }
 
-    loop {
-        let Some(options) = get_player_options(&session).await
-            .inspect_err(logging::error)
-            .ok() else { continue };
+    let ended = prev.is_some_and(CurrentTrack::reached_end);
 
-        session.tx.send(ServerMsg::Options(options)).await;
+    Some(TrackChangedEvent {
+        previous_track: prev.and_then(|track| track.index),
+        current_track: current.and_then(|track| track.index),
+        ended,
+        end_of_queue: ended && current.is_none(),
+    })
+}
 
-        let Ok(_) = watch.changed().await else { break };
+async fn current_rate(zone: &Zone, mpd: &Mpd, status: &Status) -> Result<f64> {
+    // avoid an extra round trip when rates aren't in use
+    if zone.rate_proxy.is_none() {
+        return Ok(1.0);
     }
 
-    Ok(())
+    let Some(song_id) = &status.song_id else { return Ok(1.0) };
+    let item = mpd.playlistid(song_id).await?;
+
+    Ok(zone.source(&item.file).map(|(_, rate)| rate).unwrap_or(1.0))
 }
 
-async fn get_player_options(session: &Session) -> Result<OptionsEvent> {
-    let mpd = session.ctx.mpd.read().await;
+pub async fn options_event(zone: &Zone) -> Result<OptionsEvent> {
+    let mpd = zone.mpd.read().await;
     let status = mpd.status().await?;
     let replay_gain = mpd.replay_gain_status().await?;
-    let volume = status.volume.unwrap_or(100) as f64 / 100.0;
+    let volume_limits = *zone.volume_limits.borrow();
+    let volume = volume_limits.client_volume(status.volume.unwrap_or(100));
     Ok(OptionsEvent {
         volume,
+        volume_limits,
+        auto_queue: *zone.auto_queue.borrow(),
         shuffle: status.random,
         repeat: status.repeat,
-        single: status.single,
+        single: status.single != SingleMode::Off,
+        single_mode: status.single,
+        consume: status.consume,
+        crossfade: status.crossfade.map(|s| s.0).unwrap_or_default(),
+        mixramp_db: status.mixramp_db,
+        mixramp_delay: status.mixramp_delay.map(|s| s.0).filter(|s| !s.is_nan()),
         replay_gain,
     })
 }
 
-async fn status_event_task(session: &Session) -> Result<()> {
-    queue_event_common(session, session.ctx.events.status.clone()).await
+pub async fn now_playing_event(zone: &Zone) -> Result<NowPlayingEvent> {
+    let song = zone.mpd.read().await.currentsong().await?;
+
+    let Some(song) = song else {
+        return Ok(NowPlayingEvent {
+            position: None,
+            title: None,
+            artist: None,
+            album: None,
+            station: None,
+        });
+    };
+
+    Ok(NowPlayingEvent {
+        position: Some(song.pos),
+        title: song.title,
+        artist: song.artist,
+        album: song.album,
+        station: song.name,
+    })
+}
+
+/// finished carries over the job of the previous event when it finishes
+pub async fn library_update_event(zone: &Zone, prev: Option<&LibraryUpdateEvent>) -> Result<LibraryUpdateEvent> {
+    let updating = zone.mpd.read().await.status().await?.updating_db;
+    let prev_updating = prev.and_then(|prev| prev.updating);
+
+    let finished = match prev_updating {
+        Some(job) if updating != Some(job) => Some(job),
+        _ => prev.and_then(|prev| prev.finished),
+    };
+
+    Ok(LibraryUpdateEvent { updating, finished })
+}
+
+pub async fn outputs_event(zone: &Zone) -> Result<OutputsEvent> {
+    let outputs = zone.mpd.read().await.outputs().await?;
+    Ok(OutputsEvent(outputs))
+}
+
+/// the queue as of the last event produced
+pub struct LastQueue {
+    version: u32,
+    items: Vec<PlaylistItem>,
+    tracks: Vec<AirsonicTrack>,
+}
+
+impl LastQueue {
+    pub fn items(&self) -> &[PlaylistItem] {
+        &self.items
+    }
+
+    /// resolved tracks, one for each item
+    pub fn tracks(&self) -> &[AirsonicTrack] {
+        &self.tracks
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueueDelta {
+    from_version: u32,
+    pub version: u32,
+    length: usize,
+    /// indexes in the previous queue of tracks no longer in the queue
+    removed: Vec<usize>,
+    /// tracks from the previous queue now at a different index
+    moved: Vec<MovedTrack>,
+    /// new tracks, by index in the new queue
+    added: Vec<AddedTrack>,
+    current_track: Option<usize>,
+    current_track_position: Option<f64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MovedTrack {
+    from: usize,
+    to: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AddedTrack {
+    index: usize,
+    track: AirsonicTrack,
 }
 
-async fn queue_event_task(session: &Session) -> Result<()> {
-    queue_event_common(session, session.ctx.events.queue.clone()).await
+// produces a full queue snapshot the first time, along with a delta
+// computed with plchanges after that. there's no delta if the changes
+// reported by mpd don't add up
+pub async fn queue_event(
+    zone: &Zone,
+    resolver: &Resolver<'_>,
+    last: &mut Option<LastQueue>,
+) -> Result<(QueueEvent, Option<QueueDelta>)> {
+    if let Some(prev) = last.as_ref()
+        && let Some((delta, next)) = queue_delta(zone, resolver, prev).await?
+    {
+        let queue = commands::Queue {
+            tracks: next.tracks.clone(),
+            current_track: delta.current_track,
+            current_track_position: delta.current_track_position,
+            version: delta.version,
+        };
+
+        *last = Some(next);
+        return Ok((QueueEvent(queue), Some(delta)));
+    }
+
+    let (mut queue, items) = commands::load_queue(&zone.mpd, resolver).await?;
+    zone.mark_unavailable(&items, &mut queue.tracks);
+
+    *last = Some(LastQueue {
+        version: queue.version,
+        items,
+        tracks: queue.tracks.clone(),
+    });
+
+    Ok((QueueEvent(queue), None))
 }
 
-async fn queue_event_common(session: &Session, watch: watch::Sender<()>) -> Result<()> {
-    let mut watch = watch.subscribe();
+async fn queue_delta(zone: &Zone, resolver: &Resolver<'_>, prev: &LastQueue) -> Result<Option<(QueueDelta, LastQueue)>> {
+    let (changes, status) = zone.mpd.read().await.plchanges_status(prev.version).await?;
+
+    // apply changes to the previous queue to get the new queue
+    let mut items = prev.items.iter().cloned().map(Some).collect::<Vec<_>>();
+    items.resize(status.playlist_length, None);
 
-    while watch.changed().await.is_ok() {
-        match commands::queue(session).await {
-            Ok(queue) => {
-                let msg = ServerMsg::Queue(QueueEvent(queue));
-                session.tx.send(msg).await;
+    for item in changes {
+        match usize::try_from(item.pos).ok().and_then(|pos| items.get_mut(pos)) {
+            Some(slot) => { *slot = Some(item) }
+            None => { return Ok(None) }
+        }
+    }
+
+    // a gap means the changes don't add up to the queue's new length
+    let Some(items) = items.into_iter().collect::<Option<Vec<_>>>() else {
+        return Ok(None);
+    };
+
+    let prev_index = prev.items.iter().enumerate()
+        .map(|(index, item)| (&item.id, index))
+        .collect::<HashMap<_, _>>();
+
+    let mut moved = Vec::new();
+    let mut added_items = Vec::new();
+    let mut added_indexes = Vec::new();
+
+    for (index, item) in items.iter().enumerate() {
+        match prev_index.get(&item.id) {
+            Some(&from) if from == index => {}
+            Some(&from) => moved.push(MovedTrack { from, to: index }),
+            None => {
+                added_items.push(item.clone());
+                added_indexes.push(index);
             }
-            Err(err) => {
-                logging::error(&err.context("queue event, fetching queue"));
+        }
+    }
+
+    let ids = items.iter().map(|item| &item.id).collect::<HashSet<_>>();
+    let removed = prev.items.iter().enumerate()
+        .filter(|(_, item)| !ids.contains(&item.id))
+        .map(|(index, _)| index)
+        .collect();
+
+    let mut tracks = resolver.load_tracks_for(&added_items).await?;
+    zone.mark_unavailable(&added_items, &mut tracks);
+
+    let added = added_indexes.into_iter().zip(tracks)
+        .map(|(index, track)| AddedTrack { index, track })
+        .collect::<Vec<_>>();
+
+    // carry over tracks already resolved for the previous queue
+    let mut added_tracks = added.iter().map(|added| &added.track);
+    let tracks = items.iter()
+        .map(|item| match prev_index.get(&item.id) {
+            Some(&from) => Some(prev.tracks[from].clone()),
+            None => added_tracks.next().cloned(),
+        })
+        .collect::<Option<Vec<_>>>();
+
+    let Some(tracks) = tracks else { return Ok(None) };
+
+    let delta = QueueDelta {
+        from_version: prev.version,
+        version: status.playlist_version,
+        length: items.len(),
+        removed,
+        moved,
+        added,
+        current_track: items.iter()
+            .position(|item| Some(&item.id) == status.song_id.as_ref()),
+        current_track_position: status.elapsed.map(|sec| sec.0),
+    };
+
+    let next = LastQueue {
+        version: status.playlist_version,
+        items,
+        tracks,
+    };
+
+    Ok(Some((delta, next)))
+}
+
+struct Scrobbling {
+    song_id: Id,
+    // None if the song is not a subsonic track, eg. radio or podcasts
+    track_id: Option<TrackId>,
+    started: SystemTime,
+    now_playing_sent: bool,
+    submitted: bool,
+    // fetched on first use, listenbrainz needs track metadata
+    track: Option<Track>,
+}
+
+// tracks playback in a zone and scrobbles to subsonic and listenbrainz on
+// behalf of the most recently connected session: "now playing" when a track
+// starts, and a submission once more than half of it has played
+pub async fn scrobble_task(services: Services, zone: Arc<Zone>) {
+    let mut watch = zone.events.subscribe_status();
+    let mut current = None;
+
+    loop {
+        if let Err(err) = scrobble_update(&services, &zone, &mut current).await {
+            log::warn!("scrobble: {err:?}");
+        }
+
+        tokio::select! {
+            changed = watch.changed() => {
+                if changed.is_err() { break }
             }
+            () = tokio::time::sleep(SCROBBLE_INTERVAL) => {}
         }
     }
+}
+
+async fn scrobble_update(
+    services: &Services,
+    zone: &Zone,
+    current: &mut Option<Scrobbling>,
+) -> Result<()> {
+    let subsonic = &services.subsonic;
+    let podcasts = services.podcasts.as_ref();
+
+    let mpd = zone.mpd.read().await;
+    let status = mpd.status().await?;
+
+    let Some(song_id) = status.song_id else {
+        *current = None;
+        return Ok(());
+    };
+
+    if current.as_ref().map(|current| &current.song_id) != Some(&song_id) {
+        let item = mpd.playlistid(&song_id).await?;
+
+        let track_id = zone.stream_url(&item.file)
+            .filter(|url| !podcasts.is_some_and(|podcasts| podcasts.matches_stream_url(url)))
+            .and_then(|url| subsonic.track_id_from_stream_url(&url));
+
+        *current = Some(Scrobbling {
+            song_id,
+            track_id,
+            started: SystemTime::now(),
+            now_playing_sent: false,
+            submitted: false,
+            track: None,
+        });
+    }
+
+    drop(mpd);
+
+    let Some(scrobbling) = current else { return Ok(()) };
+    if scrobbling.track_id.is_none() {
+        return Ok(());
+    }
+
+    if status.state != PlaybackState::Play {
+        return Ok(());
+    }
+
+    let Some(auth) = zone.auth() else { return Ok(()) };
+    let subsonic = subsonic.with_auth(auth);
+
+    if !scrobbling.now_playing_sent {
+        scrobbling.now_playing_sent = true;
+        scrobble(services, &subsonic, scrobbling, false).await;
+    }
+
+    let played_half = match (status.elapsed, status.duration) {
+        (Some(elapsed), Some(duration)) => elapsed.0 > duration.0 / 2.0,
+        _ => false,
+    };
+
+    if played_half && !scrobbling.submitted {
+        scrobbling.submitted = true;
+        scrobble(services, &subsonic, scrobbling, true).await;
+    }
 
     Ok(())
 }
 
-pub async fn task(mpd: Mpd, events: MpdEvents) {
-    if let Err(err) = mpd_loop(mpd, &events).await {
-        panic!("mpd task: {err:?}");
+// sends to each scrobbler independently, so one failing doesn't stop the
+// others
+async fn scrobble(services: &Services, subsonic: &Subsonic, scrobbling: &mut Scrobbling, submission: bool) {
+    let Some(track_id) = &scrobbling.track_id else { return };
+
+    if services.scrobble_subsonic
+        && let Err(err) = subsonic.scrobble(track_id, submission, scrobbling.started).await
+    {
+        log::warn!("scrobble to subsonic: {err:?}");
+    }
+
+    if let Some(listenbrainz) = &services.listenbrainz {
+        let result = async {
+            let track = match &mut scrobbling.track {
+                Some(track) => track,
+                track @ None => track.insert(subsonic.get_track(track_id).await?),
+            };
+
+            if submission {
+                listenbrainz.single(track, scrobbling.started).await
+            } else {
+                listenbrainz.playing_now(track).await
+            }
+        }.await;
+
+        if let Err(err) = result {
+            log::warn!("scrobble to listenbrainz: {err:?}");
+        }
+    }
+}
+
+// losing the idle connection would otherwise leave the zone silently without
+// events while commands carry on working, so it's made again, backing off
+// while mpd stays unreachable
+pub async fn task(source: Arc<IdleSource>, queue_debounce: Duration, events: MpdEvents) {
+    let mut backoff = RECONNECT_MIN_BACKOFF;
+
+    loop {
+        match source.connect().await {
+            Ok(mpd) => {
+                let connected = Instant::now();
+                resync(&events);
+
+                if let Err(err) = mpd_loop(mpd, queue_debounce, &events).await {
+                    log::warn!("mpd idle connection lost, reconnecting: {err:?}");
+                }
+
+                // a connection which lasted a while was healthy, start over
+                // rather than carrying on from wherever backing off had got to
+                if connected.elapsed() > RECONNECT_MAX_BACKOFF {
+                    backoff = RECONNECT_MIN_BACKOFF;
+                }
+            }
+            Err(err) => log::warn!("connecting mpd idle connection: {err:?}"),
+        }
+
+        tokio::time::sleep(backoff).await;
+        backoff = cmp::min(backoff * 2, RECONNECT_MAX_BACKOFF);
     }
 }
 
-async fn mpd_loop(mpd: Mpd, events: &MpdEvents) -> Result<()> {
+// whatever changed while disconnected went unseen, so everything is
+// refreshed as though it had, including on first connecting
+fn resync(events: &MpdEvents) {
+    events.queue.send_replace(());
+    events.status.send_replace(());
+    events.options.send_replace(());
+    events.outputs.send_replace(());
+    events.library.send_replace(());
+}
+
+async fn mpd_loop(mut mpd: MpdIdleClient, queue_debounce: Duration, events: &MpdEvents) -> Result<()> {
     let mut queue_ver = playlist_version(&mpd).await?;
+    let mut song = mpd.currentsong().await?;
+
+    // when the first and the latest queue changes not yet signalled came
+    // in. adding tracks one at a time changes the queue for each, and every
+    // signal reloads the queue for every session, so changes are held until
+    // the queue has been quiet for queue_debounce
+    let mut queue_pending: Option<(Instant, Instant)> = None;
 
     loop {
-        let changed = mpd.idle().await?;
+        // idle is interrupted now and then to check the connection still
+        // answers, a dead one would otherwise wait on idle forever
+        let wait = match queue_pending {
+            Some((first, last)) => cmp::min(
+                queue_debounce.saturating_sub(last.elapsed()),
+                QUEUE_DEBOUNCE_MAX.saturating_sub(first.elapsed()),
+            ),
+            None => WATCHDOG_INTERVAL,
+        };
+
+        let changed = mpd.idle_until(tokio::time::sleep(wait));
+        let changed = tokio::time::timeout(wait + WATCHDOG_GRACE, changed).await
+            .context("mpd idle connection stopped responding")??;
+
         log::debug!("mpd event: {:?}", changed);
 
+        let mut title_changed = false;
+
+        if changed.events().any(|event| matches!(event, MpdEvent::Player | MpdEvent::Playlist)) {
+            let new_song = mpd.currentsong().await?;
+
+            if let (Some(old), Some(new)) = (&song, &new_song)
+                && old.id == new.id
+                && old.title != new.title
+            {
+                title_changed = true;
+                events.stream_title.send_replace(Some(Arc::new(StreamTitleEvent {
+                    position: new.pos,
+                    title: new.title.clone(),
+                })));
+            }
+
+            song = new_song;
+        }
+
         for event in changed.events() {
             match event {
                 MpdEvent::Player => events.status.send_replace(()),
                 MpdEvent::Playlist => {
                     let new_ver = playlist_version(&mpd).await?;
+
+                    // mpd bumps the queue version when a stream's tags change,
+                    // a lone bump alongside a title change is nothing but that
+                    let tags_only = title_changed && new_ver == queue_ver.wrapping_add(1);
+
                     if queue_ver != new_ver {
                         queue_ver = new_ver;
-                        events.queue.send_replace(());
+
+                        if !tags_only {
+                            let now = Instant::now();
+                            let (first, _) = queue_pending.unwrap_or((now, now));
+                            queue_pending = Some((first, now));
+                        }
                     }
                 }
                 MpdEvent::Options => events.options.send_replace(()),
-                MpdEvent::Mixer => {}
+                // volume is reported with the options
+                MpdEvent::Mixer => events.options.send_replace(()),
+                MpdEvent::Output => events.outputs.send_replace(()),
+                MpdEvent::Update | MpdEvent::Database => events.library.send_replace(()),
             }
         }
+
+        // other events don't end the quiet period, only time does. a queue
+        // which never goes quiet is still signalled now and then
+        if let Some((first, last)) = queue_pending
+            && (last.elapsed() >= queue_debounce || first.elapsed() >= QUEUE_DEBOUNCE_MAX)
+        {
+            queue_pending = None;
+            events.queue.send_replace(());
+        }
     }
 }
 
-async fn playlist_version(mpd: &Mpd) -> Result<u32> {
+async fn playlist_version(mpd: &MpdIdleClient) -> Result<u32> {
     Ok(mpd.status().await?.playlist_version)
 }
@@ -0,0 +1,116 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+use url::Url;
+
+use crate::subsonic::types::TrackId;
+
+// stands in for the id while parsing templates, so that the url crate can
+// normalise them the same way as the urls they produce
+const PLACEHOLDER: &str = "sonicastexternalid";
+
+/// maps synthetic track ids onto urls outside subsonic, such as a youtube
+/// proxy or an http share, so they can be queued like any other track.
+/// configured as a json object of id prefix to url template, in which
+/// {id} is replaced by the rest of the id
+#[derive(Debug, Clone, Default)]
+pub struct ExternalSources {
+    /// longest prefix first, so the most specific rule wins
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    prefix: String,
+    /// template url either side of {id}
+    before: String,
+    after: String,
+}
+
+impl FromStr for ExternalSources {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let templates = serde_json::from_str::<HashMap<String, String>>(s)?;
+
+        let mut rules = templates.into_iter()
+            .map(|(prefix, template)| Rule::new(prefix, &template))
+            .collect::<Result<Vec<_>>>()?;
+
+        rules.sort_by_key(|rule| std::cmp::Reverse(rule.prefix.len()));
+        Ok(ExternalSources { rules })
+    }
+}
+
+impl Rule {
+    fn new(prefix: String, template: &str) -> Result<Self> {
+        anyhow::ensure!(!prefix.is_empty(), "external source prefix can't be empty");
+        anyhow::ensure!(template.matches("{id}").count() == 1,
+            "external source template for {prefix} needs exactly one {{id}}");
+
+        let url = Url::parse(&template.replace("{id}", PLACEHOLDER))
+            .with_context(|| format!("parsing external source template for {prefix}"))?;
+
+        let Some((before, after)) = url.as_str().split_once(PLACEHOLDER) else {
+            anyhow::bail!("external source template for {prefix} can't have {{id}} in the host");
+        };
+
+        Ok(Rule { before: before.to_owned(), after: after.to_owned(), prefix })
+    }
+}
+
+impl ExternalSources {
+    /// None if no rule matches id
+    pub fn stream_url(&self, id: &TrackId) -> Option<Result<Url>> {
+        self.rules.iter().find_map(|rule| {
+            let rest = id.0.strip_prefix(&rule.prefix)?;
+            let url = format!("{}{}{}", rule.before, encode(rest), rule.after);
+            Some(Url::parse(&url).with_context(|| format!("building external url for {}", id.0)))
+        })
+    }
+
+    pub fn track_id_from_stream_url(&self, url: &Url) -> Option<TrackId> {
+        self.rules.iter().find_map(|rule| {
+            let rest = url.as_str()
+                .strip_prefix(&rule.before)?
+                .strip_suffix(&rule.after)?;
+
+            Some(TrackId(format!("{}{}", rule.prefix, decode(rest)?)))
+        })
+    }
+}
+
+// percent encodes everything but unreserved characters and slashes, so
+// ids can hold paths
+fn encode(id: &str) -> String {
+    let mut encoded = String::new();
+
+    for byte in id.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                encoded.push(byte as char);
+            }
+            _ => { let _ = write!(encoded, "%{byte:02X}"); }
+        }
+    }
+
+    encoded
+}
+
+fn decode(encoded: &str) -> Option<String> {
+    let mut bytes = Vec::new();
+    let mut iter = encoded.bytes();
+
+    while let Some(byte) = iter.next() {
+        if byte == b'%' {
+            let hex = [iter.next()?, iter.next()?];
+            bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            bytes.push(byte);
+        }
+    }
+
+    String::from_utf8(bytes).ok()
+}
@@ -0,0 +1,92 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::future::Future;
+use std::sync::Mutex;
+
+use anyhow::Result;
+use tokio::sync::watch;
+
+use super::zones::Zone;
+
+/// lets concurrent identical reads share one computation, so that every
+/// session asking for the queue after it changes doesn't resolve the same
+/// tracks all over again. only successes are shared, if the computation
+/// fails or is cancelled whoever was waiting on it runs their own, so that
+/// errors keep their cause for the error code
+pub struct SingleFlight<T> {
+    flights: Mutex<HashMap<String, watch::Receiver<Option<T>>>>,
+}
+
+impl<T> Default for SingleFlight<T> {
+    fn default() -> Self {
+        SingleFlight { flights: Mutex::default() }
+    }
+}
+
+/// identifies a read for sharing. what resolves depends on who's asking,
+/// so the user is part of it, and the zone since that's what's being read
+pub fn key(user: Option<&str>, zone: &Zone, command: &str, param: impl Debug) -> String {
+    format!("{}\0{}\0{}\0{command}\0{param:?}", user.unwrap_or_default(), zone.room, zone.name)
+}
+
+enum Role<T> {
+    Lead(watch::Sender<Option<T>>),
+    Join(watch::Receiver<Option<T>>),
+}
+
+impl<T: Clone> SingleFlight<T> {
+    pub async fn run(&self, key: String, compute: impl Future<Output = Result<T>>) -> Result<T> {
+        let role = {
+            let mut flights = self.flights.lock().unwrap();
+
+            match flights.get(&key) {
+                Some(rx) => Role::Join(rx.clone()),
+                None => {
+                    let (tx, rx) = watch::channel(None);
+                    flights.insert(key.clone(), rx);
+                    Role::Lead(tx)
+                }
+            }
+        };
+
+        let tx = match role {
+            Role::Lead(tx) => tx,
+            Role::Join(mut rx) => {
+                let shared = rx.wait_for(Option::is_some).await
+                    .ok()
+                    .and_then(|value| value.clone());
+
+                return match shared {
+                    Some(value) => Ok(value),
+                    None => compute.await,
+                };
+            }
+        };
+
+        let landing = Landing { flights: &self.flights, key };
+        let result = compute.await;
+
+        // gone before sending, so nobody joins a finished flight and gets
+        // a result from before they asked
+        drop(landing);
+
+        if let Ok(value) = &result {
+            let _ = tx.send(Some(value.clone()));
+        }
+
+        result
+    }
+}
+
+// removes the flight even if the computation is cancelled, such as by its
+// session disconnecting
+struct Landing<'a, T> {
+    flights: &'a Mutex<HashMap<String, watch::Receiver<Option<T>>>>,
+    key: String,
+}
+
+impl<T> Drop for Landing<'_, T> {
+    fn drop(&mut self) {
+        self.flights.lock().unwrap().remove(&self.key);
+    }
+}
@@ -0,0 +1,32 @@
+use reqwest::StatusCode;
+
+use crate::subsonic::AuthParams;
+
+use super::HttpError;
+
+/// who may connect read only, eg. a wall mounted now playing display.
+/// guests borrow the credentials of whoever last connected to their zone,
+/// as scrobbling does, and can't run commands which change anything
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    /// connections without credentials are let in as guests
+    pub anonymous: bool,
+    /// connections passing one of these as their guest param are let in as
+    /// guests, whatever else they send
+    pub tokens: Vec<String>,
+}
+
+impl Config {
+    /// whether the connection is a guest, or an error if it asked to be one
+    /// with a token we don't know
+    pub fn admits(&self, token: Option<&str>, auth: &AuthParams) -> Result<bool, HttpError> {
+        match token {
+            Some(token) if self.tokens.iter().any(|known| known == token) => Ok(true),
+            Some(_) => {
+                log::warn!("rejecting connection with unknown guest token");
+                Err(HttpError::new(StatusCode::UNAUTHORIZED, "unauthorized", "unknown guest token"))
+            }
+            None => Ok(self.anonymous && auth.username().is_none()),
+        }
+    }
+}
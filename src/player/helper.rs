@@ -1,38 +1,143 @@
 use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use anyhow::{Context, Ok, Result};
-use futures::stream::{FuturesOrdered, TryStreamExt};
-use tokio::sync::OnceCell;
+use anyhow::{Ok, Result};
+use futures::stream::{self, StreamExt, TryStreamExt};
+use tokio::sync::Mutex;
 use url::Url;
 
 use crate::mpd::types::PlaylistItem;
 use crate::mpd::Mpd;
 use crate::podcasts::Podcasts;
 use crate::subsonic::Subsonic;
-use crate::subsonic::types::{RadioId, RadioStation};
+use crate::subsonic::types::{RadioId, RadioStation, TrackId};
 
+use super::external::ExternalSources;
+use super::rate::{self, RateProxy};
+use super::servers::Servers;
 use super::types::{AirsonicTrack, AirsonicTrackId};
 
-async fn gather<T>(iter: impl Iterator<Item = impl Future<Output = Result<T>>>) -> Result<Vec<T>> {
-    iter.collect::<FuturesOrdered<_>>()
+const RETRY_ATTEMPTS: u32 = 3;
+const RETRY_BACKOFF: Duration = Duration::from_millis(200);
+
+// stations are fetched again once this old, to pick up changes made
+// through other subsonic clients
+const STATION_CACHE_TTL: Duration = Duration::from_secs(5 * 60);
+
+// a station missing from the cache has it fetched again sooner, but no
+// more often than this
+const STATION_REFETCH_INTERVAL: Duration = Duration::from_secs(10);
+
+// runs at most `concurrency` futures at once, preserving order
+async fn gather<T>(concurrency: usize, iter: impl Iterator<Item = impl Future<Output = Result<T>>>) -> Result<Vec<T>> {
+    // collect up front rather than holding the iterator (and its closure)
+    // across awaits, which trips up rustc's Send inference in callers
+    let futs = iter.collect::<Vec<_>>();
+
+    stream::iter(futs)
+        .buffered(concurrency.max(1))
         .try_collect()
         .await
 }
 
+// retries with exponential backoff, except for errors which are not
+// going to succeed on retry
+async fn retry<T, Fut: Future<Output = Result<T>>>(f: impl Fn() -> Fut) -> Result<T> {
+    let mut backoff = RETRY_BACKOFF;
+    let mut attempt = 1;
+
+    loop {
+        match f().await {
+            Result::Ok(value) => return Ok(value),
+            Err(err) if attempt >= RETRY_ATTEMPTS || !is_transient(&err) => return Err(err),
+            Err(err) => {
+                log::warn!("retrying after error (attempt {attempt}): {err:#}");
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+                attempt += 1;
+            }
+        }
+    }
+}
+
+// only failing to reach the server, or the server failing itself, is worth
+// trying again
+fn is_transient(err: &anyhow::Error) -> bool {
+    err.chain()
+        .filter_map(|err| err.downcast_ref::<reqwest::Error>())
+        .any(|err| {
+            err.is_timeout()
+                || err.is_connect()
+                || err.status().is_some_and(|status| status.is_server_error())
+        })
+}
+
 type RadioStationMap = HashMap<RadioId, RadioStation>;
 
+/// radio stations rarely change, so they're kept until invalidated by a
+/// station being created, updated, or deleted, or until they're old enough
+/// that someone may have changed them elsewhere
+#[derive(Default)]
+pub struct StationCache {
+    /// and when they were fetched
+    stations: Mutex<Option<(Arc<RadioStationMap>, Instant)>>,
+}
+
+impl StationCache {
+    /// fetches stations again if the cached ones are older than max_age
+    async fn get(&self, subsonic: &Subsonic, max_age: Duration) -> Result<Arc<RadioStationMap>> {
+        let mut stations = self.stations.lock().await;
+
+        if let Some((stations, fetched)) = stations.as_ref()
+            && fetched.elapsed() < max_age
+        {
+            return Ok(stations.clone());
+        }
+
+        let map = Arc::new(subsonic.get_radio_stations().await?
+            .into_iter()
+            .map(|station| (station.id.clone(), station))
+            .collect::<RadioStationMap>());
+
+        *stations = Some((map.clone(), Instant::now()));
+        Ok(map)
+    }
+
+    pub async fn invalidate(&self) {
+        *self.stations.lock().await = None;
+    }
+}
+
 pub struct Resolver<'a> {
     subsonic: &'a Subsonic,
     podcasts: Option<&'a Podcasts>,
-    stations: OnceCell<RadioStationMap>,
+    stations: &'a StationCache,
+    /// None when resolving on one of the extra servers itself
+    servers: Option<&'a Servers>,
+    external: Option<&'a ExternalSources>,
+    rate_proxy: Option<&'a RateProxy>,
+    concurrency: usize,
 }
 
 impl<'a> Resolver<'a> {
-    pub fn new(subsonic: &'a Subsonic, podcasts: Option<&'a Podcasts>) -> Self {
+    pub fn new(
+        subsonic: &'a Subsonic,
+        podcasts: Option<&'a Podcasts>,
+        stations: &'a StationCache,
+        servers: &'a Servers,
+        external: &'a ExternalSources,
+        rate_proxy: Option<&'a RateProxy>,
+        concurrency: usize,
+    ) -> Self {
         Resolver {
             subsonic,
             podcasts,
-            stations: Default::default(),
+            stations,
+            servers: Some(servers),
+            external: Some(external),
+            rate_proxy,
+            concurrency,
         }
     }
 
@@ -40,16 +145,20 @@ impl<'a> Resolver<'a> {
         let futs = ids.iter()
             .map(|id| self.stream_url_for_id(id));
 
-        gather(futs).await
+        gather(self.concurrency, futs).await
     }
 
     pub async fn stream_url_for_id(&self, id: &AirsonicTrackId) -> Result<Url> {
         match id {
             AirsonicTrackId::Track(id) => {
-                if let Some(podcasts) = self.podcasts {
-                    if podcasts.matches(id) {
-                        return podcasts.stream_url(id);
-                    }
+                if let Some(podcasts) = self.podcasts
+                    && podcasts.matches(id)
+                {
+                    return podcasts.stream_url(id);
+                }
+
+                if let Some(url) = self.external.and_then(|external| external.stream_url(id)) {
+                    return url;
                 }
 
                 self.subsonic.stream_url(id)
@@ -58,30 +167,144 @@ impl<'a> Resolver<'a> {
                 let station = self.resolve_radio_id(id).await?;
                 Ok(station.stream_url.clone())
             }
+            AirsonicTrackId::Server(server, id) => {
+                Box::pin(self.on_server(server)?.stream_url_for_id(id)).await
+            }
+            AirsonicTrackId::Library(path) => {
+                anyhow::bail!("library files have no stream url, add them with AddLibraryPath: {path}")
+            }
         }
     }
 
+    /// resolves ids and urls on one of the extra servers, without the
+    /// server prefix
+    fn on_server(&self, name: &str) -> Result<Resolver<'a>> {
+        let Some(servers) = self.servers else {
+            anyhow::bail!("no such subsonic server: {name}");
+        };
+
+        let server = servers.get(name)?;
+
+        Ok(Resolver {
+            subsonic: &server.subsonic,
+            podcasts: None,
+            stations: &server.stations,
+            servers: None,
+            external: None,
+            rate_proxy: self.rate_proxy,
+            concurrency: self.concurrency,
+        })
+    }
+
+    fn extra_servers(&self) -> impl Iterator<Item = (&'a str, Resolver<'a>)> {
+        self.servers.into_iter()
+            .flat_map(Servers::iter)
+            .filter_map(|(name, _)| Some((name, self.on_server(name).ok()?)))
+    }
+
+    /// radio stations of every server, as airsonic tracks
+    pub async fn radio_stations_all(&self) -> Result<Vec<AirsonicTrack>> {
+        let mut tracks = self.radio_stations(STATION_CACHE_TTL).await?
+            .values()
+            .cloned()
+            .map(AirsonicTrack::from)
+            .collect::<Vec<_>>();
+
+        for (name, resolver) in self.extra_servers() {
+            // one server being down shouldn't hide the rest
+            let stations = match resolver.radio_stations(STATION_CACHE_TTL).await {
+                Result::Ok(stations) => stations,
+                Err(err) => {
+                    log::warn!("fetching radio stations from subsonic server {name}: {err:#}");
+                    continue;
+                }
+            };
+
+            tracks.extend(stations.values().cloned().map(|station| {
+                let mut track = AirsonicTrack::from(station);
+                track.id = track.id.on_server(name);
+                track
+            }));
+        }
+
+        tracks.sort_by(|a, b| a.details.title.cmp(&b.details.title));
+        Ok(tracks)
+    }
+
+    /// items which fail to resolve are returned as unavailable placeholders
     pub async fn load_tracks_for(&self, items: &[PlaylistItem]) -> Result<Vec<AirsonicTrack>> {
         let futs = items.iter()
-            .map(|item| self.load_track_for_url(item));
+            .map(|item| self.load_track_or_unavailable(item));
+
+        gather(self.concurrency, futs).await
+    }
+
+    async fn load_track_or_unavailable(&self, item: &PlaylistItem) -> Result<AirsonicTrack> {
+        match retry(|| self.load_track_for_url(item)).await {
+            Result::Ok(track) => Ok(track),
+            Err(err) => {
+                log::warn!("resolving queue item {}: {err:#}", item.file);
+                Ok(self.unavailable_track(item))
+            }
+        }
+    }
+
+    fn unavailable_track(&self, item: &PlaylistItem) -> AirsonicTrack {
+        let id = rate::source(self.rate_proxy, &item.file)
+            .map(|(url, _)| url)
+            .and_then(|url| self.track_id_from_stream_url(&url))
+            .unwrap_or_else(|| TrackId(item.file.clone()).into());
+
+        let title = item.title.clone()
+            .or_else(|| item.name.clone())
+            .unwrap_or_else(|| item.file.clone());
+
+        AirsonicTrack::unavailable(id, Some(title))
+    }
+
+    fn track_id_from_stream_url(&self, url: &Url) -> Option<AirsonicTrackId> {
+        if let Some(id) = self.external.and_then(|external| external.track_id_from_stream_url(url)) {
+            return Some(id.into());
+        }
+
+        if let Some(id) = self.subsonic.track_id_from_stream_url(url) {
+            return Some(id.into());
+        }
 
-        gather(futs).await
+        self.extra_servers().find_map(|(name, resolver)| {
+            let id = resolver.subsonic.track_id_from_stream_url(url)?;
+            Some(AirsonicTrackId::from(id).on_server(name))
+        })
     }
 
     pub async fn load_track_for_url(&self, item: &PlaylistItem) -> Result<AirsonicTrack> {
-        let url = Url::parse(&item.file).with_context(|| {
-            format!("parsing playlist item url: {}", item.file)
-        })?;
+        // anything that isn't a url is a file from mpd's database, which
+        // mpd already has the tags of
+        let Some((url, _)) = rate::source(self.rate_proxy, &item.file) else {
+            return Ok(AirsonicTrack::library(item));
+        };
 
-        if let Some(podcasts) = self.podcasts {
-            if let Some(id) = podcasts.track_id_from_stream_url(&url) {
-                let episode = podcasts.get_podcast_episode(&id).await?;
+        if let Some(podcasts) = self.podcasts
+            && let Some(id) = podcasts.track_id_from_stream_url(&url)
+        {
+            let episode = podcasts.get_podcast_episode(&id).await?;
 
-                let mut track: AirsonicTrack = episode.into();
-                track.details.stream_url = Some(podcasts.stream_url(&id)?);
+            let mut track: AirsonicTrack = episode.into();
+            track.details.stream_url = Some(podcasts.stream_url(&id)?);
 
-                return Ok(track);
-            }
+            return Ok(track);
+        }
+
+        // checked before subsonic, in case a template points at the same
+        // host, eg. an http share alongside the server
+        if let Some(external) = self.external
+            && let Some(id) = external.track_id_from_stream_url(&url)
+        {
+            let title = item.title.clone()
+                .or_else(|| item.name.clone())
+                .unwrap_or_else(|| id.0.clone());
+
+            return Ok(AirsonicTrack::external(id.into(), Some(title), url));
         }
 
         if let Some(id) = self.subsonic.track_id_from_stream_url(&url) {
@@ -99,30 +322,51 @@ impl<'a> Resolver<'a> {
             return Ok(track);
         }
 
+        for (name, resolver) in self.extra_servers() {
+            if resolver.subsonic.track_id_from_stream_url(&url).is_none()
+                && resolver.resolve_radio_url(&url).await?.is_none()
+            {
+                continue;
+            }
+
+            let mut track = Box::pin(resolver.load_track_for_url(item)).await?;
+            track.id = track.id.on_server(name);
+            return Ok(track);
+        }
+
         anyhow::bail!("could not resolve url: {url}")
     }
 
-    async fn radio_stations(&self) -> Result<&RadioStationMap> {
-        self.stations.get_or_try_init(|| async {
-            let stations = self.subsonic.get_radio_stations().await?;
-            Ok(stations.into_iter()
-                .map(|station| (station.id.clone(), station))
-                .collect())
-        }).await
+    async fn radio_stations(&self, max_age: Duration) -> Result<Arc<RadioStationMap>> {
+        self.stations.get(self.subsonic, max_age).await
+    }
+
+    // a station not in the cache may have been added since it was fetched
+    async fn find_station(&self, matches: impl Fn(&RadioStation) -> bool) -> Result<Option<RadioStation>> {
+        for max_age in [STATION_CACHE_TTL, STATION_REFETCH_INTERVAL] {
+            let stations = self.radio_stations(max_age).await?;
+
+            if let Some(station) = stations.values().find(|station| matches(station)) {
+                return Ok(Some(station.clone()));
+            }
+        }
+
+        Ok(None)
     }
 
     async fn resolve_radio_id(&self, id: &RadioId) -> Result<RadioStation> {
-        let stations = self.radio_stations().await?;
-        stations.get(id).cloned().ok_or_else(||
-            anyhow::format_err!("radio station not found: {id:?}"))
+        self.find_station(|station| &station.id == id).await?
+            .ok_or_else(|| anyhow::format_err!("radio station not found: {id:?}"))
     }
 
     async fn resolve_radio_url(&self, url: &Url) -> Result<Option<RadioStation>> {
-        let stations = self.radio_stations().await?;
-        Ok(stations.values().find(|station| &station.stream_url == url).cloned())
+        self.find_station(|station| &station.stream_url == url).await
     }
 }
 
+/// stored playlists with this prefix are for sonicast's internal use
+pub const INTERNAL_PLAYLIST_PREFIX: &str = "_sonicast_";
+
 pub async fn atomic_enqueue_tracks(mpd: &mut Mpd, urls: &[Url], position: Option<isize>) -> Result<()> {
     const PLAYLIST_NAME: &str = "_sonicast_atomic_queue";
     mpd.playlistclear(PLAYLIST_NAME).await?;
@@ -0,0 +1,275 @@
+use std::collections::VecDeque;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex as AsyncMutex;
+
+use crate::mpd::types::{Id, PlaybackState};
+use crate::subsonic::SubsonicBase;
+
+use super::events::redact_urls;
+use super::types::AirsonicTrackId;
+use super::zones::Zone;
+
+// entries kept across every user, the oldest go first
+const MAX_ENTRIES: usize = 5000;
+
+// the file is compacted back down to MAX_ENTRIES once it has grown this
+// many entries past it, rather than rewritten on every append
+const COMPACT_SLACK: usize = 500;
+
+// how often the position of the current track is checked
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+// tracks count as played once half way through, as for scrobbling.
+// streams have no duration, so count once listened to for this long
+const STREAM_PLAYED: f64 = 30.0;
+
+/// a track someone listened to
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    /// subsonic username of whoever was last connected to the zone
+    pub user: String,
+    /// milliseconds since the unix epoch
+    pub played_at: u64,
+    pub room: String,
+    pub zone: String,
+    /// None for streams subsonic doesn't know about
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub track: Option<AirsonicTrackId>,
+    /// stream url without its query, or the file for anything else
+    pub url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub artist: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub album: Option<String>,
+    /// radio station the track played on
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub station: Option<String>,
+    /// seconds, roughly. the track's duration, or how long a stream had
+    /// been listened to when it was recorded
+    #[serde(default)]
+    pub listened: f64,
+}
+
+/// recently played tracks, as an append only file of json lines
+pub struct History {
+    path: PathBuf,
+    entries: AsyncMutex<HistoryFile>,
+}
+
+struct HistoryFile {
+    entries: VecDeque<HistoryEntry>,
+    /// lines in the file, including those since dropped from entries
+    lines: usize,
+}
+
+impl History {
+    pub async fn open(path: PathBuf) -> Result<Self> {
+        let contents = match tokio::fs::read_to_string(&path).await {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(err) => {
+                return Err(err).with_context(|| format!("reading {}", path.display()));
+            }
+        };
+
+        let mut entries = VecDeque::new();
+        let mut lines = 0;
+        let mut redacted = false;
+
+        for line in contents.lines() {
+            lines += 1;
+
+            // a crash mid-append can leave a partial last line
+            match serde_json::from_str::<HistoryEntry>(line) {
+                // entries recorded before urls were stored without their
+                // query still carry credentials
+                Ok(mut entry) => {
+                    let url = redact_urls(&entry.url);
+                    redacted |= url != entry.url;
+                    entry.url = url;
+                    entries.push_back(entry);
+                }
+                Err(err) => log::warn!("skipping bad history entry in {}: {err}", path.display()),
+            }
+        }
+
+        while entries.len() > MAX_ENTRIES {
+            entries.pop_front();
+        }
+
+        let file = HistoryFile { entries, lines };
+        let history = History { path, entries: AsyncMutex::new(file) };
+
+        // don't leave the credentials in the file until it's next compacted
+        if redacted {
+            history.rewrite(&mut *history.entries.lock().await).await?;
+        }
+
+        Ok(history)
+    }
+
+    /// user's entries played since, in milliseconds since the unix epoch,
+    /// oldest first
+    pub async fn since(&self, user: &str, since: u64) -> Vec<HistoryEntry> {
+        self.entries.lock().await.entries.iter()
+            .filter(|entry| entry.user == user && entry.played_at >= since)
+            .cloned()
+            .collect()
+    }
+
+    /// user's entries, most recent first
+    pub async fn list(&self, user: &str, offset: usize, limit: usize) -> Vec<HistoryEntry> {
+        self.entries.lock().await.entries.iter()
+            .rev()
+            .filter(|entry| entry.user == user)
+            .skip(offset)
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+
+    pub async fn clear(&self, user: &str) -> Result<()> {
+        let mut file = self.entries.lock().await;
+        file.entries.retain(|entry| entry.user != user);
+        self.rewrite(&mut file).await
+    }
+
+    async fn record(&self, entry: HistoryEntry) -> Result<()> {
+        let mut file = self.entries.lock().await;
+
+        let mut line = serde_json::to_string(&entry)?;
+        line.push('\n');
+
+        file.entries.push_back(entry);
+        file.lines += 1;
+
+        while file.entries.len() > MAX_ENTRIES {
+            file.entries.pop_front();
+        }
+
+        if file.lines > MAX_ENTRIES + COMPACT_SLACK {
+            return self.rewrite(&mut file).await;
+        }
+
+        let mut out = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path).await
+            .with_context(|| format!("opening {}", self.path.display()))?;
+
+        out.write_all(line.as_bytes()).await
+            .with_context(|| format!("appending to {}", self.path.display()))?;
+
+        Ok(())
+    }
+
+    // write then rename so a crash mid-write can't lose the old history
+    async fn rewrite(&self, file: &mut HistoryFile) -> Result<()> {
+        let mut contents = String::new();
+
+        for entry in &file.entries {
+            contents.push_str(&serde_json::to_string(entry)?);
+            contents.push('\n');
+        }
+
+        let tmp = self.path.with_extension("tmp");
+        tokio::fs::write(&tmp, contents).await
+            .with_context(|| format!("writing {}", tmp.display()))?;
+        tokio::fs::rename(&tmp, &self.path).await
+            .with_context(|| format!("renaming {} to {}", tmp.display(), self.path.display()))?;
+
+        file.lines = file.entries.len();
+        Ok(())
+    }
+}
+
+struct Playing {
+    song_id: Id,
+    recorded: bool,
+}
+
+// records each track once it has played for long enough, including radio
+// and anything else subsonic can't attribute plays to
+pub async fn task(history: Arc<History>, subsonic: SubsonicBase, zone: Arc<Zone>) {
+    let mut watch = zone.events.subscribe_status();
+    let mut playing = None;
+
+    loop {
+        if let Err(err) = update(&history, &subsonic, &zone, &mut playing).await {
+            log::warn!("play history: {err:?}");
+        }
+
+        tokio::select! {
+            changed = watch.changed() => {
+                if changed.is_err() { break }
+            }
+            () = tokio::time::sleep(CHECK_INTERVAL) => {}
+        }
+    }
+}
+
+async fn update(history: &History, subsonic: &SubsonicBase, zone: &Zone, playing: &mut Option<Playing>) -> Result<()> {
+    let mpd = zone.mpd.read().await;
+    let status = mpd.status().await?;
+
+    let Some(song_id) = status.song_id else {
+        *playing = None;
+        return Ok(());
+    };
+
+    let playing = match playing {
+        Some(playing) if playing.song_id == song_id => playing,
+        playing => playing.insert(Playing { song_id, recorded: false }),
+    };
+
+    if playing.recorded || status.state != PlaybackState::Play {
+        return Ok(());
+    }
+
+    let elapsed = status.elapsed.map(|s| s.0).unwrap_or_default();
+    let listened = match status.duration {
+        Some(duration) if duration.0 > 0.0 => (elapsed > duration.0 / 2.0).then_some(duration.0),
+        _ => (elapsed > STREAM_PLAYED).then_some(elapsed),
+    };
+
+    let Some(listened) = listened else { return Ok(()) };
+
+    // history is kept per user, so plays before anyone connects are lost
+    let Some(user) = zone.auth().and_then(|auth| auth.username().map(str::to_owned)) else {
+        return Ok(());
+    };
+
+    let Some(song) = mpd.currentsong().await? else { return Ok(()) };
+    let item = mpd.playlistid(&song.id).await?;
+    drop(mpd);
+
+    playing.recorded = true;
+
+    let url = zone.stream_url(&item.file);
+
+    history.record(HistoryEntry {
+        user,
+        played_at: SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64,
+        room: zone.room.clone(),
+        zone: zone.name.clone(),
+        track: url.as_ref()
+            .and_then(|url| subsonic.track_id_from_stream_url(url))
+            .map(AirsonicTrackId::from),
+        // the query carries the subsonic credentials, or the stream key
+        url: url.map(|mut url| { url.set_query(None); url.to_string() }).unwrap_or(item.file),
+        title: song.title,
+        artist: song.artist,
+        album: song.album,
+        station: song.name,
+        listened,
+    }).await
+}
@@ -0,0 +1,157 @@
+use std::fmt;
+use std::os::fd::{FromRawFd, OwnedFd};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use axum::Router;
+use axum_server::tls_rustls::RustlsConfig;
+
+// first inherited file descriptor, per sd_listen_fds(3)
+const SD_LISTEN_FDS_START: i32 = 3;
+
+/// where the http server listens
+pub enum Listen {
+    Tcp(String),
+    Unix(String),
+}
+
+impl Listen {
+    /// parses "unix:/path/to/socket", or otherwise a tcp address
+    pub fn parse(listen: &str) -> Listen {
+        match listen.strip_prefix("unix:") {
+            Some(path) => Listen::Unix(path.to_string()),
+            None => Listen::Tcp(listen.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Listen {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Listen::Tcp(addr) => write!(f, "{addr}"),
+            Listen::Unix(path) => write!(f, "unix:{path}"),
+        }
+    }
+}
+
+/// certificate chain and private key, both pem encoded
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+enum Listener {
+    Tcp(tokio::net::TcpListener),
+    Unix(tokio::net::UnixListener),
+}
+
+pub async fn serve(listen: Option<&Listen>, tls: Option<&TlsConfig>, app: Router) -> Result<()> {
+    let listener = match inherited()? {
+        Some(listener) => listener,
+        None => bind(listen.context("no listen address configured")?).await?,
+    };
+
+    match (listener, tls) {
+        (Listener::Tcp(listener), None) => axum::serve(listener, app).await?,
+        (Listener::Unix(listener), None) => axum::serve(listener, app).await?,
+        (Listener::Tcp(listener), Some(tls)) => {
+            let config = tls_config(tls).await?;
+            axum_server::from_tcp_rustls(listener.into_std()?, config)
+                .serve(app.into_make_service())
+                .await?;
+        }
+        (Listener::Unix(_), Some(_)) => {
+            anyhow::bail!("tls is not supported on unix sockets");
+        }
+    }
+
+    Ok(())
+}
+
+async fn tls_config(tls: &TlsConfig) -> Result<RustlsConfig> {
+    // only the first call can install a provider, and there is only one
+    let _ = rustls::crypto::ring::default_provider().install_default();
+
+    RustlsConfig::from_pem_file(&tls.cert, &tls.key).await
+        .with_context(|| format!("loading tls certificate {} and key {}",
+            tls.cert.display(), tls.key.display()))
+}
+
+async fn bind(listen: &Listen) -> Result<Listener> {
+    match listen {
+        Listen::Tcp(addr) => {
+            let listener = tokio::net::TcpListener::bind(addr).await
+                .with_context(|| format!("binding {addr}"))?;
+            Ok(Listener::Tcp(listener))
+        }
+        Listen::Unix(path) => {
+            remove_stale_socket(Path::new(path))?;
+
+            let listener = tokio::net::UnixListener::bind(path)
+                .with_context(|| format!("binding {path}"))?;
+            Ok(Listener::Unix(listener))
+        }
+    }
+}
+
+// a socket left behind by a previous run would fail the bind. only a socket
+// nothing is listening on is removed, anything else at the path is an error
+fn remove_stale_socket(path: &Path) -> Result<()> {
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err).with_context(|| format!("checking {}", path.display())),
+    };
+
+    if !metadata.file_type().is_socket() {
+        anyhow::bail!("{} exists and is not a socket", path.display());
+    }
+
+    if UnixStream::connect(path).is_ok() {
+        anyhow::bail!("{} is in use by another process", path.display());
+    }
+
+    std::fs::remove_file(path)
+        .with_context(|| format!("removing stale socket {}", path.display()))
+}
+
+// takes over a listener passed by systemd socket activation, if any
+fn inherited() -> Result<Option<Listener>> {
+    let Ok(pid) = std::env::var("LISTEN_PID") else { return Ok(None) };
+
+    // the variables may have been inherited from a parent process meant
+    // for someone else
+    if pid.parse() != Ok(std::process::id()) {
+        return Ok(None);
+    }
+
+    let fds = std::env::var("LISTEN_FDS").unwrap_or_default();
+    let fds = fds.parse::<usize>().context("parsing LISTEN_FDS")?;
+    anyhow::ensure!(fds == 1, "expected exactly one socket from LISTEN_FDS, got {fds}");
+
+    // safety: systemd passes ownership of the descriptor to us, and nothing
+    // else in the process takes it
+    let fd = unsafe { OwnedFd::from_raw_fd(SD_LISTEN_FDS_START) };
+
+    // getsockname fails on a tcp listener if the socket is actually unix
+    let tcp = TcpListener::from(fd);
+
+    let listener = match tcp.local_addr() {
+        Ok(addr) => {
+            log::info!("listening on inherited socket {addr}");
+            tcp.set_nonblocking(true)?;
+            Listener::Tcp(tokio::net::TcpListener::from_std(tcp)?)
+        }
+        Err(_) => {
+            let unix = UnixListener::from(OwnedFd::from(tcp));
+            log::info!("listening on inherited unix socket");
+            unix.set_nonblocking(true)?;
+            Listener::Unix(tokio::net::UnixListener::from_std(unix)?)
+        }
+    };
+
+    Ok(Some(listener))
+}
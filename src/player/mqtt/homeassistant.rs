@@ -0,0 +1,127 @@
+use anyhow::{Context, Result};
+use rumqttc::{AsyncClient, QoS};
+use serde::Serialize;
+use serde_json::json;
+
+use crate::player::Command;
+use crate::player::zones::{Zone, DEFAULT_ZONE};
+
+use super::State;
+
+pub const DEFAULT_DISCOVERY_PREFIX: &str = "homeassistant";
+
+// home assistant has no mqtt media player of its own, this is the schema of
+// the mqtt_media_player integration from hacs. state topics are the ones we
+// publish anyway, commands go to <zone topic>/homeassistant/<action>
+#[derive(Serialize)]
+struct Discovery {
+    name: String,
+    unique_id: String,
+    device: Device,
+    availability_topic: String,
+    state_state_topic: String,
+    state_title_topic: String,
+    state_artist_topic: String,
+    state_album_topic: String,
+    state_duration_topic: String,
+    state_position_topic: String,
+    state_volume_topic: String,
+    state_albumart_topic: String,
+    command_volume_topic: String,
+    command_play_topic: String,
+    command_pause_topic: String,
+    command_playpause_topic: String,
+    command_next_topic: String,
+    command_previous_topic: String,
+    command_seek_topic: String,
+}
+
+#[derive(Serialize)]
+struct Device {
+    identifiers: [String; 1],
+    name: String,
+    manufacturer: &'static str,
+    sw_version: &'static str,
+}
+
+/// publishes the retained discovery config for a zone, so it shows up as a
+/// media player entity
+pub async fn announce(client: &AsyncClient, discovery_prefix: &str, topic_prefix: &str, zone: &Zone) -> Result<()> {
+    let base = super::zone_topic(topic_prefix, zone);
+    let id = object_id(&format!("sonicast_{}_{}", zone.room, zone.name));
+
+    let name = if zone.name == DEFAULT_ZONE {
+        zone.room.clone()
+    } else {
+        format!("{} {}", zone.room, zone.name)
+    };
+
+    let discovery = Discovery {
+        name: name.clone(),
+        unique_id: id.clone(),
+        device: Device {
+            identifiers: [id.clone()],
+            name,
+            manufacturer: "sonicast",
+            sw_version: env!("CARGO_PKG_VERSION"),
+        },
+        availability_topic: super::status_topic(topic_prefix),
+        state_state_topic: format!("{base}/state"),
+        state_title_topic: format!("{base}/title"),
+        state_artist_topic: format!("{base}/artist"),
+        state_album_topic: format!("{base}/album"),
+        state_duration_topic: format!("{base}/duration"),
+        state_position_topic: format!("{base}/position"),
+        state_volume_topic: format!("{base}/volume"),
+        state_albumart_topic: format!("{base}/artwork"),
+        command_volume_topic: format!("{base}/homeassistant/volume"),
+        command_play_topic: format!("{base}/homeassistant/play"),
+        command_pause_topic: format!("{base}/homeassistant/pause"),
+        command_playpause_topic: format!("{base}/homeassistant/playpause"),
+        command_next_topic: format!("{base}/homeassistant/next"),
+        command_previous_topic: format!("{base}/homeassistant/previous"),
+        command_seek_topic: format!("{base}/homeassistant/seek"),
+    };
+
+    let topic = format!("{discovery_prefix}/media_player/{id}/config");
+    client.publish(topic, QoS::AtLeastOnce, true, serde_json::to_vec(&discovery)?).await?;
+    Ok(())
+}
+
+/// translates a home assistant command into the websocket command for it
+pub fn command(zone: &Zone, action: &str, payload: &[u8]) -> Result<Command> {
+    let number = || -> Result<f64> {
+        std::str::from_utf8(payload)?.trim().parse()
+            .with_context(|| format!("invalid {action} payload"))
+    };
+
+    // mpd's pause toggles, so only send it when it'll do what's asked
+    let state = State::of(zone);
+
+    let mut command = match action {
+        "play" => json!({ "name": "play" }),
+        "pause" => match state {
+            Some(State::Playing) => json!({ "name": "pause" }),
+            _ => anyhow::bail!("{}/{} is not playing", zone.room, zone.name),
+        },
+        "playpause" => match state {
+            Some(State::Playing | State::Paused) => json!({ "name": "pause" }),
+            _ => json!({ "name": "play" }),
+        },
+        "next" => json!({ "name": "skip-next" }),
+        "previous" => json!({ "name": "skip-previous" }),
+        "volume" => json!({ "name": "set-volume", "param": { "volume": number()? } }),
+        "seek" => json!({ "name": "seek", "param": { "pos": number()? } }),
+        _ => anyhow::bail!("unknown home assistant command: {action}"),
+    };
+
+    command["seq"] = json!(0);
+    Ok(serde_json::from_value(command)?)
+}
+
+// home assistant only allows these in discovery topics and unique ids
+fn object_id(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
+        .collect()
+}
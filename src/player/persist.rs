@@ -0,0 +1,243 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex as AsyncMutex;
+
+use crate::mpd::Command;
+use crate::mpd::types::{PlaybackState, SingleMode};
+use crate::subsonic::StreamQuality;
+
+use super::zones::Zone;
+
+// state is saved shortly after changes settle, and periodically so that
+// the position within the current track is reasonably fresh
+pub const DEFAULT_SAVE_DELAY: Duration = Duration::from_secs(1);
+const SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// file holding the queue and options of every zone, so they can be
+/// restored after mpd or sonicast restart
+pub struct StateFile {
+    path: PathBuf,
+    // serialises read-modify-write of the file between zones
+    lock: AsyncMutex<()>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct SavedState {
+    zones: BTreeMap<String, ZoneSnapshot>,
+    /// by username, see SetStreamQuality
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    stream_quality: BTreeMap<String, StreamQuality>,
+    /// podcast episode positions by track id, see resume
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    positions: BTreeMap<String, f64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ZoneSnapshot {
+    files: Vec<String>,
+    index: Option<usize>,
+    elapsed: f64,
+    playing: bool,
+    random: bool,
+    repeat: bool,
+    single: SingleMode,
+    consume: bool,
+}
+
+impl StateFile {
+    pub fn new(path: PathBuf) -> Self {
+        StateFile { path, lock: AsyncMutex::new(()) }
+    }
+
+    pub async fn load(&self, zone: &Zone) -> Result<Option<ZoneSnapshot>> {
+        let _lock = self.lock.lock().await;
+        let mut state = self.read().await?;
+        Ok(state.zones.remove(&key(zone)))
+    }
+
+    async fn save(&self, zone: &Zone, snapshot: ZoneSnapshot) -> Result<()> {
+        let _lock = self.lock.lock().await;
+        let mut state = self.read().await?;
+        state.zones.insert(key(zone), snapshot);
+        self.write(&state).await
+    }
+
+    pub async fn load_stream_quality(&self) -> Result<BTreeMap<String, StreamQuality>> {
+        let _lock = self.lock.lock().await;
+        Ok(self.read().await?.stream_quality)
+    }
+
+    /// the default quality removes the user's
+    pub async fn save_stream_quality(&self, user: &str, quality: &StreamQuality) -> Result<()> {
+        let _lock = self.lock.lock().await;
+        let mut state = self.read().await?;
+
+        if *quality == StreamQuality::default() {
+            state.stream_quality.remove(user);
+        } else {
+            state.stream_quality.insert(user.to_owned(), quality.clone());
+        }
+
+        self.write(&state).await
+    }
+
+    pub async fn load_positions(&self) -> Result<BTreeMap<String, f64>> {
+        let _lock = self.lock.lock().await;
+        Ok(self.read().await?.positions)
+    }
+
+    /// no position removes the episode's
+    pub async fn save_position(&self, id: &str, position: Option<f64>) -> Result<()> {
+        let _lock = self.lock.lock().await;
+        let mut state = self.read().await?;
+
+        match position {
+            Some(position) => state.positions.insert(id.to_owned(), position),
+            None => state.positions.remove(id),
+        };
+
+        self.write(&state).await
+    }
+
+    async fn write(&self, state: &SavedState) -> Result<()> {
+        // write then rename so a crash mid-write can't lose the old state
+        let json = serde_json::to_vec_pretty(state)?;
+        let tmp = self.path.with_extension("tmp");
+        tokio::fs::write(&tmp, json).await
+            .with_context(|| format!("writing {}", tmp.display()))?;
+        tokio::fs::rename(&tmp, &self.path).await
+            .with_context(|| format!("renaming {} to {}", tmp.display(), self.path.display()))?;
+
+        Ok(())
+    }
+
+    async fn read(&self) -> Result<SavedState> {
+        let json = match tokio::fs::read(&self.path).await {
+            Ok(json) => json,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                return Ok(SavedState::default());
+            }
+            Err(err) => {
+                return Err(err).with_context(|| format!("reading {}", self.path.display()));
+            }
+        };
+
+        serde_json::from_slice(&json)
+            .with_context(|| format!("parsing {}", self.path.display()))
+    }
+}
+
+fn key(zone: &Zone) -> String {
+    format!("{}/{}", zone.room, zone.name)
+}
+
+pub async fn snapshot(zone: &Zone) -> Result<ZoneSnapshot> {
+    let mpd = zone.mpd.read().await;
+    let status = mpd.status().await?;
+    let queue = mpd.playlistinfo().await?;
+
+    Ok(ZoneSnapshot {
+        files: queue.items.into_iter().map(|item| item.file).collect(),
+        index: status.song,
+        elapsed: status.elapsed.map(|s| s.0).unwrap_or_default(),
+        playing: status.state == PlaybackState::Play,
+        random: status.random,
+        repeat: status.repeat,
+        single: status.single,
+        consume: status.consume,
+    })
+}
+
+/// replaces the zone's queue and options with the snapshot
+pub async fn restore(zone: &Zone, snapshot: &ZoneSnapshot) -> Result<()> {
+    let mut commands = vec![Command::clear()];
+    commands.extend(snapshot.files.iter().map(|file| Command::addid(file)));
+    commands.push(Command::random(snapshot.random));
+    commands.push(Command::repeat(snapshot.repeat));
+    commands.push(Command::single(snapshot.single));
+    commands.push(Command::consume(snapshot.consume));
+
+    // mpd can only set the current track by playing it, so pause straight
+    // away unless it was playing before
+    if let Some(index) = snapshot.index {
+        commands.push(Command::seek(index, snapshot.elapsed));
+
+        if !snapshot.playing {
+            commands.push(Command::pause());
+        }
+    }
+
+    zone.mpd.write().await.command_list(&commands).await?;
+    Ok(())
+}
+
+/// restores saved state into the zone if its queue is empty, as it will be
+/// after mpd restarts without its own state file
+pub async fn restore_if_empty(state_file: &StateFile, zone: &Zone) -> Result<()> {
+    let status = zone.mpd.read().await.status().await?;
+    if status.playlist_length > 0 {
+        return Ok(());
+    }
+
+    let Some(snapshot) = state_file.load(zone).await? else { return Ok(()) };
+
+    log::info!("restoring {} queued tracks to zone {}", snapshot.files.len(), key(zone));
+    restore(zone, &snapshot).await
+}
+
+// saves zone state whenever the queue, player or options change
+pub async fn task(state_file: Arc<StateFile>, save_delay: Duration, zone: Arc<Zone>) {
+    let mut queue_watch = zone.events.queue.subscribe();
+    let mut status_watch = zone.events.status.subscribe();
+    let mut options_watch = zone.events.options.subscribe();
+
+    // serialized form of the snapshot last written for this zone, wakeups
+    // from the save interval or unrelated events often find nothing changed
+    let mut last_saved: Option<Vec<u8>> = None;
+
+    loop {
+        tokio::select! {
+            changed = queue_watch.changed() => { if changed.is_err() { break } }
+            changed = status_watch.changed() => { if changed.is_err() { break } }
+            changed = options_watch.changed() => { if changed.is_err() { break } }
+            () = tokio::time::sleep(SAVE_INTERVAL) => {}
+        }
+
+        // let bursts of changes settle, eg. a queue being loaded
+        tokio::time::sleep(save_delay).await;
+
+        let result = async {
+            // waits out batches and announcements, so that an announcement
+            // isn't saved in place of the queue it interrupted
+            let snapshot = {
+                let _lock = zone.command_lock.read().await;
+                snapshot(&zone).await?
+            };
+
+            // keep the last non-empty queue, so that RestoreState can bring
+            // back a queue that was cleared by accident or by mpd restarting
+            if snapshot.files.is_empty() {
+                return Ok(());
+            }
+
+            let serialized = serde_json::to_vec(&snapshot)?;
+            if last_saved.as_ref() == Some(&serialized) {
+                return Ok(());
+            }
+
+            state_file.save(&zone, snapshot).await?;
+            last_saved = Some(serialized);
+            Ok::<_, anyhow::Error>(())
+        }.await;
+
+        if let Err(err) = result {
+            log::warn!("saving state for zone {}: {err:?}", key(&zone));
+        }
+    }
+}
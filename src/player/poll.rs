@@ -0,0 +1,222 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use axum::Json;
+use axum::extract::{Query, State};
+use futures::pin_mut;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, watch};
+
+use crate::subsonic::AuthParams;
+
+use super::events::{self, EventKind, Outlet};
+use super::zones::Zone;
+use super::{ConnectParams, Ctx, HttpError, Sender, ServerMsg, connect};
+
+pub const DEFAULT_RETENTION: usize = 1000;
+
+// how long a poll waits for new events before answering with none
+const POLL_TIMEOUT: Duration = Duration::from_secs(25);
+
+/// the most recent events of every zone, numbered in the order they were
+/// produced, for clients which can do neither websockets nor sse and poll
+/// for what happened since they last asked instead
+pub struct EventLog {
+    events: Mutex<Events>,
+    /// seq of the latest event, for polls waiting on new ones
+    latest: watch::Sender<u64>,
+}
+
+struct Events {
+    retention: usize,
+    entries: VecDeque<Arc<LoggedEvent>>,
+    next_seq: u64,
+    /// seq of the latest event dropped to stay within retention
+    dropped: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LoggedEvent {
+    seq: u64,
+    #[serde(skip)]
+    room: String,
+    #[serde(skip)]
+    zone: String,
+    #[serde(flatten)]
+    msg: ServerMsg,
+}
+
+impl LoggedEvent {
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    pub fn msg(&self) -> &ServerMsg {
+        &self.msg
+    }
+
+    fn in_zone(&self, zone: &Zone) -> bool {
+        self.room == zone.room && self.zone == zone.name
+    }
+
+    /// version of the queue after a queue event
+    pub fn queue_version(&self) -> Option<u32> {
+        match &self.msg {
+            ServerMsg::Queue(queue) => Some(queue.0.version),
+            ServerMsg::QueueDelta(delta) => Some(delta.version),
+            _ => None,
+        }
+    }
+}
+
+impl EventLog {
+    pub fn new(retention: usize) -> Self {
+        EventLog {
+            events: Mutex::new(Events {
+                retention,
+                entries: VecDeque::new(),
+                next_seq: 1,
+                dropped: 0,
+            }),
+            latest: watch::Sender::new(0),
+        }
+    }
+
+    fn push(&self, zone: &Zone, msg: ServerMsg) {
+        let mut events = self.events.lock().unwrap();
+
+        let seq = events.next_seq;
+        events.next_seq += 1;
+
+        events.entries.push_back(Arc::new(LoggedEvent {
+            seq,
+            room: zone.room.clone(),
+            zone: zone.name.clone(),
+            msg,
+        }));
+
+        while events.entries.len() > events.retention {
+            if let Some(dropped) = events.entries.pop_front() {
+                events.dropped = dropped.seq;
+            }
+        }
+
+        drop(events);
+        self.latest.send_replace(seq);
+    }
+
+    /// the zone's events after since, and whether any of them have already
+    /// been dropped
+    fn since(&self, zone: &Zone, since: u64) -> (Vec<Arc<LoggedEvent>>, bool) {
+        self.since_matching(zone, since, |_| true)
+    }
+
+    /// the zone's queue events after since, for sessions resuming
+    pub fn queue_since(&self, zone: &Zone, since: u64) -> (Vec<Arc<LoggedEvent>>, bool) {
+        self.since_matching(zone, since, |event| event.queue_version().is_some())
+    }
+
+    /// seq of the zone's latest logged queue event, if it left the queue at
+    /// version. None if it hasn't been logged yet, or the queue has changed
+    /// again since
+    pub fn queue_seq(&self, zone: &Zone, version: u32) -> Option<u64> {
+        let latest = self.latest_queue_event(zone)?;
+        (latest.queue_version() == Some(version)).then_some(latest.seq)
+    }
+
+    pub fn latest_queue_event(&self, zone: &Zone) -> Option<Arc<LoggedEvent>> {
+        self.events.lock().unwrap().entries.iter()
+            .rev()
+            .find(|event| event.in_zone(zone) && event.queue_version().is_some())
+            .cloned()
+    }
+
+    fn since_matching(&self, zone: &Zone, since: u64, matches: impl Fn(&LoggedEvent) -> bool) -> (Vec<Arc<LoggedEvent>>, bool) {
+        let events = self.events.lock().unwrap();
+
+        let entries = events.entries.iter()
+            .filter(|event| event.seq > since && event.in_zone(zone))
+            .filter(|event| matches(event))
+            .cloned()
+            .collect();
+
+        // seqs from before a restart are ahead of ours
+        let missed = since < events.dropped || since >= events.next_seq;
+
+        (entries, missed)
+    }
+}
+
+// records the zone's events in the log, through the same forwarding as a
+// session subscribed to the default events
+pub async fn task(log: Arc<EventLog>, zone: Arc<Zone>) {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let tx = Sender::channel(tx);
+    let subscriptions = watch::Sender::new(EventKind::DEFAULT.iter().copied().collect());
+
+    let outlet = Outlet { zone: &zone, subscriptions: &subscriptions, tx: &tx, log: None, resume: None };
+    let forward = events::forward_all(&outlet, None);
+    pin_mut!(forward);
+
+    loop {
+        tokio::select! {
+            result = &mut forward => {
+                if let Err(err) = result {
+                    log::warn!("event log for zone {}: {err:?}", zone.name);
+                }
+                break;
+            }
+            Some(msg) = rx.recv() => log.push(&zone, msg),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PollParams {
+    /// seq of the last event the client saw, omitted or 0 on first poll
+    #[serde(default)]
+    since: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Poll {
+    /// seq to poll since next time
+    seq: u64,
+    /// events since were dropped before the client polled for them, the
+    /// client should refetch state with commands rather than rely on these
+    missed: bool,
+    events: Vec<Arc<LoggedEvent>>,
+}
+
+// answers with the zone's events since the given seq, waiting a while for
+// some if there are none yet
+pub async fn poll(
+    ctx: State<Ctx>,
+    params: Query<ConnectParams>,
+    poll: Query<PollParams>,
+    auth: Query<AuthParams>,
+) -> Result<Json<Poll>, HttpError> {
+    let connection = connect(&ctx, &params, auth.0).await?;
+    let log = &ctx.services.event_log;
+    let since = poll.since;
+
+    let mut latest = log.latest.subscribe();
+    let deadline = tokio::time::Instant::now() + POLL_TIMEOUT;
+
+    loop {
+        let seq = *latest.borrow_and_update();
+        let (events, missed) = log.since(&connection.zone, since);
+
+        if !events.is_empty() || missed {
+            let seq = events.last().map(|event| event.seq).unwrap_or(seq);
+            return Ok(Json(Poll { seq, missed, events }));
+        }
+
+        let changed = tokio::time::timeout_at(deadline, latest.changed()).await;
+
+        if !matches!(changed, Ok(Ok(()))) {
+            return Ok(Json(Poll { seq, missed, events }));
+        }
+    }
+}
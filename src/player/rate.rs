@@ -0,0 +1,61 @@
+use url::Url;
+
+/// playback rates clients may choose from
+pub const RATE_PRESETS: &[f64] = &[0.75, 1.0, 1.25, 1.5, 1.75, 2.0];
+
+/// mpd has no tempo control of its own, so playback rate is applied by a
+/// transcoding proxy in front of the stream. the proxy is passed the
+/// original stream in the `url` query param and the rate in `rate`, and is
+/// expected to serve the audio time stretched without changing pitch, eg.
+/// by running it through ffmpeg with `-af atempo=$rate`
+#[derive(Debug, Clone)]
+pub struct RateProxy {
+    base: Url,
+}
+
+impl RateProxy {
+    pub fn new(base: Url) -> Self {
+        RateProxy { base }
+    }
+
+    pub fn wrap(&self, url: &Url, rate: f64) -> Url {
+        let mut proxied = self.base.clone();
+        proxied.query_pairs_mut()
+            .append_pair("url", url.as_str())
+            .append_pair("rate", &rate.to_string());
+        proxied
+    }
+
+    /// the original stream url and rate of a proxied url, or None if url
+    /// doesn't point at the proxy
+    pub fn unwrap(&self, url: &Url) -> Option<(Url, f64)> {
+        if url.origin() != self.base.origin() || url.path() != self.base.path() {
+            return None;
+        }
+
+        let mut stream = None;
+        let mut rate = None;
+
+        for (name, value) in url.query_pairs() {
+            match &*name {
+                "url" => stream = Url::parse(&value).ok(),
+                "rate" => rate = value.parse().ok(),
+                _ => {}
+            }
+        }
+
+        Some((stream?, rate?))
+    }
+}
+
+/// the stream url and playback rate for a queue item's file. files not
+/// going through the rate proxy play at 1.0
+pub fn source(proxy: Option<&RateProxy>, file: &str) -> Option<(Url, f64)> {
+    let url = Url::parse(file).ok()?;
+
+    if let Some(source) = proxy.and_then(|proxy| proxy.unwrap(&url)) {
+        return Some(source);
+    }
+
+    Some((url, 1.0))
+}
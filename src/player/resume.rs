@@ -0,0 +1,206 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Result;
+
+use crate::mpd::types::{Id, PlaybackState, Status};
+use crate::podcasts::PodcastsBase;
+use crate::subsonic::types::TrackId;
+
+use super::persist::StateFile;
+use super::skip::Skips;
+use super::zones::Zone;
+
+const SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+// episodes stopped within this many seconds of the end count as finished
+const FINISHED_MARGIN: f64 = 30.0;
+
+// resuming a little before where the episode stopped gives some context
+pub const REWIND: f64 = 5.0;
+
+/// saved podcast episode positions by track id, rather than by stream url,
+/// since urls carry credentials which change between sessions. kept in the
+/// state file if there is one, otherwise they only live in memory
+pub struct Positions {
+    saved: Mutex<HashMap<String, f64>>,
+    state_file: Option<Arc<StateFile>>,
+}
+
+impl Positions {
+    pub async fn open(state_file: Option<Arc<StateFile>>) -> Self {
+        let saved = match &state_file {
+            Some(state_file) => match state_file.load_positions().await {
+                Ok(positions) => positions.into_iter().collect(),
+                Err(err) => {
+                    log::warn!("loading podcast positions: {err:?}");
+                    HashMap::new()
+                }
+            },
+            None => HashMap::new(),
+        };
+
+        Positions { saved: Mutex::new(saved), state_file }
+    }
+
+    fn get(&self, id: &TrackId) -> Option<f64> {
+        self.saved.lock().unwrap().get(&id.0).copied()
+    }
+
+    /// no position forgets the episode's
+    async fn set(&self, id: &TrackId, position: Option<f64>) -> Result<()> {
+        {
+            let mut saved = self.saved.lock().unwrap();
+
+            let changed = match position {
+                Some(position) => saved.insert(id.0.clone(), position) != Some(position),
+                None => saved.remove(&id.0).is_some(),
+            };
+
+            if !changed {
+                return Ok(());
+            }
+        }
+
+        if let Some(state_file) = &self.state_file {
+            state_file.save_position(&id.0, position).await?;
+        }
+
+        Ok(())
+    }
+}
+
+struct Current {
+    id: Id,
+    // only set if the current song is a podcast episode
+    episode: Option<Episode>,
+}
+
+// positions are kept in the episode's own time, so they survive changes
+// of playback rate
+struct Episode {
+    track: TrackId,
+    rate: f64,
+    elapsed: f64,
+    duration: Option<f64>,
+}
+
+// saves podcast episode positions as they play, and seeks to the saved
+// position when an episode starts playing again, or past its feed's intro
+// if there's none
+pub async fn task(podcasts: PodcastsBase, positions: Arc<Positions>, skips: Arc<Skips>, zone: Arc<Zone>) {
+    let mut watch = zone.events.subscribe_status();
+    let mut current = None;
+
+    loop {
+        if let Err(err) = update(&zone, &podcasts, &positions, &skips, &mut current).await {
+            log::warn!("podcast resume: {err:?}");
+        }
+
+        tokio::select! {
+            changed = watch.changed() => {
+                if changed.is_err() { break }
+            }
+            () = tokio::time::sleep(SAVE_INTERVAL) => {}
+        }
+    }
+}
+
+async fn update(zone: &Zone, podcasts: &PodcastsBase, positions: &Positions, skips: &Skips, current: &mut Option<Current>) -> Result<()> {
+    let status = zone.mpd.read().await.status().await?;
+
+    if current.as_ref().map(|current| &current.id) != status.song_id.as_ref() {
+        if let Some(Current { episode: Some(episode), .. }) = current.take() {
+            finish_episode(positions, &episode).await?;
+        }
+
+        if let Some(id) = &status.song_id {
+            *current = Some(start_song(zone, podcasts, positions, skips, id, &status).await?);
+        }
+    }
+
+    let Some(Current { episode: Some(episode), .. }) = current else {
+        return Ok(());
+    };
+
+    if status.state == PlaybackState::Stop {
+        return Ok(());
+    }
+
+    // avoid resaving the position while paused
+    if let Some(elapsed) = status.elapsed.map(|s| s.0 * episode.rate)
+        && elapsed != episode.elapsed
+    {
+        episode.elapsed = elapsed;
+        episode.duration = status.duration.map(|s| s.0 * episode.rate);
+        positions.set(&episode.track, Some(elapsed)).await?;
+    }
+
+    Ok(())
+}
+
+async fn start_song(zone: &Zone, podcasts: &PodcastsBase, positions: &Positions, skips: &Skips, id: &Id, status: &Status) -> Result<Current> {
+    let item = zone.mpd.read().await.playlistid(id).await?;
+
+    let Some((track, url, rate)) = zone.source(&item.file)
+        .and_then(|(url, rate)| Some((podcasts.episode_id_from_stream_url(&url)?, url, rate)))
+    else {
+        return Ok(Current { id: id.clone(), episode: None });
+    };
+
+    let resume = zone.take_resume(url.as_str());
+    let saved = positions.get(&track).filter(|_| resume);
+
+    // only resume if the episode is starting from the beginning, otherwise
+    // the user has already chosen where to play from
+    let elapsed = status.elapsed.map(|s| s.0 * rate).unwrap_or_default();
+    let elapsed = match saved {
+        Some(saved) if elapsed < 1.0 => {
+            let saved = (saved - REWIND).max(0.0);
+            log::info!("resuming podcast episode {} at {saved}s", track.0);
+            zone.mpd.read().await.seekcur(saved / rate).await?;
+            saved
+        }
+        None if elapsed < 1.0 && resume => skip_intro(zone, podcasts, skips, &track, rate).await?
+            .unwrap_or(elapsed),
+        _ => elapsed,
+    };
+
+    Ok(Current {
+        id: id.clone(),
+        episode: Some(Episode {
+            track,
+            rate,
+            elapsed,
+            duration: status.duration.map(|s| s.0 * rate),
+        }),
+    })
+}
+
+// looking up the episode's feed needs a session's credentials, so intros
+// aren't skipped until someone has connected to the zone
+async fn skip_intro(zone: &Zone, podcasts: &PodcastsBase, skips: &Skips, id: &TrackId, rate: f64) -> Result<Option<f64>> {
+    let Some(auth) = zone.auth() else { return Ok(None) };
+    let podcasts = podcasts.with_auth(auth);
+
+    let Some(offsets) = skips.for_episode(&podcasts, id).await?
+        .filter(|offsets| offsets.intro > 0.0)
+    else {
+        return Ok(None);
+    };
+
+    log::info!("skipping {}s intro of podcast episode {}", offsets.intro, id.0);
+    zone.mpd.read().await.seekcur(offsets.intro / rate).await?;
+    Ok(Some(offsets.intro))
+}
+
+async fn finish_episode(positions: &Positions, episode: &Episode) -> Result<()> {
+    let Some(duration) = episode.duration else { return Ok(()) };
+
+    if episode.elapsed >= duration - FINISHED_MARGIN {
+        positions.set(&episode.track, None).await?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,65 @@
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+
+use crate::{jukebox, mpd};
+
+use super::visualizer;
+use super::Services;
+use super::zones::Zones;
+
+/// name of the room for the mpd instance configured by MPD_SOCKET
+pub const DEFAULT_ROOM: &str = "default";
+
+#[derive(Clone)]
+pub struct RoomConfig {
+    pub name: String,
+    pub backend: RoomBackend,
+    pub visualizer: Option<visualizer::Config>,
+}
+
+#[derive(Clone)]
+pub enum RoomBackend {
+    Mpd(mpd::Config),
+    /// subsonic's jukebox, for hosts without mpd
+    Jukebox(jukebox::Config),
+    /// audio played on this host, for small devices without mpd
+    #[cfg(feature = "local")]
+    Local,
+}
+
+/// a room is a separate mpd instance, each with its own set of zones
+pub struct Rooms {
+    rooms: BTreeMap<String, Zones>,
+}
+
+impl Rooms {
+    pub async fn open(
+        rooms: &[RoomConfig],
+        services: &Services,
+    ) -> Result<Rooms> {
+        let mut opened = BTreeMap::new();
+
+        for room in rooms {
+            let zones = Zones::open(&room.name, &room.backend, room.visualizer.clone(), services).await?;
+            opened.insert(room.name.clone(), zones);
+        }
+
+        anyhow::ensure!(opened.contains_key(DEFAULT_ROOM), "no default room configured");
+
+        Ok(Rooms { rooms: opened })
+    }
+
+    pub fn default_room(&self) -> &Zones {
+        &self.rooms[DEFAULT_ROOM]
+    }
+
+    pub fn get(&self, name: &str) -> Result<&Zones> {
+        self.rooms.get(name)
+            .ok_or_else(|| anyhow::format_err!("no such room: {name}"))
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.rooms.keys().map(String::as_str)
+    }
+}
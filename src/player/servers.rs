@@ -0,0 +1,59 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use anyhow::Result;
+use url::Url;
+
+use crate::subsonic::{AuthParams, Subsonic, SubsonicBase};
+
+use super::helper::StationCache;
+
+#[derive(Clone)]
+pub struct Config {
+    /// prefixes the ids of everything on the server, eg. navidrome:1234
+    pub name: String,
+    pub url: Url,
+    pub username: String,
+    pub password: String,
+}
+
+/// subsonic servers besides the one clients log in to. clients only hold
+/// credentials for their own server, so these use credentials configured
+/// for sonicast, as the jukebox backend does
+#[derive(Default)]
+pub struct Servers {
+    servers: BTreeMap<String, Server>,
+}
+
+pub struct Server {
+    pub subsonic: Subsonic,
+    pub stations: StationCache,
+}
+
+impl Servers {
+    pub fn new(configs: &[Config], http: &reqwest::Client) -> Self {
+        let servers = configs.iter()
+            .map(|config| {
+                let auth = AuthParams::password(&config.username, &config.password);
+
+                let server = Server {
+                    subsonic: SubsonicBase::new(&config.url, http.clone()).with_auth(Arc::new(auth)),
+                    stations: StationCache::default(),
+                };
+
+                (config.name.clone(), server)
+            })
+            .collect();
+
+        Servers { servers }
+    }
+
+    pub fn get(&self, name: &str) -> Result<&Server> {
+        self.servers.get(name)
+            .ok_or_else(|| anyhow::format_err!("no such subsonic server: {name}"))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Server)> {
+        self.servers.iter().map(|(name, server)| (name.as_str(), server))
+    }
+}
@@ -0,0 +1,158 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::mpd::types::PlaybackState;
+use crate::podcasts::{Podcasts, PodcastsBase};
+use crate::subsonic::types::TrackId;
+
+use super::zones::Zone;
+
+// checked at least this often while an episode plays, in case the outro
+// moved, eg. because offsets were changed
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// seconds of a feed's episodes to skip at either end
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+pub struct SkipOffsets {
+    #[serde(default)]
+    pub intro: f64,
+    #[serde(default)]
+    pub outro: f64,
+}
+
+/// skip offsets from config, as a json object keyed by podcast channel id
+#[derive(Debug, Default)]
+pub struct SkipOffsetList(pub HashMap<String, SkipOffsets>);
+
+impl FromStr for SkipOffsetList {
+    type Err = serde_json::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_json::from_str(s).map(SkipOffsetList)
+    }
+}
+
+/// offsets set with SetSkipOffsets only live in memory, as with alarms
+#[derive(Default)]
+pub struct Skips {
+    feeds: Mutex<HashMap<String, SkipOffsets>>,
+    /// channel of each episode looked up so far
+    channels: Mutex<HashMap<String, Option<String>>>,
+}
+
+impl Skips {
+    pub fn new(feeds: HashMap<String, SkipOffsets>) -> Self {
+        Skips { feeds: Mutex::new(feeds), channels: Default::default() }
+    }
+
+    pub fn list(&self) -> HashMap<String, SkipOffsets> {
+        self.feeds.lock().unwrap().clone()
+    }
+
+    /// offsets of zero skip nothing, and remove the feed
+    pub fn set(&self, feed: String, offsets: SkipOffsets) {
+        let mut feeds = self.feeds.lock().unwrap();
+
+        if offsets == SkipOffsets::default() {
+            feeds.remove(&feed);
+        } else {
+            feeds.insert(feed, offsets);
+        }
+    }
+
+    /// offsets of the feed an episode belongs to, if it has any
+    pub async fn for_episode(&self, podcasts: &Podcasts, id: &TrackId) -> Result<Option<SkipOffsets>> {
+        // save looking up the channel when there's nothing to find
+        if self.feeds.lock().unwrap().is_empty() {
+            return Ok(None);
+        }
+
+        let cached = self.channels.lock().unwrap().get(&id.0).cloned();
+
+        let channel = match cached {
+            Some(channel) => channel,
+            None => {
+                let channel = podcasts.get_podcast_episode(id).await?.channel_id;
+                self.channels.lock().unwrap().insert(id.0.clone(), channel.clone());
+                channel
+            }
+        };
+
+        let Some(channel) = channel else { return Ok(None) };
+        Ok(self.feeds.lock().unwrap().get(&channel).copied())
+    }
+}
+
+// advances to the next track as a podcast episode reaches its feed's
+// outro. intros are skipped by the resume task, which already decides
+// where episodes start
+pub async fn task(podcasts: PodcastsBase, skips: Arc<Skips>, zone: Arc<Zone>) {
+    let mut watch = zone.events.subscribe_status();
+
+    loop {
+        let wait = match check(&zone, &podcasts, &skips).await {
+            Ok(wait) => wait,
+            Err(err) => {
+                log::warn!("podcast skip offsets: {err:?}");
+                None
+            }
+        };
+
+        tokio::select! {
+            changed = watch.changed() => {
+                if changed.is_err() { break }
+            }
+            () = tokio::time::sleep(wait.map_or(CHECK_INTERVAL, |wait| wait.min(CHECK_INTERVAL))) => {}
+        }
+    }
+}
+
+// returns how long until the outro, if an episode with one is playing
+async fn check(zone: &Zone, podcasts: &PodcastsBase, skips: &Skips) -> Result<Option<Duration>> {
+    let Some(auth) = zone.auth() else { return Ok(None) };
+    let podcasts = podcasts.with_auth(auth);
+
+    let mpd = zone.mpd.read().await;
+    let status = mpd.status().await?;
+
+    if status.state != PlaybackState::Play {
+        return Ok(None);
+    }
+
+    let (Some(song_id), Some(elapsed), Some(duration)) = (&status.song_id, status.elapsed, status.duration) else {
+        return Ok(None);
+    };
+
+    let item = mpd.playlistid(song_id).await?;
+    drop(mpd);
+
+    let Some((url, rate)) = zone.source(&item.file) else { return Ok(None) };
+
+    let Some(id) = podcasts.track_id_from_stream_url(&url)
+        .filter(|id| podcasts.matches(id))
+    else {
+        return Ok(None);
+    };
+
+    let Some(offsets) = skips.for_episode(&podcasts, &id).await?
+        .filter(|offsets| offsets.outro > 0.0)
+    else {
+        return Ok(None);
+    };
+
+    // in the episode's own time
+    let remaining = (duration.0 - elapsed.0) * rate - offsets.outro;
+
+    if remaining <= 0.0 {
+        log::info!("skipping outro of podcast episode {}", id.0);
+        zone.mpd.write().await.next().await?;
+        return Ok(None);
+    }
+
+    Ok(Some(Duration::from_secs_f64(remaining / rate)))
+}
@@ -0,0 +1,108 @@
+use std::collections::HashSet;
+use std::time::Duration;
+
+use anyhow::Result;
+use jiff::Timestamp;
+use serde::Deserialize;
+
+use crate::subsonic::Subsonic;
+use crate::subsonic::types::{RandomSongsFilter, Track};
+
+// getRandomSongs returns at most this many songs a request
+const MAX_QUERY_SIZE: usize = 500;
+
+// queries made before giving up on filling the queue, as filtering on
+// rating or play time may leave few songs out of each
+const MAX_QUERIES: usize = 8;
+
+/// inclusive, either end may be left open
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+pub struct YearRange {
+    pub from: Option<u32>,
+    pub to: Option<u32>,
+}
+
+/// what a smart queue is made of. songs must match every rule given
+#[derive(Deserialize, Debug, Default)]
+pub struct Rules {
+    /// songs of any of these genres, or of any genre if empty
+    #[serde(default)]
+    pub genres: Vec<String>,
+    pub year_range: Option<YearRange>,
+    /// 1-5 stars, unrated songs don't match
+    pub min_rating: Option<u8>,
+    /// seconds, songs played more recently than this don't match
+    pub exclude_played_within: Option<u64>,
+}
+
+/// picks up to count songs matching rules. subsonic can only filter random
+/// songs by genre and year itself, so the rest of the rules are applied
+/// here to as many random picks as it takes
+pub async fn plan(subsonic: &Subsonic, rules: &Rules, count: usize) -> Result<Vec<Track>> {
+    let played_since = rules.exclude_played_within
+        .map(|secs| Timestamp::now().checked_sub(Duration::from_secs(secs)))
+        .transpose()?;
+
+    let years = rules.year_range.unwrap_or_default();
+
+    // one query per genre, taking turns
+    let filters = match rules.genres.as_slice() {
+        [] => vec![None],
+        genres => genres.iter().cloned().map(Some).collect(),
+    };
+
+    let mut seen = HashSet::new();
+    let mut tracks = Vec::new();
+
+    for filter in filters.iter().cycle().take(MAX_QUERIES.max(filters.len())) {
+        if tracks.len() >= count {
+            break;
+        }
+
+        let filter = RandomSongsFilter {
+            genre: filter.clone(),
+            from_year: years.from,
+            to_year: years.to,
+        };
+
+        // ask for extra, as some are bound to be filtered out
+        let size = (count - tracks.len()).saturating_mul(2).min(MAX_QUERY_SIZE);
+        let picks = subsonic.get_random_songs(size, &filter).await?;
+
+        // nothing more to find, eg. a genre with no songs
+        if picks.is_empty() && filters.len() == 1 {
+            break;
+        }
+
+        for track in picks {
+            if tracks.len() >= count {
+                break;
+            }
+
+            if !matches(rules, played_since, &track) || !seen.insert(track.id.0.clone()) {
+                continue;
+            }
+
+            tracks.push(track);
+        }
+    }
+
+    Ok(tracks)
+}
+
+fn matches(rules: &Rules, played_since: Option<Timestamp>, track: &Track) -> bool {
+    if let Some(min_rating) = rules.min_rating
+        && track.details.user_rating.is_none_or(|rating| rating < min_rating)
+    {
+        return false;
+    }
+
+    if let Some(played_since) = played_since
+        && let Some(played) = &track.details.played
+        && played.parse::<Timestamp>().is_ok_and(|played| played > played_since)
+    {
+        return false;
+    }
+
+    true
+}
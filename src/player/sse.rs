@@ -0,0 +1,59 @@
+use std::convert::Infallible;
+
+use async_stream::stream;
+use axum::extract::{Query, State};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use futures::{pin_mut, Stream};
+use tokio::sync::mpsc;
+
+use crate::logging;
+use crate::subsonic::AuthParams;
+
+use super::{ConnectParams, Ctx, HttpError, Sender, connect, events};
+
+// the same events as the websocket, for clients behind proxies which mangle
+// websockets. each event's data is a server message as the websocket would
+// send it in json, commands go to the http command routes instead
+pub async fn events(
+    ctx: State<Ctx>,
+    params: Query<ConnectParams>,
+    auth: Query<AuthParams>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, HttpError> {
+    let connection = connect(&ctx, &params, auth.0).await?;
+
+    let (tx, mut rx) = mpsc::unbounded_channel();
+
+    let session = connection.into_session(
+        ctx.0,
+        Sender::channel(tx),
+        events::EventKind::DEFAULT.iter().copied().collect(),
+    );
+
+    // the session's event tasks run as part of the stream, so they stop
+    // once the client goes away and the stream is dropped
+    let stream = stream! {
+        let forward = events::run_events(&session, None);
+        pin_mut!(forward);
+
+        loop {
+            tokio::select! {
+                result = &mut forward => {
+                    if let Err(err) = result {
+                        logging::error(&err);
+                    }
+                    break;
+                }
+                msg = rx.recv() => {
+                    let Some(msg) = msg else { break };
+
+                    match Event::default().json_data(&msg) {
+                        Ok(event) => yield Ok(event),
+                        Err(err) => log::warn!("encoding server sent event: {err}"),
+                    }
+                }
+            }
+        }
+    };
+
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
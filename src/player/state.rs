@@ -0,0 +1,304 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use tokio::sync::watch;
+
+use crate::logging;
+
+use super::events::{self, ConnectionEvent, CurrentTrack, LastQueue, LibraryUpdateEvent, NowPlayingEvent, OptionsEvent, OutputsEvent, PlaybackEvent, QueueDelta, QueueEvent, TrackChangedEvent};
+use super::helper::Resolver;
+use super::tags::Tagger;
+use super::zones::Zone;
+use super::{volume, Services};
+
+// playback events are sent on change, clients extrapolate the position in
+// between. this periodic resync corrects for any drift
+pub const DEFAULT_PLAYBACK_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+// mpd going away shows up nowhere but in its connection, so is checked for
+const CONNECTION_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// latest events for a zone, shared by every session connected to it. None
+/// until the first event has been produced
+#[derive(Default)]
+pub struct ZoneState {
+    pub playback: watch::Sender<Option<Arc<PlaybackEvent>>>,
+    pub queue: watch::Sender<Option<Arc<QueueState>>>,
+    pub options: watch::Sender<Option<Arc<OptionsEvent>>>,
+    pub outputs: watch::Sender<Option<Arc<OutputsEvent>>>,
+    pub now_playing: watch::Sender<Option<Arc<NowPlayingEvent>>>,
+    pub library_update: watch::Sender<Option<Arc<LibraryUpdateEvent>>>,
+    pub connection: watch::Sender<Option<Arc<ConnectionEvent>>>,
+    pub track_changed: watch::Sender<Option<Arc<TrackChangedEvent>>>,
+}
+
+pub struct QueueState {
+    pub queue: Arc<QueueEvent>,
+    /// changes from the previous queue state, if they could be computed
+    pub delta: Option<Arc<QueueDelta>>,
+}
+
+// produces events for the zone, so that mpd and subsonic see the same load
+// no matter how many sessions are connected
+pub async fn task(services: Services, zone: Arc<Zone>) {
+    futures::join!(
+        playback_task(&services, &zone),
+        queue_task(&services, &zone),
+        options_task(&zone),
+        outputs_task(&zone),
+        now_playing_task(&zone),
+        library_update_task(&zone),
+        connection_task(&services, &zone),
+        track_changed_task(&zone),
+    );
+}
+
+async fn track_changed_task(zone: &Zone) {
+    let mut watch = zone.events.status.subscribe();
+    let mut current = None;
+
+    loop {
+        match zone.mpd.read().await.status().await {
+            Ok(status) => {
+                let next = CurrentTrack::from_status(&status);
+
+                if let Some(event) = events::track_changed_event(current.as_ref(), next.as_ref()) {
+                    zone.state.track_changed.send_replace(Some(Arc::new(event)));
+                }
+
+                current = next;
+            }
+            Err(err) => logging::error(&err.context("track changed event, fetching status")),
+        }
+
+        let Ok(_) = watch.changed().await else { break };
+    }
+}
+
+async fn connection_task(services: &Services, zone: &Zone) {
+    let mut subsonic = services.subsonic.subscribe_reachable();
+
+    loop {
+        // reconnects if need be, so that mpd coming back shows up here even
+        // while no commands are being sent
+        let mpd = zone.mpd.read().await.check_connection().await;
+        let subsonic_up = *subsonic.borrow_and_update();
+
+        let event = ConnectionEvent {
+            mpd,
+            subsonic: subsonic_up,
+            degraded: !mpd || !subsonic_up,
+        };
+
+        // only sent on change, unlike other events this is polled for
+        zone.state.connection.send_if_modified(|prev| update_connection(&zone.name, prev, event));
+
+        tokio::select! {
+            changed = subsonic.changed() => { if changed.is_err() { break } }
+            () = tokio::time::sleep(CONNECTION_CHECK_INTERVAL) => {}
+        }
+    }
+}
+
+// true if the event is a change from prev, which it then replaces
+fn update_connection(zone: &str, prev: &mut Option<Arc<ConnectionEvent>>, event: ConnectionEvent) -> bool {
+    if prev.as_deref() == Some(&event) {
+        return false;
+    }
+
+    if event.degraded {
+        log::warn!("zone {zone} degraded: {event:?}");
+    } else if prev.as_ref().is_some_and(|prev| prev.degraded) {
+        log::info!("zone {zone} connections reestablished");
+    }
+
+    *prev = Some(Arc::new(event));
+    true
+}
+
+async fn playback_task(services: &Services, zone: &Zone) {
+    let mut watch = zone.events.status.subscribe();
+    let mut error_watch = zone.playback_error.subscribe();
+
+    loop {
+        match events::playback_event(zone).await {
+            Ok(event) => {
+                // the error was for whatever failed before playback resumed
+                if event.playing {
+                    zone.playback_error.send_if_modified(|error| error.take().is_some());
+                    error_watch.mark_unchanged();
+                }
+
+                zone.state.playback.send_replace(Some(Arc::new(event)));
+            }
+            Err(err) => logging::error(&err.context("playback event")),
+        }
+
+        tokio::select! {
+            changed = watch.changed() => { if changed.is_err() { break } }
+            changed = error_watch.changed() => { if changed.is_err() { break } }
+            () = tokio::time::sleep(services.intervals.playback_poll) => {}
+        }
+    }
+}
+
+async fn options_task(zone: &Zone) {
+    let mut watch = zone.events.options.subscribe();
+    let mut limits = zone.volume_limits.subscribe();
+    let mut auto_queue = zone.auto_queue.subscribe();
+
+    loop {
+        if let Err(err) = volume::enforce(zone).await {
+            logging::error(&err.context("enforcing volume limit"));
+        }
+
+        match events::options_event(zone).await {
+            Ok(event) => { zone.state.options.send_replace(Some(Arc::new(event))); }
+            Err(err) => logging::error(&err.context("options event")),
+        }
+
+        tokio::select! {
+            changed = watch.changed() => { if changed.is_err() { break } }
+            changed = limits.changed() => { if changed.is_err() { break } }
+            changed = auto_queue.changed() => { if changed.is_err() { break } }
+        }
+    }
+}
+
+async fn outputs_task(zone: &Zone) {
+    let mut watch = zone.events.outputs.subscribe();
+
+    loop {
+        match events::outputs_event(zone).await {
+            Ok(event) => { zone.state.outputs.send_replace(Some(Arc::new(event))); }
+            Err(err) => logging::error(&err.context("outputs event, fetching outputs")),
+        }
+
+        let Ok(_) = watch.changed().await else { break };
+    }
+}
+
+async fn library_update_task(zone: &Zone) {
+    let mut watch = zone.events.library.subscribe();
+
+    loop {
+        let prev = zone.state.library_update.borrow().clone();
+
+        match events::library_update_event(zone, prev.as_deref()).await {
+            Ok(event) => { zone.state.library_update.send_replace(Some(Arc::new(event))); }
+            Err(err) => logging::error(&err.context("library update event, fetching status")),
+        }
+
+        let Ok(_) = watch.changed().await else { break };
+    }
+}
+
+// the current track's tags change along with the queue and player, and
+// mid-track for radio streams
+async fn now_playing_task(zone: &Zone) {
+    let mut queue_watch = zone.events.queue.subscribe();
+    let mut status_watch = zone.events.status.subscribe();
+    let mut stream_title_watch = zone.events.stream_title.subscribe();
+
+    loop {
+        match events::now_playing_event(zone).await {
+            Ok(event) => { zone.state.now_playing.send_replace(Some(Arc::new(event))); }
+            Err(err) => logging::error(&err.context("now playing event, fetching current song")),
+        }
+
+        tokio::select! {
+            changed = queue_watch.changed() => { if changed.is_err() { break } }
+            changed = status_watch.changed() => { if changed.is_err() { break } }
+            changed = stream_title_watch.changed() => { if changed.is_err() { break } }
+        }
+    }
+}
+
+// produces a queue event on both queue and player changes, as the queue
+// event also carries the current track
+async fn queue_task(services: &Services, zone: &Zone) {
+    let mut queue_watch = zone.events.queue.subscribe();
+    let mut status_watch = zone.events.status.subscribe();
+    let mut unavailable_watch = zone.unavailable.subscribe();
+
+    // queue as of the last event produced, if any
+    let mut last = None;
+    let mut tagger = Tagger::default();
+
+    loop {
+        match queue_state(services, zone, &mut last).await {
+            Ok(state) => { zone.state.queue.send_replace(Some(Arc::new(state))); }
+            Err(err) => {
+                // no longer know what the last queue was, start over
+                last = None;
+                logging::error(&err.context("queue event, fetching queue"));
+            }
+        }
+
+        // the queue just had its tracks resolved, which is all tagging needs
+        if services.tag_queue
+            && let Some(last) = &last
+            && let Err(err) = tagger.tag(zone, last.items(), last.tracks()).await
+        {
+            logging::error(&err.context("tagging queue items"));
+        }
+
+        tokio::select! {
+            changed = queue_watch.changed() => { if changed.is_err() { break } }
+            changed = status_watch.changed() => { if changed.is_err() { break } }
+            changed = unavailable_watch.changed() => {
+                if changed.is_err() { break }
+
+                // tracks already sent need marking, which a delta can't do
+                last = None;
+            }
+        }
+    }
+}
+
+async fn queue_state(services: &Services, zone: &Zone, last: &mut Option<LastQueue>) -> Result<QueueState> {
+    // resolving tracks needs subsonic credentials, borrow them from whoever
+    // last connected to the zone, same as scrobbling does
+    let auth = zone.wait_auth().await;
+    let subsonic = services.subsonic.with_auth(auth.clone());
+    let podcasts = services.podcasts.as_ref().map(|podcasts| podcasts.with_auth(auth));
+
+    let resolver = Resolver::new(
+        &subsonic,
+        podcasts.as_ref(),
+        &services.stations,
+        &services.servers,
+        &services.external,
+        zone.rate_proxy.as_ref(),
+        services.resolve_concurrency,
+    );
+
+    let (queue, delta) = events::queue_event(zone, &resolver, last).await?;
+
+    Ok(QueueState {
+        queue: Arc::new(queue),
+        delta: delta.map(Arc::new),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(mpd: bool, subsonic: bool) -> ConnectionEvent {
+        ConnectionEvent { mpd, subsonic, degraded: !mpd || !subsonic }
+    }
+
+    #[test]
+    fn connection_recovery_is_sent() {
+        let mut prev = None;
+
+        assert!(update_connection("test", &mut prev, event(true, true)));
+        assert!(update_connection("test", &mut prev, event(false, true)));
+        assert!(!update_connection("test", &mut prev, event(false, true)));
+
+        assert!(update_connection("test", &mut prev, event(true, true)));
+        assert_eq!(prev.as_deref(), Some(&event(true, true)));
+    }
+}
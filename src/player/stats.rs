@@ -0,0 +1,175 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use axum::Json;
+use axum::extract::{Query, State};
+use jiff::Timestamp;
+use jiff::tz::TimeZone;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+
+use crate::subsonic::AuthParams;
+
+use super::{Ctx, HttpError, auth_error};
+use super::history::{History, HistoryEntry};
+use super::types::AirsonicTrackId;
+
+pub const DEFAULT_DAYS: u32 = 30;
+
+// entries in each top list
+const TOP_COUNT: usize = 10;
+
+/// listening over the last however many days, from the play history
+#[derive(Debug, Serialize)]
+pub struct Stats {
+    days: u32,
+    plays: usize,
+    /// seconds
+    listened: f64,
+    /// days with any listening, in local time, oldest first
+    daily: Vec<Period>,
+    /// iso weeks with any listening, oldest first
+    weekly: Vec<Period>,
+    top_artists: Vec<TopArtist>,
+    top_tracks: Vec<TopTrack>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Period {
+    /// 2024-06-30 for days, 2024-W26 for weeks
+    period: String,
+    plays: usize,
+    listened: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TopArtist {
+    artist: String,
+    plays: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TopTrack {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    track: Option<AirsonicTrackId>,
+    title: Option<String>,
+    artist: Option<String>,
+    plays: usize,
+}
+
+pub async fn stats_for(history: &History, user: &str, days: u32) -> Result<Stats> {
+    let since = SystemTime::now() - Duration::from_secs(u64::from(days) * 24 * 60 * 60);
+    let since = since.duration_since(UNIX_EPOCH)?.as_millis() as u64;
+
+    let entries = history.since(user, since).await;
+    Ok(compute(&entries, days))
+}
+
+fn compute(entries: &[HistoryEntry], days: u32) -> Stats {
+    let tz = TimeZone::system();
+
+    let mut daily = BTreeMap::<String, (usize, f64)>::new();
+    let mut weekly = BTreeMap::<String, (usize, f64)>::new();
+    let mut artists = HashMap::<&str, usize>::new();
+    let mut tracks = HashMap::<String, (&HistoryEntry, usize)>::new();
+
+    for entry in entries {
+        if let Ok(played_at) = Timestamp::from_millisecond(entry.played_at as i64) {
+            let date = played_at.to_zoned(tz.clone()).date();
+            let week = date.iso_week_date();
+
+            for (periods, period) in [
+                (&mut daily, date.to_string()),
+                (&mut weekly, format!("{}-W{:02}", week.year(), week.week())),
+            ] {
+                let (plays, listened) = periods.entry(period).or_default();
+                *plays += 1;
+                *listened += entry.listened;
+            }
+        }
+
+        if let Some(artist) = &entry.artist {
+            *artists.entry(artist).or_default() += 1;
+        }
+
+        // tracks subsonic doesn't know go by their tags instead
+        let key = match &entry.track {
+            Some(id) => format!("id:{}", String::from(id.clone())),
+            None => format!("tags:{:?}:{:?}", entry.artist, entry.title),
+        };
+
+        tracks.entry(key).or_insert((entry, 0)).1 += 1;
+    }
+
+    let periods = |periods: BTreeMap<String, (usize, f64)>| {
+        periods.into_iter()
+            .map(|(period, (plays, listened))| Period { period, plays, listened })
+            .collect()
+    };
+
+    let mut top_artists = artists.into_iter()
+        .map(|(artist, plays)| TopArtist { artist: artist.to_owned(), plays })
+        .collect::<Vec<_>>();
+
+    top_artists.sort_by(|a, b| b.plays.cmp(&a.plays).then_with(|| a.artist.cmp(&b.artist)));
+    top_artists.truncate(TOP_COUNT);
+
+    let mut top_tracks = tracks.into_values()
+        .map(|(entry, plays)| TopTrack {
+            track: entry.track.clone(),
+            title: entry.title.clone(),
+            artist: entry.artist.clone(),
+            plays,
+        })
+        .collect::<Vec<_>>();
+
+    top_tracks.sort_by(|a, b| b.plays.cmp(&a.plays).then_with(|| a.title.cmp(&b.title)));
+    top_tracks.truncate(TOP_COUNT);
+
+    Stats {
+        days,
+        plays: entries.len(),
+        listened: entries.iter().map(|entry| entry.listened).sum(),
+        daily: periods(daily),
+        weekly: periods(weekly),
+        top_artists,
+        top_tracks,
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StatsParams {
+    days: Option<u32>,
+    #[serde(flatten)]
+    auth: AuthParams,
+}
+
+// the same stats as GetStats, for dashboards and the like which would
+// rather not speak the websocket protocol
+pub async fn stats(
+    ctx: State<Ctx>,
+    params: Query<StatsParams>,
+) -> Result<Json<Stats>, HttpError> {
+    let Query(StatsParams { days, auth }) = params;
+
+    let Some(history) = &ctx.services.history else {
+        return Err(StatusCode::NOT_FOUND.into());
+    };
+
+    let subsonic = ctx.services.subsonic.authenticate(Arc::new(auth)).await
+        .map_err(auth_error)?;
+
+    let Some(user) = subsonic.auth().username() else {
+        return Err(StatusCode::BAD_REQUEST.into());
+    };
+
+    let stats = stats_for(history, user, days.unwrap_or(DEFAULT_DAYS)).await
+        .map_err(|err| {
+            log::warn!("computing stats: {err:?}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    Ok(Json(stats))
+}
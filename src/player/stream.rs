@@ -0,0 +1,98 @@
+use async_stream::stream;
+use axum::body::Body;
+use axum::extract::{Path, Query, State};
+use axum::http::{HeaderMap, header};
+use axum::response::Response;
+use reqwest::StatusCode;
+use serde::Deserialize;
+use tokio::sync::mpsc;
+
+use crate::subsonic::StreamQuality;
+use crate::subsonic::types::TrackId;
+
+use super::{Ctx, HttpError};
+
+// chunks read from subsonic ahead of mpd, so that a flaky connection to
+// subsonic has a while to recover before mpd runs dry
+const READ_AHEAD_CHUNKS: usize = 256;
+
+// response headers mpd needs to play and seek
+const RELAYED_HEADERS: &[header::HeaderName] = &[
+    header::CONTENT_TYPE,
+    header::CONTENT_LENGTH,
+    header::CONTENT_RANGE,
+    header::ACCEPT_RANGES,
+];
+
+#[derive(Debug, Deserialize)]
+pub struct StreamParams {
+    key: String,
+    format: Option<String>,
+    #[serde(rename = "maxBitRate")]
+    max_bit_rate: Option<u32>,
+}
+
+// relays a track from subsonic to mpd, when STREAM_PROXY_URL is set. mpd's
+// queue gets urls with a key in place of credentials, see
+// subsonic::StreamProxy
+pub async fn stream(
+    ctx: State<Ctx>,
+    track_id: Path<String>,
+    params: Query<StreamParams>,
+    headers: HeaderMap,
+) -> Result<Response, HttpError> {
+    let Query(StreamParams { key, format, max_bit_rate }) = params;
+    let id = TrackId(track_id.0);
+    let quality = StreamQuality { format, max_bit_rate };
+    let range = headers.get(header::RANGE);
+
+    let response = ctx.services.subsonic.proxy_stream(&key, &id, &quality, range).await
+        .map_err(|err| {
+            log::warn!("relaying stream of {}: {err:?}", id.0);
+            StatusCode::BAD_GATEWAY
+        })?;
+
+    let Some(mut response) = response else {
+        return Err(HttpError::new(StatusCode::UNAUTHORIZED, "unauthorized", "unknown or expired stream key"));
+    };
+
+    let mut relayed = Response::builder().status(response.status());
+
+    for name in RELAYED_HEADERS {
+        if let Some(value) = response.headers().get(name) {
+            relayed = relayed.header(name, value);
+        }
+    }
+
+    // reads carry on while mpd is busy with what it already has, up to the
+    // read ahead, and stop once mpd hangs up
+    let (tx, mut rx) = mpsc::channel(READ_AHEAD_CHUNKS);
+
+    tokio::spawn(async move {
+        loop {
+            let chunk = match response.chunk().await {
+                Ok(Some(chunk)) => Ok(chunk),
+                Ok(None) => break,
+                Err(err) => Err(err),
+            };
+
+            let failed = chunk.is_err();
+
+            if tx.send(chunk).await.is_err() || failed {
+                break;
+            }
+        }
+    });
+
+    let body = Body::from_stream(stream! {
+        while let Some(chunk) = rx.recv().await {
+            yield chunk;
+        }
+    });
+
+    relayed.body(body)
+        .map_err(|err| {
+            log::warn!("relaying stream of {}: {err}", id.0);
+            StatusCode::INTERNAL_SERVER_ERROR.into()
+        })
+}
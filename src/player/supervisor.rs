@@ -0,0 +1,181 @@
+use std::any::Any;
+use std::cmp;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::Json;
+use axum::extract::State;
+use axum::response::IntoResponse;
+use reqwest::StatusCode;
+use serde::Serialize;
+use tokio::task::JoinHandle;
+
+use super::Ctx;
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// owns sonicast's long lived background tasks, restarting any which panic
+/// or return so that one failure doesn't quietly take a feature down for
+/// good. tasks are named, with one running per name, and their health is
+/// served on /readyz
+#[derive(Default)]
+pub struct Supervisor {
+    tasks: Mutex<BTreeMap<String, TaskHealth>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TaskHealth {
+    state: TaskState,
+    restarts: u32,
+    /// why the task last failed, if it ever has
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_failure: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum TaskState {
+    Running,
+    /// failed, and waiting out its backoff before starting again
+    Restarting,
+    /// stopped for good, which only happens as the runtime shuts down
+    Finished,
+}
+
+impl Supervisor {
+    /// runs the future made by task, and makes another each time one panics
+    /// or returns, since tasks are meant to run as long as sonicast does
+    pub fn spawn<F, Fut>(self: &Arc<Self>, name: impl Into<String>, task: F)
+    where
+        F: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let name = name.into();
+
+        {
+            let mut tasks = self.tasks.lock().unwrap();
+
+            if tasks.get(&name).is_some_and(|health| health.state != TaskState::Finished) {
+                log::warn!("task {name} is already running, not spawning another");
+                return;
+            }
+
+            tasks.insert(name.clone(), TaskHealth {
+                state: TaskState::Running,
+                restarts: 0,
+                last_failure: None,
+            });
+        }
+
+        tokio::task::spawn(self.clone().supervise(name, task));
+    }
+
+    pub fn health(&self) -> BTreeMap<String, TaskHealth> {
+        self.tasks.lock().unwrap().clone()
+    }
+
+    async fn supervise<F, Fut>(self: Arc<Self>, name: String, mut task: F)
+    where
+        F: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let mut backoff = MIN_BACKOFF;
+
+        loop {
+            let started = Instant::now();
+
+            let reason = match tokio::task::spawn(task()).await {
+                Ok(()) => "returned unexpectedly".to_string(),
+                Err(err) if err.is_panic() => panic_message(err.into_panic()),
+                // only happens when the runtime is shutting down
+                Err(_) => {
+                    self.update(&name, |health| health.state = TaskState::Finished);
+                    return;
+                }
+            };
+
+            // a task which ran a good while before failing was healthy, so
+            // it starts backing off afresh
+            if started.elapsed() > MAX_BACKOFF {
+                backoff = MIN_BACKOFF;
+            }
+
+            let mut restarts = 0;
+
+            self.update(&name, |health| {
+                health.state = TaskState::Restarting;
+                health.restarts += 1;
+                health.last_failure = Some(reason.clone());
+                restarts = health.restarts;
+            });
+
+            tracing::warn!(
+                task = name.as_str(),
+                reason = reason.as_str(),
+                restarts,
+                backoff_secs = backoff.as_secs(),
+                "task failed, restarting",
+            );
+
+            tokio::time::sleep(backoff).await;
+            backoff = cmp::min(backoff * 2, MAX_BACKOFF);
+
+            self.update(&name, |health| health.state = TaskState::Running);
+        }
+    }
+
+    fn update(&self, name: &str, f: impl FnOnce(&mut TaskHealth)) {
+        if let Some(health) = self.tasks.lock().unwrap().get_mut(name) {
+            f(health);
+        }
+    }
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "panicked".to_string()
+    }
+}
+
+/// aborts the task when dropped, for tasks belonging to a supervised task
+/// which shouldn't outlive it when it panics and is restarted
+pub struct ChildTask(JoinHandle<()>);
+
+impl ChildTask {
+    pub fn spawn(future: impl Future<Output = ()> + Send + 'static) -> Self {
+        ChildTask(tokio::task::spawn(future))
+    }
+}
+
+impl Drop for ChildTask {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+#[derive(Serialize)]
+struct Readiness {
+    ready: bool,
+    tasks: BTreeMap<String, TaskHealth>,
+}
+
+/// ready while all of the background tasks are running, 503 otherwise
+pub async fn readyz(ctx: State<Ctx>) -> impl IntoResponse {
+    let tasks = ctx.services.supervisor.health();
+    let ready = tasks.values().all(|health| health.state == TaskState::Running);
+
+    let status = match ready {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE,
+    };
+
+    (status, Json(Readiness { ready, tasks }))
+}
@@ -0,0 +1,60 @@
+use std::collections::HashSet;
+
+use anyhow::Result;
+
+use crate::mpd::Command;
+use crate::mpd::types::{Id, PlaylistItem};
+
+use super::types::AirsonicTrack;
+use super::zones::Zone;
+
+/// copies titles, artists and albums of resolved tracks onto their queue
+/// items with addtagid, so the queue reads nicely in other mpd clients
+/// rather than as a list of stream urls
+#[derive(Default)]
+pub struct Tagger {
+    /// items already tagged, or tried and failed to be, so that an item
+    /// mpd won't tag isn't retried on every queue change
+    attempted: HashSet<Id>,
+}
+
+impl Tagger {
+    pub async fn tag(&mut self, zone: &Zone, items: &[PlaylistItem], tracks: &[AirsonicTrack]) -> Result<()> {
+        let ids = items.iter().map(|item| &item.id).collect::<HashSet<_>>();
+        self.attempted.retain(|id| ids.contains(id));
+
+        let mut commands = Vec::new();
+
+        for (item, track) in items.iter().zip(tracks) {
+            // mpd only allows tagging remote songs, and streams send their
+            // own titles as they go
+            if item.title.is_some()
+                || !item.file.contains("://")
+                || track.details.is_unavailable == Some(true)
+                || track.details.is_stream == Some(true)
+                || !self.attempted.insert(item.id.clone())
+            {
+                continue;
+            }
+
+            let tags = [
+                ("Title", &track.details.title),
+                ("Artist", &track.details.artist),
+                ("Album", &track.details.album),
+            ];
+
+            for (tag, value) in tags {
+                if let Some(value) = value {
+                    commands.push(Command::addtagid(&item.id, tag, value));
+                }
+            }
+        }
+
+        if commands.is_empty() {
+            return Ok(());
+        }
+
+        zone.mpd.write().await.command_list(&commands).await?;
+        Ok(())
+    }
+}
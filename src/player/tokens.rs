@@ -0,0 +1,72 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use base64::Engine;
+
+use crate::subsonic::AuthParams;
+
+// how long a token outlives the session it was issued to
+const TOKEN_TTL: Duration = Duration::from_secs(5 * 60);
+
+const TOKEN_BYTES: usize = 32;
+
+/// tokens issued to websocket sessions, which a client reconnecting after a
+/// dropped connection passes instead of its credentials, skipping the
+/// round trip to subsonic to check them again
+#[derive(Default)]
+pub struct ResumeTokens {
+    tokens: Mutex<HashMap<String, Issued>>,
+}
+
+struct Issued {
+    auth: Arc<AuthParams>,
+    guest: bool,
+    /// None while the session it was issued to is still connected
+    expires: Option<Instant>,
+}
+
+/// what a redeemed token stood for
+pub struct Redeemed {
+    pub auth: Arc<AuthParams>,
+    pub guest: bool,
+}
+
+impl ResumeTokens {
+    pub fn issue(&self, auth: Arc<AuthParams>, guest: bool) -> String {
+        let mut bytes = [0; TOKEN_BYTES];
+
+        // only fails where the os has no randomness to give
+        rustls::crypto::ring::default_provider().secure_random.fill(&mut bytes)
+            .expect("generating resume token");
+
+        let token = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(bytes);
+
+        let mut tokens = self.tokens.lock().unwrap();
+        purge(&mut tokens);
+        tokens.insert(token.clone(), Issued { auth, guest, expires: None });
+        token
+    }
+
+    /// starts the token's expiry once its session has ended
+    pub fn release(&self, token: &str) {
+        if let Some(issued) = self.tokens.lock().unwrap().get_mut(token) {
+            issued.expires = Some(Instant::now() + TOKEN_TTL);
+        }
+    }
+
+    /// tokens are good while their session is connected, since it may not
+    /// have noticed its connection drop yet, and for a while after
+    pub fn redeem(&self, token: &str) -> Option<Redeemed> {
+        let mut tokens = self.tokens.lock().unwrap();
+        purge(&mut tokens);
+
+        let issued = tokens.get(token)?;
+        Some(Redeemed { auth: issued.auth.clone(), guest: issued.guest })
+    }
+}
+
+fn purge(tokens: &mut HashMap<String, Issued>) {
+    let now = Instant::now();
+    tokens.retain(|_, issued| issued.expires.is_none_or(|expires| expires > now));
+}
@@ -1,9 +1,12 @@
 use derive_more::From;
 use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::mpd::types::PlaylistItem;
 
 use crate::{podcasts::PodcastEpisode, subsonic::types::{RadioId, RadioStation, Track, TrackDetails, TrackId}};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AirsonicTrack {
     pub id: AirsonicTrackId,
     #[serde(flatten)]
@@ -28,6 +31,9 @@ impl From<RadioStation> for AirsonicTrack {
             details: TrackDetails {
                 title: Some(station.name.clone()),
                 stream_url: Some(station.stream_url),
+                music_brainz_id: None,
+                user_rating: None,
+                played: None,
                 album: None,
                 track: None,
                 album_id: None,
@@ -46,6 +52,91 @@ impl From<RadioStation> for AirsonicTrack {
     }
 }
 
+impl AirsonicTrack {
+    /// placeholder for a queue item which could not be resolved, so that
+    /// one bad item doesn't prevent the rest of the queue from loading
+    pub fn unavailable(id: AirsonicTrackId, title: Option<String>) -> Self {
+        AirsonicTrack {
+            id,
+            details: TrackDetails {
+                title,
+                is_unavailable: Some(true),
+                artist: None,
+                album: None,
+                duration: None,
+                cover_art: None,
+                is_podcast: None,
+                album_id: None,
+                starred: None,
+                track: None,
+                artists: vec![],
+                is_stream: None,
+                play_count: None,
+                replay_gain: None,
+                stream_url: None,
+                music_brainz_id: None,
+                user_rating: None,
+                played: None,
+            }
+        }
+    }
+
+    /// a file from mpd's database, described by its tags
+    pub fn library(item: &PlaylistItem) -> Self {
+        AirsonicTrack {
+            id: AirsonicTrackId::Library(item.file.clone()),
+            details: TrackDetails {
+                title: item.title.clone().or_else(|| Some(item.file.clone())),
+                artist: item.artist.clone(),
+                album: item.album.clone(),
+                duration: item.duration.map(|duration| duration.0),
+                cover_art: None,
+                is_podcast: None,
+                album_id: None,
+                starred: None,
+                track: None,
+                artists: vec![],
+                is_stream: None,
+                is_unavailable: None,
+                play_count: None,
+                replay_gain: None,
+                stream_url: None,
+                music_brainz_id: None,
+                user_rating: None,
+                played: None,
+            }
+        }
+    }
+
+    /// a track from one of the external sources, which only have what
+    /// tags mpd could read for metadata
+    pub fn external(id: AirsonicTrackId, title: Option<String>, stream_url: Url) -> Self {
+        AirsonicTrack {
+            id,
+            details: TrackDetails {
+                title,
+                stream_url: Some(stream_url),
+                artist: None,
+                album: None,
+                duration: None,
+                cover_art: None,
+                is_podcast: None,
+                album_id: None,
+                starred: None,
+                track: None,
+                artists: vec![],
+                is_stream: None,
+                is_unavailable: None,
+                play_count: None,
+                replay_gain: None,
+                music_brainz_id: None,
+                user_rating: None,
+                played: None,
+            }
+        }
+    }
+}
+
 impl From<PodcastEpisode> for AirsonicTrack {
     fn from(episode: PodcastEpisode) -> Self {
         AirsonicTrack {
@@ -66,6 +157,9 @@ impl From<PodcastEpisode> for AirsonicTrack {
                 play_count: None,
                 replay_gain: None,
                 stream_url: None,
+                music_brainz_id: None,
+                user_rating: None,
+                played: None,
             }
         }
     }
@@ -76,12 +170,38 @@ impl From<PodcastEpisode> for AirsonicTrack {
 pub enum AirsonicTrackId {
     Track(#[from] TrackId),
     Radio(#[from] RadioId),
+    /// a track or radio station on one of the extra servers, see Servers
+    Server(String, Box<AirsonicTrackId>),
+    /// path of a file in mpd's own database
+    #[from(skip)]
+    Library(String),
 }
 
 const RADIO_PREFIX: &str = "radio-";
 
+// library paths may hold anything, so this is checked for first
+const LIBRARY_PREFIX: &str = "library-";
+
+// separates the server name from the id on that server. subsonic ids are
+// generally alphanumeric with dashes, so this won't turn up in them
+const SERVER_SEPARATOR: char = ':';
+
+impl AirsonicTrackId {
+    pub fn on_server(self, server: &str) -> Self {
+        AirsonicTrackId::Server(server.to_owned(), Box::new(self))
+    }
+}
+
 impl From<String> for AirsonicTrackId {
     fn from(mut value: String) -> Self {
+        if let Some(path) = value.strip_prefix(LIBRARY_PREFIX) {
+            return AirsonicTrackId::Library(path.to_owned());
+        }
+
+        if let Some((server, id)) = value.split_once(SERVER_SEPARATOR) {
+            return AirsonicTrackId::from(id.to_owned()).on_server(server);
+        }
+
         if value.starts_with(RADIO_PREFIX) {
             value.drain(0..RADIO_PREFIX.len());
             return AirsonicTrackId::Radio(RadioId(value));
@@ -91,11 +211,13 @@ impl From<String> for AirsonicTrackId {
     }
 }
 
-impl Into<String> for AirsonicTrackId {
-    fn into(self) -> String {
-        match self {
+impl From<AirsonicTrackId> for String {
+    fn from(id: AirsonicTrackId) -> Self {
+        match id {
             AirsonicTrackId::Track(TrackId(id)) => id,
             AirsonicTrackId::Radio(RadioId(id)) => format!("{RADIO_PREFIX}{id}"),
+            AirsonicTrackId::Server(server, id) => format!("{server}{SERVER_SEPARATOR}{}", String::from(*id)),
+            AirsonicTrackId::Library(path) => format!("{LIBRARY_PREFIX}{path}"),
         }
     }
 }
@@ -0,0 +1,65 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+
+use crate::mpd::types::PlaybackState;
+
+use super::Services;
+use super::zones::Zone;
+
+// watches for mpd failing to play a queue item, such as when subsonic has
+// lost the file or a radio station is down, and marks it unavailable in
+// queue events rather than leaving clients to wonder why playback stopped
+pub async fn task(services: Services, zone: Arc<Zone>) {
+    let mut watch = zone.events.subscribe_status();
+
+    loop {
+        if let Err(err) = check(&services, &zone).await {
+            log::warn!("checking for unavailable tracks in zone {}: {err:?}", zone.name);
+        }
+
+        let Ok(_) = watch.changed().await else { break };
+    }
+}
+
+async fn check(services: &Services, zone: &Zone) -> Result<()> {
+    let mpd = zone.mpd.read().await;
+    let status = mpd.status().await?;
+
+    let Some(error) = status.error else { return Ok(()) };
+
+    // kept for playback events, so that the next failure shows up as a
+    // change in status
+    zone.playback_error.send_replace(Some(error.clone()));
+    mpd.clearerror().await?;
+
+    let queue = mpd.playlistinfo().await?;
+
+    // mpd's errors name the file which failed, which isn't always the
+    // current song, eg. when decoding the next one ahead of time
+    let failed = queue.items.iter()
+        .find(|item| error.contains(&item.file))
+        .map(|item| item.id.clone())
+        .or_else(|| status.song_id.clone());
+
+    let Some(failed) = failed else { return Ok(()) };
+
+    log::warn!("zone {}: queue item {} unavailable: {error}", zone.name, failed.as_str());
+
+    zone.unavailable.send_modify(|unavailable| {
+        unavailable.retain(|id| queue.items.iter().any(|item| &item.id == id));
+        unavailable.insert(failed.clone());
+    });
+
+    // mpd stops when the current song fails, carry on with the next
+    if services.skip_unavailable
+        && status.state == PlaybackState::Stop
+        && status.song_id.as_ref() == Some(&failed)
+        && let Some(next) = status.song.map(|pos| pos + 1)
+        && next < queue.items.len()
+    {
+        mpd.playpos(next).await?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,393 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use anyhow::{Context, Result};
+use axum::Router;
+use axum::extract::{Path, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{any, get, post};
+use thiserror::Error;
+use url::Url;
+
+use crate::mpd::types::PlaybackState;
+
+use super::zones::Zone;
+use super::Ctx;
+
+mod description;
+mod ssdp;
+mod xml;
+
+pub use ssdp::task;
+
+use description::Service;
+
+#[derive(Clone)]
+pub struct Config {
+    /// where controllers can reach us, advertised in ssdp announcements
+    pub base_url: Url,
+}
+
+/// a media renderer device for each room, driving the room's default zone
+pub struct Upnp {
+    config: Config,
+    rooms: Mutex<HashMap<String, RendererState>>,
+}
+
+// what controllers told us that mpd doesn't keep for us
+#[derive(Default, Clone)]
+struct RendererState {
+    uri: String,
+    metadata: String,
+    /// volume to restore on unmute
+    muted_volume: Option<usize>,
+}
+
+impl Upnp {
+    pub fn new(config: Config) -> Self {
+        Upnp {
+            config,
+            rooms: Default::default(),
+        }
+    }
+
+    fn state(&self, room: &str) -> RendererState {
+        self.rooms.lock().unwrap().get(room).cloned().unwrap_or_default()
+    }
+
+    fn update(&self, room: &str, f: impl FnOnce(&mut RendererState)) {
+        f(self.rooms.lock().unwrap().entry(room.to_string()).or_default())
+    }
+}
+
+pub fn router() -> Router<Ctx> {
+    Router::new()
+        .route("/upnp/{room}/description.xml", get(device_description))
+        .route("/upnp/{room}/{service}/scpd.xml", get(service_description))
+        .route("/upnp/{room}/{service}/control", post(control))
+        // no eventing, controllers fall back to polling
+        .route("/upnp/{room}/{service}/event", any(|| async { StatusCode::NOT_IMPLEMENTED }))
+}
+
+fn upnp(ctx: &Ctx) -> Result<&Upnp, StatusCode> {
+    ctx.upnp.as_ref().ok_or(StatusCode::NOT_FOUND)
+}
+
+fn xml_response(body: String) -> Response {
+    ([(header::CONTENT_TYPE, "text/xml; charset=\"utf-8\"")], body).into_response()
+}
+
+async fn device_description(ctx: State<Ctx>, Path(room): Path<String>) -> Result<Response, StatusCode> {
+    upnp(&ctx)?;
+    ctx.rooms.get(&room).map_err(|_| StatusCode::NOT_FOUND)?;
+    Ok(xml_response(description::device(&room)))
+}
+
+async fn service_description(ctx: State<Ctx>, Path((_room, service)): Path<(String, String)>) -> Result<Response, StatusCode> {
+    upnp(&ctx)?;
+    let service = Service::from_name(&service).ok_or(StatusCode::NOT_FOUND)?;
+    Ok(xml_response(description::scpd(service)))
+}
+
+#[derive(Debug, Error)]
+enum UpnpError {
+    #[error("Invalid Action")]
+    InvalidAction,
+    #[error("Invalid Args")]
+    InvalidArgs,
+    #[error("Action Failed")]
+    ActionFailed(#[from] anyhow::Error),
+}
+
+impl UpnpError {
+    fn code(&self) -> u16 {
+        match self {
+            UpnpError::InvalidAction => 401,
+            UpnpError::InvalidArgs => 402,
+            UpnpError::ActionFailed(_) => 501,
+        }
+    }
+}
+
+type Args = Vec<(&'static str, String)>;
+
+async fn control(
+    ctx: State<Ctx>,
+    Path((room, service)): Path<(String, String)>,
+    headers: HeaderMap,
+    body: String,
+) -> Result<Response, StatusCode> {
+    let upnp = upnp(&ctx)?;
+    let service = Service::from_name(&service).ok_or(StatusCode::NOT_FOUND)?;
+    let zone = ctx.rooms.get(&room).map_err(|_| StatusCode::NOT_FOUND)?.default_zone();
+
+    // SOAPACTION: "urn:schemas-upnp-org:service:AVTransport:1#Play"
+    let action = headers.get("soapaction")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim_matches('"').rsplit_once('#'))
+        .map(|(_, action)| action.to_string())
+        .ok_or(StatusCode::BAD_REQUEST)?;
+
+    let result = {
+        // shared, like any other single command
+        let _lock = zone.command_lock.read().await;
+
+        match service {
+            Service::AVTransport => av_transport(upnp, &room, zone, &action, &body).await,
+            Service::RenderingControl => rendering_control(upnp, &room, zone, &action, &body).await,
+            Service::ConnectionManager => connection_manager(&action),
+        }
+    };
+
+    match result {
+        Ok(args) => Ok(xml_response(xml::soap_response(service.urn(), &action, &args))),
+        Err(err) => {
+            log::warn!("upnp {action} on {room}: {err:?}");
+            let body = xml::soap_fault(err.code(), &err.to_string());
+            Ok((StatusCode::INTERNAL_SERVER_ERROR, xml_response(body)).into_response())
+        }
+    }
+}
+
+fn arg(body: &str, name: &str) -> Result<String, UpnpError> {
+    xml::element(body, name).ok_or(UpnpError::InvalidArgs)
+}
+
+async fn av_transport(upnp: &Upnp, room: &str, zone: &Zone, action: &str, body: &str) -> Result<Args, UpnpError> {
+    let mpd = zone.mpd.write().await;
+
+    match action {
+        "SetAVTransportURI" => {
+            let uri = arg(body, "CurrentURI")?;
+            let metadata = arg(body, "CurrentURIMetaData").unwrap_or_default();
+
+            // controllers aren't authenticated, and mpd lets sonicast play
+            // local files over its unix socket
+            match Url::parse(&uri) {
+                Ok(url) if matches!(url.scheme(), "http" | "https") => {}
+                _ => return Err(UpnpError::InvalidArgs),
+            }
+
+            mpd.clear().await?;
+            mpd.addid(&uri).await?;
+
+            upnp.update(room, |state| {
+                state.uri = uri;
+                state.metadata = metadata;
+            });
+
+            Ok(vec![])
+        }
+        "Play" => {
+            mpd.play().await?;
+            Ok(vec![])
+        }
+        "Pause" => {
+            // mpd's pause toggles
+            if mpd.status().await?.state == PlaybackState::Play {
+                mpd.pause().await?;
+            }
+            Ok(vec![])
+        }
+        "Stop" => {
+            mpd.stop().await?;
+            Ok(vec![])
+        }
+        "Next" => {
+            mpd.next().await?;
+            Ok(vec![])
+        }
+        "Previous" => {
+            mpd.previous().await?;
+            Ok(vec![])
+        }
+        "Seek" => {
+            let unit = arg(body, "Unit")?;
+            let target = arg(body, "Target")?;
+
+            if unit != "REL_TIME" && unit != "ABS_TIME" {
+                return Err(UpnpError::InvalidArgs);
+            }
+
+            let position = parse_time(&target).ok_or(UpnpError::InvalidArgs)?;
+            mpd.seekcur(position).await?;
+            Ok(vec![])
+        }
+        "GetTransportInfo" => {
+            let status = mpd.status().await?;
+
+            let state = match status.state {
+                _ if status.playlist_length == 0 => "NO_MEDIA_PRESENT",
+                PlaybackState::Play => "PLAYING",
+                PlaybackState::Pause => "PAUSED_PLAYBACK",
+                PlaybackState::Stop => "STOPPED",
+            };
+
+            Ok(vec![
+                ("CurrentTransportState", state.to_string()),
+                ("CurrentTransportStatus", "OK".to_string()),
+                ("CurrentSpeed", "1".to_string()),
+            ])
+        }
+        "GetPositionInfo" => {
+            let status = mpd.status().await?;
+            let state = upnp.state(room);
+
+            let uri = match &status.song_id {
+                Some(id) => mpd.playlistid(id).await?.file,
+                None => String::new(),
+            };
+
+            // metadata only describes the uri the controller set
+            let metadata = if uri == state.uri { state.metadata } else { String::new() };
+
+            let duration = format_time(status.duration.map(|secs| secs.0).unwrap_or_default());
+            let position = format_time(status.elapsed.map(|secs| secs.0).unwrap_or_default());
+
+            Ok(vec![
+                ("Track", status.song.map(|pos| pos + 1).unwrap_or_default().to_string()),
+                ("TrackDuration", duration),
+                ("TrackMetaData", metadata),
+                ("TrackURI", uri),
+                ("RelTime", position.clone()),
+                ("AbsTime", position),
+                ("RelCount", i32::MAX.to_string()),
+                ("AbsCount", i32::MAX.to_string()),
+            ])
+        }
+        "GetMediaInfo" => {
+            let status = mpd.status().await?;
+            let state = upnp.state(room);
+
+            Ok(vec![
+                ("NrTracks", status.playlist_length.to_string()),
+                ("MediaDuration", format_time(status.duration.map(|secs| secs.0).unwrap_or_default())),
+                ("CurrentURI", state.uri),
+                ("CurrentURIMetaData", state.metadata),
+                ("NextURI", String::new()),
+                ("NextURIMetaData", String::new()),
+                ("PlayMedium", "NETWORK".to_string()),
+                ("RecordMedium", "NOT_IMPLEMENTED".to_string()),
+                ("WriteStatus", "NOT_IMPLEMENTED".to_string()),
+            ])
+        }
+        "GetTransportSettings" => Ok(vec![
+            ("PlayMode", "NORMAL".to_string()),
+            ("RecQualityMode", "NOT_IMPLEMENTED".to_string()),
+        ]),
+        "GetDeviceCapabilities" => Ok(vec![
+            ("PlayMedia", "NETWORK".to_string()),
+            ("RecMedia", "NOT_IMPLEMENTED".to_string()),
+            ("RecQualityModes", "NOT_IMPLEMENTED".to_string()),
+        ]),
+        "GetCurrentTransportActions" => Ok(vec![
+            ("Actions", "Play,Pause,Stop,Seek,Next,Previous".to_string()),
+        ]),
+        _ => Err(UpnpError::InvalidAction),
+    }
+}
+
+async fn rendering_control(upnp: &Upnp, room: &str, zone: &Zone, action: &str, body: &str) -> Result<Args, UpnpError> {
+    let mpd = zone.mpd.write().await;
+
+    match action {
+        "GetVolume" => {
+            let volume = mpd.status().await?.volume.unwrap_or(100);
+            let volume = zone.volume_limits.borrow().client_volume(volume);
+            Ok(vec![("CurrentVolume", ((volume * 100.0).round() as usize).to_string())])
+        }
+        "SetVolume" => {
+            let volume = arg(body, "DesiredVolume")?.parse::<usize>()
+                .map_err(|_| UpnpError::InvalidArgs)?;
+
+            let volume = zone.volume_limits.borrow().mpd_volume(volume as f64 / 100.0);
+            mpd.setvol(volume).await?;
+            upnp.update(room, |state| state.muted_volume = None);
+            Ok(vec![])
+        }
+        "GetMute" => {
+            let muted = upnp.state(room).muted_volume.is_some();
+            Ok(vec![("CurrentMute", if muted { "1" } else { "0" }.to_string())])
+        }
+        "SetMute" => {
+            let mute = matches!(arg(body, "DesiredMute")?.as_str(), "1" | "true");
+            let muted_volume = upnp.state(room).muted_volume;
+
+            // mpd has no mute, so this zeroes the volume and remembers it
+            match (mute, muted_volume) {
+                (true, None) => {
+                    let volume = mpd.status().await?.volume.unwrap_or(100);
+                    mpd.setvol(0).await?;
+                    upnp.update(room, |state| state.muted_volume = Some(volume));
+                }
+                (false, Some(volume)) => {
+                    mpd.setvol(volume).await?;
+                    upnp.update(room, |state| state.muted_volume = None);
+                }
+                _ => {}
+            }
+
+            Ok(vec![])
+        }
+        _ => Err(UpnpError::InvalidAction),
+    }
+}
+
+fn connection_manager(action: &str) -> Result<Args, UpnpError> {
+    match action {
+        "GetProtocolInfo" => Ok(vec![
+            ("Source", String::new()),
+            // mpd decides what it can play
+            ("Sink", "http-get:*:*:*".to_string()),
+        ]),
+        "GetCurrentConnectionIDs" => Ok(vec![
+            ("ConnectionIDs", "0".to_string()),
+        ]),
+        "GetCurrentConnectionInfo" => Ok(vec![
+            ("RcsID", "0".to_string()),
+            ("AVTransportID", "0".to_string()),
+            ("ProtocolInfo", String::new()),
+            ("PeerConnectionManager", String::new()),
+            ("PeerConnectionID", "-1".to_string()),
+            ("Direction", "Input".to_string()),
+            ("Status", "OK".to_string()),
+        ]),
+        _ => Err(UpnpError::InvalidAction),
+    }
+}
+
+// H+:MM:SS[.F+]
+fn parse_time(time: &str) -> Option<f64> {
+    let mut secs = 0.0;
+
+    for part in time.split(':') {
+        secs = secs * 60.0 + part.parse::<f64>().ok()?;
+    }
+
+    Some(secs)
+}
+
+fn format_time(secs: f64) -> String {
+    let secs = secs.max(0.0) as u64;
+    format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+// stable across restarts so controllers remember the renderer
+fn udn(room: &str) -> String {
+    // fnv-1a, twice over with different offsets for 128 bits
+    let hash = |offset: u64| {
+        room.bytes().fold(offset, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3))
+    };
+
+    let hi = hash(0xcbf29ce484222325);
+    let lo = hash(0x84222325cbf29ce4);
+
+    format!("uuid:{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+        hi >> 32, (hi >> 16) & 0xffff, hi & 0xffff, lo >> 48, lo & 0xffff_ffff_ffff)
+}
+
+fn description_url(config: &Config, room: &str) -> Result<Url> {
+    config.base_url.join(&format!("upnp/{room}/description.xml"))
+        .context("building description url")
+}
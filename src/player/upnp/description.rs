@@ -0,0 +1,223 @@
+use super::xml::escape;
+
+pub const DEVICE_TYPE: &str = "urn:schemas-upnp-org:device:MediaRenderer:1";
+
+#[derive(Debug, Clone, Copy)]
+#[allow(clippy::enum_variant_names)]
+pub enum Service {
+    AVTransport,
+    RenderingControl,
+    ConnectionManager,
+}
+
+pub const SERVICES: [Service; 3] = [
+    Service::AVTransport,
+    Service::RenderingControl,
+    Service::ConnectionManager,
+];
+
+// (name, is output, related state variable)
+type Argument = (&'static str, bool, &'static str);
+
+impl Service {
+    pub fn from_name(name: &str) -> Option<Service> {
+        SERVICES.into_iter().find(|service| service.name() == name)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Service::AVTransport => "AVTransport",
+            Service::RenderingControl => "RenderingControl",
+            Service::ConnectionManager => "ConnectionManager",
+        }
+    }
+
+    pub fn urn(self) -> &'static str {
+        match self {
+            Service::AVTransport => "urn:schemas-upnp-org:service:AVTransport:1",
+            Service::RenderingControl => "urn:schemas-upnp-org:service:RenderingControl:1",
+            Service::ConnectionManager => "urn:schemas-upnp-org:service:ConnectionManager:1",
+        }
+    }
+
+    fn actions(self) -> &'static [(&'static str, &'static [Argument])] {
+        const INSTANCE: Argument = ("InstanceID", false, "A_ARG_TYPE_InstanceID");
+        const CHANNEL: Argument = ("Channel", false, "A_ARG_TYPE_Channel");
+
+        match self {
+            Service::AVTransport => &[
+                ("SetAVTransportURI", &[
+                    INSTANCE,
+                    ("CurrentURI", false, "AVTransportURI"),
+                    ("CurrentURIMetaData", false, "AVTransportURIMetaData"),
+                ]),
+                ("GetMediaInfo", &[
+                    INSTANCE,
+                    ("NrTracks", true, "NumberOfTracks"),
+                    ("MediaDuration", true, "CurrentMediaDuration"),
+                    ("CurrentURI", true, "AVTransportURI"),
+                    ("CurrentURIMetaData", true, "AVTransportURIMetaData"),
+                    ("NextURI", true, "NextAVTransportURI"),
+                    ("NextURIMetaData", true, "NextAVTransportURIMetaData"),
+                    ("PlayMedium", true, "PlaybackStorageMedium"),
+                    ("RecordMedium", true, "RecordStorageMedium"),
+                    ("WriteStatus", true, "RecordMediumWriteStatus"),
+                ]),
+                ("GetTransportInfo", &[
+                    INSTANCE,
+                    ("CurrentTransportState", true, "TransportState"),
+                    ("CurrentTransportStatus", true, "TransportStatus"),
+                    ("CurrentSpeed", true, "TransportPlaySpeed"),
+                ]),
+                ("GetPositionInfo", &[
+                    INSTANCE,
+                    ("Track", true, "CurrentTrack"),
+                    ("TrackDuration", true, "CurrentTrackDuration"),
+                    ("TrackMetaData", true, "CurrentTrackMetaData"),
+                    ("TrackURI", true, "CurrentTrackURI"),
+                    ("RelTime", true, "RelativeTimePosition"),
+                    ("AbsTime", true, "AbsoluteTimePosition"),
+                    ("RelCount", true, "RelativeCounterPosition"),
+                    ("AbsCount", true, "AbsoluteCounterPosition"),
+                ]),
+                ("GetDeviceCapabilities", &[
+                    INSTANCE,
+                    ("PlayMedia", true, "PossiblePlaybackStorageMedia"),
+                    ("RecMedia", true, "PossibleRecordStorageMedia"),
+                    ("RecQualityModes", true, "PossibleRecordQualityModes"),
+                ]),
+                ("GetTransportSettings", &[
+                    INSTANCE,
+                    ("PlayMode", true, "CurrentPlayMode"),
+                    ("RecQualityMode", true, "CurrentRecordQualityMode"),
+                ]),
+                ("GetCurrentTransportActions", &[
+                    INSTANCE,
+                    ("Actions", true, "CurrentTransportActions"),
+                ]),
+                ("Play", &[INSTANCE, ("Speed", false, "TransportPlaySpeed")]),
+                ("Pause", &[INSTANCE]),
+                ("Stop", &[INSTANCE]),
+                ("Next", &[INSTANCE]),
+                ("Previous", &[INSTANCE]),
+                ("Seek", &[
+                    INSTANCE,
+                    ("Unit", false, "A_ARG_TYPE_SeekMode"),
+                    ("Target", false, "A_ARG_TYPE_SeekTarget"),
+                ]),
+            ],
+            Service::RenderingControl => &[
+                ("GetVolume", &[INSTANCE, CHANNEL, ("CurrentVolume", true, "Volume")]),
+                ("SetVolume", &[INSTANCE, CHANNEL, ("DesiredVolume", false, "Volume")]),
+                ("GetMute", &[INSTANCE, CHANNEL, ("CurrentMute", true, "Mute")]),
+                ("SetMute", &[INSTANCE, CHANNEL, ("DesiredMute", false, "Mute")]),
+            ],
+            Service::ConnectionManager => &[
+                ("GetProtocolInfo", &[
+                    ("Source", true, "SourceProtocolInfo"),
+                    ("Sink", true, "SinkProtocolInfo"),
+                ]),
+                ("GetCurrentConnectionIDs", &[
+                    ("ConnectionIDs", true, "CurrentConnectionIDs"),
+                ]),
+                ("GetCurrentConnectionInfo", &[
+                    ("ConnectionID", false, "A_ARG_TYPE_ConnectionID"),
+                    ("RcsID", true, "A_ARG_TYPE_RcsID"),
+                    ("AVTransportID", true, "A_ARG_TYPE_AVTransportID"),
+                    ("ProtocolInfo", true, "A_ARG_TYPE_ProtocolInfo"),
+                    ("PeerConnectionManager", true, "A_ARG_TYPE_ConnectionManager"),
+                    ("PeerConnectionID", true, "A_ARG_TYPE_ConnectionID"),
+                    ("Direction", true, "A_ARG_TYPE_Direction"),
+                    ("Status", true, "A_ARG_TYPE_ConnectionStatus"),
+                ]),
+            ],
+        }
+    }
+}
+
+// data types of state variables, anything not listed is a string
+fn data_type(variable: &str) -> &'static str {
+    match variable {
+        "A_ARG_TYPE_InstanceID" | "NumberOfTracks" | "CurrentTrack" => "ui4",
+        "RelativeCounterPosition" | "AbsoluteCounterPosition" => "i4",
+        "A_ARG_TYPE_ConnectionID" | "A_ARG_TYPE_RcsID" | "A_ARG_TYPE_AVTransportID" => "i4",
+        "Volume" => "ui2",
+        "Mute" => "boolean",
+        _ => "string",
+    }
+}
+
+pub fn device(room: &str) -> String {
+    let udn = super::udn(room);
+    let room = escape(room);
+
+    let services = SERVICES.iter()
+        .map(|service| format!(concat!(
+            "<service>",
+            "<serviceType>{urn}</serviceType>",
+            "<serviceId>urn:upnp-org:serviceId:{name}</serviceId>",
+            "<SCPDURL>/upnp/{room}/{name}/scpd.xml</SCPDURL>",
+            "<controlURL>/upnp/{room}/{name}/control</controlURL>",
+            "<eventSubURL>/upnp/{room}/{name}/event</eventSubURL>",
+            "</service>",
+        ), urn = service.urn(), name = service.name(), room = room))
+        .collect::<String>();
+
+    format!(concat!(
+        r#"<?xml version="1.0" encoding="utf-8"?>"#,
+        r#"<root xmlns="urn:schemas-upnp-org:device-1-0">"#,
+        "<specVersion><major>1</major><minor>0</minor></specVersion>",
+        "<device>",
+        "<deviceType>{device_type}</deviceType>",
+        "<friendlyName>sonicast {room}</friendlyName>",
+        "<manufacturer>sonicast</manufacturer>",
+        "<modelName>sonicast</modelName>",
+        "<modelNumber>{version}</modelNumber>",
+        "<UDN>{udn}</UDN>",
+        "<serviceList>{services}</serviceList>",
+        "</device>",
+        "</root>",
+    ), device_type = DEVICE_TYPE, room = room, version = env!("CARGO_PKG_VERSION"), udn = udn, services = services)
+}
+
+pub fn scpd(service: Service) -> String {
+    let mut variables = Vec::new();
+
+    let actions = service.actions().iter()
+        .map(|(action, args)| {
+            let args = args.iter()
+                .map(|(name, out, variable)| {
+                    if !variables.contains(variable) {
+                        variables.push(*variable);
+                    }
+
+                    format!(concat!(
+                        "<argument>",
+                        "<name>{name}</name>",
+                        "<direction>{direction}</direction>",
+                        "<relatedStateVariable>{variable}</relatedStateVariable>",
+                        "</argument>",
+                    ), name = name, direction = if *out { "out" } else { "in" }, variable = variable)
+                })
+                .collect::<String>();
+
+            format!("<action><name>{action}</name><argumentList>{args}</argumentList></action>")
+        })
+        .collect::<String>();
+
+    let variables = variables.iter()
+        .map(|variable| format!(
+            r#"<stateVariable sendEvents="no"><name>{variable}</name><dataType>{}</dataType></stateVariable>"#,
+            data_type(variable),
+        ))
+        .collect::<String>();
+
+    format!(concat!(
+        r#"<?xml version="1.0" encoding="utf-8"?>"#,
+        r#"<scpd xmlns="urn:schemas-upnp-org:service-1-0">"#,
+        "<specVersion><major>1</major><minor>0</minor></specVersion>",
+        "<actionList>{actions}</actionList>",
+        "<serviceStateTable>{variables}</serviceStateTable>",
+        "</scpd>",
+    ), actions = actions, variables = variables)
+}
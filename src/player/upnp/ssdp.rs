@@ -0,0 +1,137 @@
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::time::Duration;
+
+use anyhow::Result;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+
+use crate::player::Ctx;
+
+use super::description::{DEVICE_TYPE, SERVICES};
+
+const MULTICAST_ADDR: Ipv4Addr = Ipv4Addr::new(239, 255, 255, 250);
+const PORT: u16 = 1900;
+
+const MAX_AGE: Duration = Duration::from_secs(1800);
+
+// well within max-age, so that a missed announcement or two doesn't make
+// controllers forget us
+const NOTIFY_INTERVAL: Duration = Duration::from_secs(600);
+
+/// answers ssdp searches and periodically announces each room's renderer
+pub async fn task(ctx: Ctx) {
+    if let Err(err) = run(&ctx).await {
+        log::error!("ssdp: {err:?}");
+    }
+}
+
+async fn run(ctx: &Ctx) -> Result<()> {
+    let Some(upnp) = &ctx.upnp else { return Ok(()) };
+
+    let socket = bind()?;
+    let multicast = SocketAddr::from(SocketAddrV4::new(MULTICAST_ADDR, PORT));
+
+    let devices = ctx.rooms.names()
+        .map(|room| Ok((super::udn(room), super::description_url(&upnp.config, room)?.to_string())))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut notify = tokio::time::interval(NOTIFY_INTERVAL);
+    let mut buf = vec![0; 2048];
+
+    loop {
+        tokio::select! {
+            _ = notify.tick() => {
+                for (udn, location) in &devices {
+                    for (nt, usn) in targets(udn) {
+                        let message = format!(concat!(
+                            "NOTIFY * HTTP/1.1\r\n",
+                            "HOST: {multicast}\r\n",
+                            "CACHE-CONTROL: max-age={max_age}\r\n",
+                            "LOCATION: {location}\r\n",
+                            "NT: {nt}\r\n",
+                            "NTS: ssdp:alive\r\n",
+                            "SERVER: {server}\r\n",
+                            "USN: {usn}\r\n",
+                            "\r\n",
+                        ), multicast = multicast, max_age = MAX_AGE.as_secs(), location = location, nt = nt, server = server(), usn = usn);
+
+                        if let Err(err) = socket.send_to(message.as_bytes(), multicast).await {
+                            log::warn!("ssdp notify: {err}");
+                        }
+                    }
+                }
+            }
+            received = socket.recv_from(&mut buf) => {
+                let (len, peer) = received?;
+
+                let Ok(request) = std::str::from_utf8(&buf[..len]) else { continue };
+                let Some(st) = search_target(request) else { continue };
+
+                for (udn, location) in &devices {
+                    for (nt, usn) in targets(udn) {
+                        if st != "ssdp:all" && st != nt {
+                            continue;
+                        }
+
+                        let message = format!(concat!(
+                            "HTTP/1.1 200 OK\r\n",
+                            "CACHE-CONTROL: max-age={max_age}\r\n",
+                            "EXT:\r\n",
+                            "LOCATION: {location}\r\n",
+                            "SERVER: {server}\r\n",
+                            "ST: {nt}\r\n",
+                            "USN: {usn}\r\n",
+                            "\r\n",
+                        ), max_age = MAX_AGE.as_secs(), location = location, server = server(), nt = nt, usn = usn);
+
+                        if let Err(err) = socket.send_to(message.as_bytes(), peer).await {
+                            log::warn!("ssdp response to {peer}: {err}");
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+// other upnp software on the same host will have port 1900 too
+fn bind() -> Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SocketAddr::from(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, PORT)).into())?;
+    socket.join_multicast_v4(&MULTICAST_ADDR, &Ipv4Addr::UNSPECIFIED)?;
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+// ST of an M-SEARCH request, none for anything else
+fn search_target(request: &str) -> Option<&str> {
+    let mut lines = request.lines();
+
+    if !lines.next()?.starts_with("M-SEARCH ") {
+        return None;
+    }
+
+    lines.filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("ST"))
+        .map(|(_, value)| value.trim())
+}
+
+// (NT, USN) of everything a device announces
+fn targets(udn: &str) -> Vec<(String, String)> {
+    let mut targets = vec![
+        ("upnp:rootdevice".to_string(), format!("{udn}::upnp:rootdevice")),
+        (udn.to_string(), udn.to_string()),
+        (DEVICE_TYPE.to_string(), format!("{udn}::{DEVICE_TYPE}")),
+    ];
+
+    for service in SERVICES {
+        targets.push((service.urn().to_string(), format!("{udn}::{}", service.urn())));
+    }
+
+    targets
+}
+
+fn server() -> String {
+    format!("{} UPnP/1.0 sonicast/{}", std::env::consts::OS, env!("CARGO_PKG_VERSION"))
+}
@@ -0,0 +1,129 @@
+// just enough xml for soap. the arguments of an action are always leaf
+// elements, with any markup in them (such as didl-lite metadata) escaped
+
+/// text content of the first element with the given local name
+pub fn element(xml: &str, name: &str) -> Option<String> {
+    let mut rest = xml;
+
+    while let Some(index) = rest.find(name) {
+        let before = &rest[..index];
+        let after = &rest[index + name.len()..];
+        rest = after;
+
+        // the name must be the whole tag name, optionally with a namespace
+        // prefix: <name> or <u:name attr="..."> or <name/>
+        let Some(tag_start) = before.rfind('<') else { continue };
+        let prefix = &before[tag_start + 1..];
+
+        if prefix.starts_with('/') || prefix.contains(|c: char| c.is_whitespace() || c == '>') {
+            continue;
+        }
+
+        if !after.starts_with(['>', '/', ' ', '\t', '\r', '\n']) {
+            continue;
+        }
+
+        let tag_end = after.find('>')?;
+
+        if after[..tag_end].ends_with('/') {
+            return Some(String::new());
+        }
+
+        let content = &after[tag_end + 1..];
+        let content = &content[..content.find("</")?];
+        return Some(unescape(content));
+    }
+
+    None
+}
+
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+pub fn unescape(text: &str) -> String {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(index) = rest.find('&') {
+        unescaped.push_str(&rest[..index]);
+        rest = &rest[index..];
+
+        let Some(end) = rest.find(';') else { break };
+        let entity = &rest[1..end];
+
+        let c = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => entity.strip_prefix("#x")
+                .map(|hex| u32::from_str_radix(hex, 16))
+                .or_else(|| entity.strip_prefix('#').map(str::parse))
+                .and_then(Result::ok)
+                .and_then(char::from_u32),
+        };
+
+        match c {
+            Some(c) => {
+                unescaped.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                unescaped.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+
+    unescaped.push_str(rest);
+    unescaped
+}
+
+pub fn soap_response(service_urn: &str, action: &str, args: &[(&str, String)]) -> String {
+    let args = args.iter()
+        .map(|(name, value)| format!("<{name}>{}</{name}>", escape(value)))
+        .collect::<String>();
+
+    envelope(&format!(
+        r#"<u:{action}Response xmlns:u="{service_urn}">{args}</u:{action}Response>"#
+    ))
+}
+
+pub fn soap_fault(code: u16, description: &str) -> String {
+    envelope(&format!(concat!(
+        "<s:Fault>",
+        "<faultcode>s:Client</faultcode>",
+        "<faultstring>UPnPError</faultstring>",
+        "<detail>",
+        r#"<UPnPError xmlns="urn:schemas-upnp-org:control-1-0">"#,
+        "<errorCode>{code}</errorCode>",
+        "<errorDescription>{description}</errorDescription>",
+        "</UPnPError>",
+        "</detail>",
+        "</s:Fault>",
+    ), code = code, description = escape(description)))
+}
+
+fn envelope(body: &str) -> String {
+    format!(concat!(
+        r#"<?xml version="1.0" encoding="utf-8"?>"#,
+        r#"<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/">"#,
+        "<s:Body>{body}</s:Body>",
+        "</s:Envelope>",
+    ), body = body)
+}
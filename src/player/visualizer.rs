@@ -0,0 +1,233 @@
+use std::collections::VecDeque;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use realfft::num_complex::Complex;
+use realfft::{RealFftPlanner, RealToComplex};
+use serde::Serialize;
+use tokio::io::AsyncReadExt;
+use tokio::net::unix::pipe;
+use tokio::sync::watch;
+
+pub const DEFAULT_RATE: u32 = 30;
+pub const DEFAULT_BINS: usize = 32;
+
+// samples per fft, about 46ms at 44.1kHz
+const FFT_SIZE: usize = 2048;
+
+// frequency range covered by the bins
+const MIN_FREQ: f32 = 40.0;
+const MAX_FREQ: f32 = 16000.0;
+
+// level of an empty bin, anything quieter is shown as silence
+const FLOOR_DB: f32 = -70.0;
+
+// mpd recreates the fifo when it restarts
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Clone)]
+pub struct Config {
+    /// path of an mpd fifo output
+    pub fifo: PathBuf,
+    /// must match the format of the fifo output in mpd.conf
+    pub format: Format,
+    /// events per second
+    pub rate: u32,
+    pub bins: usize,
+}
+
+/// mpd's sample_rate:bits:channels audio format, with 16 bit samples
+#[derive(Clone, Copy)]
+pub struct Format {
+    sample_rate: u32,
+    channels: usize,
+}
+
+impl Default for Format {
+    fn default() -> Self {
+        Format { sample_rate: 44100, channels: 2 }
+    }
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = s.split(':');
+
+        let (Some(sample_rate), Some(bits), Some(channels), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            anyhow::bail!("expected sample_rate:bits:channels, not {s}");
+        };
+
+        anyhow::ensure!(bits == "16", "only 16 bit samples are supported");
+
+        Ok(Format {
+            sample_rate: sample_rate.parse()?,
+            channels: channels.parse::<usize>().ok()
+                .filter(|channels| *channels > 0)
+                .context("bad channel count")?,
+        })
+    }
+}
+
+/// spectrum of what's playing, for drawing visualizations
+#[derive(Debug, Serialize)]
+pub struct VisualizerEvent {
+    /// level of each frequency band from 0 to 1, lowest first. bands are
+    /// spaced logarithmically, as pitch is
+    pub bins: Vec<f32>,
+}
+
+pub type Visualizer = watch::Sender<Option<Arc<VisualizerEvent>>>;
+
+pub async fn task(config: Config, tx: Visualizer) {
+    loop {
+        if let Err(err) = run(&config, &tx).await {
+            log::warn!("visualizer reading {}: {err:?}", config.fifo.display());
+        }
+
+        tokio::time::sleep(RETRY_INTERVAL).await;
+    }
+}
+
+async fn run(config: &Config, tx: &Visualizer) -> Result<()> {
+    // opening read-write means reads wait for mpd rather than seeing eof
+    // whenever it has the fifo closed
+    let mut fifo = pipe::OpenOptions::new()
+        .read_write(true)
+        .open_receiver(&config.fifo)?;
+
+    let frame_size = 2 * config.format.channels;
+    let mut analyzer = Analyzer::new(config.format.sample_rate, config.bins);
+    let mut samples = VecDeque::from(vec![0.0; FFT_SIZE]);
+    let mut buf = vec![0; 8192];
+    let mut pending = Vec::new();
+
+    let mut tick = tokio::time::interval(Duration::from_secs(1) / config.rate.max(1));
+    tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+
+    // mpd stops writing while paused or stopped, nothing new since the last
+    // tick means silence
+    let mut received = false;
+    let mut silent = false;
+
+    loop {
+        tokio::select! {
+            len = fifo.read(&mut buf) => {
+                let len = len?;
+                pending.extend_from_slice(&buf[..len]);
+
+                let frames = pending.len() / frame_size;
+
+                for frame in pending[..frames * frame_size].chunks_exact(frame_size) {
+                    let sum = frame.chunks_exact(2)
+                        .map(|sample| f32::from(i16::from_le_bytes([sample[0], sample[1]])))
+                        .sum::<f32>();
+
+                    samples.pop_front();
+                    samples.push_back(sum / config.format.channels as f32 / 32768.0);
+                }
+
+                pending.drain(..frames * frame_size);
+                received = true;
+            }
+            _ = tick.tick() => {
+                if !received {
+                    if !silent {
+                        silent = true;
+                        tx.send_replace(Some(Arc::new(VisualizerEvent { bins: vec![0.0; config.bins] })));
+                    }
+                    continue;
+                }
+
+                received = false;
+                silent = false;
+
+                let bins = analyzer.analyze(samples.make_contiguous());
+                tx.send_replace(Some(Arc::new(VisualizerEvent { bins })));
+            }
+        }
+    }
+}
+
+struct Analyzer {
+    fft: Arc<dyn RealToComplex<f32>>,
+    window: Vec<f32>,
+    input: Vec<f32>,
+    spectrum: Vec<Complex<f32>>,
+    /// range of fft outputs summarised by each bin
+    bands: Vec<Range<usize>>,
+}
+
+impl Analyzer {
+    fn new(sample_rate: u32, bins: usize) -> Self {
+        let fft = RealFftPlanner::new().plan_fft_forward(FFT_SIZE);
+
+        // hann window
+        let window = (0..FFT_SIZE)
+            .map(|i| {
+                let phase = 2.0 * std::f32::consts::PI * i as f32 / FFT_SIZE as f32;
+                0.5 - 0.5 * phase.cos()
+            })
+            .collect();
+
+        let spectrum = fft.make_output_vec();
+        let hz_per_output = sample_rate as f32 / FFT_SIZE as f32;
+        let max_freq = MAX_FREQ.min(sample_rate as f32 / 2.0);
+
+        let edge = |bin: usize| {
+            let freq = MIN_FREQ * (max_freq / MIN_FREQ).powf(bin as f32 / bins as f32);
+            ((freq / hz_per_output) as usize).min(spectrum.len())
+        };
+
+        // low bands are narrower than a single fft output, so each takes at
+        // least one even if that means sharing with its neighbour
+        let bands = (0..bins)
+            .map(|bin| {
+                let start = edge(bin).min(spectrum.len() - 1);
+                start..edge(bin + 1).max(start + 1)
+            })
+            .collect();
+
+        Analyzer {
+            input: fft.make_input_vec(),
+            fft,
+            window,
+            spectrum,
+            bands,
+        }
+    }
+
+    fn analyze(&mut self, samples: &[f32]) -> Vec<f32> {
+        for ((input, sample), window) in self.input.iter_mut().zip(samples).zip(&self.window) {
+            *input = sample * window;
+        }
+
+        if self.fft.process(&mut self.input, &mut self.spectrum).is_err() {
+            return vec![0.0; self.bands.len()];
+        }
+
+        // a full scale sine comes out at the window's sum over two
+        let full_scale = FFT_SIZE as f32 / 4.0;
+
+        self.bands.iter()
+            .map(|band| {
+                let peak = self.spectrum[band.clone()].iter()
+                    .map(|output| output.norm())
+                    .fold(0.0, f32::max);
+
+                let db = 20.0 * (peak / full_scale).max(f32::MIN_POSITIVE).log10();
+                let level = ((db - FLOOR_DB) / -FLOOR_DB).clamp(0.0, 1.0);
+
+                // a few decimal places is plenty for drawing bars
+                (level * 1000.0).round() / 1000.0
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,61 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use super::zones::Zone;
+
+/// limits on how loud a zone plays, so that nobody in a shared household
+/// gets a full volume surprise. volumes clients ask for and see are before
+/// the limits are applied, mpd's own volume is after
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct VolumeLimits {
+    /// highest volume mpd is ever set to, 0-1
+    pub max_volume: f64,
+    /// gain in dB applied to every volume asked for. mpd's own
+    /// replay_gain_preamp can only be set in mpd.conf, so this scales
+    /// volumes instead
+    pub preamp: f64,
+}
+
+impl Default for VolumeLimits {
+    fn default() -> Self {
+        VolumeLimits { max_volume: 1.0, preamp: 0.0 }
+    }
+}
+
+impl VolumeLimits {
+    fn gain(&self) -> f64 {
+        10f64.powf(self.preamp / 20.0)
+    }
+
+    /// mpd volume 0-100 for a volume 0-1 as asked for by a client
+    pub fn mpd_volume(&self, volume: f64) -> usize {
+        let volume = (volume.clamp(0.0, 1.0) * self.gain()).min(self.max_volume);
+        (volume.clamp(0.0, 1.0) * 100.0).round() as usize
+    }
+
+    /// volume 0-1 as shown to clients for an mpd volume 0-100
+    pub fn client_volume(&self, volume: usize) -> f64 {
+        (volume as f64 / 100.0 / self.gain()).min(1.0)
+    }
+
+    fn max_mpd(&self) -> usize {
+        (self.max_volume.clamp(0.0, 1.0) * 100.0).round() as usize
+    }
+}
+
+/// turns the volume back down to the limit, after anything else talking to
+/// mpd turns it up past it
+pub async fn enforce(zone: &Zone) -> Result<()> {
+    let max = zone.volume_limits.borrow().max_mpd();
+
+    let mpd = zone.mpd.write().await;
+
+    if let Some(volume) = mpd.status().await?.volume
+        && volume > max
+    {
+        log::info!("turning volume of zone {}/{} down from {volume} to its limit of {max}", zone.room, zone.name);
+        mpd.setvol(max).await?;
+    }
+
+    Ok(())
+}
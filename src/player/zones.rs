@@ -0,0 +1,375 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use tokio::sync::{watch, RwLock, Mutex as AsyncMutex};
+use url::Url;
+
+use crate::jukebox::Jukebox;
+use crate::mpd::{self, Backend, Mpd, MpdIdleClient, StatusCache};
+use crate::mpd::types::{Id, PlaylistItem};
+use crate::subsonic::AuthParams;
+
+use super::rate::{self, RateProxy};
+use super::rooms::RoomBackend;
+use super::visualizer::{self, Visualizer};
+use super::volume::VolumeLimits;
+use super::types::AirsonicTrack;
+use super::{autoqueue, bookmarks, duck, events, history, persist, poll, resume, skip, state, unavailable, Services};
+
+/// name of the partition mpd creates on startup
+pub const DEFAULT_ZONE: &str = "default";
+
+/// a zone is an independent player, backed by an mpd partition. each zone
+/// has its own command and idle connections switched to that partition.
+pub struct Zone {
+    pub name: String,
+    /// name of the room whose mpd instance this zone belongs to
+    pub room: String,
+    pub mpd: RwLock<Mpd>,
+    pub events: events::MpdEvents,
+    pub state: state::ZoneState,
+    pub rate_proxy: Option<RateProxy>,
+    /// held shared by each command run against the zone, and exclusively
+    /// by batches so that they aren't interleaved with other commands
+    pub command_lock: RwLock<()>,
+    pub volume_limits: watch::Sender<VolumeLimits>,
+    pub duck: duck::Duck,
+    pub auto_queue: watch::Sender<autoqueue::AutoQueue>,
+    /// queued files which start from the beginning rather than resuming
+    /// at their saved position
+    no_resume: Mutex<HashSet<String>>,
+    /// queue items mpd failed to play, see unavailable::task
+    pub unavailable: watch::Sender<HashSet<Id>>,
+    /// why mpd last failed to play something, until playback resumes
+    pub playback_error: watch::Sender<Option<String>>,
+    /// credentials of the most recent session to connect to this zone, used
+    /// for subsonic calls made on behalf of the zone such as scrobbling
+    auth: watch::Sender<Option<Arc<AuthParams>>>,
+}
+
+impl Zone {
+    async fn connect(
+        backend: &RoomBackend,
+        services: &Services,
+        room: &str,
+        name: &str,
+    ) -> Result<Arc<Zone>> {
+        let (mpd, idle_backend) = match backend {
+            RoomBackend::Mpd(config) => {
+                (Mpd::connect(config).await?, IdleBackend::Mpd(config.clone()))
+            }
+            RoomBackend::Jukebox(config) => {
+                let jukebox = Arc::new(Jukebox::new(config));
+                (Mpd::new(jukebox.clone()), IdleBackend::Emulated(jukebox))
+            }
+            #[cfg(feature = "local")]
+            RoomBackend::Local => {
+                let local = crate::local::Local::open()?;
+                (Mpd::new(local.clone()), IdleBackend::Emulated(local))
+            }
+        };
+
+        let idle = IdleSource {
+            backend: idle_backend,
+            partition: (name != DEFAULT_ZONE).then(|| name.to_string()),
+            status_cache: mpd.status_cache(),
+        };
+
+        // connections start out in the default partition, avoid switching
+        // unless we need to so that mpd versions without partitions work
+        if name != DEFAULT_ZONE {
+            mpd.partition(name).await?;
+        }
+
+        let events = events::MpdEvents::default();
+        let supervisor = &services.supervisor;
+        let task_name = |task: &str| format!("{room}/{name} {task}");
+
+        // spawn mpd event task
+        supervisor.spawn(task_name("mpd events"), {
+            let (idle, queue_debounce, events) = (Arc::new(idle), services.intervals.queue_debounce, events.clone());
+            move || events::task(idle.clone(), queue_debounce, events.clone())
+        });
+
+        let zone = Arc::new(Zone {
+            name: name.to_string(),
+            room: room.to_string(),
+            mpd: RwLock::new(mpd),
+            events,
+            state: Default::default(),
+            rate_proxy: services.rate_proxy.clone(),
+            command_lock: RwLock::new(()),
+            volume_limits: watch::Sender::new(services.volume_limits),
+            duck: Default::default(),
+            auto_queue: Default::default(),
+            no_resume: Default::default(),
+            unavailable: Default::default(),
+            playback_error: Default::default(),
+            auth: Default::default(),
+        });
+
+        // spawn mpd keepalive task
+        if let RoomBackend::Mpd(mpd::Config { keepalive_interval: Some(interval), .. }) = backend {
+            supervisor.spawn(task_name("mpd keepalive"), {
+                let (interval, zone) = (*interval, zone.clone());
+                move || keepalive_task(zone.clone(), interval)
+            });
+        }
+
+        if let Some(state_file) = &services.state_file {
+            if services.restore_state
+                && let Err(err) = persist::restore_if_empty(state_file, &zone).await
+            {
+                log::warn!("restoring state for zone {name}: {err:?}");
+            }
+
+            // spawn state saving task
+            supervisor.spawn(task_name("state saving"), {
+                let (state_file, save_delay, zone) = (state_file.clone(), services.intervals.save_delay, zone.clone());
+                move || persist::task(state_file.clone(), save_delay, zone.clone())
+            });
+        }
+
+        // spawn shared event state task
+        supervisor.spawn(task_name("event state"), {
+            let (services, zone) = (services.clone(), zone.clone());
+            move || state::task(services.clone(), zone.clone())
+        });
+
+        // spawn event log task
+        supervisor.spawn(task_name("event log"), {
+            let (event_log, zone) = (services.event_log.clone(), zone.clone());
+            move || poll::task(event_log.clone(), zone.clone())
+        });
+
+        // spawn volume unducking task
+        supervisor.spawn(task_name("unducking"), {
+            let zone = zone.clone();
+            move || duck::task(zone.clone())
+        });
+
+        // spawn play history task
+        if let Some(history) = &services.history {
+            supervisor.spawn(task_name("play history"), {
+                let (history, subsonic, zone) = (history.clone(), services.subsonic.clone(), zone.clone());
+                move || history::task(history.clone(), subsonic.clone(), zone.clone())
+            });
+        }
+
+        // spawn unavailable track task
+        supervisor.spawn(task_name("unavailable tracks"), {
+            let (services, zone) = (services.clone(), zone.clone());
+            move || unavailable::task(services.clone(), zone.clone())
+        });
+
+        // spawn auto queue task
+        supervisor.spawn(task_name("auto queue"), {
+            let (services, zone) = (services.clone(), zone.clone());
+            move || autoqueue::task(services.clone(), zone.clone())
+        });
+
+        // spawn scrobble task
+        supervisor.spawn(task_name("scrobble"), {
+            let (services, zone) = (services.clone(), zone.clone());
+            move || events::scrobble_task(services.clone(), zone.clone())
+        });
+
+        // spawn podcast resume position task
+        if let Some(podcasts) = &services.podcasts {
+            supervisor.spawn(task_name("podcast resume"), {
+                let (podcasts, positions, skips, zone) = (podcasts.clone(), services.positions.clone(), services.skips.clone(), zone.clone());
+                move || resume::task(podcasts.clone(), positions.clone(), skips.clone(), zone.clone())
+            });
+
+            // spawn podcast outro skipping task
+            supervisor.spawn(task_name("podcast skipping"), {
+                let (podcasts, skips, zone) = (podcasts.clone(), services.skips.clone(), zone.clone());
+                move || skip::task(podcasts.clone(), skips.clone(), zone.clone())
+            });
+        }
+
+        // spawn bookmark task
+        if !services.bookmark_prefixes.is_empty() {
+            supervisor.spawn(task_name("bookmarks"), {
+                let (subsonic, prefixes, zone) = (services.subsonic.clone(), services.bookmark_prefixes.clone(), zone.clone());
+                move || bookmarks::task(subsonic.clone(), prefixes.clone(), zone.clone())
+            });
+        }
+
+        Ok(zone)
+    }
+
+    pub fn set_auth(&self, auth: Arc<AuthParams>) {
+        self.auth.send_replace(Some(auth));
+    }
+
+    pub fn auth(&self) -> Option<Arc<AuthParams>> {
+        self.auth.borrow().clone()
+    }
+
+    /// waits for a session to connect if none has yet
+    pub async fn wait_auth(&self) -> Arc<AuthParams> {
+        let mut rx = self.auth.subscribe();
+
+        loop {
+            if let Some(auth) = rx.borrow_and_update().clone() {
+                return auth;
+            }
+
+            // the sender lives as long as the zone, so this can't fail
+            let _ = rx.changed().await;
+        }
+    }
+
+    /// whether files resume from their saved position when they start
+    pub fn set_resume<'a>(&self, files: impl IntoIterator<Item = &'a str>, resume: bool) {
+        let mut no_resume = self.no_resume.lock().unwrap();
+
+        for file in files {
+            if resume {
+                no_resume.remove(file);
+            } else {
+                no_resume.insert(file.to_owned());
+            }
+        }
+    }
+
+    /// whether file should resume as it starts, once only
+    pub fn take_resume(&self, file: &str) -> bool {
+        !self.no_resume.lock().unwrap().remove(file)
+    }
+
+    /// the underlying stream url of a queue item, seeing through the rate
+    /// proxy if the item is playing at a non-default rate
+    pub fn stream_url(&self, file: &str) -> Option<Url> {
+        self.source(file).map(|(url, _)| url)
+    }
+
+    pub fn source(&self, file: &str) -> Option<(Url, f64)> {
+        rate::source(self.rate_proxy.as_ref(), file)
+    }
+
+    /// marks the tracks of items mpd failed to play, tracks are one for
+    /// each item
+    pub fn mark_unavailable(&self, items: &[PlaylistItem], tracks: &mut [AirsonicTrack]) {
+        let unavailable = self.unavailable.borrow();
+
+        for (item, track) in items.iter().zip(tracks) {
+            if unavailable.contains(&item.id) {
+                track.details.is_unavailable = Some(true);
+            }
+        }
+    }
+}
+
+// pings the zone's command connection so that mpd doesn't drop it while
+// nothing else is being sent, reconnecting first if it has gone unhealthy.
+// returns once mpd can't be reached, so the supervisor backs off before
+// trying again
+async fn keepalive_task(zone: Arc<Zone>, interval: Duration) {
+    loop {
+        tokio::time::sleep(interval).await;
+
+        if let Err(err) = zone.mpd.read().await.ping().await {
+            log::warn!("zone {} keepalive: {err:?}", zone.name);
+            return;
+        }
+    }
+}
+
+/// how the zone's idle connection is made, kept so that the mpd event task
+/// can make it again after losing it
+pub struct IdleSource {
+    backend: IdleBackend,
+    partition: Option<String>,
+    status_cache: Arc<StatusCache>,
+}
+
+enum IdleBackend {
+    Mpd(mpd::Config),
+    /// backends emulating mpd in process, idled on directly
+    Emulated(Arc<dyn Backend>),
+}
+
+impl IdleSource {
+    pub async fn connect(&self) -> Result<MpdIdleClient> {
+        let mut mpd = match &self.backend {
+            IdleBackend::Mpd(config) => MpdIdleClient::connect(config).await?,
+            IdleBackend::Emulated(backend) => MpdIdleClient::new(backend.clone()),
+        };
+
+        if let Some(partition) = &self.partition {
+            mpd.partition(partition).await?;
+        }
+
+        mpd.share_status_cache(self.status_cache.clone());
+        Ok(mpd)
+    }
+}
+
+pub struct Zones {
+    room: String,
+    backend: RoomBackend,
+    services: Services,
+    /// spectrum of what the room is playing, if it has a fifo output to
+    /// analyse
+    pub visualizer: Visualizer,
+    default: Arc<Zone>,
+    zones: AsyncMutex<HashMap<String, Arc<Zone>>>,
+}
+
+impl Zones {
+    pub async fn open(
+        room: &str,
+        backend: &RoomBackend,
+        visualizer: Option<visualizer::Config>,
+        services: &Services,
+    ) -> Result<Zones> {
+        let default = Zone::connect(backend, services, room, DEFAULT_ZONE).await?;
+
+        let (visualizer_tx, _) = watch::channel(None);
+        if let Some(config) = visualizer {
+            services.supervisor.spawn(format!("{room} visualizer"), {
+                let tx = visualizer_tx.clone();
+                move || visualizer::task(config.clone(), tx.clone())
+            });
+        }
+
+        let mut zones = HashMap::new();
+        zones.insert(DEFAULT_ZONE.to_string(), default.clone());
+
+        Ok(Zones {
+            room: room.to_string(),
+            backend: backend.clone(),
+            services: services.clone(),
+            visualizer: visualizer_tx,
+            default,
+            zones: AsyncMutex::new(zones),
+        })
+    }
+
+    pub fn default_zone(&self) -> &Arc<Zone> {
+        &self.default
+    }
+
+    /// returns the named zone, connecting to it if this is the first use.
+    /// fails if mpd has no partition by that name
+    pub async fn get(&self, name: &str) -> Result<Arc<Zone>> {
+        let mut zones = self.zones.lock().await;
+
+        if let Some(zone) = zones.get(name) {
+            return Ok(zone.clone());
+        }
+
+        let partitions = self.default.mpd.read().await.listpartitions().await?;
+        if !partitions.iter().any(|partition| partition == name) {
+            bail!("no such zone: {name}");
+        }
+
+        let zone = Zone::connect(&self.backend, &self.services, &self.room, name).await?;
+        zones.insert(name.to_string(), zone.clone());
+        Ok(zone)
+    }
+}
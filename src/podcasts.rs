@@ -16,16 +16,29 @@ pub struct PodcastsBase {
 pub struct Config {
     pub server_url: Url,
     pub episode_prefix: String,
+    pub http: reqwest::Client,
 }
 
 impl PodcastsBase {
     pub fn new(config: &Config) -> Self {
         PodcastsBase {
-            server: SubsonicBase::new(&config.server_url),
+            server: SubsonicBase::new(&config.server_url, config.http.clone()),
             episode_prefix: config.episode_prefix.clone(),
         }
     }
 
+    /// whether url is a stream url for a podcast episode. unlike
+    /// Podcasts::matches, this does not require an authenticated session
+    pub fn matches_stream_url(&self, url: &Url) -> bool {
+        self.episode_id_from_stream_url(url).is_some()
+    }
+
+    /// the id of the podcast episode a stream url is for, if it's for one
+    pub fn episode_id_from_stream_url(&self, url: &Url) -> Option<TrackId> {
+        self.server.track_id_from_stream_url(url)
+            .filter(|id| id.0.starts_with(&self.episode_prefix))
+    }
+
     pub async fn authenticate(&self, params: Arc<AuthParams>) -> Result<Podcasts> {
         let server = self.server.authenticate(params).await?;
 
@@ -34,8 +47,17 @@ impl PodcastsBase {
             episode_prefix: self.episode_prefix.clone(),
         })
     }
+
+    /// like authenticate, but trusts params without checking them
+    pub fn with_auth(&self, params: Arc<AuthParams>) -> Podcasts {
+        Podcasts {
+            server: self.server.with_auth(params),
+            episode_prefix: self.episode_prefix.clone(),
+        }
+    }
 }
 
+#[derive(Clone)]
 pub struct Podcasts {
     server: Subsonic,
     episode_prefix: String,
@@ -54,6 +76,23 @@ impl Podcasts {
         self.server.track_id_from_stream_url(url)
     }
 
+    /// asks the server to check all podcast feeds for new episodes. the
+    /// refresh itself happens in the background on the server
+    pub async fn refresh(&self) -> Result<()> {
+        self.server.call::<serde_json::Value>("refreshPodcasts", &[]).await?;
+        Ok(())
+    }
+
+    pub async fn download_episode(&self, id: &TrackId) -> Result<()> {
+        self.server.call::<serde_json::Value>("downloadPodcastEpisode", &[("id", &id.0)]).await?;
+        Ok(())
+    }
+
+    pub async fn delete_episode(&self, id: &TrackId) -> Result<()> {
+        self.server.call::<serde_json::Value>("deletePodcastEpisode", &[("id", &id.0)]).await?;
+        Ok(())
+    }
+
     pub async fn get_podcast_episode(&self, id: &TrackId) -> Result<PodcastEpisode> {
         #[derive(Deserialize, Debug)]
         #[serde(rename_all = "camelCase")]
@@ -78,4 +117,6 @@ pub struct PodcastEpisode {
     pub artist: String,
     pub duration: f64,
     pub cover_art: CoverArtId,
+    /// id of the podcast the episode belongs to
+    pub channel_id: Option<String>,
 }
@@ -0,0 +1,152 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use thiserror::Error;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+pub const DEFAULT_PORT: u16 = 1705;
+
+/// client for snapserver's json-rpc control api. requests are rare, so each
+/// one gets its own connection rather than multiplexing over a shared one
+#[derive(Clone)]
+pub struct Snapcast {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    /// host:port of the control socket
+    addr: String,
+    next_id: AtomicU64,
+}
+
+#[derive(Deserialize, Debug, Error)]
+#[error("snapcast error {code}: {message}")]
+pub struct SnapcastError {
+    code: i64,
+    message: String,
+}
+
+#[derive(Deserialize)]
+struct RpcResponse {
+    id: Option<u64>,
+    result: Option<serde_json::Value>,
+    error: Option<SnapcastError>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Group {
+    pub id: String,
+    pub name: String,
+    pub stream_id: String,
+    pub muted: bool,
+    pub clients: Vec<Client>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Client {
+    pub id: String,
+    pub connected: bool,
+    pub config: ClientConfig,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ClientConfig {
+    pub name: String,
+    pub volume: Volume,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Volume {
+    /// 0-100
+    pub percent: u8,
+    pub muted: bool,
+}
+
+impl Snapcast {
+    pub fn new(addr: String) -> Self {
+        Snapcast {
+            inner: Arc::new(Inner {
+                addr,
+                next_id: AtomicU64::new(1),
+            }),
+        }
+    }
+
+    pub async fn groups(&self) -> Result<Vec<Group>> {
+        #[derive(Deserialize)]
+        struct Status {
+            server: Server,
+        }
+
+        #[derive(Deserialize)]
+        struct Server {
+            groups: Vec<Group>,
+        }
+
+        let status = self.call::<Status>("Server.GetStatus", json!({})).await?;
+        Ok(status.server.groups)
+    }
+
+    pub async fn set_client_volume(&self, client: &str, volume: Volume) -> Result<()> {
+        self.call::<serde_json::Value>("Client.SetVolume", json!({ "id": client, "volume": volume })).await?;
+        Ok(())
+    }
+
+    /// moves clients into a group, any of the group's clients not listed are
+    /// moved out into groups of their own
+    pub async fn set_group_clients(&self, group: &str, clients: &[String]) -> Result<()> {
+        self.call::<serde_json::Value>("Group.SetClients", json!({ "id": group, "clients": clients })).await?;
+        Ok(())
+    }
+
+    pub async fn set_group_stream(&self, group: &str, stream: &str) -> Result<()> {
+        self.call::<serde_json::Value>("Group.SetStream", json!({ "id": group, "stream_id": stream })).await?;
+        Ok(())
+    }
+
+    #[tracing::instrument(level = "trace", name = "snapcast", skip(self, params))]
+    async fn call<T: DeserializeOwned>(&self, method: &str, params: serde_json::Value) -> Result<T> {
+        let id = self.inner.next_id.fetch_add(1, Ordering::Relaxed);
+
+        let request = json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": method,
+            "params": params,
+        });
+
+        let mut stream = TcpStream::connect(&self.inner.addr).await
+            .with_context(|| format!("connecting to snapserver at {}", self.inner.addr))?;
+
+        let mut line = serde_json::to_vec(&request)?;
+        line.push(b'\n');
+        stream.write_all(&line).await?;
+
+        let mut lines = BufReader::new(stream).lines();
+
+        // snapserver sends notifications on every control connection, skip
+        // over any which arrive before our response
+        while let Some(line) = lines.next_line().await? {
+            let response = serde_json::from_str::<RpcResponse>(&line)
+                .context("parsing snapserver response")?;
+
+            if response.id != Some(id) {
+                continue;
+            }
+
+            if let Some(err) = response.error {
+                return Err(err.into());
+            }
+
+            let result = response.result.unwrap_or_default();
+            return Ok(serde_json::from_value(result)?);
+        }
+
+        anyhow::bail!("snapserver closed the connection without responding to {method}")
+    }
+}
@@ -1,13 +1,116 @@
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, RandomState};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use derive_more::Display;
-use reqwest::{Method, Url};
+use reqwest::header::{self, HeaderMap, HeaderName, HeaderValue};
+use reqwest::{Method, StatusCode, Url};
 use anyhow::{Result, Context};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
+use tokio::sync::watch;
 
+mod breaker;
+mod cache;
+mod proxy;
 pub mod types;
-use types::{Track, TrackId, RadioStation};
+use breaker::Breaker;
+use cache::ResponseCache;
+use proxy::StreamProxy;
+pub use proxy::Config as StreamProxyConfig;
+pub use breaker::CircuitOpen;
+use types::{AlbumId, ArtistId, Bookmark, RandomSongsFilter, SearchPage, SearchResult, CoverArtId, JukeboxPlaylist, JukeboxStatus, PlayQueue, Playlist, RadioId, PlaylistId, PlaylistWithTracks, StructuredLyrics, Track, TrackId, RadioStation};
+
+// how long credentials which passed authenticate are trusted for without
+// checking them again, so that clients reconnecting over and over on a
+// flaky network don't each cost a round trip to subsonic
+const AUTH_CACHE_TTL: Duration = Duration::from_secs(60);
+
+// how often subsonic is checked on while calls to it are failing fast
+const PROBE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// settings for the http client every subsonic and podcast server is
+/// reached through, for self hosted servers with self signed certs or
+/// behind proxies
+#[derive(Debug, Default)]
+pub struct ClientConfig {
+    /// for whole requests, none by default
+    pub timeout: Option<Duration>,
+    pub connect_timeout: Option<Duration>,
+    /// instead of any proxy set in the environment
+    pub proxy: Option<Url>,
+    /// pem file of a certificate to trust on top of the system's
+    pub root_ca: Option<PathBuf>,
+    /// skip verifying certificates altogether
+    pub accept_invalid_certs: bool,
+    /// sent with every request, for servers behind something which wants
+    /// its own auth
+    pub headers: Vec<(String, String)>,
+}
+
+impl ClientConfig {
+    pub fn build(&self) -> Result<reqwest::Client> {
+        let mut builder = self.builder()?;
+
+        if let Some(timeout) = self.timeout {
+            builder = builder.timeout(timeout);
+        }
+
+        Ok(builder.build()?)
+    }
+
+    /// for relaying streams, which last as long as their track, so the
+    /// timeout is for each read rather than the whole request
+    pub fn build_for_streams(&self) -> Result<reqwest::Client> {
+        let mut builder = self.builder()?;
+
+        if let Some(timeout) = self.timeout {
+            builder = builder.read_timeout(timeout);
+        }
+
+        Ok(builder.build()?)
+    }
+
+    fn builder(&self) -> Result<reqwest::ClientBuilder> {
+        let mut headers = HeaderMap::new();
+
+        for (name, value) in &self.headers {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .with_context(|| format!("invalid header name: {name}"))?;
+
+            let value = HeaderValue::from_str(value)
+                .with_context(|| format!("invalid value for header {name}"))?;
+
+            headers.insert(name, value);
+        }
+
+        let mut builder = reqwest::Client::builder()
+            .danger_accept_invalid_certs(self.accept_invalid_certs)
+            .default_headers(headers);
+
+        if let Some(timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(timeout);
+        }
+
+        if let Some(proxy) = &self.proxy {
+            builder = builder.proxy(reqwest::Proxy::all(proxy.clone())?);
+        }
+
+        if let Some(path) = &self.root_ca {
+            let pem = std::fs::read(path)
+                .with_context(|| format!("reading root ca {}", path.display()))?;
+
+            let cert = reqwest::Certificate::from_pem(&pem)
+                .with_context(|| format!("parsing root ca {}", path.display()))?;
+
+            builder = builder.add_root_certificate(cert);
+        }
+
+        Ok(builder)
+    }
+}
 
 #[derive(Clone)]
 pub struct SubsonicBase {
@@ -17,9 +120,33 @@ pub struct SubsonicBase {
 struct Inner {
     client: reqwest::Client,
     base_url: reqwest::Url,
+    /// when credentials last passed authenticate, by hash so that they
+    /// aren't kept around in the clear
+    authenticated: Mutex<HashMap<u64, Instant>>,
+    /// randomly keyed, so hashes can't be worked out ahead of time
+    auth_hasher: RandomState,
+    /// whether the last call got a response from the server, error or not
+    reachable: watch::Sender<bool>,
+    breaker: Breaker,
+    cache: ResponseCache,
+    stream_proxy: Option<StreamProxy>,
+    /// by username, see Subsonic::stream_url
+    stream_quality: Mutex<HashMap<String, StreamQuality>>,
+}
+
+impl Inner {
+    fn track_id_from_stream_url(&self, url: &Url) -> Option<TrackId> {
+        self.stream_proxy.as_ref()
+            .and_then(|proxy| proxy.track_id(url))
+            .or_else(|| track_id_from_stream_url(&self.base_url, url))
+    }
+
+    fn set_reachable(&self, reachable: bool) {
+        self.reachable.send_if_modified(|prev| std::mem::replace(prev, reachable) != reachable);
+    }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Hash, Deserialize, Serialize)]
 pub struct AuthParams {
     #[serde(rename = "u")]
     username: Option<String>,
@@ -31,34 +158,206 @@ pub struct AuthParams {
     password: Option<String>,
 }
 
+/// transcoding asked of subsonic for a user's streams, so that a remote
+/// location on a slow connection can have smaller ones without changing
+/// the server's transcoding settings
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct StreamQuality {
+    /// eg. opus or mp3, or raw for the original file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<String>,
+    /// kbps, 0 for no limit
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_bit_rate: Option<u32>,
+}
+
+impl StreamQuality {
+    /// params of subsonic's stream endpoint
+    pub fn query(&self) -> Vec<(&'static str, String)> {
+        let mut query = Vec::new();
+
+        if let Some(format) = &self.format {
+            query.push(("format", format.clone()));
+        }
+
+        if let Some(max_bit_rate) = self.max_bit_rate {
+            query.push(("maxBitRate", max_bit_rate.to_string()));
+        }
+
+        query
+    }
+}
+
+impl AuthParams {
+    /// credentials configured for sonicast itself, rather than passed on from
+    /// a client. hex encoding the password keeps it out of access logs at a
+    /// glance, it's no protection otherwise
+    pub fn password(username: &str, password: &str) -> Self {
+        let hex = password.bytes().map(|byte| format!("{byte:02x}")).collect::<String>();
+
+        AuthParams {
+            username: Some(username.to_string()),
+            salt: None,
+            token: None,
+            password: Some(format!("enc:{hex}")),
+        }
+    }
+
+    pub fn username(&self) -> Option<&str> {
+        self.username.as_deref()
+    }
+}
+
 impl SubsonicBase {
-    pub fn new(base_url: &Url) -> Self {
+    pub fn new(base_url: &Url, client: reqwest::Client) -> Self {
+        Self::build(base_url, client, None)
+    }
+
+    /// streams are relayed through sonicast, see proxy_stream
+    pub fn proxied(base_url: &Url, client: reqwest::Client, stream_proxy: StreamProxyConfig) -> Self {
+        Self::build(base_url, client, Some(StreamProxy::new(stream_proxy)))
+    }
+
+    fn build(base_url: &Url, client: reqwest::Client, stream_proxy: Option<StreamProxy>) -> Self {
         SubsonicBase {
             inner: Arc::new(Inner {
-                client: reqwest::Client::new(),
+                client,
                 base_url: base_url.clone(),
+                authenticated: Default::default(),
+                auth_hasher: RandomState::new(),
+                reachable: watch::Sender::new(true),
+                breaker: Breaker::default(),
+                cache: ResponseCache::default(),
+                stream_proxy,
+                stream_quality: Default::default(),
             }),
         }
     }
 
+    pub fn track_id_from_stream_url(&self, url: &Url) -> Option<TrackId> {
+        self.inner.track_id_from_stream_url(url)
+    }
+
+    /// applies to streams queued from now on, the default quality leaves
+    /// transcoding up to subsonic
+    pub fn set_stream_quality(&self, user: String, quality: StreamQuality) {
+        let mut qualities = self.inner.stream_quality.lock().unwrap();
+
+        if quality == StreamQuality::default() {
+            qualities.remove(&user);
+        } else {
+            qualities.insert(user, quality);
+        }
+    }
+
+    /// requests a stream for the proxy from subsonic, with the credentials
+    /// its key stands for. None if the key isn't one we gave out for the
+    /// track, or has expired
+    pub async fn proxy_stream(&self, key: &str, id: &TrackId, quality: &StreamQuality, range: Option<&HeaderValue>) -> Result<Option<reqwest::Response>> {
+        let Some(proxy) = &self.inner.stream_proxy else { return Ok(None) };
+        let Some(auth) = proxy.auth(key, id) else { return Ok(None) };
+
+        let url = self.inner.base_url.join("rest/stream").unwrap();
+
+        let mut request = proxy.config.http.get(url)
+            .query(&*auth)
+            .query(&[
+                ("f", "json"),
+                ("c", "sonicast"),
+                ("v", env!("CARGO_PKG_VERSION")),
+                ("id", &id.0),
+            ])
+            .query(&quality.query());
+
+        // mpd seeks with ranges
+        if let Some(range) = range {
+            request = request.header(header::RANGE, range);
+        }
+
+        Ok(Some(request.send().await?.error_for_status()?))
+    }
+
+    /// changes to false while calls to subsonic can't get a response, and
+    /// back to true once one does
+    pub fn subscribe_reachable(&self) -> watch::Receiver<bool> {
+        self.inner.reachable.subscribe()
+    }
+
     pub async fn authenticate(&self, params: Arc<AuthParams>) -> Result<Subsonic> {
-        let subsonic = Subsonic {
-            inner: self.inner.clone(),
-            auth: params,
-        };
+        let hash = self.inner.auth_hasher.hash_one(&*params);
+        let subsonic = self.with_auth(params);
+
+        let cached = self.inner.authenticated.lock().unwrap()
+            .get(&hash)
+            .is_some_and(|at| at.elapsed() < AUTH_CACHE_TTL);
+
+        if cached {
+            return Ok(subsonic);
+        }
 
         // test auth details:
         subsonic.ping().await?;
 
+        let mut authenticated = self.inner.authenticated.lock().unwrap();
+        authenticated.retain(|_, at| at.elapsed() < AUTH_CACHE_TTL);
+        authenticated.insert(hash, Instant::now());
+
         Ok(subsonic)
     }
+
+    /// like authenticate, but skips testing auth details. for use with
+    /// credentials that have already been through authenticate
+    pub fn with_auth(&self, params: Arc<AuthParams>) -> Subsonic {
+        Subsonic {
+            inner: self.inner.clone(),
+            auth: params,
+        }
+    }
 }
 
+#[derive(Clone)]
 pub struct Subsonic {
     inner: Arc<Inner>,
     auth: Arc<AuthParams>,
 }
 
+// pings subsonic until it answers, then lets calls through again. without
+// credentials, since any answer at all means it's back
+async fn probe(inner: Arc<Inner>) {
+    let url = inner.base_url.join("rest/ping").unwrap();
+
+    loop {
+        tokio::time::sleep(PROBE_INTERVAL).await;
+
+        let response = inner.client.get(url.clone())
+            .query(&[("f", "json"), ("c", "sonicast"), ("v", env!("CARGO_PKG_VERSION"))])
+            .timeout(PROBE_INTERVAL)
+            .send().await;
+
+        let recovered = match response {
+            Ok(response) => !response.status().is_server_error(),
+            Err(_) => false,
+        };
+
+        if recovered {
+            log::info!("subsonic has recovered");
+            inner.breaker.success();
+            inner.set_reachable(true);
+            return;
+        }
+    }
+}
+
+fn track_id_from_stream_url(base_url: &Url, url: &Url) -> Option<TrackId> {
+    if base_url.origin() != url.origin() {
+        return None;
+    }
+
+    url.query_pairs()
+        .find(|(name, _)| name == "id")
+        .map(|(_, value)| TrackId(value.to_string()))
+}
+
 #[derive(Deserialize, Debug, Error)]
 #[error("subsonic error {code}: {message}")]
 pub struct SubsonicError {
@@ -66,16 +365,25 @@ pub struct SubsonicError {
     message: String,
 }
 
+impl SubsonicError {
+    pub fn code(&self) -> &SubsonicErrorCode {
+        &self.code
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize, Display)]
 #[serde(from = "usize")]
 pub enum SubsonicErrorCode {
     NotFound,
+    /// wrong credentials, or the user isn't allowed to do that
+    Unauthorized,
     Other(usize),
 }
 
 impl From<usize> for SubsonicErrorCode {
     fn from(code: usize) -> Self {
         match code {
+            40 | 41 | 50 => SubsonicErrorCode::Unauthorized,
             70 => SubsonicErrorCode::NotFound,
             _ => SubsonicErrorCode::Other(code),
         }
@@ -89,8 +397,7 @@ impl Subsonic {
         Ok(())
     }
 
-    #[allow(unused)]
-    pub async fn get_random_songs(&self) -> Result<Vec<Track>> {
+    pub async fn get_random_songs(&self, size: usize, filter: &RandomSongsFilter) -> Result<Vec<Track>> {
         #[derive(Deserialize, Debug)]
         struct RandomSongs {
             #[serde(rename = "randomSongs")]
@@ -99,18 +406,192 @@ impl Subsonic {
 
         #[derive(Deserialize, Debug)]
         struct Tracks {
-            #[serde(rename = "song")]
+            #[serde(rename = "song", default)]
             tracks: Vec<Track>,
         }
 
-        Ok(self.call::<RandomSongs>("getRandomSongs", &[])
+        let size = size.to_string();
+        let from_year = filter.from_year.map(|year| year.to_string());
+        let to_year = filter.to_year.map(|year| year.to_string());
+
+        let mut params = vec![("size", size.as_str())];
+        params.extend(filter.genre.as_deref().map(|genre| ("genre", genre)));
+        params.extend(from_year.as_deref().map(|year| ("fromYear", year)));
+        params.extend(to_year.as_deref().map(|year| ("toYear", year)));
+
+        Ok(self.call::<RandomSongs>("getRandomSongs", &params)
             .await?
             .random_songs
             .tracks)
     }
 
-    pub fn base_url(&self) -> &Url {
-        &self.inner.base_url
+    /// songs similar to an artist's, including the artist's own
+    pub async fn get_similar_songs2(&self, id: &ArtistId, count: usize) -> Result<Vec<Track>> {
+        #[derive(Deserialize, Debug)]
+        struct SimilarSongs {
+            #[serde(rename = "similarSongs2")]
+            similar_songs: Tracks,
+        }
+
+        #[derive(Deserialize, Debug)]
+        struct Tracks {
+            #[serde(rename = "song", default)]
+            tracks: Vec<Track>,
+        }
+
+        let count = count.to_string();
+
+        Ok(self.call::<SimilarSongs>("getSimilarSongs2", &[("id", &id.0), ("count", &count)])
+            .await?
+            .similar_songs
+            .tracks)
+    }
+
+    /// time is when the track started playing. a submission of false
+    /// only updates "now playing"
+    pub async fn scrobble(&self, id: &TrackId, submission: bool, time: SystemTime) -> Result<()> {
+        let time = time.duration_since(UNIX_EPOCH)?.as_millis().to_string();
+        let submission = if submission { "true" } else { "false" };
+
+        self.call::<serde_json::Value>("scrobble", &[
+            ("id", &id.0),
+            ("submission", submission),
+            ("time", &time),
+        ]).await?;
+
+        Ok(())
+    }
+
+    pub async fn get_playlists(&self) -> Result<Vec<Playlist>> {
+        #[derive(Deserialize, Debug)]
+        struct GetPlaylists {
+            playlists: Playlists,
+        }
+
+        #[derive(Deserialize, Debug)]
+        struct Playlists {
+            #[serde(default)]
+            playlist: Vec<Playlist>,
+        }
+
+        Ok(self.call::<GetPlaylists>("getPlaylists", &[])
+            .await?
+            .playlists
+            .playlist)
+    }
+
+    pub async fn get_playlist(&self, id: &PlaylistId) -> Result<PlaylistWithTracks> {
+        #[derive(Deserialize, Debug)]
+        struct GetPlaylist {
+            playlist: PlaylistWithTracks,
+        }
+
+        Ok(self.call::<GetPlaylist>("getPlaylist", &[("id", &id.0)])
+            .await?
+            .playlist)
+    }
+
+    /// position is in milliseconds within the current track
+    pub async fn save_play_queue(&self, ids: &[TrackId], current: Option<&TrackId>, position: u64) -> Result<()> {
+        let position = position.to_string();
+
+        let mut params = ids.iter()
+            .map(|id| ("id", id.0.as_str()))
+            .collect::<Vec<_>>();
+
+        if let Some(current) = current {
+            params.push(("current", &current.0));
+            params.push(("position", &position));
+        }
+
+        self.call::<serde_json::Value>("savePlayQueue", &params).await?;
+        Ok(())
+    }
+
+    /// returns an empty play queue if none has been saved
+    pub async fn get_play_queue(&self) -> Result<PlayQueue> {
+        #[derive(Deserialize, Debug)]
+        struct GetPlayQueue {
+            #[serde(rename = "playQueue", default)]
+            play_queue: PlayQueue,
+        }
+
+        Ok(self.call::<GetPlayQueue>("getPlayQueue", &[])
+            .await?
+            .play_queue)
+    }
+
+    pub async fn search3(&self, query: &str, artists: SearchPage, albums: SearchPage, tracks: SearchPage) -> Result<SearchResult> {
+        #[derive(Deserialize, Debug)]
+        struct Search3 {
+            #[serde(rename = "searchResult3", default)]
+            result: SearchResult,
+        }
+
+        let params = [
+            ("artistCount", artists.count),
+            ("artistOffset", artists.offset),
+            ("albumCount", albums.count),
+            ("albumOffset", albums.offset),
+            ("songCount", tracks.count),
+            ("songOffset", tracks.offset),
+        ].map(|(name, value)| (name, value.to_string()));
+
+        let mut params = params.iter()
+            .map(|(name, value)| (*name, value.as_str()))
+            .collect::<Vec<_>>();
+
+        params.push(("query", query));
+
+        Ok(self.call::<Search3>("search3", &params).await?.result)
+    }
+
+    /// position is in milliseconds, replacing any existing bookmark for id
+    pub async fn create_bookmark(&self, id: &TrackId, position: u64) -> Result<()> {
+        let position = position.to_string();
+
+        self.call::<serde_json::Value>("createBookmark", &[
+            ("id", &id.0),
+            ("position", &position),
+        ]).await?;
+
+        Ok(())
+    }
+
+    pub async fn get_bookmarks(&self) -> Result<Vec<Bookmark>> {
+        #[derive(Deserialize, Debug)]
+        struct GetBookmarks {
+            bookmarks: Bookmarks,
+        }
+
+        #[derive(Deserialize, Debug)]
+        struct Bookmarks {
+            #[serde(default)]
+            bookmark: Vec<Bookmark>,
+        }
+
+        Ok(self.call::<GetBookmarks>("getBookmarks", &[])
+            .await?
+            .bookmarks
+            .bookmark)
+    }
+
+    pub async fn delete_bookmark(&self, id: &TrackId) -> Result<()> {
+        self.call::<serde_json::Value>("deleteBookmark", &[("id", &id.0)]).await?;
+        Ok(())
+    }
+
+    pub fn auth(&self) -> &Arc<AuthParams> {
+        &self.auth
+    }
+
+    pub fn stream_quality(&self) -> StreamQuality {
+        let Some(user) = self.auth.username() else { return StreamQuality::default() };
+
+        self.inner.stream_quality.lock().unwrap()
+            .get(user)
+            .cloned()
+            .unwrap_or_default()
     }
 
     pub async fn get_track(&self, id: &TrackId) -> Result<Track> {
@@ -124,6 +605,79 @@ impl Subsonic {
             .song)
     }
 
+    pub async fn get_album(&self, id: &AlbumId) -> Result<Vec<Track>> {
+        #[derive(Deserialize, Debug)]
+        struct GetAlbum {
+            album: Album,
+        }
+
+        #[derive(Deserialize, Debug)]
+        struct Album {
+            #[serde(default)]
+            song: Vec<Track>,
+        }
+
+        Ok(self.call::<GetAlbum>("getAlbum", &[("id", &id.0)])
+            .await?
+            .album
+            .song)
+    }
+
+    /// getTopSongs takes an artist name rather than id, so this looks the
+    /// artist up first
+    pub async fn get_top_songs(&self, id: &ArtistId) -> Result<Vec<Track>> {
+        #[derive(Deserialize, Debug)]
+        struct GetArtist {
+            artist: Artist,
+        }
+
+        #[derive(Deserialize, Debug)]
+        struct Artist {
+            name: String,
+        }
+
+        #[derive(Deserialize, Debug)]
+        struct GetTopSongs {
+            #[serde(rename = "topSongs")]
+            top_songs: TopSongs,
+        }
+
+        #[derive(Deserialize, Debug)]
+        struct TopSongs {
+            #[serde(default)]
+            song: Vec<Track>,
+        }
+
+        let artist = self.call::<GetArtist>("getArtist", &[("id", &id.0)])
+            .await?
+            .artist;
+
+        Ok(self.call::<GetTopSongs>("getTopSongs", &[("artist", &artist.name)])
+            .await?
+            .top_songs
+            .song)
+    }
+
+    /// OpenSubsonic extension, returns one entry per language/variant
+    pub async fn get_lyrics(&self, id: &TrackId) -> Result<Vec<StructuredLyrics>> {
+        #[derive(Deserialize, Debug)]
+        struct GetLyrics {
+            #[serde(rename = "lyricsList")]
+            lyrics_list: LyricsList,
+        }
+
+        #[derive(Deserialize, Debug)]
+        struct LyricsList {
+            #[serde(rename = "structuredLyrics", default)]
+            structured_lyrics: Vec<StructuredLyrics>,
+        }
+
+        Ok(self.call::<GetLyrics>("getLyricsBySongId", &[("id", &id.0)])
+            .await?
+            .lyrics_list
+            .structured_lyrics)
+    }
+
     pub async fn get_radio_stations(&self) -> Result<Vec<RadioStation>> {
         #[derive(Deserialize, Debug)]
         struct Stations {
@@ -143,24 +697,84 @@ impl Subsonic {
             .station)
     }
 
+    pub async fn create_radio_station(&self, name: &str, stream_url: &Url, homepage_url: Option<&str>) -> Result<()> {
+        let mut params = vec![("name", name), ("streamUrl", stream_url.as_str())];
+        params.extend(homepage_url.map(|url| ("homepageUrl", url)));
+
+        self.call::<serde_json::Value>("createInternetRadioStation", &params).await?;
+        Ok(())
+    }
+
+    pub async fn update_radio_station(&self, id: &RadioId, name: &str, stream_url: &Url, homepage_url: Option<&str>) -> Result<()> {
+        let mut params = vec![("id", id.0.as_str()), ("name", name), ("streamUrl", stream_url.as_str())];
+        params.extend(homepage_url.map(|url| ("homepageUrl", url)));
+
+        self.call::<serde_json::Value>("updateInternetRadioStation", &params).await?;
+        Ok(())
+    }
+
+    pub async fn delete_radio_station(&self, id: &RadioId) -> Result<()> {
+        self.call::<serde_json::Value>("deleteInternetRadioStation", &[("id", &id.0)]).await?;
+        Ok(())
+    }
+
+    /// controls playback on the server's own audio device, see the jukebox
+    /// backend. every action besides get responds with the jukebox status
+    pub async fn jukebox_control(&self, action: &str, params: &[(&str, &str)]) -> Result<JukeboxStatus> {
+        #[derive(Deserialize, Debug)]
+        struct Status {
+            #[serde(rename = "jukeboxStatus")]
+            status: JukeboxStatus,
+        }
+
+        let mut params = params.to_vec();
+        params.push(("action", action));
+
+        Ok(self.call::<Status>("jukeboxControl", &params).await?.status)
+    }
+
+    pub async fn jukebox_playlist(&self) -> Result<JukeboxPlaylist> {
+        #[derive(Deserialize, Debug)]
+        struct Playlist {
+            #[serde(rename = "jukeboxPlaylist")]
+            playlist: JukeboxPlaylist,
+        }
+
+        Ok(self.call::<Playlist>("jukeboxControl", &[("action", "get")]).await?.playlist)
+    }
+
+    /// with the user's stream quality, if they've set one
     pub fn stream_url(&self, id: &TrackId) -> Result<Url> {
+        let quality = self.stream_quality();
+
+        if let Some(proxy) = &self.inner.stream_proxy {
+            let key = proxy.key(id, &self.auth);
+            return Ok(proxy.stream_url(id, &key, &quality));
+        }
+
         let req = self
             .request(Method::GET, "rest/stream")
-            .query(&[("id", &id.0)]);
+            .query(&[("id", &id.0)])
+            .query(&quality.query());
 
         Ok(req.build()?.url().clone())
     }
 
-    pub fn track_id_from_stream_url(&self, url: &Url) -> Option<TrackId> {
-        if self.base_url().origin() != url.origin() {
-            return None;
-        }
+    pub async fn get_cover_art(&self, id: &CoverArtId, size: u32) -> Result<Vec<u8>> {
+        let response = self
+            .request(Method::GET, "rest/getCoverArt")
+            .query(&[("id", id.0.as_str()), ("size", &size.to_string())])
+            .send().await?
+            .error_for_status()?;
+
+        Ok(response.bytes().await?.to_vec())
+    }
 
-        url.query_pairs()
-            .find(|(name, _)| name == "id")
-            .map(|(_, value)| TrackId(value.to_string()))
+    pub fn track_id_from_stream_url(&self, url: &Url) -> Option<TrackId> {
+        self.inner.track_id_from_stream_url(url)
     }
 
+    #[tracing::instrument(level = "trace", name = "subsonic", skip(self, params))]
     pub async fn call<T>(&self, method: &str, params: &[(&str, &str)]) -> Result<T>
         where T: serde::de::DeserializeOwned
     {
@@ -177,14 +791,38 @@ impl Subsonic {
             Err { error: SubsonicError }
         }
 
-        let request = self.request(Method::GET, &format!("rest/{method}"))
+        let mut request = self.request(Method::GET, &format!("rest/{method}"))
             .query(params)
             .build()?;
 
-        let response = self.inner.client.execute(request).await?;
-        response.error_for_status_ref()?;
+        let cache_key = cache::is_cacheable(method)
+            .then(|| self.inner.auth_hasher.hash_one(request.url().as_str()));
 
-        let text = response.text().await?;
+        let cached = cache_key.and_then(|key| self.inner.cache.get(key));
+
+        // headers of a response to cache once it's known to be a success
+        let mut fetched = None;
+
+        let text = match (cache_key, cached) {
+            (Some(_), Some(entry)) if entry.is_fresh() => entry.body.clone(),
+            (Some(key), Some(entry)) => {
+                entry.conditional(request.headers_mut());
+                let response = self.send(request).await?;
+
+                if response.status() == StatusCode::NOT_MODIFIED {
+                    self.inner.cache.revalidated(key, &entry);
+                    entry.body.clone()
+                } else {
+                    fetched = Some(response.headers().clone());
+                    response.text().await?
+                }
+            }
+            _ => {
+                let response = self.send(request).await?;
+                fetched = Some(response.headers().clone());
+                response.text().await?
+            }
+        };
 
         let root = serde_json::from_str::<RootResponse<T>>(&text)
             .map_err(anyhow::Error::from)
@@ -196,11 +834,45 @@ impl Subsonic {
             })?;
 
         match root.response {
-            SubsonicResponse::Ok(data) => Ok(data),
+            SubsonicResponse::Ok(data) => {
+                if let (Some(key), Some(headers)) = (cache_key, fetched) {
+                    self.inner.cache.insert(key, method, text, &headers);
+                }
+
+                self.inner.cache.invalidate(method);
+                Ok(data)
+            }
             SubsonicResponse::Err { error } => Err(error.into()),
         }
     }
 
+    // sends a request unless subsonic is known to be down, keeping track of
+    // whether it is
+    async fn send(&self, request: reqwest::Request) -> Result<reqwest::Response> {
+        self.inner.breaker.check()?;
+
+        let response = self.inner.client.execute(request).await
+            .and_then(|response| response.error_for_status());
+
+        // subsonic reports its own errors with a 200, so no response or a
+        // 5xx means it's down or something in front of it is failing
+        let reachable = match &response {
+            Ok(_) => true,
+            Err(err) => err.status().is_some_and(|status| !status.is_server_error()),
+        };
+
+        self.inner.set_reachable(reachable);
+
+        if reachable {
+            self.inner.breaker.success();
+        } else if self.inner.breaker.failure() {
+            log::warn!("subsonic is failing, failing calls fast until it recovers");
+            tokio::spawn(probe(self.inner.clone()));
+        }
+
+        Ok(response?)
+    }
+
     fn request(&self, method: reqwest::Method, path: &str) -> reqwest::RequestBuilder {
         let url = self.inner.base_url.join(path).unwrap();
 
@@ -0,0 +1,53 @@
+use std::sync::Mutex;
+
+use thiserror::Error;
+
+// consecutive failed calls before calls stop being made at all
+const FAILURE_THRESHOLD: u32 = 5;
+
+/// stops calls to subsonic once it's down, so that every event needing
+/// something from it doesn't sit through a doomed request first. once open,
+/// calls fail straight away until a probe finds subsonic back up
+#[derive(Default)]
+pub struct Breaker {
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    failures: u32,
+    open: bool,
+}
+
+#[derive(Debug, Error)]
+#[error("subsonic is unreachable, not calling it until it recovers")]
+pub struct CircuitOpen;
+
+impl Breaker {
+    pub fn check(&self) -> Result<(), CircuitOpen> {
+        match self.state.lock().unwrap().open {
+            true => Err(CircuitOpen),
+            false => Ok(()),
+        }
+    }
+
+    /// true if this failure opened the circuit, in which case the caller is
+    /// the one to start probing
+    pub fn failure(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.failures += 1;
+
+        if state.open || state.failures < FAILURE_THRESHOLD {
+            return false;
+        }
+
+        state.open = true;
+        true
+    }
+
+    pub fn success(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.failures = 0;
+        state.open = false;
+    }
+}
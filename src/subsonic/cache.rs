@@ -0,0 +1,124 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use reqwest::header::{self, HeaderMap, HeaderValue};
+
+// how long a response is used without asking subsonic again. after that
+// it's revalidated if subsonic gave an etag or last modified, else refetched
+const TTL: Duration = Duration::from_secs(30);
+
+// responses not revalidated in this long are dropped to make room
+const MAX_AGE: Duration = Duration::from_secs(10 * 60);
+
+const MAX_ENTRIES: usize = 10_000;
+
+/// calls whose responses are cached. reads which come up every time the
+/// queue is refreshed, and change rarely enough that being a little out of
+/// date doesn't matter
+const CACHEABLE: &[&str] = &[
+    "getSong",
+    "getAlbum",
+    "getPlaylists",
+    "getInternetRadioStations",
+    "getLyricsBySongId",
+];
+
+/// what each call we make ourselves leaves out of date
+const INVALIDATES: &[(&str, &str)] = &[
+    ("createInternetRadioStation", "getInternetRadioStations"),
+    ("updateInternetRadioStation", "getInternetRadioStations"),
+    ("deleteInternetRadioStation", "getInternetRadioStations"),
+];
+
+pub fn is_cacheable(method: &str) -> bool {
+    CACHEABLE.contains(&method)
+}
+
+/// successful responses to cacheable calls, by a hash of their url, which
+/// includes the credentials so that users only see their own
+#[derive(Default)]
+pub struct ResponseCache {
+    entries: Mutex<HashMap<u64, Arc<Entry>>>,
+}
+
+pub struct Entry {
+    method: String,
+    pub body: String,
+    etag: Option<HeaderValue>,
+    last_modified: Option<HeaderValue>,
+    fetched: Instant,
+}
+
+impl Entry {
+    pub fn is_fresh(&self) -> bool {
+        self.fetched.elapsed() < TTL
+    }
+
+    /// turns the request into one subsonic can answer with not modified
+    pub fn conditional(&self, headers: &mut HeaderMap) {
+        if let Some(etag) = &self.etag {
+            headers.insert(header::IF_NONE_MATCH, etag.clone());
+        }
+
+        if let Some(last_modified) = &self.last_modified {
+            headers.insert(header::IF_MODIFIED_SINCE, last_modified.clone());
+        }
+    }
+}
+
+impl ResponseCache {
+    pub fn get(&self, key: u64) -> Option<Arc<Entry>> {
+        self.entries.lock().unwrap().get(&key).cloned()
+    }
+
+    pub fn insert(&self, key: u64, method: &str, body: String, headers: &HeaderMap) {
+        let entry = Entry {
+            method: method.to_string(),
+            body,
+            etag: headers.get(header::ETAG).cloned(),
+            last_modified: headers.get(header::LAST_MODIFIED).cloned(),
+            fetched: Instant::now(),
+        };
+
+        let mut entries = self.entries.lock().unwrap();
+
+        if entries.len() >= MAX_ENTRIES {
+            entries.retain(|_, entry| entry.fetched.elapsed() < MAX_AGE);
+        }
+
+        if entries.len() >= MAX_ENTRIES {
+            entries.clear();
+        }
+
+        entries.insert(key, Arc::new(entry));
+    }
+
+    /// subsonic said the entry is still current, so it's fresh again
+    pub fn revalidated(&self, key: u64, entry: &Entry) {
+        let entry = Entry {
+            method: entry.method.clone(),
+            body: entry.body.clone(),
+            etag: entry.etag.clone(),
+            last_modified: entry.last_modified.clone(),
+            fetched: Instant::now(),
+        };
+
+        self.entries.lock().unwrap().insert(key, Arc::new(entry));
+    }
+
+    /// drops whatever a successful call to method leaves out of date
+    pub fn invalidate(&self, method: &str) {
+        let stale = INVALIDATES.iter()
+            .filter(|(call, _)| *call == method)
+            .map(|(_, stale)| *stale)
+            .collect::<Vec<_>>();
+
+        if stale.is_empty() {
+            return;
+        }
+
+        self.entries.lock().unwrap()
+            .retain(|_, entry| !stale.contains(&entry.method.as_str()));
+    }
+}
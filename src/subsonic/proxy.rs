@@ -0,0 +1,146 @@
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::Engine;
+use reqwest::Url;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+
+use super::{AuthParams, StreamQuality};
+use super::types::TrackId;
+
+// keys stop working this long after they're made, so a url which escapes
+// mpd's queue isn't good forever. tracks queued for longer than this have
+// to be queued again
+const KEY_LIFETIME: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// where mpd is sent for streams instead of subsonic, see player::stream
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// sonicast's own address as mpd reaches it, eg. http://127.0.0.1:8080/
+    pub url: Url,
+    /// keys are sealed with this, so that they're still good after sonicast
+    /// restarts. made up on startup if not configured
+    pub secret: Option<String>,
+    /// without a timeout for the whole request, since streams last as long
+    /// as their track
+    pub http: reqwest::Client,
+}
+
+/// stream urls point at sonicast, with a key standing in for the
+/// credentials of whoever queued the track, so that they stay out of mpd's
+/// queue and logs. keys are the credentials themselves, encrypted along
+/// with the track id and an expiry, so nothing needs remembering
+pub struct StreamProxy {
+    pub config: Config,
+    key: LessSafeKey,
+    rng: SystemRandom,
+}
+
+/// what a key stands for
+#[derive(Serialize, Deserialize)]
+struct Claims {
+    auth: Arc<AuthParams>,
+    id: String,
+    /// seconds since the unix epoch
+    expires: u64,
+}
+
+impl StreamProxy {
+    pub fn new(config: Config) -> Self {
+        let rng = SystemRandom::new();
+
+        let secret = match &config.secret {
+            Some(secret) => secret.as_bytes().to_vec(),
+            None => {
+                log::warn!("STREAM_PROXY_SECRET not set, queued streams won't play after sonicast restarts");
+                let mut secret = [0; 32];
+                // only fails where the os has no randomness to give
+                rng.fill(&mut secret).expect("generating stream proxy secret");
+                secret.to_vec()
+            }
+        };
+
+        // derived rather than used directly, since the secret can be any
+        // length
+        let derived = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, &secret), b"sonicast stream key");
+        let key = UnboundKey::new(&CHACHA20_POLY1305, derived.as_ref())
+            .expect("hmac-sha256 makes keys of the right length");
+
+        StreamProxy { config, key: LessSafeKey::new(key), rng }
+    }
+
+    /// a key for streaming the track with auth
+    pub fn key(&self, id: &TrackId, auth: &Arc<AuthParams>) -> String {
+        let claims = Claims {
+            auth: auth.clone(),
+            id: id.0.clone(),
+            expires: (now() + KEY_LIFETIME).as_secs(),
+        };
+
+        let mut nonce = [0; NONCE_LEN];
+        // only fails where the os has no randomness to give
+        self.rng.fill(&mut nonce).expect("generating stream key");
+
+        let mut sealed = rmp_serde::to_vec(&claims).expect("serializing stream key");
+        self.key.seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::empty(), &mut sealed)
+            .expect("sealing stream key");
+
+        let mut bytes = nonce.to_vec();
+        bytes.extend(sealed);
+        base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(bytes)
+    }
+
+    /// the credentials key stands for, None unless we made it for this
+    /// track and it hasn't expired
+    pub fn auth(&self, key: &str, id: &TrackId) -> Option<Arc<AuthParams>> {
+        let bytes = base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(key).ok()?;
+        let (nonce, sealed) = bytes.split_at_checked(NONCE_LEN)?;
+
+        let nonce = Nonce::try_assume_unique_for_key(nonce).ok()?;
+        let mut sealed = sealed.to_vec();
+        let claims = self.key.open_in_place(nonce, Aad::empty(), &mut sealed).ok()?;
+        let claims: Claims = rmp_serde::from_slice(claims).ok()?;
+
+        if claims.id != id.0 || claims.expires < now().as_secs() {
+            return None;
+        }
+
+        Some(claims.auth)
+    }
+
+    pub fn stream_url(&self, id: &TrackId, key: &str, quality: &StreamQuality) -> Url {
+        let mut url = self.config.url.clone();
+
+        // checked to be a base when read from config
+        url.path_segments_mut()
+            .expect("stream proxy url is a base")
+            .pop_if_empty()
+            .push("stream")
+            .push(&id.0);
+
+        url.query_pairs_mut()
+            .append_pair("key", key)
+            .extend_pairs(quality.query());
+
+        url
+    }
+
+    pub fn track_id(&self, url: &Url) -> Option<TrackId> {
+        if self.config.url.origin() != url.origin() {
+            return None;
+        }
+
+        let base = self.config.url.path().trim_end_matches('/');
+        let id = url.path().strip_prefix(base)?.strip_prefix("/stream/")?;
+        let id = percent_encoding::percent_decode_str(id).decode_utf8().ok()?;
+
+        Some(TrackId(id.into_owned()))
+    }
+}
+
+fn now() -> Duration {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default()
+}
@@ -8,7 +8,7 @@ pub struct Track {
     pub details: TrackDetails,
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct TrackDetails {
     pub artist: Option<String>,
     pub title: Option<String>,
@@ -36,14 +36,24 @@ pub struct TrackDetails {
     pub replay_gain: Option<serde_json::Value>,
     #[serde(rename = "streamUrl", skip_serializing_if = "Option::is_none")]
     pub stream_url: Option<Url>,
+    /// recording mbid, from opensubsonic servers
+    #[serde(rename = "musicBrainzId", skip_serializing_if = "Option::is_none")]
+    pub music_brainz_id: Option<String>,
+    /// 1-5 stars
+    #[serde(rename = "userRating", skip_serializing_if = "Option::is_none")]
+    pub user_rating: Option<u8>,
+    /// when the user last played the track, from opensubsonic servers
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub played: Option<String>,
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct TrackArtist {
     pub name: String,
     pub id: ArtistId,
 }
 
+#[allow(unused)]
 #[derive(Deserialize, Serialize, Debug)]
 pub struct ReplayGain {
     #[serde(rename = "trackGain")]
@@ -71,6 +81,149 @@ pub struct ArtistId(pub String);
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct CoverArtId(pub String);
 
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct PlaylistId(pub String);
+
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Playlist {
+    pub id: PlaylistId,
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+    pub song_count: usize,
+    pub duration: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cover_art: Option<CoverArtId>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Artist {
+    pub id: ArtistId,
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cover_art: Option<CoverArtId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub album_count: Option<usize>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Album {
+    pub id: AlbumId,
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub artist: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub artist_id: Option<ArtistId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cover_art: Option<CoverArtId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub song_count: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub year: Option<u32>,
+}
+
+/// which songs getRandomSongs picks from, unset fields don't filter
+#[derive(Debug, Clone, Default)]
+pub struct RandomSongsFilter {
+    pub genre: Option<String>,
+    pub from_year: Option<u32>,
+    pub to_year: Option<u32>,
+}
+
+/// results of search3, each kind paged separately
+#[derive(Deserialize, Debug, Default)]
+pub struct SearchResult {
+    #[serde(rename = "artist", default)]
+    pub artists: Vec<Artist>,
+    #[serde(rename = "album", default)]
+    pub albums: Vec<Album>,
+    #[serde(rename = "song", default)]
+    pub tracks: Vec<Track>,
+}
+
+/// how many of one kind of search result to return, after skipping offset
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SearchPage {
+    pub count: usize,
+    pub offset: usize,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PlaylistWithTracks {
+    #[serde(flatten)]
+    pub playlist: Playlist,
+    #[serde(rename = "entry", default)]
+    pub tracks: Vec<Track>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct StructuredLyrics {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub display_artist: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub display_title: Option<String>,
+    pub lang: String,
+    pub synced: bool,
+    /// offset in milliseconds to apply to line start times
+    #[serde(default)]
+    pub offset: i64,
+    #[serde(rename = "line", default)]
+    pub lines: Vec<LyricLine>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct LyricLine {
+    /// start time in milliseconds, only present for synced lyrics
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub start: Option<u64>,
+    pub value: String,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct PlayQueue {
+    #[serde(rename = "entry", default)]
+    pub tracks: Vec<Track>,
+    pub current: Option<TrackId>,
+    /// position within the current track in milliseconds
+    pub position: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub struct JukeboxStatus {
+    /// -1 when there's nothing to play
+    pub current_index: i64,
+    pub playing: bool,
+    /// volume, 0-1
+    pub gain: f64,
+    /// position within the current track in seconds
+    #[serde(default)]
+    pub position: u64,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct JukeboxPlaylist {
+    #[serde(flatten)]
+    pub status: JukeboxStatus,
+    #[serde(rename = "entry", default)]
+    pub tracks: Vec<Track>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Bookmark {
+    /// milliseconds into the track
+    pub position: u64,
+    pub entry: Track,
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct RadioStation {
     pub id: RadioId,